@@ -8,8 +8,7 @@ use ogma_db::common::{
 fn main() {
     if let Ok(mut buf_sock) = connect("127.0.0.1:7971") {
         println!("Connected to the server!");
-        let example_query =
-            RequestType::Query(String::from("The actual contents don't matter yet!"));
+        let example_query = RequestType::Query(String::from("SELECT * FROM currency"));
         buf_sock
             .send(&example_query)
             .expect("Blew up while sending...");
@@ -18,14 +17,25 @@ fn main() {
 
         match example_response {
             ResponseType::Error(_) => todo!(),
-            ResponseType::QueryHandle { qid, .. } => {
+            ResponseType::QueryHandle { qid, .. } => loop {
                 buf_sock
-                    .send(&RequestType::More(qid))
+                    .send(&RequestType::More {
+                        qid,
+                        batch_size: 100,
+                    })
                     .expect("Blew up while sending...");
                 let another_response = buf_sock.receive().expect("Blew up while receiving...");
                 println!("Response: {:?} -- was received", another_response);
-            }
+                if !matches!(another_response, ResponseType::Data(_)) {
+                    break;
+                }
+            },
             ResponseType::Data(_) => todo!(),
+            ResponseType::Inserted { count, ids } => {
+                println!("Inserted {} rows with ids {:?}", count, ids)
+            }
+            ResponseType::Affected(count) => println!("Affected {} rows", count),
+            ResponseType::Done => println!("Done"),
             ResponseType::Empty => todo!(),
         }
     } else {
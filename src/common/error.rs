@@ -15,6 +15,12 @@ pub enum Error {
     PathError(String),
     SerdeError(serde_json::Error),
     SchemaError(String),
+    ParseError(sqlparser::parser::ParserError),
+    // The SQL parsed fine, but asks for something we can't do (yet)
+    QueryError(String),
+    // Waiting for a lock would have waited forever, so the statement was
+    // given up on, along with its transaction if it had one
+    Deadlock(String),
     // An error occurred trying to report an error...
     MetaError(Box<Error>),
     // StringForm exists for client deserialization, since we can't guarantee
@@ -29,6 +35,9 @@ impl Display for Error {
             Error::PathError(err) => write!(f, "{err}"),
             Error::SerdeError(err) => write!(f, "{err}"),
             Error::SchemaError(err) => write!(f, "{err}"),
+            Error::ParseError(err) => write!(f, "{err}"),
+            Error::QueryError(err) => write!(f, "{err}"),
+            Error::Deadlock(err) => write!(f, "{err}"),
             Error::MetaError(err) => write!(f, "{err}"),
             Error::StringForm(err) => write!(f, "{err}"),
         }
@@ -88,3 +97,9 @@ impl From<serde_json::Error> for Error {
         Error::SerdeError(value)
     }
 }
+
+impl From<sqlparser::parser::ParserError> for Error {
+    fn from(value: sqlparser::parser::ParserError) -> Self {
+        Error::ParseError(value)
+    }
+}
@@ -1,10 +1,13 @@
 // STD Imports
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 
 // Third party library imports
 use byteorder::{ByteOrder, LE};
 use serde::{Deserialize, Serialize};
 
+use error::Error;
+
 pub mod error;
 pub mod network;
 
@@ -17,18 +20,18 @@ pub const COLUMN_WIDTH: usize = 8;
 pub enum ColumnType {
     Integer, // i64
     Boolean,
-    Text, // for short strings
+    Text, // UTF-8 strings of any length
     Clob, // for long strings
     Blob, // for any size binary data
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum DataType {
     Integer(i64),
     Boolean(bool),
-    Text([char; 8]),
-    ClobRef(u64),
-    BlobRef(u64),
+    Text(String),
+    Clob(String),
+    Blob(Vec<u8>),
 }
 
 pub type Row = Vec<DataType>;
@@ -51,26 +54,127 @@ pub fn map_table_info(table_info: &TableInfo) -> TableInfoMap {
     table_map
 }
 
-pub fn convert_field(field: u64, to_type: &ColumnType) -> DataType {
-    match to_type {
+/// Keeps the values too big for a field somewhere else, handing back a
+/// reference to find them by.
+pub trait Overflow {
+    fn read_text(&self, reference: u64) -> Result<String, Error>;
+    fn write_text(&self, text: &str) -> Result<u64, Error>;
+    // The contents of Clob and Blob fields. An empty field is stored as 0,
+    // which is never handed out as a reference.
+    fn read_object(&self, reference: u64) -> Result<Vec<u8>, Error>;
+    fn write_object(&self, bytes: &[u8]) -> Result<u64, Error>;
+}
+
+// Text fields hold up to 8 bytes of UTF-8 in place, padded with zeros.
+// Longer text overflows, and its field holds the reference to it, marked
+// with a last byte UTF-8 never uses.
+const OVERFLOW_MARK: u64 = 0xFF << 56;
+
+/// The field holding text in place, if it fits.
+pub fn inline_text(text: &str) -> Option<u64> {
+    (text.len() <= COLUMN_WIDTH && !text.contains('\0')).then(|| {
+        let mut bytes = [0u8; COLUMN_WIDTH];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        LE::read_u64(&bytes)
+    })
+}
+
+/// Where the text of a field overflowed to, if it's not held in place.
+pub fn overflow_reference(field: u64) -> Option<u64> {
+    (field & OVERFLOW_MARK == OVERFLOW_MARK).then_some(field & !OVERFLOW_MARK)
+}
+
+pub fn convert_field(
+    field: u64,
+    to_type: &ColumnType,
+    overflow: &dyn Overflow,
+) -> Result<DataType, Error> {
+    Ok(match to_type {
         ColumnType::Integer => DataType::Integer(field as i64),
         ColumnType::Boolean => DataType::Boolean(field != 0),
-        ColumnType::Text => DataType::Text(field.to_le_bytes().map(|byte| byte as char)),
-        ColumnType::Clob => DataType::ClobRef(field),
-        ColumnType::Blob => DataType::BlobRef(field),
+        ColumnType::Text => DataType::Text(match overflow_reference(field) {
+            Some(reference) => overflow.read_text(reference)?,
+            None => {
+                let bytes = field.to_le_bytes();
+                let length = bytes
+                    .iter()
+                    .position(|byte| *byte == 0)
+                    .unwrap_or(bytes.len());
+                String::from_utf8_lossy(&bytes[..length]).into_owned()
+            }
+        }),
+        ColumnType::Clob => {
+            let bytes = read_object(field, overflow)?;
+            DataType::Clob(String::from_utf8(bytes).map_err(|err| {
+                Error::SchemaError(format!("Clob at {} isn't UTF-8: {}", field, err))
+            })?)
+        }
+        ColumnType::Blob => DataType::Blob(read_object(field, overflow)?),
+    })
+}
+
+fn read_object(field: u64, overflow: &dyn Overflow) -> Result<Vec<u8>, Error> {
+    match field {
+        0 => Ok(Vec::new()),
+        reference => overflow.read_object(reference),
+    }
+}
+
+fn write_object(bytes: &[u8], overflow: &dyn Overflow) -> Result<u64, Error> {
+    match bytes {
+        [] => Ok(0),
+        bytes => overflow.write_object(bytes),
     }
 }
 
-pub fn convert_row(raw_row: RawRow, table_info: &TableInfo) -> Row {
+pub fn encode_field(field: &DataType, overflow: &dyn Overflow) -> Result<u64, Error> {
+    Ok(match field {
+        DataType::Integer(value) => *value as u64,
+        DataType::Boolean(value) => *value as u64,
+        DataType::Text(value) => match inline_text(value) {
+            Some(field) => field,
+            None => OVERFLOW_MARK | overflow.write_text(value)?,
+        },
+        DataType::Clob(text) => write_object(text.as_bytes(), overflow)?,
+        DataType::Blob(bytes) => write_object(bytes, overflow)?,
+    })
+}
+
+pub fn convert_row(
+    raw_row: RawRow,
+    table_info: &TableInfo,
+    overflow: &dyn Overflow,
+) -> Result<Row, Error> {
     raw_row
         .iter()
         .zip(table_info.iter())
-        .map(|(field, (_, to_type))| convert_field(field.to_owned(), to_type))
+        .map(|(field, (_, to_type))| convert_field(field.to_owned(), to_type, overflow))
         .collect()
 }
 
-pub fn convert_row_field(raw_row: &RawRow, to_type: &ColumnType, offset: u64) -> Option<DataType> {
-    Some(convert_field(*raw_row.get(offset as usize)?, to_type))
+pub fn convert_row_field(
+    raw_row: &RawRow,
+    to_type: &ColumnType,
+    offset: u64,
+    overflow: &dyn Overflow,
+) -> Option<Result<DataType, Error>> {
+    Some(convert_field(
+        *raw_row.get(offset as usize)?,
+        to_type,
+        overflow,
+    ))
+}
+
+impl DataType {
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            DataType::Integer(_) => ColumnType::Integer,
+            DataType::Boolean(_) => ColumnType::Boolean,
+            DataType::Text(_) => ColumnType::Text,
+            DataType::Clob(_) => ColumnType::Clob,
+            DataType::Blob(_) => ColumnType::Blob,
+        }
+    }
 }
 
 impl PartialEq for DataType {
@@ -79,21 +183,36 @@ impl PartialEq for DataType {
             (Self::Integer(l0), Self::Integer(r0)) => l0 == r0,
             (Self::Boolean(l0), Self::Boolean(r0)) => l0 == r0,
             (Self::Text(l0), Self::Text(r0)) => l0 == r0,
-            (Self::ClobRef(l0), Self::ClobRef(r0)) => l0 == r0,
-            (Self::BlobRef(l0), Self::BlobRef(r0)) => l0 == r0,
+            (Self::Clob(l0), Self::Clob(r0)) => l0 == r0,
+            (Self::Blob(l0), Self::Blob(r0)) => l0 == r0,
             _ => false,
         }
     }
 }
 
+impl Eq for DataType {}
+
+impl Hash for DataType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Self::Integer(value) => value.hash(state),
+            Self::Boolean(value) => value.hash(state),
+            Self::Text(value) => value.hash(state),
+            Self::Clob(value) => value.hash(state),
+            Self::Blob(value) => value.hash(state),
+        }
+    }
+}
+
 impl PartialOrd for DataType {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
             (Self::Integer(l0), Self::Integer(r0)) => l0.partial_cmp(r0),
             (Self::Boolean(l0), Self::Boolean(r0)) => l0.partial_cmp(r0),
             (Self::Text(l0), Self::Text(r0)) => l0.partial_cmp(r0),
-            (Self::ClobRef(l0), Self::ClobRef(r0)) => l0.partial_cmp(r0),
-            (Self::BlobRef(l0), Self::BlobRef(r0)) => l0.partial_cmp(r0),
+            (Self::Clob(l0), Self::Clob(r0)) => l0.partial_cmp(r0),
+            (Self::Blob(l0), Self::Blob(r0)) => l0.partial_cmp(r0),
             _ => None,
         }
     }
@@ -111,12 +230,41 @@ impl From<bool> for DataType {
     }
 }
 
-impl From<[char; 8]> for DataType {
-    fn from(value: [char; 8]) -> Self {
+impl From<&str> for DataType {
+    fn from(value: &str) -> Self {
+        Self::Text(value.to_owned())
+    }
+}
+
+impl From<String> for DataType {
+    fn from(value: String) -> Self {
         Self::Text(value)
     }
 }
 
+/// Reads the row in slot `slot` of a block laid out in rows of `columns` columns.
+pub fn read_row(block: &Block, slot: usize, columns: usize) -> RawRow {
+    let row_width = columns * COLUMN_WIDTH;
+    let start = slot * row_width;
+    block[start..start + row_width]
+        .chunks_exact(COLUMN_WIDTH)
+        .map(LE::read_u64)
+        .collect()
+}
+
+/// Writes `raw_row` over the row slot `slot` of a block laid out in rows of
+/// `raw_row.len()` columns.
+pub fn write_row(block: &mut Block, slot: usize, raw_row: &RawRow) {
+    let row_width = raw_row.len() * COLUMN_WIDTH;
+    let start = slot * row_width;
+    for (chunk, field) in block[start..start + row_width]
+        .chunks_exact_mut(COLUMN_WIDTH)
+        .zip(raw_row)
+    {
+        LE::write_u64(chunk, *field);
+    }
+}
+
 pub trait AsRawRows {
     fn as_rows(&self, columns: usize) -> Vec<RawRow>;
     fn as_filtered_rows<P>(&self, columns: usize, predicate: &mut P) -> Vec<RawRow>
@@ -159,6 +307,8 @@ impl AsRawRows for Vec<Block> {
 
 #[cfg(test)]
 mod tests {
+    use crate::test_utils::MemoryOverflow;
+
     use super::*;
 
     fn make_table_info() -> TableInfo {
@@ -189,16 +339,77 @@ mod tests {
     #[test]
     fn test_field_and_row_conversion() {
         let table_info = make_table_info();
-        let raw_row: RawRow = vec![1u64, 1u64, 0u64, 0u64, 64u64, 128u64];
+        let overflow = MemoryOverflow::default();
+        let clob = overflow.write_object(b"plenty").unwrap();
+        let blob = overflow.write_object(&[0, 255]).unwrap();
+        let raw_row: RawRow = vec![1u64, 1u64, 0u64, 0u64, clob, blob];
 
-        for item in convert_row(raw_row, &table_info) {
+        for item in convert_row(raw_row, &table_info, &overflow).unwrap() {
             match item {
                 DataType::Integer(val) => assert_eq!(val, 1i64),
-                DataType::Boolean(val) => assert_eq!(val, false),
-                DataType::Text(val) => assert_eq!(val, [0x0 as char; 8]),
-                DataType::ClobRef(val) => assert_eq!(val, 64u64),
-                DataType::BlobRef(val) => assert_eq!(val, 128u64),
+                DataType::Boolean(val) => assert!(!val),
+                DataType::Text(val) => assert_eq!(val, ""),
+                DataType::Clob(val) => assert_eq!(val, "plenty"),
+                DataType::Blob(val) => assert_eq!(val, vec![0, 255]),
             }
         }
     }
+
+    #[test]
+    fn test_write_row() {
+        let mut block = [0u8; BLOCK_SIZE];
+        write_row(&mut block, 0, &vec![1, 2, 3]);
+        write_row(&mut block, 2, &vec![7, 8, 9]);
+
+        assert_eq!(read_row(&block, 2, 3), vec![7, 8, 9]);
+        assert_eq!(read_row(&block, 1, 3), vec![0, 0, 0]);
+
+        let rows = block.as_rows(3);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], vec![1, 2, 3]);
+        assert_eq!(rows[1], vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn test_field_encoding_round_trip() {
+        let table_info = make_table_info();
+        let overflow = MemoryOverflow::default();
+        let word = LE::read_u64("bird\0\0\0\0".as_bytes());
+        let clob = overflow.write_object("plenty".as_bytes()).unwrap();
+        // An empty Blob has nothing to refer to
+        let raw_row: RawRow = vec![u64::MAX, 42u64, 1u64, word, clob, 0u64];
+
+        for (field, (_, to_type)) in raw_row.iter().zip(table_info.iter()) {
+            let converted = convert_field(*field, to_type, &overflow).unwrap();
+            assert_eq!(&converted.column_type(), to_type);
+            assert_eq!(encode_field(&converted, &overflow).unwrap(), *field);
+        }
+    }
+
+    #[test]
+    fn test_text_fields() {
+        let overflow = MemoryOverflow::default();
+        // Up to 8 bytes stay in place, however many characters they make
+        for text in ["", "bird", "abcdefgh", "día", "🐉🐉"] {
+            let field = encode_field(&DataType::from(text), &overflow).unwrap();
+            assert_eq!(Some(field), inline_text(text));
+            let converted = convert_field(field, &ColumnType::Text, &overflow).unwrap();
+            assert_eq!(converted, DataType::from(text));
+        }
+
+        // Longer text, or text with zeros in it, overflows
+        for text in ["abcdefghi", "Sir Lancelot du Lac", "🐉🐉🐉", "a\0b"] {
+            let field = encode_field(&DataType::from(text), &overflow).unwrap();
+            assert_eq!(inline_text(text), None);
+            assert!(overflow_reference(field).is_some());
+            let converted = convert_field(field, &ColumnType::Text, &overflow).unwrap();
+            assert_eq!(converted, DataType::from(text));
+        }
+
+        // Text compares by character, not by how it's stored
+        let text = |text: &str| DataType::from(text);
+        assert!(text("abc") < text("abcdefghijk"));
+        assert!(text("abcdefghijk") < text("abd"));
+        assert!(text("z") < text("é"));
+    }
 }
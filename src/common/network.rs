@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::common::error::Error;
 
-use super::{RawRow, TableInfoMap};
+use super::{Row, TableInfoMap};
 
 pub struct BufSocket {
     reader: BufReader<TcpStream>,
@@ -74,13 +74,17 @@ impl Server for BufSocket {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestType {
     Query(String),
-    More(u64),
+    // Asks for up to batch_size more rows of a running query
+    More { qid: u64, batch_size: u64 },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ResponseType {
     Error(Error),
     QueryHandle { schema: TableInfoMap, qid: u64 },
-    Data(Vec<RawRow>),
+    Data(Vec<Row>),
+    Inserted { count: u64, ids: Vec<u64> },
+    Affected(u64),
+    Done,
     Empty,
 }
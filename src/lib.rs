@@ -20,11 +20,18 @@ mod tests {
 
 #[cfg(test)]
 pub mod test_utils {
-    use std::{collections::HashMap, path::Path};
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        sync::Mutex,
+    };
 
-    use super::{common::*, storage_engine::DataBase};
+    use super::{
+        common::{error::Error, *},
+        storage_engine::DataBase,
+    };
 
-    pub fn init_test_db() {
+    pub fn test_schema() -> DBSchema {
         let mut schema: DBSchema = HashMap::new();
         schema.insert(
             "attributes".into(),
@@ -48,6 +55,11 @@ pub mod test_utils {
                 ("Copper".into(), ColumnType::Integer),
             ],
         );
+        schema
+    }
+
+    pub fn init_test_db() {
+        let schema = test_schema();
 
         match DataBase::create(Path::new("./data/test.ogmadb"), schema) {
             Ok(db) => {
@@ -66,6 +78,72 @@ pub mod test_utils {
         }
     }
 
+    /// A scratch directory that is cleaned up when dropped.
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("ogma_{}_{:x}", name, rand::random::<u64>()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn db_path(&self) -> PathBuf {
+            self.0.join("test.ogmadb")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A fresh, empty database with the test schema. Keep the TempDir around
+    /// for as long as the database is in use.
+    pub fn temp_db(name: &str) -> (TempDir, DataBase) {
+        let dir = TempDir::new(name);
+        let db = DataBase::create(&dir.db_path(), test_schema()).unwrap();
+        (dir, db)
+    }
+
+    /// Keeps overflowing values in memory, each one once, for tests without
+    /// a database.
+    #[derive(Default)]
+    pub struct MemoryOverflow {
+        texts: Mutex<Vec<String>>,
+        objects: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl Overflow for MemoryOverflow {
+        fn read_text(&self, reference: u64) -> Result<String, Error> {
+            Ok(self.texts.lock().unwrap()[reference as usize].to_owned())
+        }
+
+        fn write_text(&self, text: &str) -> Result<u64, Error> {
+            let mut texts = self.texts.lock().unwrap();
+            if let Some(reference) = texts.iter().position(|kept| kept == text) {
+                return Ok(reference as u64);
+            }
+            texts.push(text.to_owned());
+            Ok(texts.len() as u64 - 1)
+        }
+
+        fn read_object(&self, reference: u64) -> Result<Vec<u8>, Error> {
+            Ok(self.objects.lock().unwrap()[reference as usize - 1].to_owned())
+        }
+
+        fn write_object(&self, bytes: &[u8]) -> Result<u64, Error> {
+            let mut objects = self.objects.lock().unwrap();
+            if let Some(offset) = objects.iter().position(|kept| kept == bytes) {
+                return Ok(offset as u64 + 1);
+            }
+            objects.push(bytes.to_owned());
+            Ok(objects.len() as u64)
+        }
+    }
+
     pub fn mint(start_id: usize) -> Block {
         const ROW_WIDTH: usize = 5 * COLUMN_WIDTH;
         const ROWS_IN_BLOCK: usize = BLOCK_SIZE / ROW_WIDTH;
@@ -1,6 +1,1416 @@
-use crate::storage_engine::Action;
+use sqlparser::ast::{
+    AlterTableOperation, Assignment, BinaryOperator, DataType as SqlDataType, Expr, Function,
+    FunctionArg, FunctionArgExpr, Ident, JoinConstraint, JoinOperator, ObjectName, ObjectType,
+    OrderByExpr, Query, Select, SelectItem, SetExpr, Statement, TableAlias, TableFactor,
+    TableWithJoins, UnaryOperator, Value,
+};
 
-pub fn process_query(query: String) -> Action {
+use crate::common::{error::Error, ColumnType, DataType};
+use crate::parser::generate_ast;
+use crate::storage_engine::{
+    Action, Alteration, Direction, Expression, FilterType, Function as AggregateFunction, Grouping,
+    IndexKind, Join, Limit, Operator, Predicate, Projection, Source,
+};
+
+pub fn process_query(query: String) -> Result<Action, Error> {
     println!("Processing Query: {}", query);
-    Action::GetAll("currency".into())
+    let mut statements = generate_ast(&query)?;
+    if statements.len() != 1 {
+        return Err(Error::QueryError(format!(
+            "Expected exactly one statement, found {}",
+            statements.len()
+        )));
+    }
+
+    match statements.remove(0) {
+        Statement::Query(query) => process_select(*query),
+        Statement::Insert {
+            or: None,
+            table_name,
+            columns,
+            overwrite: false,
+            source,
+            partitioned: None,
+            after_columns,
+            table: false,
+            on: None,
+            returning: None,
+            ..
+        } if after_columns.is_empty() => process_insert(&table_name, columns, *source),
+        Statement::Update {
+            table,
+            assignments,
+            from: None,
+            selection,
+            returning: None,
+        } if table.joins.is_empty() => process_update(&table.relation, assignments, selection),
+        Statement::CreateTable {
+            or_replace: false,
+            temporary: false,
+            external: false,
+            if_not_exists,
+            name,
+            columns,
+            constraints,
+            query: None,
+            like: None,
+            clone: None,
+            ..
+        } if constraints.is_empty() => {
+            let table_info = columns
+                .iter()
+                .map(|column| {
+                    Ok((
+                        column.name.value.to_owned(),
+                        column_type(&column.data_type)?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok(Action::CreateTable(
+                object_name(&name)?,
+                table_info,
+                if_not_exists,
+            ))
+        }
+        Statement::Drop {
+            object_type: ObjectType::Table,
+            if_exists,
+            names,
+            ..
+        } => match names.as_slice() {
+            [name] => Ok(Action::DropTable(object_name(name)?, if_exists)),
+            _ => Err(Error::QueryError(
+                "DROP TABLE must name exactly one table".into(),
+            )),
+        },
+        Statement::CreateIndex {
+            name: Some(name),
+            table_name,
+            using,
+            columns,
+            unique: false,
+            concurrently: false,
+            if_not_exists,
+            include,
+            nulls_distinct: None,
+            predicate: None,
+        } if include.is_empty() => match columns.as_slice() {
+            [OrderByExpr {
+                expr: Expr::Identifier(column),
+                asc: None,
+                nulls_first: None,
+            }] => Ok(Action::CreateIndex(
+                object_name(&name)?,
+                object_name(&table_name)?,
+                column.value.to_owned(),
+                index_kind(using)?,
+                if_not_exists,
+            )),
+            _ => Err(Error::QueryError(
+                "CREATE INDEX must name exactly one column".into(),
+            )),
+        },
+        Statement::Drop {
+            object_type: ObjectType::Index,
+            if_exists,
+            names,
+            ..
+        } => match names.as_slice() {
+            [name] => Ok(Action::DropIndex(object_name(name)?, if_exists)),
+            _ => Err(Error::QueryError(
+                "DROP INDEX must name exactly one index".into(),
+            )),
+        },
+        Statement::AlterTable { name, operation } => Ok(Action::AlterTable(
+            object_name(&name)?,
+            alteration(operation)?,
+        )),
+        Statement::Delete {
+            tables,
+            from,
+            using: None,
+            selection,
+            returning: None,
+        } if tables.is_empty() => match from.as_slice() {
+            [table] if table.joins.is_empty() => Ok(Action::Delete(
+                table_name(&table.relation)?,
+                where_clause(selection)?,
+            )),
+            _ => Err(Error::QueryError(
+                "DELETE must remove from exactly one table".into(),
+            )),
+        },
+        Statement::StartTransaction { modes, .. } if modes.is_empty() => Ok(Action::Begin),
+        Statement::Commit { chain: false } => Ok(Action::Commit),
+        Statement::Rollback { chain: false } => Ok(Action::Rollback),
+        statement => Err(unsupported("Statement", &statement)),
+    }
+}
+
+fn process_select(query: Query) -> Result<Action, Error> {
+    if query.with.is_some() {
+        return Err(Error::QueryError("WITH clauses are not supported".into()));
+    }
+    if query.fetch.is_some() {
+        return Err(Error::QueryError(
+            "FETCH is not supported, use LIMIT instead".into(),
+        ));
+    }
+    let limit = Limit {
+        count: query.limit.as_ref().map(row_count).transpose()?,
+        offset: match &query.offset {
+            Some(offset) => row_count(&offset.value)?,
+            None => 0,
+        },
+    };
+
+    let select = match *query.body {
+        SetExpr::Select(select) => *select,
+        body => return Err(unsupported("Query body", &body)),
+    };
+
+    let Select {
+        distinct,
+        top,
+        projection,
+        into,
+        from,
+        lateral_views,
+        selection,
+        group_by,
+        cluster_by,
+        distribute_by,
+        sort_by,
+        having,
+        named_window,
+        qualify,
+    } = select;
+
+    if distinct.is_some() || top.is_some() || into.is_some() {
+        return Err(Error::QueryError(
+            "DISTINCT, TOP and INTO are not supported".into(),
+        ));
+    }
+    if !lateral_views.is_empty()
+        || !cluster_by.is_empty()
+        || !distribute_by.is_empty()
+        || !sort_by.is_empty()
+        || !named_window.is_empty()
+        || qualify.is_some()
+    {
+        return Err(Error::QueryError(
+            "Dialect specific SELECT clauses are not supported".into(),
+        ));
+    }
+    let mut grouping = Grouping {
+        keys: group_by.iter().map(expression).collect::<Result<_, _>>()?,
+        ..Grouping::default()
+    };
+    if let Some(having) = having {
+        grouping.having = predicate(name_aggregates(having, &mut grouping.aggregates)?)?;
+    }
+
+    let projections = projection
+        .into_iter()
+        .map(select_item)
+        .collect::<Result<Vec<_>, _>>()?;
+    let order_by = query
+        .order_by
+        .iter()
+        .map(order_by_expr)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let table = source(from)?;
+
+    match selection {
+        Some(expr) => Ok(Action::GetFiltered(
+            table,
+            predicate(expr)?,
+            projections,
+            grouping,
+            order_by,
+            limit,
+        )),
+        None => Ok(Action::GetAll(
+            table,
+            projections,
+            grouping,
+            order_by,
+            limit,
+        )),
+    }
+}
+
+fn source(from: Vec<TableWithJoins>) -> Result<Source, Error> {
+    let mut tables = from.into_iter();
+    let mut source = match tables.next() {
+        Some(table) => joined_source(table)?,
+        None => {
+            return Err(Error::QueryError(
+                "SELECT must read from at least one table".into(),
+            ))
+        }
+    };
+    // `FROM a, b` pairs every row of a with every row of b
+    for table in tables {
+        source = Source::Join(
+            Box::new(source),
+            Box::new(joined_source(table)?),
+            Join::Cross,
+        );
+    }
+    Ok(source)
+}
+
+fn joined_source(table: TableWithJoins) -> Result<Source, Error> {
+    let mut source = relation_source(&table.relation)?;
+    for join in table.joins {
+        let right = relation_source(&join.relation)?;
+        let join = match join.join_operator {
+            JoinOperator::Inner(constraint) => {
+                let (keys, filter) = join_constraint(constraint)?;
+                Join::Inner(keys, filter)
+            }
+            JoinOperator::LeftOuter(constraint) => {
+                let (keys, filter) = join_constraint(constraint)?;
+                Join::Left(keys, filter)
+            }
+            JoinOperator::CrossJoin => Join::Cross,
+            _ => return Err(unsupported("Join", &join)),
+        };
+        source = Source::Join(Box::new(source), Box::new(right), join);
+    }
+    Ok(source)
+}
+
+fn relation_source(relation: &TableFactor) -> Result<Source, Error> {
+    match relation {
+        TableFactor::Table {
+            name,
+            alias,
+            args: None,
+            ..
+        } => match alias {
+            None => Ok(Source::Table(object_name(name)?, None)),
+            Some(TableAlias {
+                name: alias,
+                columns,
+            }) if columns.is_empty() => Ok(Source::Table(
+                object_name(name)?,
+                Some(alias.value.to_owned()),
+            )),
+            Some(alias) => Err(unsupported("Table alias", alias)),
+        },
+        relation => Err(unsupported("Table reference", relation)),
+    }
+}
+
+/// Splits a join condition into pairs of columns that must be equal, which
+/// the storage engine can hash on, and a predicate for anything else.
+fn join_constraint(
+    constraint: JoinConstraint,
+) -> Result<(Vec<(String, String)>, Predicate), Error> {
+    match constraint {
+        JoinConstraint::On(expr) => {
+            let mut keys = Vec::new();
+            let mut filters = Vec::new();
+            for expr in conjuncts(expr) {
+                match &expr {
+                    Expr::BinaryOp {
+                        left,
+                        op: BinaryOperator::Eq,
+                        right,
+                    } => match (column_name(left), column_name(right)) {
+                        (Ok(left), Ok(right)) => keys.push((left, right)),
+                        _ => filters.push(predicate(expr)?),
+                    },
+                    _ => filters.push(predicate(expr)?),
+                }
+            }
+            let filter = match filters.len() {
+                0 => Predicate::Filter(FilterType::All),
+                1 => filters.remove(0),
+                _ => Predicate::And(filters),
+            };
+            Ok((keys, filter))
+        }
+        JoinConstraint::Using(columns) => Ok((
+            columns
+                .into_iter()
+                .map(|column| (column.value.to_owned(), column.value))
+                .collect(),
+            Predicate::Filter(FilterType::All),
+        )),
+        constraint => Err(Error::QueryError(format!(
+            "Joins need an ON or USING condition, found {:?}",
+            constraint
+        ))),
+    }
+}
+
+fn conjuncts(expr: Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Nested(expr) => conjuncts(*expr),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut exprs = conjuncts(*left);
+            exprs.append(&mut conjuncts(*right));
+            exprs
+        }
+        expr => vec![expr],
+    }
+}
+
+/// HAVING filters on aggregates, which the storage engine works out as
+/// columns named after them. Swaps each aggregate call in `expr` for such a
+/// column, collecting the calls so they can be worked out.
+fn name_aggregates(expr: Expr, aggregates: &mut Vec<Expression>) -> Result<Expr, Error> {
+    let mut rename = |expr: Box<Expr>| name_aggregates(*expr, aggregates).map(Box::new);
+    Ok(match expr {
+        Expr::Function(function) => {
+            let aggregate = aggregate(&function)?;
+            let column = Expr::Identifier(Ident::new(aggregate.to_string()));
+            aggregates.push(aggregate);
+            column
+        }
+        Expr::Nested(expr) => Expr::Nested(rename(expr)?),
+        Expr::UnaryOp { op, expr } => Expr::UnaryOp {
+            op,
+            expr: rename(expr)?,
+        },
+        Expr::BinaryOp { left, op, right } => Expr::BinaryOp {
+            left: rename(left)?,
+            op,
+            right: rename(right)?,
+        },
+        Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => Expr::Between {
+            expr: rename(expr)?,
+            negated,
+            low,
+            high,
+        },
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => Expr::InList {
+            expr: rename(expr)?,
+            list,
+            negated,
+        },
+        expr => expr,
+    })
+}
+
+fn row_count(expr: &Expr) -> Result<u64, Error> {
+    match literal(expr)? {
+        DataType::Integer(count) if count >= 0 => Ok(count as u64),
+        _ => Err(Error::QueryError(format!(
+            "LIMIT and OFFSET need a non-negative integer, not {}",
+            expr
+        ))),
+    }
+}
+
+fn order_by_expr(order_by: &OrderByExpr) -> Result<(Expression, Direction), Error> {
+    if order_by.nulls_first.is_some() {
+        return Err(unsupported("ORDER BY", order_by));
+    }
+    if let Expr::Value(Value::Number(..)) = order_by.expr {
+        return Err(Error::QueryError(
+            "ORDER BY column positions are not supported, name the column instead".into(),
+        ));
+    }
+    let direction = match order_by.asc {
+        Some(false) => Direction::Descending,
+        _ => Direction::Ascending,
+    };
+    Ok((expression(&order_by.expr)?, direction))
+}
+
+fn process_insert(
+    table_name: &ObjectName,
+    columns: Vec<Ident>,
+    source: Query,
+) -> Result<Action, Error> {
+    let rows = match *source.body {
+        SetExpr::Values(values) => values.rows,
+        body => return Err(unsupported("INSERT source", &body)),
+    };
+    let rows = rows
+        .iter()
+        .map(|row| row.iter().map(literal).collect::<Result<Vec<_>, _>>())
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Action::Insert(
+        object_name(table_name)?,
+        columns.into_iter().map(|column| column.value).collect(),
+        rows,
+    ))
+}
+
+fn process_update(
+    relation: &TableFactor,
+    assignments: Vec<Assignment>,
+    selection: Option<Expr>,
+) -> Result<Action, Error> {
+    let assignments = assignments
+        .iter()
+        .map(|assignment| match assignment.id.as_slice() {
+            [column] => Ok((column.value.to_owned(), expression(&assignment.value)?)),
+            _ => Err(unsupported("Assignment", assignment)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Action::Update(
+        table_name(relation)?,
+        assignments,
+        where_clause(selection)?,
+    ))
+}
+
+/// A missing WHERE clause matches every row.
+fn where_clause(selection: Option<Expr>) -> Result<Predicate, Error> {
+    match selection {
+        Some(expr) => predicate(expr),
+        None => Ok(Predicate::Filter(FilterType::All)),
+    }
+}
+
+fn alteration(operation: AlterTableOperation) -> Result<Alteration, Error> {
+    match operation {
+        AlterTableOperation::AddColumn {
+            if_not_exists,
+            column_def,
+            ..
+        } if column_def.options.is_empty() => Ok(Alteration::AddColumn(
+            column_def.name.value,
+            column_type(&column_def.data_type)?,
+            if_not_exists,
+        )),
+        AlterTableOperation::DropColumn {
+            column_name,
+            if_exists,
+            cascade: false,
+        } => Ok(Alteration::DropColumn(column_name.value, if_exists)),
+        AlterTableOperation::RenameColumn {
+            old_column_name,
+            new_column_name,
+        } => Ok(Alteration::RenameColumn(
+            old_column_name.value,
+            new_column_name.value,
+        )),
+        operation => Err(unsupported("ALTER TABLE operation", &operation)),
+    }
+}
+
+fn column_type(data_type: &SqlDataType) -> Result<ColumnType, Error> {
+    match data_type {
+        SqlDataType::Int(_)
+        | SqlDataType::Integer(_)
+        | SqlDataType::BigInt(_)
+        | SqlDataType::Int8(_)
+        | SqlDataType::SmallInt(_)
+        | SqlDataType::TinyInt(_) => Ok(ColumnType::Integer),
+        SqlDataType::Bool | SqlDataType::Boolean => Ok(ColumnType::Boolean),
+        SqlDataType::Text
+        | SqlDataType::String
+        | SqlDataType::Char(_)
+        | SqlDataType::Varchar(_) => Ok(ColumnType::Text),
+        SqlDataType::Clob(_) => Ok(ColumnType::Clob),
+        SqlDataType::Blob(_) => Ok(ColumnType::Blob),
+        data_type => Err(unsupported("Column type", data_type)),
+    }
+}
+
+fn select_item(item: SelectItem) -> Result<Projection, Error> {
+    match item {
+        SelectItem::Wildcard(_) => Ok(Projection::Wildcard),
+        SelectItem::UnnamedExpr(expr) => {
+            // Bare columns keep their name, anything else is named after its SQL
+            let name = match column_name(&expr) {
+                Ok(column) => column,
+                Err(_) => expr.to_string(),
+            };
+            Ok(Projection::Named(expression(&expr)?, name))
+        }
+        SelectItem::ExprWithAlias { expr, alias } => {
+            Ok(Projection::Named(expression(&expr)?, alias.value))
+        }
+        item => Err(unsupported("Select item", &item)),
+    }
+}
+
+fn expression(expr: &Expr) -> Result<Expression, Error> {
+    match expr {
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_) => {
+            Ok(Expression::Column(column_name(expr)?))
+        }
+        Expr::Nested(expr) => expression(expr),
+        Expr::Value(_) => Ok(Expression::Literal(literal(expr)?)),
+        Expr::UnaryOp {
+            op: UnaryOperator::Plus,
+            expr,
+        } => expression(expr),
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr: inner,
+        } => match literal(expr) {
+            Ok(value) => Ok(Expression::Literal(value)),
+            Err(_) => Ok(Expression::Negate(Box::new(expression(inner)?))),
+        },
+        Expr::BinaryOp { left, op, right } => {
+            let operator = match op {
+                BinaryOperator::Plus => Operator::Add,
+                BinaryOperator::Minus => Operator::Subtract,
+                BinaryOperator::Multiply => Operator::Multiply,
+                BinaryOperator::Divide => Operator::Divide,
+                BinaryOperator::Modulo => Operator::Modulo,
+                op => return Err(unsupported("Operator", op)),
+            };
+            Ok(Expression::Arithmetic(
+                Box::new(expression(left)?),
+                operator,
+                Box::new(expression(right)?),
+            ))
+        }
+        Expr::Function(function) => aggregate(function),
+        expr => Err(unsupported("Expression", expr)),
+    }
+}
+
+fn aggregate(function: &Function) -> Result<Expression, Error> {
+    if function.over.is_some() || function.distinct || !function.order_by.is_empty() {
+        return Err(unsupported("Function call", function));
+    }
+    let name = object_name(&function.name)?.to_uppercase();
+    let aggregate = match name.as_str() {
+        "COUNT" => AggregateFunction::Count,
+        "SUM" => AggregateFunction::Sum,
+        "MIN" => AggregateFunction::Min,
+        "MAX" => AggregateFunction::Max,
+        "AVG" => AggregateFunction::Avg,
+        _ => return Err(unsupported("Function", &function.name)),
+    };
+    let argument = match (aggregate, function.args.as_slice()) {
+        // Every row counts, there's no NULL to skip
+        (AggregateFunction::Count, [FunctionArg::Unnamed(FunctionArgExpr::Wildcard)]) => {
+            Expression::Literal(DataType::Integer(1))
+        }
+        (_, [FunctionArg::Unnamed(FunctionArgExpr::Expr(expr))]) => expression(expr)?,
+        _ => {
+            return Err(Error::QueryError(format!(
+                "{} takes exactly one argument",
+                name
+            )))
+        }
+    };
+    Ok(Expression::Aggregate(aggregate, Box::new(argument)))
+}
+
+fn table_name(relation: &TableFactor) -> Result<String, Error> {
+    match relation {
+        TableFactor::Table {
+            name,
+            alias: None,
+            args: None,
+            ..
+        } => object_name(name),
+        relation => Err(unsupported("Table reference", relation)),
+    }
+}
+
+/// The kind of index named after `USING`, a B+tree unless it says otherwise.
+fn index_kind(using: Option<Ident>) -> Result<IndexKind, Error> {
+    match using {
+        None => Ok(IndexKind::BTree),
+        Some(kind) if kind.value.eq_ignore_ascii_case("btree") => Ok(IndexKind::BTree),
+        Some(kind) if kind.value.eq_ignore_ascii_case("hash") => Ok(IndexKind::Hash),
+        Some(kind) => Err(unsupported("Index kind", &kind)),
+    }
+}
+
+fn object_name(name: &ObjectName) -> Result<String, Error> {
+    match name.0.as_slice() {
+        [ident] => Ok(ident.value.to_owned()),
+        _ => Err(unsupported("Qualified name", name)),
+    }
+}
+
+/// Builds the predicate tree the storage engine evaluates against every row.
+fn predicate(expr: Expr) -> Result<Predicate, Error> {
+    match expr {
+        Expr::Nested(expr) => predicate(*expr),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => Ok(Predicate::And(flatten(
+            predicate(*left)?,
+            predicate(*right)?,
+            |predicate| match predicate {
+                Predicate::And(branches) => Ok(branches),
+                predicate => Err(predicate),
+            },
+        ))),
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Or,
+            right,
+        } => Ok(Predicate::Or(flatten(
+            predicate(*left)?,
+            predicate(*right)?,
+            |predicate| match predicate {
+                Predicate::Or(branches) => Ok(branches),
+                predicate => Err(predicate),
+            },
+        ))),
+        Expr::UnaryOp {
+            op: UnaryOperator::Not,
+            expr,
+        } => Ok(Predicate::Not(Box::new(predicate(*expr)?))),
+        Expr::BinaryOp { left, op, right } => comparison(*left, op, *right),
+        Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            // SQL's BETWEEN is inclusive, FilterType::Between is not
+            let column = column_name(&expr)?;
+            let between = Predicate::And(vec![
+                Predicate::Filter(FilterType::LessThanEqualTo(
+                    column.to_owned(),
+                    literal(&low)?,
+                )),
+                Predicate::Filter(FilterType::GreaterThanEqualTo(column, literal(&high)?)),
+            ]);
+            Ok(negate_if(negated, between))
+        }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let values = list.iter().map(literal).collect::<Result<_, _>>()?;
+            let in_list = Predicate::Filter(FilterType::In(column_name(&expr)?, values));
+            Ok(negate_if(negated, in_list))
+        }
+        Expr::Value(Value::Boolean(value)) => {
+            Ok(negate_if(!value, Predicate::Filter(FilterType::All)))
+        }
+        // A bare column is treated as a Boolean flag
+        Expr::Identifier(ident) => Ok(Predicate::Filter(FilterType::EqualTo(
+            ident.value,
+            DataType::Boolean(true),
+        ))),
+        expr => Err(unsupported("Expression", &expr)),
+    }
+}
+
+/// Joins two branches under one node, pulling up the children of any branch
+/// that is already the same kind of node so `a AND b AND c` stays shallow.
+fn flatten<F>(left: Predicate, right: Predicate, children: F) -> Vec<Predicate>
+where
+    F: Fn(Predicate) -> Result<Vec<Predicate>, Predicate>,
+{
+    let mut branches = Vec::new();
+    for branch in [left, right] {
+        match children(branch) {
+            Ok(mut nested) => branches.append(&mut nested),
+            Err(branch) => branches.push(branch),
+        }
+    }
+    branches
+}
+
+fn negate_if(negated: bool, predicate: Predicate) -> Predicate {
+    if negated {
+        Predicate::Not(Box::new(predicate))
+    } else {
+        predicate
+    }
+}
+
+/// Turns `column <op> literal` (or `literal <op> column`) into a FilterType.
+///
+/// FilterTypes read as `value <op> field`, so `Gold > 10` is stored as
+/// `LessThan("Gold", 10)`, while `10 > Gold` becomes `GreaterThan("Gold", 10)`.
+fn comparison(left: Expr, op: BinaryOperator, right: Expr) -> Result<Predicate, Error> {
+    let (column, value, op) = match (column_name(&left), column_name(&right)) {
+        (Ok(column), Err(_)) => (column, literal(&right)?, flip(op)),
+        (Err(_), Ok(column)) => (column, literal(&left)?, op),
+        _ => {
+            return Err(Error::QueryError(format!(
+                "Comparisons must be between a column and a literal, found {left} {op} {right}"
+            )))
+        }
+    };
+
+    let filter = match op {
+        BinaryOperator::Gt => FilterType::GreaterThan(column, value),
+        BinaryOperator::GtEq => FilterType::GreaterThanEqualTo(column, value),
+        BinaryOperator::Lt => FilterType::LessThan(column, value),
+        BinaryOperator::LtEq => FilterType::LessThanEqualTo(column, value),
+        BinaryOperator::Eq => FilterType::EqualTo(column, value),
+        BinaryOperator::NotEq => {
+            return Ok(Predicate::Not(Box::new(Predicate::Filter(
+                FilterType::EqualTo(column, value),
+            ))))
+        }
+        op => return Err(unsupported("Operator", &op)),
+    };
+    Ok(Predicate::Filter(filter))
+}
+
+fn flip(op: BinaryOperator) -> BinaryOperator {
+    match op {
+        BinaryOperator::Gt => BinaryOperator::Lt,
+        BinaryOperator::GtEq => BinaryOperator::LtEq,
+        BinaryOperator::Lt => BinaryOperator::Gt,
+        BinaryOperator::LtEq => BinaryOperator::GtEq,
+        op => op,
+    }
+}
+
+fn column_name(expr: &Expr) -> Result<String, Error> {
+    match expr {
+        Expr::Identifier(ident) => Ok(ident.value.to_owned()),
+        // Columns of joined tables can be qualified with the table's name
+        Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
+            Ok(format!("{}.{}", idents[0].value, idents[1].value))
+        }
+        Expr::Nested(expr) => column_name(expr),
+        expr => Err(unsupported("Column reference", expr)),
+    }
+}
+
+fn literal(expr: &Expr) -> Result<DataType, Error> {
+    match expr {
+        Expr::Value(Value::Number(number, false)) => number
+            .parse::<i64>()
+            .map(DataType::Integer)
+            .map_err(|_| Error::QueryError(format!("{number} is not a valid Integer"))),
+        Expr::Value(Value::Boolean(value)) => Ok(DataType::Boolean(*value)),
+        Expr::Value(Value::SingleQuotedString(text)) => Ok(DataType::Text(text.to_owned())),
+        Expr::Value(Value::HexStringLiteral(hex)) => hex_literal(hex),
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => match literal(expr)? {
+            DataType::Integer(value) => Ok(DataType::Integer(-value)),
+            _ => Err(unsupported("Negated literal", expr)),
+        },
+        Expr::Nested(expr) => literal(expr),
+        expr => Err(unsupported("Literal", expr)),
+    }
+}
+
+/// Binary data written out as X'…', two hex digits to a byte.
+fn hex_literal(hex: &str) -> Result<DataType, Error> {
+    let invalid = || Error::QueryError(format!("X'{hex}' is not valid hex"));
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| {
+            let pair = hex.get(at..at + 2).ok_or_else(invalid)?;
+            u8::from_str_radix(pair, 16).map_err(|_| invalid())
+        })
+        .collect::<Result<_, _>>()
+        .map(DataType::Blob)
+}
+
+fn unsupported<T: std::fmt::Display>(kind: &str, item: &T) -> Error {
+    Error::QueryError(format!("{kind} is not supported: {item}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_all() {
+        assert_eq!(
+            process_query("SELECT * FROM currency".into()).unwrap(),
+            Action::GetAll(
+                "currency".into(),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_projection() {
+        let action = process_query(
+            "SELECT Charisma, Strength AS str, 1, -Wisdom, (Dexterity + 2) * 3 FROM attributes"
+                .into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::GetAll(
+                "attributes".into(),
+                vec![
+                    Projection::Named(Expression::Column("Charisma".into()), "Charisma".into()),
+                    Projection::Named(Expression::Column("Strength".into()), "str".into()),
+                    Projection::Named(Expression::Literal(DataType::Integer(1)), "1".into()),
+                    Projection::Named(
+                        Expression::Negate(Box::new(Expression::Column("Wisdom".into()))),
+                        "-Wisdom".into()
+                    ),
+                    Projection::Named(
+                        Expression::Arithmetic(
+                            Box::new(Expression::Arithmetic(
+                                Box::new(Expression::Column("Dexterity".into())),
+                                Operator::Add,
+                                Box::new(Expression::Literal(DataType::Integer(2))),
+                            )),
+                            Operator::Multiply,
+                            Box::new(Expression::Literal(DataType::Integer(3))),
+                        ),
+                        "(Dexterity + 2) * 3".into()
+                    ),
+                ],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_filtered() {
+        let action = process_query(
+            "SELECT * FROM currency WHERE Gold > 10 AND 3 >= Silver AND index IN (1, 2, 3)".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::GetFiltered(
+                "currency".into(),
+                Predicate::And(vec![
+                    Predicate::Filter(FilterType::LessThan("Gold".into(), DataType::Integer(10))),
+                    Predicate::Filter(FilterType::GreaterThanEqualTo(
+                        "Silver".into(),
+                        DataType::Integer(3)
+                    )),
+                    Predicate::Filter(FilterType::In(
+                        "index".into(),
+                        vec![
+                            DataType::Integer(1),
+                            DataType::Integer(2),
+                            DataType::Integer(3)
+                        ]
+                    )),
+                ]),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_literals() {
+        let action = process_query(
+            "SELECT * FROM t WHERE a = -4 AND b = true AND c = 'bird' AND d BETWEEN 1 AND 5".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::GetFiltered(
+                "t".into(),
+                Predicate::And(vec![
+                    Predicate::Filter(FilterType::EqualTo("a".into(), DataType::Integer(-4))),
+                    Predicate::Filter(FilterType::EqualTo("b".into(), DataType::Boolean(true))),
+                    Predicate::Filter(FilterType::EqualTo(
+                        "c".into(),
+                        DataType::Text("bird".into())
+                    )),
+                    Predicate::Filter(FilterType::LessThanEqualTo(
+                        "d".into(),
+                        DataType::Integer(1)
+                    )),
+                    Predicate::Filter(FilterType::GreaterThanEqualTo(
+                        "d".into(),
+                        DataType::Integer(5)
+                    )),
+                ]),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_predicate_tree() {
+        let action = process_query(
+            "SELECT * FROM currency WHERE Gold > 10 OR Platinum > 0 OR NOT (Copper != 3 AND Silver NOT IN (1))".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::GetFiltered(
+                "currency".into(),
+                Predicate::Or(vec![
+                    Predicate::Filter(FilterType::LessThan("Gold".into(), DataType::Integer(10))),
+                    Predicate::Filter(FilterType::LessThan(
+                        "Platinum".into(),
+                        DataType::Integer(0)
+                    )),
+                    Predicate::Not(Box::new(Predicate::And(vec![
+                        Predicate::Not(Box::new(Predicate::Filter(FilterType::EqualTo(
+                            "Copper".into(),
+                            DataType::Integer(3)
+                        )))),
+                        Predicate::Not(Box::new(Predicate::Filter(FilterType::In(
+                            "Silver".into(),
+                            vec![DataType::Integer(1)]
+                        )))),
+                    ]))),
+                ]),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_ordered() {
+        let action = process_query(
+            "SELECT Gold AS g FROM currency ORDER BY Gold DESC, Silver ASC, Copper".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::GetAll(
+                "currency".into(),
+                vec![Projection::Named(
+                    Expression::Column("Gold".into()),
+                    "g".into()
+                )],
+                Grouping::default(),
+                vec![
+                    (Expression::Column("Gold".into()), Direction::Descending),
+                    (Expression::Column("Silver".into()), Direction::Ascending),
+                    (Expression::Column("Copper".into()), Direction::Ascending),
+                ],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_limit() {
+        let action = process_query("SELECT * FROM currency LIMIT 5 OFFSET 2".into()).unwrap();
+        assert_eq!(
+            action,
+            Action::GetAll(
+                "currency".into(),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit {
+                    count: Some(5),
+                    offset: 2
+                }
+            )
+        );
+        assert!(process_query("SELECT * FROM currency LIMIT -1".into()).is_err());
+        assert!(process_query("SELECT * FROM currency LIMIT Gold".into()).is_err());
+        assert!(process_query("SELECT * FROM currency FETCH FIRST 5 ROWS ONLY".into()).is_err());
+    }
+
+    #[test]
+    fn test_select_aggregates() {
+        let action =
+            process_query("SELECT count(*), SUM(Gold) + 1 AS total FROM currency".into()).unwrap();
+        assert_eq!(
+            action,
+            Action::GetAll(
+                "currency".into(),
+                vec![
+                    Projection::Named(
+                        Expression::Aggregate(
+                            AggregateFunction::Count,
+                            Box::new(Expression::Literal(DataType::Integer(1)))
+                        ),
+                        "count(*)".into()
+                    ),
+                    Projection::Named(
+                        Expression::Arithmetic(
+                            Box::new(Expression::Aggregate(
+                                AggregateFunction::Sum,
+                                Box::new(Expression::Column("Gold".into()))
+                            )),
+                            Operator::Add,
+                            Box::new(Expression::Literal(DataType::Integer(1)))
+                        ),
+                        "total".into()
+                    ),
+                ],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+        for sql in [
+            "SELECT SUM(Gold, Silver) FROM currency",
+            "SELECT SUM(*) FROM currency",
+            "SELECT COUNT(DISTINCT Gold) FROM currency",
+            "SELECT SQRT(Gold) FROM currency",
+        ] {
+            assert!(process_query(sql.into()).is_err(), "{sql} should fail");
+        }
+    }
+
+    #[test]
+    fn test_select_group_by() {
+        let action = process_query(
+            "SELECT Platinum, AVG(Gold) FROM currency GROUP BY Platinum HAVING SUM(Gold) > 100 AND Platinum != 0".into(),
+        )
+        .unwrap();
+        let sum_gold = Expression::Aggregate(
+            AggregateFunction::Sum,
+            Box::new(Expression::Column("Gold".into())),
+        );
+        assert_eq!(
+            action,
+            Action::GetAll(
+                "currency".into(),
+                vec![
+                    Projection::Named(Expression::Column("Platinum".into()), "Platinum".into()),
+                    Projection::Named(
+                        Expression::Aggregate(
+                            AggregateFunction::Avg,
+                            Box::new(Expression::Column("Gold".into()))
+                        ),
+                        "AVG(Gold)".into()
+                    ),
+                ],
+                Grouping {
+                    keys: vec![Expression::Column("Platinum".into())],
+                    having: Predicate::And(vec![
+                        Predicate::Filter(FilterType::LessThan(
+                            "SUM(Gold)".into(),
+                            DataType::Integer(100)
+                        )),
+                        Predicate::Not(Box::new(Predicate::Filter(FilterType::EqualTo(
+                            "Platinum".into(),
+                            DataType::Integer(0)
+                        )))),
+                    ]),
+                    aggregates: vec![sum_gold],
+                },
+                vec![],
+                Limit::default()
+            )
+        );
+    }
+
+    #[test]
+    fn test_select_joined() {
+        let action = process_query(
+            "SELECT a.Strength, Gold FROM attributes AS a LEFT JOIN currency ON currency.index = a.index AND Gold > 10 CROSS JOIN spells, runes".into(),
+        )
+        .unwrap();
+        let joined = Source::Join(
+            Box::new(Source::Table("attributes".into(), Some("a".into()))),
+            Box::new("currency".into()),
+            Join::Left(
+                vec![("currency.index".into(), "a.index".into())],
+                Predicate::Filter(FilterType::LessThan("Gold".into(), DataType::Integer(10))),
+            ),
+        );
+        assert_eq!(
+            action,
+            Action::GetAll(
+                Source::Join(
+                    Box::new(Source::Join(
+                        Box::new(joined),
+                        Box::new("spells".into()),
+                        Join::Cross
+                    )),
+                    Box::new("runes".into()),
+                    Join::Cross
+                ),
+                vec![
+                    Projection::Named(Expression::Column("a.Strength".into()), "a.Strength".into()),
+                    Projection::Named(Expression::Column("Gold".into()), "Gold".into()),
+                ],
+                Grouping::default(),
+                vec![],
+                Limit::default()
+            )
+        );
+
+        match process_query("SELECT * FROM a JOIN b USING (id)".into()).unwrap() {
+            Action::GetAll(Source::Join(_, _, join), ..) => assert_eq!(
+                join,
+                Join::Inner(
+                    vec![("id".into(), "id".into())],
+                    Predicate::Filter(FilterType::All)
+                )
+            ),
+            action => panic!("Expected a join, got {action:?}"),
+        }
+    }
+
+    #[test]
+    fn test_insert() {
+        let action =
+            process_query("INSERT INTO currency (Gold, Silver) VALUES (1, 2), (-3, 4)".into())
+                .unwrap();
+        assert_eq!(
+            action,
+            Action::Insert(
+                "currency".into(),
+                vec!["Gold".into(), "Silver".into()],
+                vec![
+                    vec![DataType::Integer(1), DataType::Integer(2)],
+                    vec![DataType::Integer(-3), DataType::Integer(4)],
+                ]
+            )
+        );
+
+        let action = process_query("INSERT INTO flags VALUES (true, 'hi')".into()).unwrap();
+        assert_eq!(
+            action,
+            Action::Insert(
+                "flags".into(),
+                vec![],
+                vec![vec![DataType::Boolean(true), DataType::Text("hi".into())]]
+            )
+        );
+
+        let action = process_query("INSERT INTO portraits VALUES (X'00fF7a', X'')".into()).unwrap();
+        assert_eq!(
+            action,
+            Action::Insert(
+                "portraits".into(),
+                vec![],
+                vec![vec![
+                    DataType::Blob(vec![0, 255, 122]),
+                    DataType::Blob(vec![])
+                ]]
+            )
+        );
+
+        assert!(process_query("INSERT INTO currency SELECT * FROM currency".into()).is_err());
+        assert!(process_query("INSERT INTO portraits VALUES (X'abc')".into()).is_err());
+        assert!(process_query("INSERT INTO portraits VALUES (X'zz')".into()).is_err());
+        assert!(process_query("INSERT INTO currency VALUES (Gold)".into()).is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let action = process_query(
+            "UPDATE attributes SET Strength = Strength + 2, Wisdom = 3 WHERE index = 5".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::Update(
+                "attributes".into(),
+                vec![
+                    (
+                        "Strength".into(),
+                        Expression::Arithmetic(
+                            Box::new(Expression::Column("Strength".into())),
+                            Operator::Add,
+                            Box::new(Expression::Literal(DataType::Integer(2)))
+                        )
+                    ),
+                    ("Wisdom".into(), Expression::Literal(DataType::Integer(3))),
+                ],
+                Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(5)))
+            )
+        );
+
+        let action = process_query("UPDATE currency SET Gold = 0".into()).unwrap();
+        assert_eq!(
+            action,
+            Action::Update(
+                "currency".into(),
+                vec![("Gold".into(), Expression::Literal(DataType::Integer(0)))],
+                Predicate::Filter(FilterType::All)
+            )
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        assert_eq!(
+            process_query("DELETE FROM currency WHERE Gold = 0".into()).unwrap(),
+            Action::Delete(
+                "currency".into(),
+                Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(0)))
+            )
+        );
+        assert_eq!(
+            process_query("DELETE FROM currency".into()).unwrap(),
+            Action::Delete("currency".into(), Predicate::Filter(FilterType::All))
+        );
+    }
+
+    #[test]
+    fn test_create_and_drop_table() {
+        let action = process_query(
+            "CREATE TABLE IF NOT EXISTS spells (\"index\" INTEGER, name TEXT, ritual BOOLEAN, notes CLOB, icon BLOB)".into(),
+        )
+        .unwrap();
+        assert_eq!(
+            action,
+            Action::CreateTable(
+                "spells".into(),
+                vec![
+                    ("index".into(), ColumnType::Integer),
+                    ("name".into(), ColumnType::Text),
+                    ("ritual".into(), ColumnType::Boolean),
+                    ("notes".into(), ColumnType::Clob),
+                    ("icon".into(), ColumnType::Blob),
+                ],
+                true
+            )
+        );
+        assert_eq!(
+            process_query("DROP TABLE spells".into()).unwrap(),
+            Action::DropTable("spells".into(), false)
+        );
+        assert_eq!(
+            process_query("DROP TABLE IF EXISTS spells".into()).unwrap(),
+            Action::DropTable("spells".into(), true)
+        );
+    }
+
+    #[test]
+    fn test_create_and_drop_index() {
+        assert_eq!(
+            process_query("CREATE INDEX IF NOT EXISTS by_level ON attributes (Level)".into())
+                .unwrap(),
+            Action::CreateIndex(
+                "by_level".into(),
+                "attributes".into(),
+                "Level".into(),
+                IndexKind::BTree,
+                true
+            )
+        );
+        assert_eq!(
+            process_query("CREATE INDEX by_name ON characters USING HASH (Name)".into()).unwrap(),
+            Action::CreateIndex(
+                "by_name".into(),
+                "characters".into(),
+                "Name".into(),
+                IndexKind::Hash,
+                false
+            )
+        );
+        assert_eq!(
+            process_query("CREATE INDEX by_level ON attributes USING btree (Level)".into())
+                .unwrap(),
+            Action::CreateIndex(
+                "by_level".into(),
+                "attributes".into(),
+                "Level".into(),
+                IndexKind::BTree,
+                false
+            )
+        );
+        assert_eq!(
+            process_query("DROP INDEX by_level".into()).unwrap(),
+            Action::DropIndex("by_level".into(), false)
+        );
+    }
+
+    #[test]
+    fn test_alter_table() {
+        assert_eq!(
+            process_query("ALTER TABLE currency ADD COLUMN IF NOT EXISTS Electrum INTEGER".into())
+                .unwrap(),
+            Action::AlterTable(
+                "currency".into(),
+                Alteration::AddColumn("Electrum".into(), ColumnType::Integer, true)
+            )
+        );
+        assert_eq!(
+            process_query("ALTER TABLE currency DROP COLUMN Gold".into()).unwrap(),
+            Action::AlterTable(
+                "currency".into(),
+                Alteration::DropColumn("Gold".into(), false)
+            )
+        );
+        assert_eq!(
+            process_query("ALTER TABLE currency RENAME COLUMN Silver TO Shillings".into()).unwrap(),
+            Action::AlterTable(
+                "currency".into(),
+                Alteration::RenameColumn("Silver".into(), "Shillings".into())
+            )
+        );
+    }
+
+    #[test]
+    fn test_transactions() {
+        for (sql, action) in [
+            ("BEGIN", Action::Begin),
+            ("START TRANSACTION", Action::Begin),
+            ("COMMIT", Action::Commit),
+            ("ROLLBACK WORK", Action::Rollback),
+        ] {
+            assert_eq!(process_query(sql.into()).unwrap(), action);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_queries() {
+        for sql in [
+            "SELECT * FROM",
+            "SELECT * FROM currency; SELECT * FROM attributes",
+            "SELECT * FROM currency WHERE Gold > Silver",
+            "SELECT * FROM currency NATURAL JOIN attributes",
+            "SELECT * FROM currency FULL JOIN attributes ON currency.index = attributes.index",
+            "SELECT Gold || Silver FROM currency",
+            "SELECT currency.* FROM currency",
+            "CREATE TABLE spells (index INTEGER, power FLOAT)",
+            "DROP VIEW currency",
+            "ALTER TABLE currency RENAME TO money",
+            "ALTER TABLE currency DROP COLUMN Gold CASCADE",
+            "DROP TABLE currency, attributes",
+            "CREATE INDEX ON currency (Gold)",
+            "CREATE UNIQUE INDEX by_gold ON currency (Gold)",
+            "CREATE INDEX by_coins ON currency (Gold, Silver)",
+            "CREATE INDEX by_gold ON currency (Gold DESC)",
+            "CREATE INDEX by_gold ON currency USING GIST (Gold)",
+            "START TRANSACTION READ ONLY",
+            "COMMIT AND CHAIN",
+            "SELECT * FROM currency ORDER BY 2",
+            "SELECT * FROM currency ORDER BY Gold NULLS FIRST",
+        ] {
+            assert!(process_query(sql.into()).is_err(), "{sql} should fail");
+        }
+        assert!(matches!(
+            process_query("SELECT * FROM".into()),
+            Err(Error::ParseError(_))
+        ));
+        assert!(matches!(
+            process_query("DROP VIEW currency".into()),
+            Err(Error::QueryError(_))
+        ));
+    }
 }
@@ -2,8 +2,18 @@
 // use ogma_db::parser::repl;
 
 mod network;
+mod pool;
 
 fn main() {
     // repl()
-    network::start_server("127.0.0.1:7971").unwrap();
+    // The only argument is how many clients to serve at once
+    let max_connections = match std::env::args().nth(1) {
+        Some(arg) => arg
+            .parse()
+            .ok()
+            .filter(|&max| max > 0)
+            .expect("the connection limit should be a positive number"),
+        None => network::DEFAULT_MAX_CONNECTIONS,
+    };
+    network::start_server("127.0.0.1:7971", max_connections).unwrap();
 }
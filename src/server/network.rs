@@ -1,6 +1,10 @@
 use std::{
     net::{TcpListener, TcpStream, ToSocketAddrs},
     path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use ogma_db::{
@@ -9,40 +13,105 @@ use ogma_db::{
         network::{BufSocket, RequestType, ResponseType, Server},
     },
     query_engine::process_query,
-    storage_engine::{Action, DataBase, Reaction},
+    storage_engine::{Action, DataBase, Reaction, SessionId, SharedDataBase},
 };
 
-pub fn start_server<A: ToSocketAddrs>(addr: A) -> Result<(), Error> {
+use crate::pool::ThreadPool;
+
+/// How many clients are served at once, unless the server is told otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+pub fn start_server<A: ToSocketAddrs>(addr: A, max_connections: usize) -> Result<(), Error> {
     let listener = TcpListener::bind(addr)?;
 
-    let mut db = DataBase::open(Path::new("./data/test.ogmadb"))?;
+    let db = DataBase::open(Path::new("./data/test.ogmadb"))?;
+    serve(listener, db, max_connections);
+    Ok(())
+}
+
+/// Serves each client on a thread of its own, with up to `max_connections`
+/// at once. Clients that connect past that are told the server is full and
+/// let go.
+///
+/// Sessions only wait on each other for the rows and tables they lock, see
+/// `SharedDataBase`.
+fn serve(listener: TcpListener, db: DataBase, max_connections: usize) {
+    let db = Arc::new(SharedDataBase::new(db));
+    let connections = Arc::new(AtomicUsize::new(0));
+    let pool = ThreadPool::new(max_connections);
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                handle_client(&mut db, stream).unwrap_or_else(|err| println!("{:?}", err))
+                // Only this loop adds connections, so one counted free here
+                // is still free when the client is handed over
+                if connections.load(Ordering::SeqCst) >= max_connections {
+                    reject_client(stream, max_connections)
+                        .unwrap_or_else(|err| println!("{:?}", err));
+                    continue;
+                }
+                let slot = ConnectionSlot::take(&connections);
+                let db = Arc::clone(&db);
+                pool.execute(move || {
+                    let _slot = slot;
+                    handle_client(&db, stream).unwrap_or_else(|err| println!("{:?}", err))
+                });
             }
             Err(err) => println!("{:?}", err),
         }
     }
-    Ok(())
 }
 
-fn handle_client(db: &mut DataBase, stream: TcpStream) -> Result<(), Error> {
+/// Counts a client against the connection limit for as long as it's held,
+/// even if serving the client panics.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(connections: &Arc<AtomicUsize>) -> Self {
+        connections.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(connections))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn reject_client(stream: TcpStream, max_connections: usize) -> Result<(), Error> {
     let mut buf_sock = BufSocket::new(stream)?;
-    // TODO, keep track of client queries to drop when client disconnects
-    // let queries = Vec::<u64>::new();
+    buf_sock.send(&ResponseType::Error(Error::QueryError(format!(
+        "The server is full, it serves at most {} clients at once",
+        max_connections
+    ))))
+}
+
+fn handle_client(db: &SharedDataBase, stream: TcpStream) -> Result<(), Error> {
+    let mut buf_sock = BufSocket::new(stream)?;
+    let session = db.connect();
+    let result = serve_session(db, session, &mut buf_sock);
+    // Whatever the client left uncommitted, unread or locked goes with it
+    db.disconnect(session);
+    result
+}
+
+fn serve_session(
+    db: &SharedDataBase,
+    session: SessionId,
+    buf_sock: &mut BufSocket,
+) -> Result<(), Error> {
     loop {
         match buf_sock.receive() {
-            Ok(request) => match handle_request(db, &mut buf_sock, request) {
+            Ok(request) => match handle_request(db, session, buf_sock, request) {
                 Ok(_) => (),
                 Err(err) => {
-                    if let Err(err) = handle_error(&mut buf_sock, err) {
+                    if let Err(err) = handle_error(buf_sock, err) {
                         break Err(err);
                     }
                 }
             },
             Err(err) => {
-                if let Err(err) = handle_error(&mut buf_sock, err) {
+                if let Err(err) = handle_error(buf_sock, err) {
                     break Err(err);
                 }
             }
@@ -53,7 +122,10 @@ fn handle_client(db: &mut DataBase, stream: TcpStream) -> Result<(), Error> {
 fn handle_error(buf_sock: &mut BufSocket, err: Error) -> Result<(), Error> {
     let error_response = ResponseType::Error(err);
     match buf_sock.send(&error_response) {
-        Ok(_) => Ok(println!("Response: {:?} -- was sent", error_response)),
+        Ok(_) => {
+            println!("Response: {:?} -- was sent", error_response);
+            Ok(())
+        }
         Err(err) => {
             println!("Encountered: {:?}", err);
             println!("Trying to send: {:?}", error_response);
@@ -63,22 +135,82 @@ fn handle_error(buf_sock: &mut BufSocket, err: Error) -> Result<(), Error> {
 }
 
 fn handle_request(
-    db: &mut DataBase,
+    db: &SharedDataBase,
+    session: SessionId,
     buf_sock: &mut BufSocket,
     request: RequestType,
 ) -> Result<(), Error> {
     println!("Request: {:?} -- received", request);
     let reaction = match request {
-        RequestType::Query(query) => db.execute(process_query(query)),
-        RequestType::More(qid) => db.execute(Action::GetMore(qid)),
+        RequestType::Query(query) => db.execute_in(session, process_query(query)?),
+        RequestType::More { qid, batch_size } => {
+            db.execute_in(session, Action::GetMore(qid, batch_size))
+        }
     };
 
     let response = match reaction {
         Reaction::Error(err) => ResponseType::Error(err),
         Reaction::QueryStart { schema, qid } => ResponseType::QueryHandle { schema, qid },
         Reaction::Data(data) => ResponseType::Data(data),
+        Reaction::Inserted { count, ids } => ResponseType::Inserted { count, ids },
+        Reaction::Affected(count) => ResponseType::Affected(count),
+        Reaction::Done => ResponseType::Done,
         Reaction::Empty => ResponseType::Empty,
     };
 
     buf_sock.send(&response)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, thread};
+
+    use ogma_db::common::{network::Client, ColumnType};
+
+    use super::*;
+
+    fn connect(addr: std::net::SocketAddr) -> BufSocket {
+        BufSocket::new(TcpStream::connect(addr).unwrap()).unwrap()
+    }
+
+    fn query(buf_sock: &mut BufSocket, sql: &str) -> ResponseType {
+        Client::send(buf_sock, &RequestType::Query(sql.into())).unwrap();
+        Client::receive(buf_sock).unwrap()
+    }
+
+    #[test]
+    fn test_clients_served_at_once() {
+        let dir = std::env::temp_dir().join(format!("ogma_server_{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let schema = HashMap::from([(
+            "heroes".to_string(),
+            vec![
+                ("index".to_string(), ColumnType::Integer),
+                ("Level".to_string(), ColumnType::Integer),
+            ],
+        )]);
+        let db = DataBase::create(&dir.join("test.ogmadb"), schema).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, db, 2));
+
+        // The first client staying connected doesn't hold up the second
+        let mut first = connect(addr);
+        let mut second = connect(addr);
+        let response = query(&mut first, "INSERT INTO heroes (Level) VALUES (3)");
+        assert!(matches!(response, ResponseType::Inserted { count: 1, .. }));
+        let response = query(&mut second, "SELECT * FROM heroes");
+        assert!(matches!(response, ResponseType::QueryHandle { .. }));
+        let response = query(&mut first, "SELECT * FROM heroes");
+        assert!(matches!(response, ResponseType::QueryHandle { .. }));
+
+        // A third is turned away without being asked for anything
+        let mut third = connect(addr);
+        match Client::receive(&mut third).unwrap() {
+            ResponseType::Error(err) => assert!(err.to_string().contains("server is full")),
+            response => panic!("Expected the server to be full, got {:?}", response),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
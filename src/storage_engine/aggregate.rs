@@ -0,0 +1,651 @@
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::common::{
+    encode_field, error::Error, ColumnType, DataType, Overflow, RawRow, TableInfoMap,
+};
+
+use super::projection::{Expression, Projection};
+use super::sort::Direction;
+use super::{FilterType, Predicate};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Function {
+    Count,
+    Sum,
+    Min,
+    Max,
+    // Rounds toward zero, since we've only got integers to give back
+    Avg,
+}
+
+impl Function {
+    pub fn output_type(&self, argument: ColumnType) -> Result<ColumnType, Error> {
+        match (self, argument) {
+            (Function::Count, _) => Ok(ColumnType::Integer),
+            (Function::Sum | Function::Avg, ColumnType::Integer) => Ok(ColumnType::Integer),
+            (Function::Min | Function::Max, argument @ (ColumnType::Clob | ColumnType::Blob)) => {
+                Err(Error::QueryError(format!(
+                    "{} can't compare {:?} values",
+                    self, argument
+                )))
+            }
+            (Function::Min | Function::Max, argument) => Ok(argument),
+            (function, argument) => Err(Error::QueryError(format!(
+                "{} needs an Integer argument, not {:?}",
+                function, argument
+            ))),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Function::Count => "COUNT",
+            Function::Sum => "SUM",
+            Function::Min => "MIN",
+            Function::Max => "MAX",
+            Function::Avg => "AVG",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The running state of one aggregate over the rows seen so far.
+enum Accumulator {
+    Count(i64),
+    Sum(i64),
+    Min(Option<DataType>),
+    Max(Option<DataType>),
+    // Kept wide so the total can't overflow before we divide
+    Avg(i128, i64),
+}
+
+impl Accumulator {
+    fn new(function: Function) -> Self {
+        match function {
+            Function::Count => Accumulator::Count(0),
+            Function::Sum => Accumulator::Sum(0),
+            Function::Min => Accumulator::Min(None),
+            Function::Max => Accumulator::Max(None),
+            Function::Avg => Accumulator::Avg(0, 0),
+        }
+    }
+
+    fn add(&mut self, value: DataType, name: &str) -> Result<(), Error> {
+        match (self, value) {
+            (Accumulator::Count(count), _) => *count += 1,
+            (Accumulator::Sum(sum), DataType::Integer(value)) => {
+                *sum = sum
+                    .checked_add(value)
+                    .ok_or_else(|| Error::QueryError(format!("Integer overflow in {}", name)))?;
+            }
+            (Accumulator::Avg(sum, count), DataType::Integer(value)) => {
+                *sum += value as i128;
+                *count += 1;
+            }
+            (Accumulator::Min(min), value) => {
+                if min.as_ref().is_none_or(|min| value < *min) {
+                    *min = Some(value);
+                }
+            }
+            (Accumulator::Max(max), value) => {
+                if max.as_ref().is_none_or(|max| value > *max) {
+                    *max = Some(value);
+                }
+            }
+            (_, value) => {
+                return Err(Error::QueryError(format!(
+                    "{} can't take {:?}",
+                    name, value
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the result. There's no NULL to give back when no rows were
+    /// seen, so SUM, MIN, MAX and AVG come out as zero, like an empty slot.
+    fn finish(self, overflow: &dyn Overflow) -> Result<u64, Error> {
+        Ok(match self {
+            Accumulator::Count(count) | Accumulator::Sum(count) => count as u64,
+            Accumulator::Min(value) | Accumulator::Max(value) => match value {
+                Some(value) => encode_field(&value, overflow)?,
+                None => 0,
+            },
+            Accumulator::Avg(_, 0) => 0,
+            Accumulator::Avg(sum, count) => (sum / count as i128) as i64 as u64,
+        })
+    }
+}
+
+/// Holds the long values of aggregated rows for as long as the query needs
+/// them. They only live that long, and Clob and Blob values have no table to
+/// be kept in, so grouping never writes to the database's files.
+#[derive(Default)]
+pub struct GroupValues {
+    texts: RefCell<Vec<String>>,
+    objects: RefCell<Vec<Vec<u8>>>,
+}
+
+impl Overflow for GroupValues {
+    fn read_text(&self, reference: u64) -> Result<String, Error> {
+        self.texts
+            .borrow()
+            .get(reference as usize)
+            .cloned()
+            .ok_or_else(|| Error::QueryError(format!("No grouped text at {}", reference)))
+    }
+
+    fn write_text(&self, text: &str) -> Result<u64, Error> {
+        let mut texts = self.texts.borrow_mut();
+        texts.push(text.to_owned());
+        Ok(texts.len() as u64 - 1)
+    }
+
+    fn read_object(&self, reference: u64) -> Result<Vec<u8>, Error> {
+        // References start at 1, as 0 is an empty value
+        self.objects
+            .borrow()
+            .get((reference as usize).wrapping_sub(1))
+            .cloned()
+            .ok_or_else(|| Error::QueryError(format!("No grouped value at {}", reference)))
+    }
+
+    fn write_object(&self, bytes: &[u8]) -> Result<u64, Error> {
+        let mut objects = self.objects.borrow_mut();
+        objects.push(bytes.to_owned());
+        Ok(objects.len() as u64)
+    }
+}
+
+/// GROUP BY keys, and the HAVING predicate each group has to pass.
+#[derive(Debug, PartialEq)]
+pub struct Grouping {
+    pub keys: Vec<Expression>,
+    pub having: Predicate,
+    // The aggregate calls `having` refers to. Its filters name each one by
+    // how it displays, like `SUM(Gold)`.
+    pub aggregates: Vec<Expression>,
+}
+
+impl Default for Grouping {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            having: Predicate::Filter(FilterType::All),
+            aggregates: Vec::new(),
+        }
+    }
+}
+
+/// Collapses the rows matching a query into one row per group, holding the
+/// group's key columns followed by its aggregates, in a schema of its own
+/// for projection to read from. The aggregated rows' long values are read
+/// back from `values`.
+pub struct Aggregation {
+    keys: Vec<Expression>,
+    // Each aggregate's function and argument, along with the column it fills
+    aggregates: Vec<(String, Function, Expression)>,
+    having: Predicate,
+    schema: TableInfoMap,
+    values: GroupValues,
+}
+
+impl Aggregation {
+    pub fn schema(&self) -> &TableInfoMap {
+        &self.schema
+    }
+
+    pub fn values(&self) -> &GroupValues {
+        &self.values
+    }
+
+    /// Folds rows into their groups as they come, so only the groups are
+    /// ever held in memory. `overflow` is what the rows' own long values are
+    /// read from.
+    pub fn run<I>(
+        &self,
+        rows: I,
+        table_schema: &TableInfoMap,
+        overflow: &dyn Overflow,
+    ) -> Result<Vec<RawRow>, Error>
+    where
+        I: IntoIterator<Item = Result<RawRow, Error>>,
+    {
+        let new_group = || -> Vec<_> {
+            self.aggregates
+                .iter()
+                .map(|(_, function, _)| Accumulator::new(*function))
+                .collect()
+        };
+        // Groups come out in the order they were first seen
+        let mut group_offsets: HashMap<Vec<DataType>, usize> = HashMap::new();
+        let mut groups = Vec::new();
+        if self.keys.is_empty() {
+            // Without GROUP BY there's always exactly one group, rows or not
+            group_offsets.insert(Vec::new(), 0);
+            groups.push((Vec::new(), new_group()));
+        }
+
+        for raw_row in rows {
+            let raw_row = &raw_row?;
+            let key = self
+                .keys
+                .iter()
+                .map(|key| key.evaluate(raw_row, table_schema, overflow))
+                .collect::<Result<Vec<_>, _>>()?;
+            let offset = match group_offsets.get(&key) {
+                Some(offset) => *offset,
+                None => {
+                    group_offsets.insert(key.to_owned(), groups.len());
+                    groups.push((key, new_group()));
+                    groups.len() - 1
+                }
+            };
+            for ((name, _, argument), accumulator) in
+                self.aggregates.iter().zip(groups[offset].1.iter_mut())
+            {
+                accumulator.add(argument.evaluate(raw_row, table_schema, overflow)?, name)?;
+            }
+        }
+
+        let mut aggregated = Vec::new();
+        for (key, accumulators) in groups {
+            let raw_row = key
+                .iter()
+                .map(|value| encode_field(value, &self.values))
+                .chain(
+                    accumulators
+                        .into_iter()
+                        .map(|accumulator| accumulator.finish(&self.values)),
+                )
+                .collect::<Result<RawRow, _>>()?;
+            if self.having.evaluate(&raw_row, &self.schema, &self.values)? {
+                aggregated.push(raw_row);
+            }
+        }
+        Ok(aggregated)
+    }
+}
+
+/// Pulls the aggregate calls and GROUP BY keys out of a query's projections
+/// and sort keys, swapping each for a column of the aggregated rows. Gives
+/// back None, and changes nothing, when the query doesn't aggregate.
+pub fn plan_aggregation(
+    projections: &mut [Projection],
+    grouping: Grouping,
+    order_by: &mut [(Expression, Direction)],
+    table_schema: &TableInfoMap,
+) -> Result<Option<Aggregation>, Error> {
+    let aggregating = !grouping.keys.is_empty()
+        || !grouping.aggregates.is_empty()
+        || grouping.having != Predicate::Filter(FilterType::All)
+        || projections.iter().any(|projection| match projection {
+            Projection::Named(expression, _) => expression.has_aggregate(),
+            Projection::Wildcard => false,
+        })
+        || order_by
+            .iter()
+            .any(|(expression, _)| expression.has_aggregate());
+    if !aggregating {
+        return Ok(None);
+    }
+
+    let mut aggregation = Aggregation {
+        keys: Vec::new(),
+        aggregates: Vec::new(),
+        having: grouping.having,
+        schema: TableInfoMap::new(),
+        values: GroupValues::default(),
+    };
+    for key in grouping.keys {
+        if key.has_aggregate() {
+            return Err(Error::QueryError(format!(
+                "Can't GROUP BY an aggregate function: {}",
+                key
+            )));
+        }
+        let column_type = key.output_type(table_schema)?;
+        if let Entry::Vacant(entry) = aggregation.schema.entry(key.to_string()) {
+            entry.insert((column_type, aggregation.keys.len() as u64));
+            aggregation.keys.push(key);
+        }
+    }
+
+    for projection in projections.iter_mut() {
+        match projection {
+            Projection::Named(expression, _) => {
+                aggregation.extract(expression, table_schema)?;
+            }
+            Projection::Wildcard => {
+                return Err(Error::QueryError(
+                    "SELECT * can't be used with GROUP BY or aggregate functions".into(),
+                ))
+            }
+        }
+    }
+    for (expression, _) in order_by.iter_mut() {
+        aggregation.extract(expression, table_schema)?;
+    }
+    for mut expression in grouping.aggregates {
+        aggregation.extract(&mut expression, table_schema)?;
+    }
+    for column in aggregation.having.columns() {
+        if !aggregation.schema.contains_key(column) {
+            return Err(Error::QueryError(format!(
+                "HAVING can only use grouped columns and aggregates, not {}",
+                column
+            )));
+        }
+    }
+    Ok(Some(aggregation))
+}
+
+impl Aggregation {
+    fn extract(
+        &mut self,
+        expression: &mut Expression,
+        table_schema: &TableInfoMap,
+    ) -> Result<(), Error> {
+        let name = expression.to_string();
+        if self.keys.contains(expression) {
+            *expression = Expression::Column(name);
+            return Ok(());
+        }
+        match expression {
+            Expression::Aggregate(function, argument) => {
+                if argument.has_aggregate() {
+                    return Err(Error::QueryError(format!(
+                        "Aggregate functions can't be nested: {}",
+                        name
+                    )));
+                }
+                if !self.schema.contains_key(&name) {
+                    let column_type = function.output_type(argument.output_type(table_schema)?)?;
+                    let offset = (self.keys.len() + self.aggregates.len()) as u64;
+                    self.schema.insert(name.to_owned(), (column_type, offset));
+                    self.aggregates
+                        .push((name.to_owned(), *function, *argument.to_owned()));
+                }
+                *expression = Expression::Column(name);
+            }
+            // Anything that isn't a source column may be an output alias,
+            // which gets sorted out once the projection is planned
+            Expression::Column(column) if table_schema.contains_key(column) => {
+                return Err(Error::QueryError(format!(
+                    "Column {} must be in GROUP BY or used in an aggregate function",
+                    column
+                )))
+            }
+            Expression::Column(_) | Expression::Literal(_) => (),
+            Expression::Negate(inner) => self.extract(inner, table_schema)?,
+            Expression::Arithmetic(left, _, right) => {
+                self.extract(left, table_schema)?;
+                self.extract(right, table_schema)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{convert_field, map_table_info, Row, TableInfo};
+    use crate::storage_engine::Operator;
+    use crate::test_utils::MemoryOverflow;
+
+    use super::*;
+
+    fn test_schema() -> TableInfoMap {
+        let table_info: TableInfo = vec![
+            ("index".into(), ColumnType::Integer),
+            ("Gold".into(), ColumnType::Integer),
+            ("name".into(), ColumnType::Text),
+            ("notes".into(), ColumnType::Clob),
+        ];
+        map_table_info(&table_info)
+    }
+
+    fn aggregate(function: Function, column: &str) -> Expression {
+        Expression::Aggregate(function, Box::new(Expression::Column(column.into())))
+    }
+
+    fn run(
+        expressions: Vec<Expression>,
+        rows: &[RawRow],
+        overflow: &MemoryOverflow,
+    ) -> Result<Row, Error> {
+        let table_schema = test_schema();
+        let mut projections: Vec<_> = expressions
+            .into_iter()
+            .enumerate()
+            .map(|(i, expression)| Projection::Named(expression, i.to_string()))
+            .collect();
+        let aggregation = plan_aggregation(
+            &mut projections,
+            Grouping::default(),
+            &mut [],
+            &table_schema,
+        )?
+        .expect("query aggregates");
+        let raw_row = aggregation
+            .run(rows.iter().cloned().map(Ok), &table_schema, overflow)?
+            .remove(0);
+        // Long values are read back from the aggregation, not the table
+        let mut columns: Vec<_> = aggregation.schema().values().collect();
+        columns.sort_by_key(|(_, offset)| *offset);
+        columns
+            .into_iter()
+            .map(|(column_type, offset)| {
+                convert_field(raw_row[*offset as usize], column_type, aggregation.values())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let overflow = MemoryOverflow::default();
+        let word = |text: &str| encode_field(&text.into(), &overflow).unwrap();
+        let rows: Vec<RawRow> = vec![
+            vec![1, 10, word("dragon"), 0],
+            vec![2, (-4i64) as u64, word("bat"), 0],
+            vec![3, 7, word("imp of the perverse"), 0],
+        ];
+        let result = run(
+            vec![
+                aggregate(Function::Count, "index"),
+                aggregate(Function::Sum, "Gold"),
+                aggregate(Function::Min, "Gold"),
+                aggregate(Function::Max, "name"),
+                aggregate(Function::Avg, "Gold"),
+            ],
+            &rows,
+            &overflow,
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            vec![
+                3.into(),
+                13.into(),
+                (-4).into(),
+                "imp of the perverse".into(),
+                4.into()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_aggregates_over_no_rows() {
+        let result = run(
+            vec![
+                aggregate(Function::Count, "index"),
+                aggregate(Function::Sum, "Gold"),
+                aggregate(Function::Max, "Gold"),
+                aggregate(Function::Avg, "Gold"),
+            ],
+            &[],
+            &MemoryOverflow::default(),
+        )
+        .unwrap();
+        assert_eq!(result, vec![DataType::Integer(0); 4]);
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let rows: Vec<RawRow> = vec![vec![1, i64::MAX as u64, 0, 0], vec![2, 1, 0, 0]];
+        let overflow = MemoryOverflow::default();
+        assert!(run(vec![aggregate(Function::Sum, "Gold")], &rows, &overflow).is_err());
+        // AVG doesn't overflow on the way to a result that fits
+        assert_eq!(
+            run(vec![aggregate(Function::Avg, "Gold")], &rows, &overflow).unwrap(),
+            vec![(i64::MAX / 2 + 1).into()]
+        );
+    }
+
+    #[test]
+    fn test_group_by_and_having() {
+        let table_schema = test_schema();
+        let overflow = MemoryOverflow::default();
+        let rows: Vec<RawRow> = vec![
+            vec![1, 10, 2, 0],
+            vec![2, 5, 1, 0],
+            vec![3, 7, 2, 0],
+            vec![4, 1, 3, 0],
+            vec![5, 3, 1, 0],
+        ];
+        // SELECT name, SUM(Gold) ... GROUP BY name HAVING COUNT(index) > 1
+        let mut projections = vec![
+            Projection::Named(Expression::Column("name".into()), "name".into()),
+            Projection::Named(aggregate(Function::Sum, "Gold"), "gold".into()),
+        ];
+        let grouping = Grouping {
+            keys: vec![Expression::Column("name".into())],
+            having: Predicate::Filter(FilterType::LessThan(
+                "COUNT(index)".into(),
+                DataType::Integer(1),
+            )),
+            aggregates: vec![aggregate(Function::Count, "index")],
+        };
+        let aggregation = plan_aggregation(&mut projections, grouping, &mut [], &table_schema)
+            .unwrap()
+            .unwrap();
+        assert_eq!(aggregation.schema()["name"], (ColumnType::Text, 0));
+        assert_eq!(aggregation.schema()["SUM(Gold)"], (ColumnType::Integer, 1));
+        assert_eq!(
+            aggregation.schema()["COUNT(index)"],
+            (ColumnType::Integer, 2)
+        );
+        assert_eq!(
+            projections[0],
+            Projection::Named(Expression::Column("name".into()), "name".into())
+        );
+
+        // Groups come out in the order they're first seen, and the lone
+        // group 3 is filtered out by HAVING
+        assert_eq!(
+            aggregation
+                .run(rows.into_iter().map(Ok), &table_schema, &overflow)
+                .unwrap(),
+            vec![vec![2, 17, 2], vec![1, 8, 2]]
+        );
+        // With GROUP BY, no rows means no groups
+        assert!(aggregation
+            .run(vec![], &table_schema, &overflow)
+            .unwrap()
+            .is_empty());
+
+        let mut ungrouped = vec![Projection::Named(
+            Expression::Column("Gold".into()),
+            "Gold".into(),
+        )];
+        let grouping = Grouping {
+            keys: vec![Expression::Column("name".into())],
+            ..Grouping::default()
+        };
+        assert!(plan_aggregation(&mut ungrouped, grouping, &mut [], &table_schema).is_err());
+
+        let mut projections = vec![Projection::Named(
+            aggregate(Function::Sum, "Gold"),
+            "g".into(),
+        )];
+        let grouping = Grouping {
+            having: Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(1))),
+            ..Grouping::default()
+        };
+        assert!(plan_aggregation(&mut projections, grouping, &mut [], &table_schema).is_err());
+    }
+
+    #[test]
+    fn test_plan_aggregation() {
+        let table_schema = test_schema();
+        let mut projections = vec![
+            Projection::Named(
+                Expression::Arithmetic(
+                    Box::new(aggregate(Function::Sum, "Gold")),
+                    Operator::Multiply,
+                    Box::new(Expression::Literal(DataType::Integer(2))),
+                ),
+                "double".into(),
+            ),
+            Projection::Named(aggregate(Function::Sum, "Gold"), "total".into()),
+        ];
+        let mut order_by = vec![(aggregate(Function::Max, "Gold"), Direction::Ascending)];
+        let aggregation = plan_aggregation(
+            &mut projections,
+            Grouping::default(),
+            &mut order_by,
+            &table_schema,
+        )
+        .unwrap()
+        .unwrap();
+
+        // The same aggregate is only worked out once
+        assert_eq!(aggregation.schema().len(), 2);
+        assert_eq!(aggregation.schema()["SUM(Gold)"], (ColumnType::Integer, 0));
+        assert_eq!(
+            projections[1],
+            Projection::Named(Expression::Column("SUM(Gold)".into()), "total".into())
+        );
+        assert_eq!(order_by[0].0, Expression::Column("MAX(Gold)".into()));
+
+        let mut plain = vec![Projection::Wildcard];
+        assert!(
+            plan_aggregation(&mut plain, Grouping::default(), &mut [], &table_schema)
+                .unwrap()
+                .is_none()
+        );
+
+        for bad in [
+            vec![
+                Projection::Named(Expression::Column("Gold".into()), "Gold".into()),
+                Projection::Named(aggregate(Function::Count, "index"), "count".into()),
+            ],
+            vec![
+                Projection::Wildcard,
+                Projection::Named(aggregate(Function::Count, "index"), "count".into()),
+            ],
+            vec![Projection::Named(
+                aggregate(Function::Sum, "name"),
+                "s".into(),
+            )],
+            vec![Projection::Named(
+                aggregate(Function::Min, "notes"),
+                "m".into(),
+            )],
+            vec![Projection::Named(
+                Expression::Aggregate(Function::Sum, Box::new(aggregate(Function::Count, "index"))),
+                "nested".into(),
+            )],
+        ] {
+            let mut bad = bad;
+            assert!(
+                plan_aggregation(&mut bad, Grouping::default(), &mut [], &table_schema).is_err()
+            );
+        }
+    }
+}
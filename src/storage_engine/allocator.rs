@@ -0,0 +1,141 @@
+use std::collections::BTreeSet;
+
+use byteorder::{ByteOrder, LE};
+
+use crate::common::{error::Error, Block, BLOCK_SIZE, COLUMN_WIDTH};
+
+/// Where a row lives in its table file: (block offset, slot within block)
+pub type RowLocation = (u64, u64);
+
+/// Hands out row ids and free row slots for a single table.
+///
+/// Built by scanning the table once, then kept up to date as rows are written,
+/// so inserts don't have to go looking for space every time.
+pub struct TableAllocator {
+    row_width: usize,
+    next_id: u64,
+    block_count: u64,
+    free_slots: BTreeSet<RowLocation>,
+}
+
+impl TableAllocator {
+    pub fn new(columns: usize) -> Self {
+        Self {
+            row_width: columns * COLUMN_WIDTH,
+            next_id: 1,
+            block_count: 0,
+            free_slots: BTreeSet::new(),
+        }
+    }
+
+    pub fn rows_per_block(&self) -> u64 {
+        (BLOCK_SIZE / self.row_width) as u64
+    }
+
+    /// Records what is in the block at `offset`, blocks must be scanned in order.
+    pub fn scan_block(&mut self, offset: u64, block: &Block) {
+        for slot in 0..self.rows_per_block() {
+            let start = slot as usize * self.row_width;
+            let id = LE::read_u64(&block[start..start + COLUMN_WIDTH]);
+            if id == 0 {
+                self.free_slots.insert((offset, slot));
+            } else if id >= self.next_id {
+                self.next_id = id + 1;
+            }
+        }
+        self.block_count = self.block_count.max(offset + 1);
+    }
+
+    /// Records that ids up to `id` were handed out before, even if no row
+    /// holds them any more.
+    pub fn skip_ids_to(&mut self, id: u64) {
+        self.next_id = self.next_id.max(id.saturating_add(1));
+    }
+
+    pub fn next_id(&mut self) -> Result<u64, Error> {
+        let id = self.next_id;
+        self.next_id = id
+            .checked_add(1)
+            .ok_or_else(|| Error::SchemaError("Ran out of row ids".into()))?;
+        Ok(id)
+    }
+
+    /// Hands a slot back once its row has been deleted.
+    pub fn free_slot(&mut self, location: RowLocation) {
+        self.free_slots.insert(location);
+    }
+
+    /// Takes the lowest free slot, growing the table by a block if there are none.
+    pub fn take_slot(&mut self) -> RowLocation {
+        if self.free_slots.is_empty() {
+            let offset = self.block_count;
+            self.block_count += 1;
+            for slot in 0..self.rows_per_block() {
+                self.free_slots.insert((offset, slot));
+            }
+        }
+        self.free_slots
+            .pop_first()
+            .expect("a fresh block always has free slots")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_and_allocate() {
+        // 5 columns of 8 bytes gives 204 rows a block
+        let mut allocator = TableAllocator::new(5);
+        assert_eq!(allocator.rows_per_block(), 204);
+
+        let mut block = [0u8; BLOCK_SIZE];
+        for slot in [0usize, 1, 3] {
+            let id = (slot as u64 + 1) * 10;
+            LE::write_u64(&mut block[slot * 40..slot * 40 + 8], id);
+        }
+        allocator.scan_block(0, &block);
+
+        assert_eq!(allocator.next_id().unwrap(), 41);
+        assert_eq!(allocator.next_id().unwrap(), 42);
+        assert_eq!(allocator.take_slot(), (0, 2));
+        assert_eq!(allocator.take_slot(), (0, 4));
+        for _ in 5..204 {
+            allocator.take_slot();
+        }
+        // Block 0 is full now, so the table grows
+        assert_eq!(allocator.take_slot(), (1, 0));
+        assert_eq!(allocator.take_slot(), (1, 1));
+    }
+
+    #[test]
+    fn test_freed_slots_are_reused_first() {
+        let mut allocator = TableAllocator::new(5);
+        let mut block = [0u8; BLOCK_SIZE];
+        for slot in 0..204usize {
+            LE::write_u64(&mut block[slot * 40..slot * 40 + 8], slot as u64 + 1);
+        }
+        allocator.scan_block(0, &block);
+
+        allocator.free_slot((0, 17));
+        allocator.free_slot((0, 3));
+        assert_eq!(allocator.take_slot(), (0, 3));
+        assert_eq!(allocator.take_slot(), (0, 17));
+        assert_eq!(allocator.take_slot(), (1, 0));
+        // Ids are never handed out twice, even once rows are gone
+        assert_eq!(allocator.next_id().unwrap(), 205);
+
+        // Nor are ids of rows deleted before the table was scanned
+        allocator.skip_ids_to(300);
+        allocator.skip_ids_to(250);
+        assert_eq!(allocator.next_id().unwrap(), 301);
+    }
+
+    #[test]
+    fn test_empty_table() {
+        let mut allocator = TableAllocator::new(7);
+        assert_eq!(allocator.next_id().unwrap(), 1);
+        assert_eq!(allocator.take_slot(), (0, 0));
+    }
+}
@@ -0,0 +1,407 @@
+use byteorder::{ByteOrder, LE};
+
+use crate::common::{error::Error, Block, BLOCK_SIZE};
+
+use super::allocator::RowLocation;
+
+/// An index entry: the key of a row's indexed column, then the block and slot
+/// the row is in. Rows with equal keys are told apart, and kept in order, by
+/// where they are.
+pub type Entry = (u64, u64, u64);
+
+// Every node page starts with its kind, how many entries or keys it holds,
+// and for leaves the page of the next leaf along, or 0 for the last one
+const HEADER: usize = 3 * 8;
+const ENTRY_WIDTH: usize = 3 * 8;
+const LEAF_CAPACITY: usize = (BLOCK_SIZE - HEADER) / ENTRY_WIDTH;
+// Internal nodes hold one more child than keys
+const INTERNAL_CAPACITY: usize = (BLOCK_SIZE - HEADER - 8) / (ENTRY_WIDTH + 8);
+
+const LEAF: u64 = 1;
+const INTERNAL: u64 = 2;
+
+// Page 0 holds the page of the root, how many pages the file has and the
+// highest key the tree has ever held
+const META_PAGE: u64 = 0;
+
+/// Where a tree keeps its pages.
+pub trait Pages {
+    fn read_page(&self, page: u64) -> Result<Block, Error>;
+    fn write_page(&self, page: u64, block: &Block) -> Result<(), Error>;
+}
+
+enum Node {
+    // Entries in order, and the page of the next leaf
+    Leaf(Vec<Entry>, u64),
+    // Keys in order, and the children between them. Every entry under
+    // children[i + 1] is at least keys[i].
+    Internal(Vec<Entry>, Vec<u64>),
+}
+
+impl Node {
+    fn read(block: &Block) -> Result<Self, Error> {
+        let count = read_u64(block, 8) as usize;
+        match read_u64(block, 0) {
+            LEAF => Ok(Node::Leaf(
+                (0..count)
+                    .map(|index| read_entry(block, HEADER + index * ENTRY_WIDTH))
+                    .collect(),
+                read_u64(block, 16),
+            )),
+            INTERNAL => {
+                let stride = ENTRY_WIDTH + 8;
+                Ok(Node::Internal(
+                    (0..count)
+                        .map(|index| read_entry(block, HEADER + 8 + index * stride))
+                        .collect(),
+                    (0..=count)
+                        .map(|index| read_u64(block, HEADER + index * stride))
+                        .collect(),
+                ))
+            }
+            kind => Err(Error::SchemaError(format!(
+                "Index page of unknown kind {}",
+                kind
+            ))),
+        }
+    }
+
+    fn write(&self) -> Block {
+        let mut block = [0u8; BLOCK_SIZE];
+        match self {
+            Node::Leaf(entries, next) => {
+                write_u64(&mut block, 0, LEAF);
+                write_u64(&mut block, 8, entries.len() as u64);
+                write_u64(&mut block, 16, *next);
+                for (index, entry) in entries.iter().enumerate() {
+                    write_entry(&mut block, HEADER + index * ENTRY_WIDTH, entry);
+                }
+            }
+            Node::Internal(keys, children) => {
+                let stride = ENTRY_WIDTH + 8;
+                write_u64(&mut block, 0, INTERNAL);
+                write_u64(&mut block, 8, keys.len() as u64);
+                for (index, child) in children.iter().enumerate() {
+                    write_u64(&mut block, HEADER + index * stride, *child);
+                }
+                for (index, key) in keys.iter().enumerate() {
+                    write_entry(&mut block, HEADER + 8 + index * stride, key);
+                }
+            }
+        }
+        block
+    }
+}
+
+/// A B+tree of index entries, kept in pages of its own.
+///
+/// Pages are never merged: a leaf emptied by removals stays in the chain of
+/// leaves until the index is built again.
+pub struct BTree<P: Pages> {
+    pages: P,
+}
+
+impl<P: Pages> BTree<P> {
+    pub fn new(pages: P) -> Self {
+        Self { pages }
+    }
+
+    /// Adds an entry, unless the tree already has it.
+    pub fn insert(&self, entry: Entry) -> Result<(), Error> {
+        self.raise_highest_key(entry.0)?;
+        let (root, _) = self.meta()?;
+        if let Some((separator, right)) = self.insert_into(root, entry)? {
+            // The root split, so the tree grows a level
+            let new_root = self.allocate()?;
+            let node = Node::Internal(vec![separator], vec![root, right]);
+            self.pages.write_page(new_root, &node.write())?;
+            let (_, page_count) = self.meta()?;
+            self.write_meta(new_root, page_count)?;
+        }
+        Ok(())
+    }
+
+    /// Takes an entry out, giving back whether the tree had it.
+    pub fn remove(&self, entry: Entry) -> Result<bool, Error> {
+        let (page, mut node) = self.find_leaf(entry)?;
+        if let Node::Leaf(entries, _) = &mut node {
+            if let Ok(index) = entries.binary_search(&entry) {
+                entries.remove(index);
+                self.pages.write_page(page, &node.write())?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Where every row with a key from `low` to `high`, inclusive, is.
+    pub fn range(&self, low: u64, high: u64) -> Result<Vec<RowLocation>, Error> {
+        let mut locations = Vec::new();
+        if low > high {
+            return Ok(locations);
+        }
+        let (_, mut node) = self.find_leaf((low, 0, 0))?;
+        loop {
+            let Node::Leaf(entries, next) = node else {
+                unreachable!("find_leaf only gives back leaves")
+            };
+            for (key, offset, slot) in entries {
+                if key > high {
+                    return Ok(locations);
+                }
+                if key >= low {
+                    locations.push((offset, slot));
+                }
+            }
+            if next == 0 {
+                return Ok(locations);
+            }
+            node = Node::read(&self.pages.read_page(next)?)?;
+        }
+    }
+
+    /// The leaf an entry belongs in, and its page.
+    fn find_leaf(&self, entry: Entry) -> Result<(u64, Node), Error> {
+        let (mut page, _) = self.meta()?;
+        loop {
+            match Node::read(&self.pages.read_page(page)?)? {
+                Node::Internal(keys, children) => {
+                    page = children[keys.partition_point(|key| *key <= entry)];
+                }
+                leaf => return Ok((page, leaf)),
+            }
+        }
+    }
+
+    /// Inserts below `page`, giving back the separator and page of the new
+    /// right sibling if the node had to split.
+    fn insert_into(&self, page: u64, entry: Entry) -> Result<Option<(Entry, u64)>, Error> {
+        let mut node = Node::read(&self.pages.read_page(page)?)?;
+        let split = match &mut node {
+            Node::Leaf(entries, next) => {
+                match entries.binary_search(&entry) {
+                    Ok(_) => return Ok(None),
+                    Err(index) => entries.insert(index, entry),
+                }
+                if entries.len() > LEAF_CAPACITY {
+                    let right = self.allocate()?;
+                    let moved = entries.split_off(entries.len() / 2);
+                    let separator = moved[0];
+                    let sibling = Node::Leaf(moved, *next);
+                    self.pages.write_page(right, &sibling.write())?;
+                    *next = right;
+                    Some((separator, right))
+                } else {
+                    None
+                }
+            }
+            Node::Internal(keys, children) => {
+                let index = keys.partition_point(|key| *key <= entry);
+                let Some((separator, right)) = self.insert_into(children[index], entry)? else {
+                    return Ok(None);
+                };
+                keys.insert(index, separator);
+                children.insert(index + 1, right);
+                if keys.len() > INTERNAL_CAPACITY {
+                    let sibling_page = self.allocate()?;
+                    let middle = keys.len() / 2;
+                    let moved_keys = keys.split_off(middle + 1);
+                    let separator = keys.pop().expect("the middle key is there");
+                    let moved_children = children.split_off(middle + 1);
+                    let sibling = Node::Internal(moved_keys, moved_children);
+                    self.pages.write_page(sibling_page, &sibling.write())?;
+                    Some((separator, sibling_page))
+                } else {
+                    None
+                }
+            }
+        };
+        self.pages.write_page(page, &node.write())?;
+        Ok(split)
+    }
+
+    /// The highest key the tree has held, even if it was removed since.
+    pub fn highest_key(&self) -> Result<u64, Error> {
+        Ok(read_u64(&self.pages.read_page(META_PAGE)?, 16))
+    }
+
+    /// Makes `key` the highest key the tree has held, if it's higher.
+    pub fn raise_highest_key(&self, key: u64) -> Result<(), Error> {
+        let mut block = self.pages.read_page(META_PAGE)?;
+        if key > read_u64(&block, 16) {
+            write_u64(&mut block, 16, key);
+            self.pages.write_page(META_PAGE, &block)?;
+        }
+        Ok(())
+    }
+
+    fn meta(&self) -> Result<(u64, u64), Error> {
+        let block = self.pages.read_page(META_PAGE)?;
+        Ok((read_u64(&block, 0), read_u64(&block, 8)))
+    }
+
+    fn write_meta(&self, root: u64, page_count: u64) -> Result<(), Error> {
+        let mut block = self.pages.read_page(META_PAGE)?;
+        write_u64(&mut block, 0, root);
+        write_u64(&mut block, 8, page_count);
+        self.pages.write_page(META_PAGE, &block)
+    }
+
+    /// Claims a page at the end of the file.
+    fn allocate(&self) -> Result<u64, Error> {
+        let (root, page_count) = self.meta()?;
+        self.write_meta(root, page_count + 1)?;
+        Ok(page_count)
+    }
+}
+
+/// Lays out the pages of a tree holding `entries`, which must be sorted and
+/// free of repeats. The leaves are packed full, and the tree built up from
+/// them a level at a time.
+pub fn build(entries: &[Entry]) -> Vec<Block> {
+    let mut pages = vec![[0u8; BLOCK_SIZE]];
+    let chunks: Vec<&[Entry]> = if entries.is_empty() {
+        vec![&[]]
+    } else {
+        entries.chunks(LEAF_CAPACITY).collect()
+    };
+
+    // Each node of the level being built, by its smallest entry and its page
+    let mut level = Vec::new();
+    let first_leaf = pages.len() as u64;
+    for (index, chunk) in chunks.iter().enumerate() {
+        let page = first_leaf + index as u64;
+        let next = if index + 1 < chunks.len() {
+            page + 1
+        } else {
+            0
+        };
+        pages.push(Node::Leaf(chunk.to_vec(), next).write());
+        level.push((chunk.first().copied().unwrap_or_default(), page));
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(INTERNAL_CAPACITY + 1)
+            .map(|children| {
+                let keys = children[1..].iter().map(|(first, _)| *first).collect();
+                let pages_of = children.iter().map(|(_, page)| *page).collect();
+                pages.push(Node::Internal(keys, pages_of).write());
+                (children[0].0, pages.len() as u64 - 1)
+            })
+            .collect();
+    }
+
+    let mut meta = [0u8; BLOCK_SIZE];
+    write_u64(&mut meta, 0, level[0].1);
+    write_u64(&mut meta, 8, pages.len() as u64);
+    write_u64(&mut meta, 16, entries.last().map_or(0, |(key, _, _)| *key));
+    pages[META_PAGE as usize] = meta;
+    pages
+}
+
+pub(super) fn read_u64(block: &Block, at: usize) -> u64 {
+    LE::read_u64(&block[at..at + 8])
+}
+
+pub(super) fn write_u64(block: &mut Block, at: usize, value: u64) {
+    LE::write_u64(&mut block[at..at + 8], value);
+}
+
+pub(super) fn read_entry(block: &Block, at: usize) -> Entry {
+    (
+        read_u64(block, at),
+        read_u64(block, at + 8),
+        read_u64(block, at + 16),
+    )
+}
+
+pub(super) fn write_entry(block: &mut Block, at: usize, (key, offset, slot): &Entry) {
+    write_u64(block, at, *key);
+    write_u64(block, at + 8, *offset);
+    write_u64(block, at + 16, *slot);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    // Pages kept in memory, growing as they're written
+    impl Pages for RefCell<Vec<Block>> {
+        fn read_page(&self, page: u64) -> Result<Block, Error> {
+            Ok(self.borrow()[page as usize])
+        }
+
+        fn write_page(&self, page: u64, block: &Block) -> Result<(), Error> {
+            let mut pages = self.borrow_mut();
+            if pages.len() <= page as usize {
+                pages.resize(page as usize + 1, [0u8; BLOCK_SIZE]);
+            }
+            pages[page as usize] = *block;
+            Ok(())
+        }
+    }
+
+    /// Where the rows with keys from `low` to `high` are, found the slow way.
+    fn expected(entries: &[Entry], low: u64, high: u64) -> Vec<RowLocation> {
+        let mut entries: Vec<_> = entries
+            .iter()
+            .filter(|(key, _, _)| (low..=high).contains(key))
+            .copied()
+            .collect();
+        entries.sort();
+        entries
+            .into_iter()
+            .map(|(_, offset, slot)| (offset, slot))
+            .collect()
+    }
+
+    #[test]
+    fn test_insert_split_and_remove() {
+        let tree = BTree::new(RefCell::new(build(&[])));
+        // Enough entries, with plenty of repeated keys, to split internal
+        // nodes as well as leaves
+        let entries: Vec<Entry> = (0..100_000u64)
+            .map(|n| (n * 7919 % 1000, n / 200, n % 200))
+            .collect();
+        for entry in &entries {
+            tree.insert(*entry).unwrap();
+        }
+        tree.insert(entries[0]).unwrap();
+        assert!(tree.pages.borrow().len() > LEAF_CAPACITY);
+
+        for (low, high) in [(0, 999), (500, 500), (10, 20), (999, 5000), (7, 3)] {
+            assert_eq!(
+                tree.range(low, high).unwrap(),
+                expected(&entries, low, high)
+            );
+        }
+
+        // Every key below 500 is taken out
+        let (removed, kept): (Vec<Entry>, Vec<Entry>) =
+            entries.iter().partition(|(key, _, _)| *key < 500);
+        for entry in &removed {
+            assert!(tree.remove(*entry).unwrap());
+        }
+        assert!(!tree.remove(removed[0]).unwrap());
+        assert!(tree.range(0, 499).unwrap().is_empty());
+        assert_eq!(tree.range(0, 999).unwrap(), expected(&kept, 0, 999));
+    }
+
+    #[test]
+    fn test_built_tree_takes_inserts() {
+        let mut entries: Vec<Entry> = (0..50_000u64).map(|n| (n / 3, n, 0)).collect();
+        let tree = BTree::new(RefCell::new(build(&entries)));
+        assert_eq!(tree.range(0, u64::MAX).unwrap().len(), entries.len());
+        assert_eq!(tree.range(100, 101).unwrap(), expected(&entries, 100, 101));
+
+        for n in 0..1000u64 {
+            let entry = (n * 17, 100_000 + n, 1);
+            tree.insert(entry).unwrap();
+            entries.push(entry);
+        }
+        assert_eq!(tree.range(0, u64::MAX).unwrap().len(), entries.len());
+        assert_eq!(tree.range(340, 680).unwrap(), expected(&entries, 340, 680));
+    }
+}
@@ -1,22 +1,422 @@
 use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::os::unix::prelude::FileExt;
 
-use crate::common::Block;
+use crate::common::{error::Error, Block, BLOCK_SIZE};
 
-struct Cache {
+use super::wal::WriteAheadLog;
+
+/// How many blocks the buffer pool holds, 8MiB worth.
+pub const CACHE_BLOCKS: usize = 1024;
+
+/// Where a pinned block sits in the pool.
+pub type FrameId = usize;
+
+/// A fixed-size pool of blocks shared by every table.
+///
+/// Blocks are pinned while in use and can't be evicted until unpinned. Once
+/// the pool is full, room is made with the CLOCK algorithm, and a block that
+/// changed since it was read is written back to its table before it goes.
+/// Changed blocks have to be in the write-ahead log before that can happen,
+/// so until they're logged they stay put too, along with what they held
+/// before in case the statement changing them fails. A statement is only
+/// logged once it's done, so one that changes more blocks than the pool
+/// holds has the pool grow past its size to hold them.
+pub struct Cache {
+    capacity: usize,
+    frames: Vec<SmartBlock>,
     tables: HashMap<String, TableCache>,
+    // Frames whose block was thrown away, ready to be reused
+    free: Vec<FrameId>,
+    hand: usize,
 }
 
+/// The frame holding each cached block of a table, by block offset.
+#[derive(Default)]
 struct TableCache {
-    row_length: u64,
-    blocks: BTreeMap<u64, SmartBlock>,
+    blocks: BTreeMap<u64, FrameId>,
 }
 
 struct SmartBlock {
-    rows: Vec<RowInfo>,
+    // The table and offset of the block, None once it's been thrown away
+    owner: Option<(String, u64)>,
     block: Block,
+    pins: usize,
+    // Changed since it was read from or written to its table
+    dirty: bool,
+    // Its latest contents are in the write-ahead log
+    logged: bool,
+    // What it held before its changes that aren't logged yet, and whether it
+    // was dirty then, so they can be thrown away
+    undo: Option<(Box<Block>, bool)>,
+    // Set whenever the block is pinned, and cleared as the clock hand passes.
+    // The hand only evicts blocks it finds cleared.
+    referenced: bool,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: Vec::new(),
+            tables: HashMap::new(),
+            free: Vec::new(),
+            hand: 0,
+        }
+    }
+
+    /// Pins a block into the pool, reading it from its table's file in
+    /// `files` if it isn't there yet.
+    pub fn pin(
+        &mut self,
+        files: &HashMap<String, File>,
+        table_name: &str,
+        offset: u64,
+    ) -> Result<FrameId, Error> {
+        let file = files
+            .get(table_name)
+            .ok_or_else(|| Error::SchemaError(format!("Table {} does not exist", table_name)))?;
+        let cached = self
+            .tables
+            .get(table_name)
+            .and_then(|table| table.blocks.get(&offset));
+        if let Some(frame) = cached.copied() {
+            let smart_block = &mut self.frames[frame];
+            smart_block.pins += 1;
+            smart_block.referenced = true;
+            return Ok(frame);
+        }
+
+        let mut block = [0u8; BLOCK_SIZE];
+        // Reading past the end of the file leaves the block zeroed
+        file.read_at(&mut block, offset * BLOCK_SIZE as u64)?;
+        let smart_block = SmartBlock {
+            owner: Some((table_name.to_owned(), offset)),
+            block,
+            pins: 1,
+            dirty: false,
+            logged: false,
+            undo: None,
+            referenced: true,
+        };
+        let frame = match self.free.pop() {
+            Some(frame) => {
+                self.frames[frame] = smart_block;
+                frame
+            }
+            None if self.frames.len() < self.capacity => {
+                self.frames.push(smart_block);
+                self.frames.len() - 1
+            }
+            None => match self.evict(files)? {
+                Some(frame) => {
+                    self.frames[frame] = smart_block;
+                    frame
+                }
+                None => {
+                    self.frames.push(smart_block);
+                    self.frames.len() - 1
+                }
+            },
+        };
+        self.tables
+            .entry(table_name.to_owned())
+            .or_default()
+            .blocks
+            .insert(offset, frame);
+        Ok(frame)
+    }
+
+    /// Releases a pin, noting whether the block was changed while pinned.
+    pub fn unpin(&mut self, frame: FrameId, dirty: bool) {
+        let smart_block = &mut self.frames[frame];
+        smart_block.pins -= 1;
+        if dirty {
+            smart_block.dirty = true;
+            smart_block.logged = false;
+        }
+    }
+
+    pub fn block(&self, frame: FrameId) -> &Block {
+        &self.frames[frame].block
+    }
+
+    pub fn block_mut(&mut self, frame: FrameId) -> &mut Block {
+        let smart_block = &mut self.frames[frame];
+        if smart_block.undo.is_none() {
+            smart_block.undo = Some((Box::new(smart_block.block), smart_block.dirty));
+        }
+        &mut smart_block.block
+    }
+
+    /// One past the last block of a table the pool holds, which can be past
+    /// the end of the table's file until it's written back.
+    pub fn block_count(&self, table_name: &str) -> u64 {
+        self.tables
+            .get(table_name)
+            .and_then(|table| table.blocks.keys().next_back())
+            .map_or(0, |offset| offset + 1)
+    }
+
+    /// Appends every block changed since it was last logged to the log, as
+    /// one commit.
+    pub fn log_changes(&mut self, wal: &mut WriteAheadLog) -> Result<(), Error> {
+        let unlogged: Vec<_> = self
+            .frames
+            .iter_mut()
+            .filter(|frame| frame.dirty && !frame.logged)
+            .collect();
+        if unlogged.is_empty() {
+            return Ok(());
+        }
+        wal.append(unlogged.iter().filter_map(|smart_block| {
+            let (table_name, offset) = smart_block.owner.as_ref()?;
+            Some((table_name.as_str(), *offset, &smart_block.block))
+        }))?;
+        for smart_block in unlogged {
+            smart_block.logged = true;
+            smart_block.undo = None;
+        }
+        Ok(())
+    }
+
+    /// Puts back what the blocks of some tables held before their changes
+    /// that aren't logged yet, for when whatever made them failed part way.
+    pub fn discard_unlogged(&mut self, table_names: &[String]) {
+        for table_name in table_names {
+            let Some(table) = self.tables.get(table_name) else {
+                continue;
+            };
+            for frame in table.blocks.values() {
+                let smart_block = &mut self.frames[*frame];
+                if let Some((block, dirty)) = smart_block.undo.take() {
+                    smart_block.block = *block;
+                    smart_block.dirty = dirty;
+                    smart_block.logged = dirty;
+                }
+            }
+        }
+    }
+
+    /// Writes every changed block back to its table. They should all be
+    /// logged first.
+    pub fn flush(&mut self, files: &HashMap<String, File>) -> Result<(), Error> {
+        for smart_block in self.frames.iter_mut().filter(|frame| frame.dirty) {
+            smart_block.write_back(files)?;
+        }
+        Ok(())
+    }
+
+    /// Throws away a table's blocks without writing them back, for when the
+    /// table's file is removed or replaced.
+    pub fn forget(&mut self, table_name: &str) {
+        if let Some(table) = self.tables.remove(table_name) {
+            for frame in table.blocks.into_values() {
+                let smart_block = &mut self.frames[frame];
+                smart_block.owner = None;
+                smart_block.dirty = false;
+                smart_block.undo = None;
+                self.free.push(frame);
+            }
+        }
+    }
+
+    /// Finds an unpinned block the clock hand passes twice without it being
+    /// used, writes it back if need be, and gives up its frame. Gives back
+    /// None if only blocks waiting to be logged are in the way.
+    fn evict(&mut self, files: &HashMap<String, File>) -> Result<Option<FrameId>, Error> {
+        let mut unlogged = false;
+        // The first lap clears every reference bit, so a second lap finds a
+        // block unless they're all pinned or unlogged
+        for _ in 0..2 * self.frames.len() {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+            let smart_block = &mut self.frames[frame];
+            if smart_block.pins > 0 {
+                continue;
+            }
+            if smart_block.dirty && !smart_block.logged {
+                unlogged = true;
+                continue;
+            }
+            if smart_block.referenced {
+                smart_block.referenced = false;
+                continue;
+            }
+            if smart_block.dirty {
+                smart_block.write_back(files)?;
+            }
+            if let Some((table_name, offset)) = smart_block.owner.take() {
+                if let Some(table) = self.tables.get_mut(&table_name) {
+                    table.blocks.remove(&offset);
+                }
+            }
+            return Ok(Some(frame));
+        }
+        if unlogged {
+            return Ok(None);
+        }
+        Err(Error::QueryError(
+            "Every block in the buffer pool is pinned".into(),
+        ))
+    }
+}
+
+impl SmartBlock {
+    fn write_back(&mut self, files: &HashMap<String, File>) -> Result<(), Error> {
+        if let Some((table_name, offset)) = &self.owner {
+            let file = files.get(table_name).ok_or_else(|| {
+                Error::SchemaError(format!("Table {} does not exist", table_name))
+            })?;
+            file.write_at(&self.block, offset * BLOCK_SIZE as u64)?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
 }
 
-struct RowInfo {
-    id: u64,
-    offset: u64,
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::TempDir;
+
+    use super::*;
+
+    fn table_files(dir: &TempDir, names: &[&str]) -> HashMap<String, File> {
+        names
+            .iter()
+            .map(|name| {
+                let file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(dir.0.join(name))
+                    .unwrap();
+                (name.to_string(), file)
+            })
+            .collect()
+    }
+
+    fn store(cache: &mut Cache, files: &HashMap<String, File>, table: &str, offset: u64, byte: u8) {
+        let frame = cache.pin(files, table, offset).unwrap();
+        cache.block_mut(frame)[0] = byte;
+        cache.unpin(frame, true);
+    }
+
+    #[test]
+    fn test_write_back_on_eviction() {
+        let dir = TempDir::new("cache_eviction");
+        let files = table_files(&dir, &["heroes"]);
+        let mut wal = WriteAheadLog::open(&dir.db_path()).unwrap();
+        let mut cache = Cache::new(2);
+
+        store(&mut cache, &files, "heroes", 0, 7);
+        store(&mut cache, &files, "heroes", 1, 8);
+        // Nothing has been written yet, but the pool knows the table grew
+        assert_eq!(files["heroes"].metadata().unwrap().len(), 0);
+        assert_eq!(cache.block_count("heroes"), 2);
+        // Neither block is in the log, so neither can be written back, and
+        // the pool grows to make room instead
+        let frame = cache.pin(&files, "heroes", 2).unwrap();
+        cache.unpin(frame, false);
+        assert_eq!(cache.frames.len(), 3);
+        assert_eq!(files["heroes"].metadata().unwrap().len(), 0);
+
+        // Once they're logged, a fourth block pushes out the first
+        cache.log_changes(&mut wal).unwrap();
+        let frame = cache.pin(&files, "heroes", 3).unwrap();
+        cache.unpin(frame, false);
+        assert_eq!(cache.frames.len(), 3);
+        let mut block = [0u8; BLOCK_SIZE];
+        files["heroes"].read_at(&mut block, 0).unwrap();
+        assert_eq!(block[0], 7);
+
+        // It's read back from disk when it's needed again
+        let frame = cache.pin(&files, "heroes", 0).unwrap();
+        assert_eq!(cache.block(frame)[0], 7);
+        cache.unpin(frame, false);
+
+        cache.flush(&files).unwrap();
+        files["heroes"]
+            .read_at(&mut block, BLOCK_SIZE as u64)
+            .unwrap();
+        assert_eq!(block[0], 8);
+    }
+
+    #[test]
+    fn test_discard_unlogged() {
+        let dir = TempDir::new("cache_discard");
+        let files = table_files(&dir, &["heroes", "purses"]);
+        let mut wal = WriteAheadLog::open(&dir.db_path()).unwrap();
+        let mut cache = Cache::new(4);
+        let byte = |cache: &mut Cache, table, offset| {
+            let frame = cache.pin(&files, table, offset).unwrap();
+            let byte = cache.block(frame)[0];
+            cache.unpin(frame, false);
+            byte
+        };
+
+        store(&mut cache, &files, "heroes", 0, 7);
+        cache.log_changes(&mut wal).unwrap();
+        store(&mut cache, &files, "heroes", 0, 9);
+        store(&mut cache, &files, "heroes", 0, 10);
+        store(&mut cache, &files, "heroes", 1, 4);
+        store(&mut cache, &files, "purses", 0, 5);
+        cache.discard_unlogged(&["heroes".into()]);
+
+        // The logged change is back, and still has to be written
+        assert_eq!(byte(&mut cache, "heroes", 0), 7);
+        assert_eq!(byte(&mut cache, "heroes", 1), 0);
+        assert_eq!(byte(&mut cache, "purses", 0), 5);
+        assert!(cache
+            .frames
+            .iter()
+            .all(|frame| frame.undo.is_none() || frame.owner == Some(("purses".into(), 0))));
+        cache.log_changes(&mut wal).unwrap();
+        cache.flush(&files).unwrap();
+        let mut block = [0u8; BLOCK_SIZE];
+        files["heroes"].read_at(&mut block, 0).unwrap();
+        assert_eq!(block[0], 7);
+        files["purses"].read_at(&mut block, 0).unwrap();
+        assert_eq!(block[0], 5);
+    }
+
+    #[test]
+    fn test_pinned_blocks_stay() {
+        let dir = TempDir::new("cache_pins");
+        let files = table_files(&dir, &["heroes", "purses"]);
+        let mut cache = Cache::new(2);
+
+        let pinned = cache.pin(&files, "heroes", 0).unwrap();
+        let frame = cache.pin(&files, "purses", 0).unwrap();
+        cache.unpin(frame, false);
+        // Only the purse can make room
+        let other = cache.pin(&files, "purses", 1).unwrap();
+        let again = cache.pin(&files, "heroes", 0).unwrap();
+        assert_eq!(again, pinned);
+
+        // With everything pinned there's nowhere to put another block
+        assert!(cache.pin(&files, "purses", 2).is_err());
+        cache.unpin(other, false);
+        cache.unpin(again, false);
+        cache.unpin(pinned, false);
+        assert!(cache.pin(&files, "purses", 2).is_ok());
+        assert!(cache.pin(&files, "dragons", 0).is_err());
+    }
+
+    #[test]
+    fn test_forget_table() {
+        let dir = TempDir::new("cache_forget");
+        let files = table_files(&dir, &["heroes"]);
+        let mut cache = Cache::new(2);
+
+        store(&mut cache, &files, "heroes", 0, 7);
+        cache.forget("heroes");
+        cache.flush(&files).unwrap();
+        assert_eq!(cache.block_count("heroes"), 0);
+        assert_eq!(files["heroes"].metadata().unwrap().len(), 0);
+
+        let frame = cache.pin(&files, "heroes", 0).unwrap();
+        assert_eq!(cache.block(frame)[0], 0);
+    }
 }
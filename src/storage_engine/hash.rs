@@ -0,0 +1,407 @@
+use crate::common::{error::Error, Block, BLOCK_SIZE};
+
+use super::allocator::RowLocation;
+use super::btree::{read_entry, read_u64, write_entry, write_u64, Entry, Pages};
+
+// Every bucket page starts with how many entries it holds, and the page of
+// the next one in the bucket's chain, or 0 for the last
+const HEADER: usize = 2 * 8;
+const ENTRY_WIDTH: usize = 3 * 8;
+const BUCKET_CAPACITY: usize = (BLOCK_SIZE - HEADER) / ENTRY_WIDTH;
+// Another bucket is split off once they hold more than this on average
+const FILL: usize = BUCKET_CAPACITY * 3 / 4;
+
+// Page 0 holds the level, the next bucket to split, how many pages and
+// entries the file has, then the pages of the directory. Directory pages
+// hold the first page of every bucket.
+const META_PAGE: u64 = 0;
+const META_HEADER: usize = 4 * 8;
+const DIRECTORY_CAPACITY: usize = BLOCK_SIZE / 8;
+// Past this, buckets just grow longer chains
+const MAX_BUCKETS: usize = (BLOCK_SIZE - META_HEADER) / 8 * DIRECTORY_CAPACITY;
+
+/// Spreads keys evenly over the buckets, however alike they are.
+fn hash(key: u64) -> u64 {
+    let mut hash = key ^ (key >> 30);
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+struct Meta {
+    // There are 2^level buckets, plus those split off so far this round
+    level: u32,
+    split: u64,
+    page_count: u64,
+    entry_count: u64,
+    directory: Vec<u64>,
+}
+
+impl Meta {
+    fn read(block: &Block) -> Self {
+        let mut meta = Meta {
+            level: read_u64(block, 0) as u32,
+            split: read_u64(block, 8),
+            page_count: read_u64(block, 16),
+            entry_count: read_u64(block, 24),
+            directory: Vec::new(),
+        };
+        meta.directory = (0..meta.bucket_count().div_ceil(DIRECTORY_CAPACITY))
+            .map(|index| read_u64(block, META_HEADER + index * 8))
+            .collect();
+        meta
+    }
+
+    fn write(&self) -> Block {
+        let mut block = [0u8; BLOCK_SIZE];
+        write_u64(&mut block, 0, self.level as u64);
+        write_u64(&mut block, 8, self.split);
+        write_u64(&mut block, 16, self.page_count);
+        write_u64(&mut block, 24, self.entry_count);
+        for (index, page) in self.directory.iter().enumerate() {
+            write_u64(&mut block, META_HEADER + index * 8, *page);
+        }
+        block
+    }
+
+    fn bucket_count(&self) -> usize {
+        (1 << self.level) + self.split as usize
+    }
+
+    /// The bucket a key belongs in. Buckets already split this round are
+    /// told apart by one more bit of the hash.
+    fn bucket_of(&self, key: u64) -> usize {
+        let hash = hash(key);
+        let bucket = hash & ((1 << self.level) - 1);
+        if bucket < self.split {
+            (hash & ((2 << self.level) - 1)) as usize
+        } else {
+            bucket as usize
+        }
+    }
+
+    /// Claims a page at the end of the file.
+    fn allocate(&mut self) -> u64 {
+        self.page_count += 1;
+        self.page_count - 1
+    }
+}
+
+fn read_bucket(block: &Block) -> (Vec<Entry>, u64) {
+    let count = read_u64(block, 0) as usize;
+    let entries = (0..count)
+        .map(|index| read_entry(block, HEADER + index * ENTRY_WIDTH))
+        .collect();
+    (entries, read_u64(block, 8))
+}
+
+fn write_bucket(entries: &[Entry], next: u64) -> Block {
+    let mut block = [0u8; BLOCK_SIZE];
+    write_u64(&mut block, 0, entries.len() as u64);
+    write_u64(&mut block, 8, next);
+    for (index, entry) in entries.iter().enumerate() {
+        write_entry(&mut block, HEADER + index * ENTRY_WIDTH, entry);
+    }
+    block
+}
+
+/// The pages' worth of entries a bucket holds. Even an empty bucket has a
+/// page.
+fn chunks(entries: &[Entry]) -> Vec<&[Entry]> {
+    if entries.is_empty() {
+        vec![&[]]
+    } else {
+        entries.chunks(BUCKET_CAPACITY).collect()
+    }
+}
+
+/// A linear hash of index entries, kept in pages of its own. It only finds
+/// rows by a single key, but does so in a read or two however many there
+/// are.
+///
+/// Buckets are split one at a time, in turn, as the index fills up. Pages
+/// emptied by removals stay in their bucket's chain until the index is
+/// built again.
+pub struct HashIndex<P: Pages> {
+    pages: P,
+}
+
+impl<P: Pages> HashIndex<P> {
+    pub fn new(pages: P) -> Self {
+        Self { pages }
+    }
+
+    /// Where every row with the key is.
+    pub fn get(&self, key: u64) -> Result<Vec<RowLocation>, Error> {
+        let meta = self.meta()?;
+        let mut locations = Vec::new();
+        for (_, entries, _) in self.chain(self.bucket_page(&meta, meta.bucket_of(key))?)? {
+            locations.extend(
+                entries
+                    .into_iter()
+                    .filter(|(entry_key, _, _)| *entry_key == key)
+                    .map(|(_, offset, slot)| (offset, slot)),
+            );
+        }
+        Ok(locations)
+    }
+
+    /// Adds an entry, unless the index already has it.
+    pub fn insert(&self, entry: Entry) -> Result<(), Error> {
+        let mut meta = self.meta()?;
+        let chain = self.chain(self.bucket_page(&meta, meta.bucket_of(entry.0))?)?;
+        if chain.iter().any(|(_, entries, _)| entries.contains(&entry)) {
+            return Ok(());
+        }
+        match chain
+            .iter()
+            .find(|(_, entries, _)| entries.len() < BUCKET_CAPACITY)
+        {
+            Some((page, entries, next)) => {
+                let mut entries = entries.to_owned();
+                entries.push(entry);
+                self.pages
+                    .write_page(*page, &write_bucket(&entries, *next))?;
+            }
+            None => {
+                // Every page is full, so the chain grows by one
+                let page = meta.allocate();
+                self.pages.write_page(page, &write_bucket(&[entry], 0))?;
+                let (last, entries, _) = chain.last().expect("every bucket has a page");
+                self.pages.write_page(*last, &write_bucket(entries, page))?;
+            }
+        }
+
+        meta.entry_count += 1;
+        let bucket_count = meta.bucket_count();
+        if meta.entry_count as usize > bucket_count * FILL && bucket_count < MAX_BUCKETS {
+            self.split(&mut meta)?;
+        }
+        self.pages.write_page(META_PAGE, &meta.write())
+    }
+
+    /// Takes an entry out, giving back whether the index had it.
+    pub fn remove(&self, entry: Entry) -> Result<bool, Error> {
+        let mut meta = self.meta()?;
+        for (page, mut entries, next) in
+            self.chain(self.bucket_page(&meta, meta.bucket_of(entry.0))?)?
+        {
+            if let Some(index) = entries.iter().position(|found| *found == entry) {
+                entries.remove(index);
+                self.pages.write_page(page, &write_bucket(&entries, next))?;
+                meta.entry_count -= 1;
+                self.pages.write_page(META_PAGE, &meta.write())?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Moves the entries of the next bucket in turn that belong in a new
+    /// bucket over to it. The new bucket takes whatever pages the old one
+    /// no longer needs.
+    fn split(&self, meta: &mut Meta) -> Result<(), Error> {
+        let old_bucket = meta.split as usize;
+        let new_bucket = old_bucket + (1 << meta.level);
+        let chain = self.chain(self.bucket_page(meta, old_bucket)?)?;
+        let pages = chain.iter().map(|(page, _, _)| *page).collect();
+        let (kept, moved): (Vec<Entry>, Vec<Entry>) = chain
+            .into_iter()
+            .flat_map(|(_, entries, _)| entries)
+            .partition(|(key, _, _)| hash(*key) & ((2 << meta.level) - 1) == old_bucket as u64);
+
+        let (_, spare) = self.write_chain(meta, pages, &kept)?;
+        // Any pages still spare are lost until the index is built again
+        let (new_page, _) = self.write_chain(meta, spare, &moved)?;
+        if new_bucket.is_multiple_of(DIRECTORY_CAPACITY) {
+            let directory_page = meta.allocate();
+            meta.directory.push(directory_page);
+            self.pages.write_page(directory_page, &[0u8; BLOCK_SIZE])?;
+        }
+        let directory_page = meta.directory[new_bucket / DIRECTORY_CAPACITY];
+        let mut directory = self.pages.read_page(directory_page)?;
+        write_u64(
+            &mut directory,
+            (new_bucket % DIRECTORY_CAPACITY) * 8,
+            new_page,
+        );
+        self.pages.write_page(directory_page, &directory)?;
+
+        meta.split += 1;
+        if meta.split == 1 << meta.level {
+            meta.level += 1;
+            meta.split = 0;
+        }
+        Ok(())
+    }
+
+    /// Writes a bucket's entries to a chain of pages, using up `spare` pages
+    /// before claiming new ones. Gives back the chain's first page and the
+    /// pages it didn't need.
+    fn write_chain(
+        &self,
+        meta: &mut Meta,
+        spare: Vec<u64>,
+        entries: &[Entry],
+    ) -> Result<(u64, Vec<u64>), Error> {
+        let chunks = chunks(entries);
+        let mut spare = spare.into_iter();
+        let pages: Vec<u64> = chunks
+            .iter()
+            .map(|_| spare.next().unwrap_or_else(|| meta.allocate()))
+            .collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(index + 1).copied().unwrap_or(0);
+            self.pages
+                .write_page(pages[index], &write_bucket(chunk, next))?;
+        }
+        Ok((pages[0], spare.collect()))
+    }
+
+    /// Every page of a bucket's chain, with the entries it holds and the
+    /// page after it.
+    fn chain(&self, mut page: u64) -> Result<Vec<(u64, Vec<Entry>, u64)>, Error> {
+        let mut chain = Vec::new();
+        loop {
+            let (entries, next) = read_bucket(&self.pages.read_page(page)?);
+            chain.push((page, entries, next));
+            if next == 0 {
+                return Ok(chain);
+            }
+            page = next;
+        }
+    }
+
+    fn bucket_page(&self, meta: &Meta, bucket: usize) -> Result<u64, Error> {
+        let directory = self
+            .pages
+            .read_page(meta.directory[bucket / DIRECTORY_CAPACITY])?;
+        Ok(read_u64(&directory, (bucket % DIRECTORY_CAPACITY) * 8))
+    }
+
+    fn meta(&self) -> Result<Meta, Error> {
+        Ok(Meta::read(&self.pages.read_page(META_PAGE)?))
+    }
+}
+
+/// Lays out the pages of an index holding `entries`, which must be free of
+/// repeats. It starts with enough buckets that none need splitting yet.
+pub fn build(entries: &[Entry]) -> Vec<Block> {
+    let mut level = 0;
+    while FILL << level < entries.len() && 2 << level <= MAX_BUCKETS {
+        level += 1;
+    }
+    let bucket_count = 1 << level;
+    let mut buckets = vec![Vec::new(); bucket_count];
+    for entry in entries {
+        buckets[(hash(entry.0) & (bucket_count as u64 - 1)) as usize].push(*entry);
+    }
+
+    let directory_pages = bucket_count.div_ceil(DIRECTORY_CAPACITY);
+    let mut pages = vec![[0u8; BLOCK_SIZE]; 1 + directory_pages];
+    for (bucket, entries) in buckets.iter().enumerate() {
+        let first = pages.len() as u64;
+        write_u64(
+            &mut pages[1 + bucket / DIRECTORY_CAPACITY],
+            (bucket % DIRECTORY_CAPACITY) * 8,
+            first,
+        );
+        let chunks = chunks(entries);
+        for (index, chunk) in chunks.iter().enumerate() {
+            let next = if index + 1 < chunks.len() {
+                first + index as u64 + 1
+            } else {
+                0
+            };
+            pages.push(write_bucket(chunk, next));
+        }
+    }
+
+    let meta = Meta {
+        level: level as u32,
+        split: 0,
+        page_count: pages.len() as u64,
+        entry_count: entries.len() as u64,
+        directory: (1..=directory_pages as u64).collect(),
+    };
+    pages[META_PAGE as usize] = meta.write();
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    // Pages kept in memory come from the B+tree's tests
+
+    /// Where the rows with a key are, found the slow way.
+    fn expected(entries: &[Entry], key: u64) -> Vec<RowLocation> {
+        let mut locations: Vec<_> = entries
+            .iter()
+            .filter(|(entry_key, _, _)| *entry_key == key)
+            .map(|(_, offset, slot)| (*offset, *slot))
+            .collect();
+        locations.sort();
+        locations
+    }
+
+    fn get(index: &HashIndex<RefCell<Vec<Block>>>, key: u64) -> Vec<RowLocation> {
+        let mut locations = index.get(key).unwrap();
+        locations.sort();
+        locations
+    }
+
+    #[test]
+    fn test_insert_split_and_remove() {
+        let index = HashIndex::new(RefCell::new(build(&[])));
+        // Enough entries to split buckets over a few rounds, with keys that
+        // are all unique as well as ones repeated hundreds of times
+        let entries: Vec<Entry> = (0..100_000u64)
+            .map(|n| {
+                let key = if n % 2 == 0 { n } else { n % 100 };
+                (key, n / 200, n % 200)
+            })
+            .collect();
+        for entry in &entries {
+            index.insert(*entry).unwrap();
+        }
+        index.insert(entries[0]).unwrap();
+        let meta = index.meta().unwrap();
+        assert_eq!(meta.entry_count, entries.len() as u64);
+        assert!(meta.bucket_count() > 256);
+
+        for key in [0, 1, 2, 99, 500, 99_998, 100_000] {
+            assert_eq!(get(&index, key), expected(&entries, key));
+        }
+
+        // Every even key is taken out
+        let (removed, kept): (Vec<Entry>, Vec<Entry>) =
+            entries.iter().partition(|(key, _, _)| key % 2 == 0);
+        for entry in &removed {
+            assert!(index.remove(*entry).unwrap());
+        }
+        assert!(!index.remove(removed[0]).unwrap());
+        for key in [0, 1, 2, 99, 500] {
+            assert_eq!(get(&index, key), expected(&kept, key));
+        }
+    }
+
+    #[test]
+    fn test_built_index_takes_inserts() {
+        let mut entries: Vec<Entry> = (0..50_000u64).map(|n| (n / 3, n, 0)).collect();
+        let index = HashIndex::new(RefCell::new(build(&entries)));
+        assert_eq!(get(&index, 100), expected(&entries, 100));
+
+        for n in 0..20_000u64 {
+            let entry = (n * 17, 100_000 + n, 1);
+            index.insert(entry).unwrap();
+            entries.push(entry);
+        }
+        for key in [0, 17, 340, 16_666, 339_983] {
+            assert_eq!(get(&index, key), expected(&entries, key));
+        }
+    }
+}
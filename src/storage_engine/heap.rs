@@ -0,0 +1,646 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+
+use crate::common::{
+    error::Error, read_row, ColumnType, Overflow, RawRow, BLOCK_SIZE, COLUMN_WIDTH,
+};
+
+use super::allocator::RowLocation;
+use super::btree::{read_u64, write_u64};
+use super::mvcc::CommitId;
+use super::{DataBase, SessionId};
+
+// Each page of a chain starts with the page after it, 0 for the last, and
+// how many bytes of the value it holds
+const PAGE_HEADER: usize = 16;
+const PAGE_CAPACITY: usize = BLOCK_SIZE - PAGE_HEADER;
+// A reference holds the heap's id above the first page of its chain
+const PAGE_BITS: u32 = 48;
+const MAX_HEAP_ID: u64 = (1 << (64 - PAGE_BITS)) - 1;
+
+/// The file holding the Clob and Blob values of a table's rows, each chained
+/// across as many pages as it takes. Its first page holds the id references
+/// to it are marked with, so a row joined to another table's still finds
+/// its values.
+pub(super) fn heap_file_name(table_name: &str) -> String {
+    format!("{}.heap.pages", table_name)
+}
+
+/// Which pages of a heap are in use, worked out by following every chain
+/// the table's rows refer to when the database is opened.
+///
+/// A chain is only handed back once no snapshot can read a row referring
+/// to it, or straight away if no row that did was ever committed. Chains
+/// that couldn't be followed to hand them back are found the next time the
+/// database is opened.
+pub(super) struct Heap {
+    id: u64,
+    page_count: u64,
+    free_pages: BTreeSet<u64>,
+}
+
+#[derive(Default)]
+pub(super) struct Heaps {
+    tables: HashMap<String, Heap>,
+    // Chains overwritten rows referred to, by the commit that overwrote them
+    released: Vec<(CommitId, String, u64)>,
+}
+
+/// Stores a table's values, writing Clob and Blob values to the table's heap
+/// and keeping track of the chains and texts it wrote.
+pub(super) struct TableOverflow<'a> {
+    db: &'a DataBase,
+    table_name: &'a str,
+    written: RefCell<Vec<u64>>,
+    texts: RefCell<Vec<u64>>,
+}
+
+impl Overflow for TableOverflow<'_> {
+    fn read_text(&self, reference: u64) -> Result<String, Error> {
+        self.db.read_text(reference)
+    }
+
+    fn write_text(&self, text: &str) -> Result<u64, Error> {
+        let reference = self.db.write_text(text)?;
+        self.texts.borrow_mut().push(reference);
+        Ok(reference)
+    }
+
+    fn read_object(&self, reference: u64) -> Result<Vec<u8>, Error> {
+        self.db.read_object(reference)
+    }
+
+    fn write_object(&self, bytes: &[u8]) -> Result<u64, Error> {
+        let reference = self.db.write_object_to(self.table_name, bytes)?;
+        self.written.borrow_mut().push(reference);
+        Ok(reference)
+    }
+}
+
+impl DataBase {
+    /// Runs a statement that writes to a table's heap through the overflow
+    /// it's given. The chains it wrote are freed again if it fails, and noted
+    /// if its session's in a transaction, to be freed if the transaction
+    /// doesn't commit them. The texts it wrote stay pinned for as long.
+    pub(super) fn writing_objects<T, F>(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        run: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&TableOverflow) -> Result<T, Error>,
+    {
+        let overflow = TableOverflow {
+            db: self,
+            table_name,
+            written: RefCell::new(Vec::new()),
+            texts: RefCell::new(Vec::new()),
+        };
+        let result = run(&overflow);
+        let written = overflow
+            .written
+            .into_inner()
+            .into_iter()
+            .map(|reference| (table_name.to_owned(), reference));
+        let mut texts = overflow.texts.into_inner();
+        if result.is_err() {
+            self.free_objects(written);
+        } else if let Some(transaction) = self.transactions().get_mut(&session) {
+            transaction.objects.extend(written);
+            transaction.texts.append(&mut texts);
+        }
+        self.unpin_texts(texts);
+        result
+    }
+
+    /// The chains the rows of a transaction refer to.
+    pub(super) fn referred_objects(
+        &self,
+        writes: &HashMap<String, BTreeMap<RowLocation, RawRow>>,
+    ) -> HashSet<u64> {
+        let mut referred = HashSet::new();
+        for (table_name, rows) in writes {
+            let Some(table_info) = self.schema.get(table_name) else {
+                continue;
+            };
+            for column in object_columns(table_info) {
+                referred.extend(rows.values().map(|raw_row| raw_row[column]));
+            }
+        }
+        referred
+    }
+
+    /// Hands back chains no committed row ever referred to, so nothing can
+    /// be reading them.
+    pub(super) fn free_objects<I>(&self, objects: I)
+    where
+        I: IntoIterator<Item = (String, u64)>,
+    {
+        for (table_name, reference) in objects {
+            // One that can't be followed is found when the database is opened
+            let Ok(chain) = self.chain(&table_name, reference) else {
+                continue;
+            };
+            if let Some(heap) = self.heaps().tables.get_mut(&table_name) {
+                heap.free_pages
+                    .extend(chain.into_iter().map(|(page, _)| page));
+            }
+        }
+    }
+
+    /// Starts a table's heap off empty, under an id no other heap has.
+    pub(super) fn create_heap(&mut self, table_name: &str) -> Result<(), Error> {
+        let id = {
+            let heaps = self.heaps();
+            let taken: BTreeSet<u64> = heaps.tables.values().map(|heap| heap.id).collect();
+            (0..=MAX_HEAP_ID)
+                .find(|id| !taken.contains(id))
+                .ok_or_else(|| Error::SchemaError("Ran out of heap ids".into()))?
+        };
+        let file_name = heap_file_name(table_name);
+        let heap_path = self.path_info()?.generate_table_path(&file_name);
+        let mut heap_file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(heap_path)?;
+        let mut first_page = [0u8; BLOCK_SIZE];
+        write_u64(&mut first_page, 0, id);
+        heap_file.write_all(&first_page)?;
+        heap_file.sync_all()?;
+        self.cache().forget(&file_name);
+        self.tables.insert(file_name, heap_file);
+        self.heaps().tables.insert(
+            table_name.to_owned(),
+            Heap {
+                id,
+                page_count: 1,
+                free_pages: BTreeSet::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Works out which pages of every table's heap are free, giving tables
+    /// made before there were heaps one of their own.
+    pub(super) fn open_heaps(&mut self) -> Result<(), Error> {
+        let mut missing = Vec::new();
+        for table_name in self.schema.keys() {
+            if self.tables.contains_key(&heap_file_name(table_name)) {
+                let heap = self.scan_heap(table_name)?;
+                self.heaps().tables.insert(table_name.to_owned(), heap);
+            } else {
+                missing.push(table_name.to_owned());
+            }
+        }
+        for table_name in missing {
+            self.create_heap(&table_name)?;
+        }
+        Ok(())
+    }
+
+    /// Works a heap's free pages out again once its table's rows have been
+    /// rewritten. Nothing it had released is waiting to be reclaimed any more.
+    pub(super) fn rescan_heap(&self, table_name: &str) -> Result<(), Error> {
+        let heap = self.scan_heap(table_name)?;
+        let mut heaps = self.heaps();
+        heaps.released.retain(|(_, table, _)| table != table_name);
+        heaps.tables.insert(table_name.to_owned(), heap);
+        Ok(())
+    }
+
+    pub(super) fn remove_heap(&mut self, table_name: &str) -> Result<(), Error> {
+        {
+            let mut heaps = self.heaps();
+            heaps.tables.remove(table_name);
+            heaps.released.retain(|(_, table, _)| table != table_name);
+        }
+        let file_name = heap_file_name(table_name);
+        self.tables.remove(&file_name);
+        self.cache().forget(&file_name);
+        std::fs::remove_file(self.path_info()?.generate_table_path(&file_name))?;
+        Ok(())
+    }
+
+    /// The chains a row referred to that its new version doesn't.
+    pub(super) fn replaced_objects(
+        &self,
+        table_name: &str,
+        old: &RawRow,
+        new: &RawRow,
+    ) -> Result<Vec<u64>, Error> {
+        Ok(object_columns(self.table_info(table_name)?)
+            .filter(|column| old[*column] != 0 && old[*column] != new[*column])
+            .map(|column| old[column])
+            .collect())
+    }
+
+    /// Hands back chains replaced by `commit`, once nothing can read the rows
+    /// that referred to them.
+    pub(super) fn release_objects(&self, table_name: &str, commit: CommitId, references: Vec<u64>) {
+        let mut heaps = self.heaps();
+        for reference in references {
+            heaps
+                .released
+                .push((commit, table_name.to_owned(), reference));
+        }
+    }
+
+    fn write_object_to(&self, table_name: &str, bytes: &[u8]) -> Result<u64, Error> {
+        let (id, pages) = {
+            let mut heaps = self.heaps();
+            self.reclaim(&mut heaps)?;
+            let heap = heaps
+                .tables
+                .get_mut(table_name)
+                .ok_or_else(|| Error::SchemaError(format!("Table {} has no heap", table_name)))?;
+            let wanted = bytes.len().div_ceil(PAGE_CAPACITY).max(1);
+            let mut pages = Vec::with_capacity(wanted);
+            while pages.len() < wanted {
+                match heap.free_pages.pop_first() {
+                    Some(page) => pages.push(page),
+                    None => {
+                        pages.push(heap.page_count);
+                        heap.page_count += 1;
+                    }
+                }
+            }
+            (heap.id, pages)
+        };
+
+        // The pages are ours alone now
+        let file_name = heap_file_name(table_name);
+        let mut chunks = bytes.chunks(PAGE_CAPACITY);
+        for (at, page) in pages.iter().enumerate() {
+            let chunk = chunks.next().unwrap_or_default();
+            let mut block = [0u8; BLOCK_SIZE];
+            write_u64(&mut block, 0, pages.get(at + 1).copied().unwrap_or(0));
+            write_u64(&mut block, 8, chunk.len() as u64);
+            block[PAGE_HEADER..PAGE_HEADER + chunk.len()].copy_from_slice(chunk);
+            self.store_block_at(&file_name, *page, &block)?;
+        }
+        Ok(id << PAGE_BITS | pages[0])
+    }
+
+    /// Frees the chains released by commits no snapshot needs any more.
+    fn reclaim(&self, heaps: &mut Heaps) -> Result<(), Error> {
+        let (reclaimed, kept) = std::mem::take(&mut heaps.released)
+            .into_iter()
+            .partition(|(commit, _, _)| !self.versions.still_seen(*commit));
+        heaps.released = kept;
+        for (_, table_name, reference) in reclaimed {
+            let chain = self.chain(&table_name, reference)?;
+            if let Some(heap) = heaps.tables.get_mut(&table_name) {
+                heap.free_pages
+                    .extend(chain.into_iter().map(|(page, _)| page));
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows a chain, giving back each page and the block it holds.
+    fn chain(
+        &self,
+        table_name: &str,
+        reference: u64,
+    ) -> Result<Vec<(u64, [u8; BLOCK_SIZE])>, Error> {
+        let file_name = heap_file_name(table_name);
+        let page_count = self.block_count(&file_name)?;
+        let mut chain = Vec::new();
+        let mut page = reference & ((1 << PAGE_BITS) - 1);
+        while page != 0 {
+            if page >= page_count || chain.len() as u64 >= page_count {
+                return Err(Error::SchemaError(format!(
+                    "Value {} of {} runs off the end of its heap",
+                    reference, table_name
+                )));
+            }
+            let block = self.load_block_at(&file_name, page)?;
+            chain.push((page, block));
+            page = read_u64(&block, 0);
+        }
+        Ok(chain)
+    }
+
+    /// A table's heap, with every page its rows' chains don't use free.
+    fn scan_heap(&self, table_name: &str) -> Result<Heap, Error> {
+        let id = read_u64(&self.load_block_at(&heap_file_name(table_name), 0)?, 0);
+        let page_count = self.block_count(&heap_file_name(table_name))?;
+        let table_info = self.table_info(table_name)?;
+        let columns: Vec<usize> = object_columns(table_info).collect();
+        let rows_per_block = BLOCK_SIZE / (table_info.len() * COLUMN_WIDTH);
+
+        let mut free_pages: BTreeSet<u64> = (1..page_count).collect();
+        if !columns.is_empty() {
+            for offset in 0..self.block_count(table_name)? {
+                let block = self.load_block_at(table_name, offset)?;
+                for slot in 0..rows_per_block {
+                    let raw_row = read_row(&block, slot, table_info.len());
+                    if raw_row[0] == 0 {
+                        continue;
+                    }
+                    for column in &columns {
+                        if raw_row[*column] >> PAGE_BITS == id && raw_row[*column] != 0 {
+                            for (page, _) in self.chain(table_name, raw_row[*column])? {
+                                free_pages.remove(&page);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Heap {
+            id,
+            page_count: page_count.max(1),
+            free_pages,
+        })
+    }
+
+    /// Reads a Clob or Blob value back from whichever heap it's kept in.
+    pub(super) fn read_heap_object(&self, reference: u64) -> Result<Vec<u8>, Error> {
+        let id = reference >> PAGE_BITS;
+        let table_name = self
+            .heaps()
+            .tables
+            .iter()
+            .find(|(_, heap)| heap.id == id)
+            .map(|(table_name, _)| table_name.to_owned())
+            .ok_or_else(|| Error::SchemaError(format!("No heap holds value {}", reference)))?;
+        let mut bytes = Vec::new();
+        for (_, block) in self.chain(&table_name, reference)? {
+            let length = (read_u64(&block, 8) as usize).min(PAGE_CAPACITY);
+            bytes.extend_from_slice(&block[PAGE_HEADER..PAGE_HEADER + length]);
+        }
+        Ok(bytes)
+    }
+}
+
+/// The offsets of a table's Clob and Blob columns.
+fn object_columns(table_info: &[(String, ColumnType)]) -> impl Iterator<Item = usize> + '_ {
+    table_info
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, column_type))| matches!(column_type, ColumnType::Clob | ColumnType::Blob))
+        .map(|(column, _)| column)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{convert_field, DataType, TableInfo};
+    use crate::storage_engine::{
+        Direction, Expression, FilterType, Function, Grouping, Limit, Predicate, Projection,
+        DEFAULT_SESSION,
+    };
+    use crate::test_utils::temp_db;
+
+    use super::*;
+
+    fn portraits(db: &mut DataBase) {
+        let table_info: TableInfo = vec![
+            ("index".into(), ColumnType::Integer),
+            ("backstory".into(), ColumnType::Clob),
+            ("portrait".into(), ColumnType::Blob),
+        ];
+        db.create_table("heroes", table_info, false).unwrap();
+    }
+
+    fn add_hero(db: &DataBase, backstory: &str, portrait: Vec<u8>) -> u64 {
+        let row = vec![backstory.into(), DataType::Blob(portrait)];
+        db.insert(DEFAULT_SESSION, "heroes", vec![], vec![row])
+            .unwrap()[0]
+    }
+
+    fn remove_hero(db: &DataBase, id: u64) {
+        let hero = Predicate::Filter(FilterType::EqualTo(
+            "index".into(),
+            DataType::Integer(id as i64),
+        ));
+        db.delete(DEFAULT_SESSION, "heroes", hero).unwrap();
+    }
+
+    // The raw fields of each hero, in slot order
+    fn fields(db: &DataBase) -> Vec<RawRow> {
+        let block = db.load_block_at("heroes", 0).unwrap();
+        (0..BLOCK_SIZE / (3 * COLUMN_WIDTH))
+            .map(|slot| read_row(&block, slot, 3))
+            .filter(|raw_row| raw_row[0] != 0)
+            .collect()
+    }
+
+    fn first_page(reference: u64) -> u64 {
+        reference & ((1 << PAGE_BITS) - 1)
+    }
+
+    #[test]
+    fn test_chained_values() {
+        let (dir, mut db) = temp_db("heap_chains");
+        portraits(&mut db);
+        let backstory = "Raised by wolves in the Misty Mountains. ".repeat(500);
+        let portrait: Vec<u8> = (0..3 * BLOCK_SIZE).map(|at| at as u8).collect();
+        add_hero(&db, &backstory, portrait.clone());
+        add_hero(&db, "", vec![]);
+
+        let check = |db: &DataBase| {
+            let heroes = fields(db);
+            let read = |raw_row: &RawRow| {
+                [(1, ColumnType::Clob), (2, ColumnType::Blob)]
+                    .map(|(column, column_type)| convert_field(raw_row[column], &column_type, db))
+                    .map(Result::unwrap)
+            };
+            let [told, drawn] = read(&heroes[0]);
+            assert_eq!(told, DataType::Clob(backstory.clone()));
+            assert_eq!(drawn, DataType::Blob(portrait.clone()));
+            // Empty values take no pages at all
+            assert_eq!(heroes[1][1..], [0, 0]);
+            assert_eq!(
+                read(&heroes[1]),
+                [DataType::Clob("".into()), DataType::Blob(vec![])]
+            );
+        };
+        check(&db);
+        // Each value is chained across the pages it needs, after the header
+        assert_eq!(
+            db.block_count(&heap_file_name("heroes")).unwrap(),
+            1 + 3 + 4
+        );
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        check(&db);
+    }
+
+    #[test]
+    fn test_reclaimed_pages() {
+        let (dir, mut db) = temp_db("heap_reclaim");
+        portraits(&mut db);
+        let backstory = "Once upon a time ".repeat(1000);
+        let first = add_hero(&db, &backstory, vec![7; 10]);
+        let [_, told, drawn] = fields(&db)[0][..] else {
+            panic!("Expected one hero")
+        };
+        let pages = db.block_count(&heap_file_name("heroes")).unwrap();
+
+        // A snapshot that can still read the hero keeps its pages in use
+        let snapshot = db.versions.snapshot();
+        remove_hero(&db, first);
+        let second = add_hero(&db, &backstory, vec![7; 10]);
+        assert!(db.block_count(&heap_file_name("heroes")).unwrap() > pages);
+        drop(snapshot);
+
+        remove_hero(&db, second);
+        add_hero(&db, &backstory, vec![7; 10]);
+        let reused = fields(&db)[0].clone();
+        assert_eq!(first_page(reused[1]), first_page(told));
+        assert_eq!(first_page(reused[2]), first_page(drawn));
+        drop(db);
+
+        // Pages nothing refers to are found free again on opening
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        let grown = db.block_count(&heap_file_name("heroes")).unwrap();
+        add_hero(&db, "Lately arrived", vec![]);
+        assert_eq!(db.block_count(&heap_file_name("heroes")).unwrap(), grown);
+    }
+
+    #[test]
+    fn test_unkept_chains_freed() {
+        let (_dir, mut db) = temp_db("heap_unkept");
+        portraits(&mut db);
+        let heap_pages = |db: &DataBase| db.block_count(&heap_file_name("heroes")).unwrap();
+        let pages = heap_pages(&db);
+        let portrait = vec![7; 2 * BLOCK_SIZE];
+
+        // A row that doesn't fit stops the insert before anything's written
+        let rows = vec![
+            vec!["Told".into(), DataType::Blob(portrait.clone())],
+            vec![DataType::Integer(1), DataType::Blob(vec![])],
+        ];
+        assert!(db.insert(DEFAULT_SESSION, "heroes", vec![], rows).is_err());
+        assert_eq!(heap_pages(&db), pages);
+
+        // A rolled back transaction hands its chains back
+        db.begin(DEFAULT_SESSION).unwrap();
+        add_hero(&db, "", portrait.clone());
+        db.rollback(DEFAULT_SESSION).unwrap();
+        let grown = heap_pages(&db);
+        add_hero(&db, "", portrait.clone());
+        assert_eq!(heap_pages(&db), grown);
+
+        // As does a value a transaction replaced before committing
+        db.begin(DEFAULT_SESSION).unwrap();
+        let id = add_hero(&db, "", portrait.clone());
+        let hero = Predicate::Filter(FilterType::EqualTo(
+            "index".into(),
+            DataType::Integer(id as i64),
+        ));
+        let redrawn = Expression::Literal(DataType::Blob(vec![8; 10]));
+        db.update(
+            DEFAULT_SESSION,
+            "heroes",
+            vec![("portrait".into(), redrawn)],
+            hero,
+        )
+        .unwrap();
+        db.commit(DEFAULT_SESSION).unwrap();
+        let grown = heap_pages(&db);
+        add_hero(&db, "", portrait);
+        assert_eq!(heap_pages(&db), grown);
+    }
+
+    #[test]
+    fn test_heap_lifecycle() {
+        let (dir, mut db) = temp_db("heap_lifecycle");
+        portraits(&mut db);
+        add_hero(&db, "Came down from the north", vec![1, 2, 3]);
+        let heap_path = dir.0.join("test_heroes.heap.pages.ogmadb");
+        assert!(heap_path.exists());
+
+        // Values can't be kept anywhere but a table
+        assert!(db.write_object(b"loose").is_err());
+
+        // Text set on a Clob column is kept as a Clob
+        let retold = Expression::Literal("Came up from the south".into());
+        let everyone = Predicate::And(vec![]);
+        db.update(
+            DEFAULT_SESSION,
+            "heroes",
+            vec![("backstory".into(), retold)],
+            everyone,
+        )
+        .unwrap();
+        let [_, told, _] = fields(&db)[0][..] else {
+            panic!("Expected one hero")
+        };
+        assert_eq!(
+            convert_field(told, &ColumnType::Clob, &db).unwrap(),
+            DataType::Clob("Came up from the south".into())
+        );
+
+        db.drop_table("heroes", false).unwrap();
+        assert!(!heap_path.exists());
+        portraits(&mut db);
+        add_hero(&db, "Back again", vec![]);
+        let [_, told, _] = fields(&db)[0][..] else {
+            panic!("Expected one hero")
+        };
+        assert_eq!(
+            convert_field(told, &ColumnType::Clob, &db).unwrap(),
+            DataType::Clob("Back again".into())
+        );
+    }
+
+    #[test]
+    fn test_group_by_clob() {
+        let (_dir, mut db) = temp_db("heap_group_by");
+        portraits(&mut db);
+        let wolves = "Raised by wolves. ".repeat(1000);
+        for backstory in [&wolves, "Orphaned", &wolves] {
+            add_hero(&db, backstory, vec![]);
+        }
+
+        // SELECT backstory, COUNT(index) FROM heroes GROUP BY backstory
+        let backstory = || Expression::Column("backstory".into());
+        let groups = |db: &DataBase, order_by: Vec<(Expression, Direction)>| {
+            let (qid, _) = db
+                .begin_query(
+                    DEFAULT_SESSION,
+                    "heroes".into(),
+                    Predicate::Filter(FilterType::All),
+                    vec![
+                        Projection::Named(backstory(), "backstory".into()),
+                        Projection::Named(
+                            Expression::Aggregate(Function::Count, Box::new(backstory())),
+                            "heroes".into(),
+                        ),
+                    ],
+                    Grouping {
+                        keys: vec![backstory()],
+                        ..Grouping::default()
+                    },
+                    order_by,
+                    Limit::default(),
+                )
+                .unwrap();
+            let mut rows = Vec::new();
+            while let Some(batch) = db.next_batch(qid, 10).unwrap() {
+                rows.extend(batch);
+            }
+            rows
+        };
+        let pages = db.block_count(&heap_file_name("heroes")).unwrap();
+        let expected = vec![
+            vec![DataType::Clob(wolves.clone()), 2.into()],
+            vec![DataType::Clob("Orphaned".into()), 1.into()],
+        ];
+        assert_eq!(groups(&db, vec![]), expected);
+        assert_eq!(
+            groups(&db, vec![(backstory(), Direction::Descending)]),
+            expected
+        );
+        // Grouping keeps the values it holds to itself
+        assert_eq!(db.block_count(&heap_file_name("heroes")).unwrap(), pages);
+    }
+}
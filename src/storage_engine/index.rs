@@ -0,0 +1,837 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::{
+    convert_field, error::Error, read_row, Block, ColumnType, DataType, RawRow, TableInfo,
+    TableInfoMap, BLOCK_SIZE, COLUMN_WIDTH,
+};
+
+use super::allocator::RowLocation;
+use super::btree::{self, BTree, Entry, Pages};
+use super::hash::{self, HashIndex};
+use super::mvcc::Snapshot;
+use super::{
+    sync_parent_dir, validate_name, write_atomically, DataBase, FilterType, Predicate, SessionId,
+};
+
+/// How an index file keeps its entries.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum IndexKind {
+    // In order, for lookups of ranges as well as single keys
+    #[default]
+    BTree,
+    // Hashed, for lookups of single keys only
+    Hash,
+}
+
+/// A column of a table kept in an index file, so filters on it can go
+/// straight to the rows they match.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IndexInfo {
+    pub table_name: String,
+    // The offset of the column, which stays put when it's renamed
+    pub column: usize,
+    // Indexes made before there were hash indexes are all B+trees
+    #[serde(default)]
+    pub kind: IndexKind,
+}
+
+/// Every index of the database, by name.
+pub type IndexCatalog = BTreeMap<String, IndexInfo>;
+
+/// The lowest and highest keys of a lookup, inclusive.
+type KeyRange = (u64, u64);
+
+impl IndexInfo {
+    /// The name the index's file goes by among the tables. Table names can't
+    /// hold a '.', so it never clashes with one.
+    pub fn file_name(&self, index_name: &str) -> String {
+        format!("{}.{}", self.table_name, index_name)
+    }
+}
+
+/// The name of the file of a table's primary index, which finds each row by
+/// its id. Other index files have a single '.' in their names, so it never
+/// clashes with one.
+pub(super) fn primary_file_name(table_name: &str) -> String {
+    format!("{}.primary.ids", table_name)
+}
+
+/// An index a lookup can go through.
+struct Lookup {
+    file_name: String,
+    column: usize,
+    kind: IndexKind,
+    // No two rows have the same key
+    unique: bool,
+}
+
+/// The pages of an index file, read and written through the buffer pool so
+/// they're logged along with the rows they point to.
+pub struct IndexPages<'a> {
+    db: &'a DataBase,
+    file_name: String,
+}
+
+impl<'a> IndexPages<'a> {
+    pub(super) fn new(db: &'a DataBase, file_name: &str) -> Self {
+        Self {
+            db,
+            file_name: file_name.to_owned(),
+        }
+    }
+}
+
+impl Pages for IndexPages<'_> {
+    fn read_page(&self, page: u64) -> Result<Block, Error> {
+        self.db.load_block_at(&self.file_name, page)
+    }
+
+    fn write_page(&self, page: u64, block: &Block) -> Result<(), Error> {
+        self.db.store_block_at(&self.file_name, page, block)
+    }
+}
+
+/// An open index file, of either kind.
+enum IndexFile<'a> {
+    BTree(BTree<IndexPages<'a>>),
+    Hash(HashIndex<IndexPages<'a>>),
+}
+
+impl IndexFile<'_> {
+    fn insert(&self, entry: Entry) -> Result<(), Error> {
+        match self {
+            IndexFile::BTree(tree) => tree.insert(entry),
+            IndexFile::Hash(index) => index.insert(entry),
+        }
+    }
+
+    fn remove(&self, entry: Entry) -> Result<bool, Error> {
+        match self {
+            IndexFile::BTree(tree) => tree.remove(entry),
+            IndexFile::Hash(index) => index.remove(entry),
+        }
+    }
+
+    /// Where every row with a key in the range is. Hash indexes are only
+    /// asked for a single key.
+    fn find(&self, (low, high): KeyRange) -> Result<Vec<RowLocation>, Error> {
+        match self {
+            IndexFile::BTree(tree) => tree.range(low, high),
+            IndexFile::Hash(index) => {
+                debug_assert_eq!(low, high, "hash indexes only find single keys");
+                index.get(low)
+            }
+        }
+    }
+}
+
+pub(super) fn catalog_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".indexes");
+    PathBuf::from(path)
+}
+
+/// Reads the index catalog, which databases made before there were indexes
+/// don't have.
+pub(super) fn read_catalog(db_path: &Path) -> Result<IndexCatalog, Error> {
+    match std::fs::read(catalog_path(db_path)) {
+        Ok(raw_catalog) => Ok(serde_json::from_slice(&raw_catalog)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(IndexCatalog::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub(super) fn write_catalog(db_path: &Path, catalog: &IndexCatalog) -> Result<(), Error> {
+    write_atomically(&catalog_path(db_path), &serde_json::to_vec(catalog)?)
+}
+
+/// Maps a value to a key that sorts the way values compare. Text and
+/// binary data longer than a key are cut short, so rows found by key are
+/// checked against the filter again.
+fn index_key(value: &DataType) -> u64 {
+    match value {
+        // Flipping the sign bit puts the negative numbers first
+        DataType::Integer(value) => (*value as u64) ^ (1 << 63),
+        DataType::Boolean(value) => *value as u64,
+        // The rest compare byte by byte, starting from the first
+        DataType::Text(text) | DataType::Clob(text) => prefix_key(text.as_bytes()),
+        DataType::Blob(bytes) => prefix_key(bytes),
+    }
+}
+
+fn prefix_key(bytes: &[u8]) -> u64 {
+    let mut prefix = [0u8; COLUMN_WIDTH];
+    let length = bytes.len().min(COLUMN_WIDTH);
+    prefix[..length].copy_from_slice(&bytes[..length]);
+    u64::from_be_bytes(prefix)
+}
+
+/// The keys, as inclusive ranges, a filter on an indexed column can match.
+/// Filters name the value first, so `GreaterThan` matches fields below it.
+fn key_ranges(filter: &FilterType, column_type: &ColumnType) -> Option<Vec<KeyRange>> {
+    // A value of another type matches nothing, which a scan works out as well
+    let key = |value: &DataType| (value.column_type() == *column_type).then(|| index_key(value));
+    Some(match filter {
+        FilterType::EqualTo(_, value) => {
+            let key = key(value)?;
+            vec![(key, key)]
+        }
+        FilterType::In(_, values) => values
+            .iter()
+            .map(|value| key(value).map(|key| (key, key)))
+            .collect::<Option<_>>()?,
+        FilterType::GreaterThan(_, value) | FilterType::GreaterThanEqualTo(_, value) => {
+            vec![(0, key(value)?)]
+        }
+        FilterType::LessThan(_, value) | FilterType::LessThanEqualTo(_, value) => {
+            vec![(key(value)?, u64::MAX)]
+        }
+        FilterType::Between(_, lower, upper) => vec![(key(lower)?, key(upper)?)],
+        FilterType::All => return None,
+    })
+}
+
+impl DataBase {
+    /// Indexes a column of a table, reading through its rows to fill the
+    /// index.
+    ///
+    /// The index file is complete before the catalog names it, so a crash
+    /// part way through leaves at worst a file nothing uses.
+    pub fn create_index(
+        &mut self,
+        index_name: &str,
+        table_name: &str,
+        column: &str,
+        kind: IndexKind,
+        if_not_exists: bool,
+    ) -> Result<(), Error> {
+        if self.indexes.contains_key(index_name) {
+            return if if_not_exists {
+                Ok(())
+            } else {
+                Err(Error::SchemaError(format!(
+                    "Index {} already exists",
+                    index_name
+                )))
+            };
+        }
+        validate_name("Index", index_name)?;
+        let info = IndexInfo {
+            table_name: table_name.to_owned(),
+            column: self
+                .table_info(table_name)?
+                .iter()
+                .position(|(name, _)| name == column)
+                .ok_or_else(|| Error::SchemaError(format!("Column {} does not exist", column)))?,
+            kind,
+        };
+        let entries = self.index_entries(table_name, info.column)?;
+        self.write_index(&info.file_name(index_name), kind, &entries)?;
+
+        let mut indexes = self.indexes.clone();
+        indexes.insert(index_name.to_owned(), info);
+        write_catalog(&self.path, &indexes)?;
+        self.indexes = indexes;
+        Ok(())
+    }
+
+    /// Removes an index and its file.
+    pub fn drop_index(&mut self, index_name: &str, if_exists: bool) -> Result<(), Error> {
+        let Some(info) = self.indexes.get(index_name).cloned() else {
+            return if if_exists {
+                Ok(())
+            } else {
+                Err(Error::SchemaError(format!(
+                    "Index {} does not exist",
+                    index_name
+                )))
+            };
+        };
+
+        // The log mustn't hold pages for an index file that's gone
+        self.checkpoint()?;
+        let mut indexes = self.indexes.clone();
+        indexes.remove(index_name);
+        write_catalog(&self.path, &indexes)?;
+        self.indexes = indexes;
+        self.remove_index(&info.file_name(index_name))
+    }
+
+    /// Every index of a table, by name.
+    pub(super) fn table_indexes<'a>(
+        &'a self,
+        table_name: &'a str,
+    ) -> impl Iterator<Item = (&'a String, &'a IndexInfo)> + 'a {
+        self.indexes
+            .iter()
+            .filter(move |(_, info)| info.table_name == table_name)
+    }
+
+    /// The entry of every live row of a table in an index of `column`,
+    /// sorted.
+    pub(super) fn index_entries(
+        &self,
+        table_name: &str,
+        column: usize,
+    ) -> Result<Vec<Entry>, Error> {
+        let column_type = self.indexed_type(table_name, column)?;
+        let columns = self.table_info(table_name)?.len();
+        let rows_per_block = BLOCK_SIZE / (columns * COLUMN_WIDTH);
+        let mut entries = Vec::new();
+        for offset in 0..self.block_count(table_name)? {
+            let block = self.load_block_at(table_name, offset)?;
+            for slot in 0..rows_per_block {
+                let raw_row = read_row(&block, slot, columns);
+                if raw_row[0] != 0 {
+                    let value = convert_field(raw_row[column], &column_type, self)?;
+                    entries.push((index_key(&value), offset, slot as u64));
+                }
+            }
+        }
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    fn primary_index(&self, table_name: &str) -> BTree<IndexPages<'_>> {
+        BTree::new(IndexPages::new(self, &primary_file_name(table_name)))
+    }
+
+    /// The highest id a row of the table has ever had, or 0 if none has.
+    /// Its primary index remembers it, so ids of deleted rows aren't handed
+    /// out again.
+    pub(super) fn highest_id(&self, table_name: &str) -> Result<u64, Error> {
+        match self.primary_index(table_name).highest_key()? {
+            0 => Ok(0),
+            key => Ok(key ^ (1 << 63)),
+        }
+    }
+
+    /// Builds a table's primary index from its rows, remembering that ids up
+    /// to `highest_id` were handed out even if no row holds them now.
+    pub(super) fn build_primary_index(
+        &mut self,
+        table_name: &str,
+        highest_id: u64,
+    ) -> Result<(), Error> {
+        let entries = self.index_entries(table_name, 0)?;
+        self.write_index(&primary_file_name(table_name), IndexKind::BTree, &entries)?;
+        if highest_id > 0 {
+            self.primary_index(table_name)
+                .raise_highest_key(index_key(&DataType::Integer(highest_id as i64)))?;
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out an index file holding `entries` in one go, replacing any
+    /// file of the same name, and opens it.
+    pub(super) fn write_index(
+        &mut self,
+        file_name: &str,
+        kind: IndexKind,
+        entries: &[Entry],
+    ) -> Result<(), Error> {
+        let index_path = self.path_info()?.generate_table_path(&file_name.to_owned());
+        let mut build_path = index_path.as_os_str().to_owned();
+        build_path.push(".build");
+        let mut build = File::create(&build_path)?;
+        let pages = match kind {
+            IndexKind::BTree => btree::build(entries),
+            IndexKind::Hash => hash::build(entries),
+        };
+        for page in pages {
+            build.write_all(&page)?;
+        }
+        build.sync_all()?;
+        std::fs::rename(&build_path, &index_path)?;
+        sync_parent_dir(&self.path)?;
+
+        self.cache().forget(file_name);
+        self.tables.insert(
+            file_name.to_owned(),
+            File::options().read(true).write(true).open(index_path)?,
+        );
+        Ok(())
+    }
+
+    /// Closes and deletes an index file. The log mustn't hold any of its
+    /// pages.
+    pub(super) fn remove_index(&mut self, file_name: &str) -> Result<(), Error> {
+        self.tables.remove(file_name);
+        self.cache().forget(file_name);
+        std::fs::remove_file(self.path_info()?.generate_table_path(&file_name.to_owned()))?;
+        sync_parent_dir(&self.path)
+    }
+
+    /// Every index of a table lookups can go through, hash indexes first as
+    /// they find single keys in fewer reads.
+    fn lookups(&self, table_name: &str) -> Vec<Lookup> {
+        let mut lookups = vec![Lookup {
+            file_name: primary_file_name(table_name),
+            column: 0,
+            kind: IndexKind::BTree,
+            unique: true,
+        }];
+        lookups.extend(
+            self.table_indexes(table_name)
+                .map(|(index_name, info)| Lookup {
+                    file_name: info.file_name(index_name),
+                    column: info.column,
+                    kind: info.kind,
+                    unique: false,
+                }),
+        );
+        lookups.sort_by_key(|lookup| lookup.kind == IndexKind::BTree);
+        lookups
+    }
+
+    /// The files of every index of a table, its primary index among them.
+    pub(super) fn index_files(&self, table_name: &str) -> Vec<String> {
+        self.lookups(table_name)
+            .into_iter()
+            .map(|lookup| lookup.file_name)
+            .collect()
+    }
+
+    /// The type of a column, as long as it's one that can be indexed.
+    fn indexed_type(&self, table_name: &str, column: usize) -> Result<ColumnType, Error> {
+        let table_info: &TableInfo = self.table_info(table_name)?;
+        match table_info.get(column) {
+            Some((
+                _,
+                column_type @ (ColumnType::Integer | ColumnType::Boolean | ColumnType::Text),
+            )) => Ok(column_type.to_owned()),
+            Some((name, column_type)) => Err(Error::SchemaError(format!(
+                "Column {} holds {:?}, which can't be indexed",
+                name, column_type
+            ))),
+            None => Err(Error::SchemaError(format!(
+                "Table {} has no column {}",
+                table_name, column
+            ))),
+        }
+    }
+
+    fn index_file(&self, lookup: &Lookup) -> IndexFile<'_> {
+        let pages = IndexPages {
+            db: self,
+            file_name: lookup.file_name.to_owned(),
+        };
+        match lookup.kind {
+            IndexKind::BTree => IndexFile::BTree(BTree::new(pages)),
+            IndexKind::Hash => IndexFile::Hash(HashIndex::new(pages)),
+        }
+    }
+
+    /// Moves a row's entries in the indexes of its table from what it held
+    /// to what it holds now. Empty rows have no entries. A row can't take an
+    /// id another row already has.
+    pub(super) fn reindex_row(
+        &self,
+        table_name: &str,
+        (offset, slot): RowLocation,
+        old: &RawRow,
+        new: &RawRow,
+    ) -> Result<(), Error> {
+        for lookup in self.lookups(table_name) {
+            let column_type = self.indexed_type(table_name, lookup.column)?;
+            let entry = |raw_row: &RawRow| -> Result<Option<Entry>, Error> {
+                if raw_row[0] == 0 {
+                    return Ok(None);
+                }
+                let value = convert_field(raw_row[lookup.column], &column_type, self)?;
+                Ok(Some((index_key(&value), offset, slot)))
+            };
+            let (old_entry, new_entry) = (entry(old)?, entry(new)?);
+            if old_entry == new_entry {
+                continue;
+            }
+            let index = self.index_file(&lookup);
+            if let Some(entry) = old_entry {
+                index.remove(entry)?;
+            }
+            if let Some(entry @ (key, _, _)) = new_entry {
+                if lookup.unique && !index.find((key, key))?.is_empty() {
+                    return Err(Error::SchemaError(format!(
+                        "Row id {} is already taken",
+                        new[0]
+                    )));
+                }
+                index.insert(entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where the rows of a table that could match a predicate are, if an
+    /// index narrows them down. Only those rows need reading, though they
+    /// still have to be checked against the predicate.
+    pub(super) fn index_candidates(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        table_schema: &TableInfoMap,
+        predicate: &Predicate,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<Option<BTreeSet<RowLocation>>, Error> {
+        let Some((lookup, ranges)) = self.plan_lookup(table_name, table_schema, predicate) else {
+            return Ok(None);
+        };
+        let index = self.index_file(&lookup);
+
+        // Nothing is written while the index is read, so what it holds is
+        // all of one commit, and so are the rows kept since the snapshot
+        let _applying = self.applying();
+        let mut locations = BTreeSet::new();
+        for range in ranges {
+            locations.extend(index.find(range)?);
+        }
+        // The index only knows the latest committed rows. Rows changed since
+        // the snapshot, or by the session's transaction, may match as the
+        // query sees them whatever the index says.
+        if let Some(snapshot) = snapshot {
+            locations.extend(self.versions.changed_since(table_name, snapshot.commit()));
+        }
+        if let Some(transaction) = self.transactions().get(&session) {
+            locations.extend(transaction.written(table_name));
+        }
+        Ok(Some(locations))
+    }
+
+    /// Picks an index for a filter of the predicate that every matching row
+    /// has to pass, along with the keys the filter can match. Hash indexes
+    /// only serve filters naming exact values.
+    fn plan_lookup(
+        &self,
+        table_name: &str,
+        table_schema: &TableInfoMap,
+        predicate: &Predicate,
+    ) -> Option<(Lookup, Vec<KeyRange>)> {
+        match predicate {
+            Predicate::Filter(filter) => {
+                let (_, offset) = table_schema.get(filter.column()?)?;
+                self.lookups(table_name)
+                    .into_iter()
+                    .filter(|lookup| lookup.column as u64 == *offset)
+                    .filter(|lookup| {
+                        lookup.kind == IndexKind::BTree
+                            || matches!(filter, FilterType::EqualTo(..) | FilterType::In(..))
+                    })
+                    .find_map(|lookup| {
+                        let column_type = self.indexed_type(table_name, lookup.column).ok()?;
+                        let ranges = key_ranges(filter, &column_type)?;
+                        Some((lookup, ranges))
+                    })
+            }
+            Predicate::And(predicates) => predicates
+                .iter()
+                .find_map(|predicate| self.plan_lookup(table_name, table_schema, predicate)),
+            Predicate::Or(_) | Predicate::Not(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{encode_field, map_table_info, DataType};
+    use crate::storage_engine::{
+        Action, Alteration, Expression, Grouping, Limit, Projection, Reaction, DEFAULT_SESSION,
+    };
+    use crate::test_utils::{mint, temp_db};
+
+    use super::*;
+
+    fn select(db: &mut DataBase, predicate: Predicate) -> Vec<RawRow> {
+        let action = Action::GetFiltered(
+            "currency".into(),
+            predicate,
+            vec![Projection::Wildcard],
+            Grouping::default(),
+            vec![],
+            Limit::default(),
+        );
+        let qid = match db.execute(action) {
+            Reaction::QueryStart { qid, .. } => qid,
+            Reaction::Error(err) => panic!("Query failed with {err}"),
+            _ => panic!("Query didn't start"),
+        };
+        let mut rows = Vec::new();
+        while let Reaction::Data(data) = db.execute(Action::GetMore(qid, 100)) {
+            for row in data {
+                let raw_row = row.iter().map(|value| encode_field(value, db));
+                rows.push(raw_row.collect::<Result<_, _>>().unwrap());
+            }
+        }
+        rows
+    }
+
+    /// The columns of currency, by their bare and qualified names, as a
+    /// query sees them.
+    fn currency_schema(db: &DataBase) -> TableInfoMap {
+        let mut table_schema = map_table_info(&db.schema["currency"]);
+        for (column, entry) in table_schema.clone() {
+            table_schema.insert(format!("currency.{}", column), entry);
+        }
+        table_schema
+    }
+
+    fn candidates(db: &DataBase, predicate: &Predicate) -> Option<usize> {
+        let table_schema = currency_schema(db);
+        db.index_candidates(DEFAULT_SESSION, "currency", &table_schema, predicate, None)
+            .unwrap()
+            .map(|locations| locations.len())
+    }
+
+    fn filter(filter: FilterType) -> Predicate {
+        Predicate::Filter(filter)
+    }
+
+    #[test]
+    fn test_index_keys_sort_like_values() {
+        let integers = [i64::MIN, -5, -1, 0, 1, 5, i64::MAX];
+        for pair in integers.windows(2) {
+            let [low, high] = [pair[0], pair[1]].map(|value| index_key(&value.into()));
+            assert!(low < high, "{:?} should sort before {:?}", pair[0], pair[1]);
+        }
+        let text = |word: &str| index_key(&word.into());
+        assert!(text("ab") < text("b"));
+        assert!(text("a") < text("ab"));
+        assert!(text("zz") < text("zza"));
+        assert!(text("z") < text("é"));
+        // Past the length of a key, text only ever ties
+        assert!(text("abcdefgh") <= text("abcdefghij"));
+        assert!(text("abcdefghij") <= text("abcdefgi"));
+    }
+
+    #[test]
+    fn test_filters_use_index() {
+        let (_dir, mut db) = temp_db("index_lookup");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        let reaction = db.execute(Action::CreateIndex(
+            "by_platinum".into(),
+            "currency".into(),
+            "Platinum".into(),
+            IndexKind::BTree,
+            false,
+        ));
+        assert!(matches!(reaction, Reaction::Done));
+        assert!(matches!(
+            db.execute(Action::CreateIndex(
+                "by_copper".into(),
+                "currency".into(),
+                "Nickel".into(),
+                IndexKind::BTree,
+                false
+            )),
+            Reaction::Error(_)
+        ));
+
+        let platinum = DataType::Integer;
+        let cases = [
+            (FilterType::EqualTo("Platinum".into(), platinum(42)), 4),
+            (
+                FilterType::In("currency.Platinum".into(), vec![platinum(1), platinum(2)]),
+                10,
+            ),
+            // Platinum < 10
+            (FilterType::GreaterThan("Platinum".into(), platinum(10)), 48),
+            // Platinum >= 95
+            (
+                FilterType::LessThanEqualTo("Platinum".into(), platinum(95)),
+                20,
+            ),
+            (
+                FilterType::Between("Platinum".into(), platinum(10), platinum(13)),
+                8,
+            ),
+        ];
+        for (filter_type, expected) in cases {
+            let predicate = filter(filter_type);
+            let rows = select(&mut db, predicate.clone());
+            assert_eq!(rows.len(), expected, "{:?}", predicate);
+            let table_schema = currency_schema(&db);
+            assert!(rows
+                .iter()
+                .all(|raw_row| predicate.evaluate(raw_row, &table_schema, &db).unwrap()));
+            // Only rows with a key in range are read
+            assert!(candidates(&db, &predicate).unwrap() <= expected + 8);
+        }
+
+        // Filters the index can't help with fall back to a scan
+        let gold = filter(FilterType::EqualTo("Gold".into(), DataType::Integer(9)));
+        assert_eq!(candidates(&db, &gold), None);
+        let both = Predicate::And(vec![
+            gold,
+            filter(FilterType::EqualTo("Platinum".into(), platinum(3))),
+        ]);
+        assert_eq!(candidates(&db, &both), Some(5));
+        assert_eq!(select(&mut db, both).len(), 5);
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let (dir, mut db) = temp_db("index_hash");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        db.create_index(
+            "by_platinum",
+            "currency",
+            "Platinum",
+            IndexKind::Hash,
+            false,
+        )
+        .unwrap();
+        let platinum = |value| FilterType::EqualTo("Platinum".into(), DataType::Integer(value));
+        assert_eq!(candidates(&db, &filter(platinum(42))), Some(4));
+        let either = FilterType::In(
+            "Platinum".into(),
+            vec![DataType::Integer(1), DataType::Integer(2)],
+        );
+        assert_eq!(candidates(&db, &filter(either)), Some(10));
+        // Ranges of keys are scanned for
+        let below = FilterType::GreaterThan("Platinum".into(), DataType::Integer(10));
+        assert_eq!(candidates(&db, &filter(below)), None);
+
+        db.execute(Action::Update(
+            "currency".into(),
+            vec![(
+                "Platinum".into(),
+                Expression::Literal(DataType::Integer(100)),
+            )],
+            filter(platinum(42)),
+        ));
+        assert_eq!(select(&mut db, filter(platinum(100))).len(), 4);
+        assert_eq!(candidates(&db, &filter(platinum(42))), Some(0));
+
+        // It's still a hash index after reopening, and after the table moves
+        drop(db);
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(db.indexes["by_platinum"].kind, IndexKind::Hash);
+        db.alter_table("currency", Alteration::DropColumn("Gold".into(), false))
+            .unwrap();
+        assert_eq!(db.indexes["by_platinum"].kind, IndexKind::Hash);
+        assert_eq!(candidates(&db, &filter(platinum(100))), Some(4));
+        assert_eq!(select(&mut db, filter(platinum(100))).len(), 4);
+    }
+
+    #[test]
+    fn test_index_kept_up_to_date() {
+        let (dir, mut db) = temp_db("index_upkeep");
+        db.store("currency", vec![mint(0)]).unwrap();
+        db.create_index("by_gold", "currency", "Gold", IndexKind::BTree, false)
+            .unwrap();
+        let gold = |value| filter(FilterType::EqualTo("Gold".into(), DataType::Integer(value)));
+        assert_eq!(select(&mut db, gold(3)).len(), 21);
+
+        // Every Gold of 3 becomes 100
+        let reaction = db.execute(Action::Update(
+            "currency".into(),
+            vec![("Gold".into(), Expression::Literal(DataType::Integer(100)))],
+            gold(3),
+        ));
+        assert!(matches!(reaction, Reaction::Affected(21)));
+        assert_eq!(select(&mut db, gold(3)).len(), 0);
+        assert_eq!(candidates(&db, &gold(3)), Some(0));
+        assert_eq!(select(&mut db, gold(100)).len(), 21);
+
+        db.execute(Action::Delete("currency".into(), gold(100)));
+        db.execute(Action::Insert(
+            "currency".into(),
+            vec!["Gold".into()],
+            vec![vec![DataType::Integer(100)]],
+        ));
+        assert_eq!(select(&mut db, gold(100)).len(), 1);
+
+        // A transaction sees its own rows, which aren't indexed until it commits
+        db.execute(Action::Begin);
+        db.execute(Action::Update(
+            "currency".into(),
+            vec![("Gold".into(), Expression::Literal(DataType::Integer(7)))],
+            gold(100),
+        ));
+        assert_eq!(select(&mut db, gold(100)).len(), 0);
+        assert_eq!(select(&mut db, gold(7)).len(), 21);
+        db.execute(Action::Rollback);
+        assert_eq!(select(&mut db, gold(7)).len(), 20);
+
+        // The index is still there after reopening
+        drop(db);
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(candidates(&db, &gold(100)), Some(1));
+        assert_eq!(select(&mut db, gold(100)).len(), 1);
+
+        assert!(matches!(
+            db.execute(Action::DropIndex("by_gold".into(), false)),
+            Reaction::Done
+        ));
+        assert_eq!(candidates(&db, &gold(100)), None);
+        assert!(matches!(
+            db.execute(Action::DropIndex("by_gold".into(), true)),
+            Reaction::Done
+        ));
+    }
+
+    #[test]
+    fn test_rows_found_by_id() {
+        let (dir, mut db) = temp_db("index_primary");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        let id = |value| {
+            filter(FilterType::EqualTo(
+                "index".into(),
+                DataType::Integer(value),
+            ))
+        };
+        assert_eq!(candidates(&db, &id(300)), Some(1));
+        assert_eq!(select(&mut db, id(300))[0][0], 300);
+
+        // Ids are unique, wherever the row would go
+        let taken = db.store("currency", vec![mint(0), mint(0)]);
+        assert!(matches!(taken, Err(Error::SchemaError(_))));
+
+        // A lost primary index is built again on opening
+        let primary = db
+            .path_info()
+            .unwrap()
+            .generate_table_path(&primary_file_name("currency"));
+        drop(db);
+        std::fs::remove_file(primary).unwrap();
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(candidates(&db, &id(300)), Some(1));
+        assert_eq!(select(&mut db, id(300)).len(), 1);
+    }
+
+    #[test]
+    fn test_indexes_follow_their_columns() {
+        let (_dir, mut db) = temp_db("index_alter");
+        db.store("currency", vec![mint(0)]).unwrap();
+        db.create_index("by_silver", "currency", "Silver", IndexKind::BTree, false)
+            .unwrap();
+        db.create_index("by_gold", "currency", "Gold", IndexKind::BTree, false)
+            .unwrap();
+        let silver =
+            |column: &str| filter(FilterType::EqualTo(column.into(), DataType::Integer(5)));
+
+        db.alter_table(
+            "currency",
+            Alteration::RenameColumn("Silver".into(), "Shillings".into()),
+        )
+        .unwrap();
+        assert_eq!(candidates(&db, &silver("Shillings")), Some(102));
+
+        // Dropping Gold moves Silver over, and takes Gold's index with it
+        db.alter_table("currency", Alteration::DropColumn("Gold".into(), false))
+            .unwrap();
+        assert_eq!(db.indexes.keys().collect::<Vec<_>>(), vec!["by_silver"]);
+        assert_eq!(candidates(&db, &silver("Shillings")), Some(102));
+        let rows = select(&mut db, silver("Shillings"));
+        assert_eq!(rows.len(), 102);
+        assert!(rows.iter().all(|raw_row| raw_row[2] == 5));
+
+        db.drop_table("currency", false).unwrap();
+        assert!(db.indexes.is_empty());
+    }
+}
@@ -0,0 +1,681 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::common::{
+    convert_field, error::Error, ColumnType, DataType, Overflow, RawRow, TableInfoMap,
+};
+
+use super::mvcc::Snapshot;
+use super::scan::{Rows, TableScan};
+use super::{DataBase, FilterType, Predicate, SessionId};
+
+/// Where a query's rows come from: a table, or tables joined together.
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    // A table, and the alias its columns are qualified with if not its name
+    Table(String, Option<String>),
+    Join(Box<Source>, Box<Source>, Join),
+}
+
+impl Source {
+    /// Every table the source reads from.
+    pub(super) fn table_names(&self) -> Vec<String> {
+        match self {
+            Source::Table(table_name, _) => vec![table_name.to_owned()],
+            Source::Join(left, right, _) => {
+                let mut table_names = left.table_names();
+                table_names.extend(right.table_names());
+                table_names
+            }
+        }
+    }
+}
+
+impl From<&str> for Source {
+    fn from(table_name: &str) -> Self {
+        Source::Table(table_name.to_owned(), None)
+    }
+}
+
+/// How two sources are joined. Inner and left joins match rows on pairs of
+/// equal columns, one from each side, and on a predicate over the joined row.
+#[derive(Debug, PartialEq)]
+pub enum Join {
+    Inner(Vec<(String, String)>, Predicate),
+    // Left rows without a match are kept, with the right side zeroed
+    Left(Vec<(String, String)>, Predicate),
+    Cross,
+}
+
+/// The table and name of each column of a source, in order.
+type Columns = Vec<(String, String, ColumnType)>;
+
+/// Rows read from a source up front, along with the table and name of each
+/// column.
+pub struct Relation {
+    columns: Columns,
+    pub rows: Vec<RawRow>,
+}
+
+/// The rows of the right side of a join, kept in memory while the left
+/// side streams past them.
+enum RightRows {
+    // With equal columns to go on, a hash join saves comparing every pair.
+    // Rows are kept by the values of their join columns, and the left row's
+    // columns to look them up by go along with them.
+    Hashed(
+        Vec<(usize, ColumnType)>,
+        HashMap<Vec<DataType>, Vec<RawRow>>,
+    ),
+    // Without, each left row is paired with every one of them
+    All(Vec<RawRow>),
+}
+
+/// The right side of a join, and how rows of the left side are matched to
+/// it.
+struct RightSide {
+    rows: RightRows,
+    // Which pairs the join keeps, along with their keys
+    on: Predicate,
+    // Left rows without a match are kept, with the right side zeroed
+    outer: bool,
+    schema: TableInfoMap,
+    // What's left of the query's predicate once the filters on one table
+    // have been handed to its scan
+    filter: Predicate,
+    unmatched: RawRow,
+}
+
+impl RightSide {
+    /// Gets a relation ready to be joined to rows with `left_columns`,
+    /// giving back the columns of the joined rows along with it.
+    fn new(
+        left_columns: Columns,
+        right: Relation,
+        join: Join,
+        overflow: &dyn Overflow,
+    ) -> Result<(Columns, Self), Error> {
+        let tables = |columns: &Columns| -> HashSet<String> {
+            columns
+                .iter()
+                .map(|(table, _, _)| table.to_owned())
+                .collect()
+        };
+        if let Some(table) = tables(&left_columns)
+            .intersection(&tables(&right.columns))
+            .next()
+        {
+            return Err(Error::QueryError(format!(
+                "Table {} is joined more than once, give it an alias",
+                table
+            )));
+        }
+
+        let left_schema = qualified_schema(&left_columns);
+        let right_schema = qualified_schema(&right.columns);
+        let unmatched = vec![0u64; right.columns.len()];
+        let mut columns = left_columns;
+        columns.extend(right.columns);
+
+        let (keys, on, outer) = match join {
+            Join::Cross => (vec![], Predicate::Filter(FilterType::All), false),
+            Join::Inner(keys, on) => (keys, on, false),
+            Join::Left(keys, on) => (keys, on, true),
+        };
+        let keys = keys
+            .iter()
+            .map(|(left, right)| join_key(left, right, &left_schema, &right_schema))
+            .collect::<Result<Vec<_>, _>>()?;
+        let rows = if keys.is_empty() {
+            RightRows::All(right.rows)
+        } else {
+            let mut matches: HashMap<Vec<DataType>, Vec<RawRow>> = HashMap::new();
+            for right_row in right.rows {
+                let key = key_values(
+                    &right_row,
+                    keys.iter().map(|(_, right, t)| (*right, t)),
+                    overflow,
+                )?;
+                matches.entry(key).or_default().push(right_row);
+            }
+            let left_keys = keys
+                .into_iter()
+                .map(|(left, _, column_type)| (left, column_type))
+                .collect();
+            RightRows::Hashed(left_keys, matches)
+        };
+
+        let right = RightSide {
+            rows,
+            on,
+            outer,
+            schema: qualified_schema(&columns),
+            filter: Predicate::Filter(FilterType::All),
+            unmatched,
+        };
+        Ok((columns, right))
+    }
+
+    /// Every joined row a row of the left side makes that passes the filter.
+    fn join_row(&self, left_row: &RawRow, overflow: &dyn Overflow) -> Result<Vec<RawRow>, Error> {
+        let candidates: &[RawRow] = match &self.rows {
+            RightRows::All(rows) => rows,
+            RightRows::Hashed(keys, matches) => {
+                let key = key_values(left_row, keys.iter().map(|(left, t)| (*left, t)), overflow)?;
+                matches.get(&key).map_or(&[], Vec::as_slice)
+            }
+        };
+        let mut matched = false;
+        let mut rows = Vec::new();
+        for right_row in candidates {
+            let row = concat(left_row, right_row);
+            if self.on.evaluate(&row, &self.schema, overflow)? {
+                matched = true;
+                if self.filter.evaluate(&row, &self.schema, overflow)? {
+                    rows.push(row);
+                }
+            }
+        }
+        if self.outer && !matched {
+            let row = concat(left_row, &self.unmatched);
+            if self.filter.evaluate(&row, &self.schema, overflow)? {
+                rows.push(row);
+            }
+        }
+        Ok(rows)
+    }
+}
+
+/// Two sources joined together. Rows of the left side are read as the
+/// joined rows are asked for, and matched up with the right side, which is
+/// read up front.
+pub struct JoinedRows {
+    left: Rows,
+    right: RightSide,
+    // Joined rows of the last left row that haven't been taken yet
+    rows: std::vec::IntoIter<RawRow>,
+}
+
+impl JoinedRows {
+    pub fn next_row(&mut self, db: &DataBase) -> Option<Result<RawRow, Error>> {
+        loop {
+            if let Some(raw_row) = self.rows.next() {
+                return Some(Ok(raw_row));
+            }
+            let rows = self
+                .left
+                .next_row(db)?
+                .and_then(|left_row| self.right.join_row(&left_row, db));
+            match rows {
+                Ok(rows) => self.rows = rows.into_iter(),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// Works out which tables of a source the parts of a predicate that read
+/// from a single table can go to, so their scans skip rows the query would
+/// throw away, through an index if there is one. Takes the columns of each
+/// table, and gives back what each is given, both in the order the source
+/// reads them, and what's left.
+///
+/// Nothing goes to a table on the right of a left join, whose rows are
+/// zeroed rather than left out when they don't match.
+fn push_down(
+    source: &Source,
+    tables: &[Columns],
+    predicate: Predicate,
+) -> (Vec<Vec<Predicate>>, Vec<Predicate>) {
+    // Whether each table's rows can be zeroed
+    fn find_zeroed(source: &Source, right_of_left: bool, zeroed: &mut Vec<bool>) {
+        match source {
+            Source::Table(_, _) => zeroed.push(right_of_left),
+            Source::Join(left, right, join) => {
+                find_zeroed(left, right_of_left, zeroed);
+                let right_of_left = right_of_left || matches!(join, Join::Left(..));
+                find_zeroed(right, right_of_left, zeroed);
+            }
+        }
+    }
+    let mut zeroed = Vec::new();
+    find_zeroed(source, false, &mut zeroed);
+
+    // Which table each column of a joined row is from
+    let owners: Vec<usize> = tables
+        .iter()
+        .enumerate()
+        .flat_map(|(owner, columns)| std::iter::repeat_n(owner, columns.len()))
+        .collect();
+    let schema = qualified_schema(&tables.concat());
+    let mut pushed = vec![Vec::new(); tables.len()];
+    let mut left = Vec::new();
+    let mut parts = vec![predicate];
+    while let Some(part) = parts.pop() {
+        let part = match part {
+            Predicate::And(predicates) => {
+                parts.extend(predicates);
+                continue;
+            }
+            part => part,
+        };
+        let mut owner = None;
+        let single = part.columns().iter().all(|column| {
+            let Some((_, offset)) = schema.get(column.as_str()) else {
+                return false;
+            };
+            let column_owner = owners[*offset as usize];
+            *owner.get_or_insert(column_owner) == column_owner
+        });
+        match owner {
+            Some(owner) if single && !zeroed[owner] => pushed[owner].push(part),
+            _ => left.push(part),
+        }
+    }
+    (pushed, left)
+}
+
+fn qualified_schema(columns: &Columns) -> TableInfoMap {
+    let mut bare_names: HashMap<&str, usize> = HashMap::new();
+    for (_, column, _) in columns {
+        *bare_names.entry(column).or_default() += 1;
+    }
+
+    let mut schema = TableInfoMap::new();
+    for (offset, (table, column, column_type)) in columns.iter().enumerate() {
+        let entry = (column_type.to_owned(), offset as u64);
+        schema.insert(format!("{}.{}", table, column), entry.to_owned());
+        if bare_names[column.as_str()] == 1 {
+            schema.insert(column.to_owned(), entry);
+        }
+    }
+    schema
+}
+
+fn concat(left: &RawRow, right: &RawRow) -> RawRow {
+    left.iter().chain(right).copied().collect()
+}
+
+fn key_values<'a, I>(
+    raw_row: &RawRow,
+    columns: I,
+    overflow: &dyn Overflow,
+) -> Result<Vec<DataType>, Error>
+where
+    I: Iterator<Item = (usize, &'a ColumnType)>,
+{
+    columns
+        .map(|(offset, column_type)| convert_field(raw_row[offset], column_type, overflow))
+        .collect()
+}
+
+/// Works out which side of the join each of a pair of columns is on, giving
+/// back their offsets into the left and right rows.
+fn join_key(
+    first: &str,
+    second: &str,
+    left_schema: &TableInfoMap,
+    right_schema: &TableInfoMap,
+) -> Result<(usize, usize, ColumnType), Error> {
+    let ((left_type, left), (right_type, right)) = match (
+        left_schema.get(first),
+        right_schema.get(second),
+        left_schema.get(second),
+        right_schema.get(first),
+    ) {
+        (Some(left), Some(right), _, _) | (_, _, Some(left), Some(right)) => (left, right),
+        _ => {
+            return Err(Error::QueryError(format!(
+                "Can't join on {} = {}, it needs a column from each side",
+                first, second
+            )))
+        }
+    };
+    if left_type != right_type {
+        return Err(Error::QueryError(format!(
+            "Can't join {:?} column {} to {:?} column {}",
+            left_type, first, right_type, second
+        )));
+    }
+    Ok((*left as usize, *right as usize, left_type.to_owned()))
+}
+
+impl DataBase {
+    /// Gives back the schema of a source, and its rows that match a predicate.
+    /// Tables are scanned as their rows are asked for, reading only the rows
+    /// an index points to if one can, except for the right side of each
+    /// join, which is read up front.
+    pub(super) fn scan_source(
+        &self,
+        session: SessionId,
+        source: Source,
+        predicate: Predicate,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<(TableInfoMap, Rows), Error> {
+        match source {
+            Source::Table(table_name, alias) => {
+                let (columns, rows) =
+                    self.scan_table(session, &table_name, alias, predicate, snapshot)?;
+                Ok((qualified_schema(&columns), rows))
+            }
+            source => {
+                let tables = self.source_tables(&source)?;
+                let (pushed, left) = push_down(&source, &tables, predicate);
+                let (columns, rows) =
+                    self.read_source(session, source, &mut pushed.into_iter(), snapshot)?;
+                let Rows::Join(mut joined) = rows else {
+                    unreachable!("a source that isn't a table is a join")
+                };
+                joined.right.filter = Predicate::And(left);
+                Ok((qualified_schema(&columns), Rows::Join(joined)))
+            }
+        }
+    }
+
+    /// Reads a source, giving each table in turn the next predicate of
+    /// `pushed` to scan for.
+    fn read_source(
+        &self,
+        session: SessionId,
+        source: Source,
+        pushed: &mut std::vec::IntoIter<Vec<Predicate>>,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<(Columns, Rows), Error> {
+        match source {
+            Source::Table(table_name, alias) => {
+                let predicate = Predicate::And(pushed.next().unwrap_or_default());
+                self.scan_table(session, &table_name, alias, predicate, snapshot)
+            }
+            Source::Join(left, right, join) => {
+                let (left_columns, left) = self.read_source(session, *left, pushed, snapshot)?;
+                let (columns, mut rows) = self.read_source(session, *right, pushed, snapshot)?;
+                let right = Relation {
+                    columns,
+                    rows: rows.read(self).collect::<Result<_, _>>()?,
+                };
+                let (columns, right) = RightSide::new(left_columns, right, join, self)?;
+                let joined = JoinedRows {
+                    left,
+                    right,
+                    rows: Vec::new().into_iter(),
+                };
+                Ok((columns, Rows::Join(Box::new(joined))))
+            }
+        }
+    }
+
+    fn scan_table(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        alias: Option<String>,
+        predicate: Predicate,
+        snapshot: Option<&Snapshot>,
+    ) -> Result<(Columns, Rows), Error> {
+        self.lock_for_reading(session, table_name)?;
+        let columns = self.table_columns(table_name, alias)?;
+        let table_schema = qualified_schema(&columns);
+        let locations =
+            self.index_candidates(session, table_name, &table_schema, &predicate, snapshot)?;
+        let mut scan = TableScan::new(
+            session,
+            table_name,
+            columns.len(),
+            predicate,
+            table_schema,
+            snapshot.cloned(),
+        );
+        if let Some(locations) = locations {
+            scan = scan.with_locations(locations);
+        }
+        Ok((columns, Rows::Scan(Box::new(scan))))
+    }
+
+    /// The columns of each table of a source, in the order they're read.
+    fn source_tables(&self, source: &Source) -> Result<Vec<Columns>, Error> {
+        match source {
+            Source::Table(table_name, alias) => {
+                Ok(vec![self.table_columns(table_name, alias.to_owned())?])
+            }
+            Source::Join(left, right, _) => {
+                let mut tables = self.source_tables(left)?;
+                tables.extend(self.source_tables(right)?);
+                Ok(tables)
+            }
+        }
+    }
+
+    fn table_columns(&self, table_name: &str, alias: Option<String>) -> Result<Columns, Error> {
+        let qualifier = alias.unwrap_or_else(|| table_name.to_owned());
+        Ok(self
+            .table_info(table_name)?
+            .iter()
+            .map(|(column, column_type)| {
+                (
+                    qualifier.to_owned(),
+                    column.to_owned(),
+                    column_type.to_owned(),
+                )
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage_engine::DEFAULT_SESSION;
+    use crate::test_utils::{mint, temp_db, MemoryOverflow};
+
+    use super::*;
+
+    fn relation(table: &str, columns: &[&str], rows: Vec<RawRow>) -> Relation {
+        Relation {
+            columns: columns
+                .iter()
+                .map(|column| (table.to_owned(), column.to_string(), ColumnType::Integer))
+                .collect(),
+            rows,
+        }
+    }
+
+    fn heroes() -> Relation {
+        relation(
+            "heroes",
+            &["index", "level"],
+            vec![vec![1, 3], vec![2, 5], vec![3, 5]],
+        )
+    }
+
+    fn purses() -> Relation {
+        relation(
+            "purses",
+            &["index", "owner", "gold"],
+            vec![vec![1, 2, 10], vec![2, 1, 20], vec![3, 2, 30]],
+        )
+    }
+
+    /// Joins every row of the left relation, giving back the joined schema
+    /// and rows.
+    fn join(
+        left: Relation,
+        right: Relation,
+        join: Join,
+    ) -> Result<(TableInfoMap, Vec<RawRow>), Error> {
+        let overflow = MemoryOverflow::default();
+        let (columns, right) = RightSide::new(left.columns, right, join, &overflow)?;
+        let mut rows = Vec::new();
+        for left_row in &left.rows {
+            rows.extend(right.join_row(left_row, &overflow)?);
+        }
+        Ok((qualified_schema(&columns), rows))
+    }
+
+    fn owner_key() -> Vec<(String, String)> {
+        // Either order works, each column is found on its own side
+        vec![("purses.owner".into(), "heroes.index".into())]
+    }
+
+    #[test]
+    fn test_joined_schema() {
+        let (schema, _) = join(heroes(), purses(), Join::Cross).unwrap();
+        assert_eq!(schema["heroes.index"], (ColumnType::Integer, 0));
+        assert_eq!(schema["purses.index"], (ColumnType::Integer, 2));
+        assert_eq!(schema["gold"], (ColumnType::Integer, 4));
+        // Both tables have an index, so it has to be qualified
+        assert!(!schema.contains_key("index"));
+    }
+
+    #[test]
+    fn test_cross_join() {
+        let (_, rows) = join(heroes(), purses(), Join::Cross).unwrap();
+        assert_eq!(rows.len(), 9);
+        assert_eq!(rows[1], vec![1, 3, 2, 1, 20]);
+    }
+
+    #[test]
+    fn test_inner_joins() {
+        let expected = vec![
+            vec![1, 3, 2, 1, 20],
+            vec![2, 5, 1, 2, 10],
+            vec![2, 5, 3, 2, 30],
+        ];
+        let everything = || Predicate::Filter(FilterType::All);
+
+        let (_, hash_join) =
+            join(heroes(), purses(), Join::Inner(owner_key(), everything())).unwrap();
+        assert_eq!(hash_join, expected);
+
+        // With nothing to hash on, the predicate picks the pairs out
+        let rich = Predicate::Filter(FilterType::LessThan("gold".into(), DataType::Integer(15)));
+        let (_, nested_loop) = join(heroes(), purses(), Join::Inner(vec![], rich)).unwrap();
+        assert_eq!(nested_loop.len(), 6);
+        assert!(nested_loop.iter().all(|row| row[4] > 15));
+    }
+
+    #[test]
+    fn test_left_join() {
+        let filter = Predicate::Filter(FilterType::LessThan("gold".into(), DataType::Integer(15)));
+        let (_, joined) = join(heroes(), purses(), Join::Left(owner_key(), filter)).unwrap();
+        assert_eq!(
+            joined,
+            vec![
+                vec![1, 3, 2, 1, 20],
+                vec![2, 5, 3, 2, 30],
+                vec![3, 5, 0, 0, 0]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_joins() {
+        let everything = || Predicate::Filter(FilterType::All);
+        assert!(join(heroes(), heroes(), Join::Cross).is_err());
+        for keys in [
+            vec![("heroes.index".into(), "heroes.level".into())],
+            vec![("heroes.index".into(), "purses.mithril".into())],
+        ] {
+            assert!(join(heroes(), purses(), Join::Inner(keys, everything())).is_err());
+        }
+    }
+
+    #[test]
+    fn test_scan_source() {
+        let (_dir, db) = temp_db("scan_source");
+        let source = Source::Join(
+            Box::new(Source::Table("attributes".into(), Some("a".into()))),
+            Box::new("currency".into()),
+            Join::Cross,
+        );
+        let (schema, mut rows) = db
+            .scan_source(
+                DEFAULT_SESSION,
+                source,
+                Predicate::Filter(FilterType::All),
+                None,
+            )
+            .unwrap();
+        assert!(schema.contains_key("a.index"));
+        assert!(schema.contains_key("currency.Gold"));
+        assert!(schema.contains_key("Gold"));
+        assert!(rows.next_row(&db).is_none());
+    }
+
+    #[test]
+    fn test_push_down() {
+        let source =
+            |join| Source::Join(Box::new("heroes".into()), Box::new("purses".into()), join);
+        let tables = [heroes().columns, purses().columns];
+        let filter = |column: &str| {
+            Predicate::Filter(FilterType::EqualTo(column.into(), DataType::Integer(5)))
+        };
+        let predicate = || {
+            Predicate::And(vec![
+                filter("level"),
+                Predicate::And(vec![filter("purses.gold"), filter("mithril")]),
+                // Reads from both tables, so it stays with the join
+                Predicate::Or(vec![filter("level"), filter("gold")]),
+                // Both tables have an index
+                filter("index"),
+            ])
+        };
+
+        let (pushed, left) = push_down(&source(Join::Cross), &tables, predicate());
+        assert_eq!(
+            pushed,
+            vec![vec![filter("level")], vec![filter("purses.gold")]]
+        );
+        assert_eq!(left.len(), 3);
+        assert!(left.contains(&filter("mithril")));
+
+        // The right of a left join keeps its rows, zeroed, when they don't
+        // match, so they can't be left out of its scan
+        let (pushed, left) = push_down(
+            &source(Join::Left(owner_key(), Predicate::Filter(FilterType::All))),
+            &tables,
+            predicate(),
+        );
+        assert_eq!(pushed, vec![vec![filter("level")], vec![]]);
+        assert!(left.contains(&filter("purses.gold")));
+    }
+
+    #[test]
+    fn test_join_streams_left_side() {
+        let (_dir, db) = temp_db("join_streams");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        db.insert(
+            DEFAULT_SESSION,
+            "attributes",
+            vec!["Strength".into()],
+            vec![vec![DataType::Integer(8)], vec![DataType::Integer(18)]],
+        )
+        .unwrap();
+        let source = Source::Join(
+            Box::new("currency".into()),
+            Box::new("attributes".into()),
+            Join::Cross,
+        );
+        let predicate = Predicate::And(vec![
+            Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(3))),
+            Predicate::Filter(FilterType::LessThan(
+                "Strength".into(),
+                DataType::Integer(10),
+            )),
+        ]);
+        let (schema, mut rows) = db
+            .scan_source(DEFAULT_SESSION, source, predicate, None)
+            .unwrap();
+        let first = rows.next_row(&db).unwrap().unwrap();
+        assert_eq!(first[schema["Strength"].1 as usize], 18);
+
+        // Rows of the left side written after the join started are picked up
+        // once its scan gets there
+        db.store("currency", vec![mint(0), mint(204), mint(408)])
+            .unwrap();
+        let rest = rows.read(&db).collect::<Result<Vec<_>, _>>().unwrap();
+        let expected = (1..=3 * 204).filter(|id| id * 3 % 10 == 3).count();
+        assert_eq!(rest.len() + 1, expected);
+        assert!(rest
+            .iter()
+            .all(|row| row[schema["Gold"].1 as usize] == 3
+                && row[schema["Strength"].1 as usize] == 18));
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::common::error::Error;
+
+use super::allocator::RowLocation;
+use super::{Action, DataBase, Reaction, SessionId};
+
+/// Something a session can lock. Tables are locked with an intention mode
+/// before any of their rows are, so a table lock also covers its rows.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockTarget {
+    // Locked exclusively by changes to tables, and with an intention mode by
+    // everything else
+    Database,
+    Table(String),
+    Row(String, RowLocation),
+}
+
+impl Display for LockTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockTarget::Database => write!(f, "the database"),
+            LockTarget::Table(table_name) => write!(f, "table {}", table_name),
+            LockTarget::Row(table_name, (offset, slot)) => {
+                write!(
+                    f,
+                    "row {} of block {} of table {}",
+                    slot, offset, table_name
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    // Some of the target's rows will be locked shared
+    IntentionShared,
+    // Some of the target's rows will be locked exclusively
+    IntentionExclusive,
+    Shared,
+    // Shared, while some rows are also locked exclusively
+    SharedIntentionExclusive,
+    Exclusive,
+}
+
+impl LockMode {
+    /// Whether two sessions can hold these modes on the same target at once.
+    fn compatible(self, other: LockMode) -> bool {
+        use LockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) | (Shared, Shared) => true,
+            _ => false,
+        }
+    }
+
+    /// The weakest mode that allows everything both modes do, which is what
+    /// a session holding one ends up with when it asks for the other.
+    fn join(self, other: LockMode) -> LockMode {
+        use LockMode::*;
+        match (self, other) {
+            _ if self == other => self,
+            (Exclusive, _) | (_, Exclusive) => Exclusive,
+            (IntentionShared, mode) | (mode, IntentionShared) => mode,
+            // Whatever's left is some mix of Shared and IntentionExclusive
+            _ => SharedIntentionExclusive,
+        }
+    }
+}
+
+/// Hands out shared and exclusive locks to sessions, making them wait while
+/// someone else holds a lock that conflicts.
+///
+/// A session about to wait on another that's already waiting on it, however
+/// indirectly, would wait forever, so it's given a Deadlock error instead.
+/// Locks are held until they're all let go of at once.
+#[derive(Default)]
+pub struct LockManager {
+    table: Mutex<LockTable>,
+    // Signalled whenever a session lets go of its locks
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockTable {
+    granted: HashMap<LockTarget, HashMap<SessionId, LockMode>>,
+    // Everything each session holds, so it can all be let go of at once
+    held: HashMap<SessionId, HashSet<LockTarget>>,
+    // The sessions each waiting session is waiting for
+    waits_for: HashMap<SessionId, HashSet<SessionId>>,
+}
+
+impl LockManager {
+    /// Locks `target` for `session`, waiting for as long as another session
+    /// holds it in a mode that conflicts. A session that already holds the
+    /// target has its lock strengthened to cover `mode` as well.
+    pub fn lock(
+        &self,
+        session: SessionId,
+        target: LockTarget,
+        mode: LockMode,
+    ) -> Result<(), Error> {
+        let mut table = self.table();
+        loop {
+            let holders = table.granted.get(&target);
+            let held = holders.and_then(|holders| holders.get(&session)).copied();
+            let wanted = held.map_or(mode, |held| held.join(mode));
+            if held == Some(wanted) {
+                return Ok(());
+            }
+            let blockers: HashSet<SessionId> = holders
+                .into_iter()
+                .flatten()
+                .filter(|(holder, held)| **holder != session && !held.compatible(wanted))
+                .map(|(holder, _)| *holder)
+                .collect();
+            if blockers.is_empty() {
+                table.waits_for.remove(&session);
+                table
+                    .granted
+                    .entry(target.to_owned())
+                    .or_default()
+                    .insert(session, wanted);
+                table.held.entry(session).or_default().insert(target);
+                return Ok(());
+            }
+
+            table.waits_for.insert(session, blockers);
+            if table.waits_on_itself(session) {
+                table.waits_for.remove(&session);
+                return Err(Error::Deadlock(format!(
+                    "Deadlock waiting to lock {}",
+                    target
+                )));
+            }
+            table = self
+                .released
+                .wait(table)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /// Lets go of every lock a session holds, waking whoever was waiting.
+    pub fn release_all(&self, session: SessionId) {
+        let mut table = self.table();
+        table.waits_for.remove(&session);
+        let Some(held) = table.held.remove(&session) else {
+            return;
+        };
+        for target in held {
+            if let Some(holders) = table.granted.get_mut(&target) {
+                holders.remove(&session);
+                if holders.is_empty() {
+                    table.granted.remove(&target);
+                }
+            }
+        }
+        self.released.notify_all();
+    }
+
+    fn table(&self) -> MutexGuard<'_, LockTable> {
+        self.table
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl LockTable {
+    /// Whether following who waits for whom from `session` leads back to it.
+    fn waits_on_itself(&self, session: SessionId) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack: Vec<SessionId> = self.waits_for[&session].iter().copied().collect();
+        while let Some(waiting) = stack.pop() {
+            if waiting == session {
+                return true;
+            }
+            if seen.insert(waiting) {
+                if let Some(blockers) = self.waits_for.get(&waiting) {
+                    stack.extend(blockers);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// A database shared between threads, one session to each.
+///
+/// Everything but changes to tables runs side by side, with the lock manager
+/// keeping sessions off each other's rows. Changes to tables lock the whole
+/// database and have it to themselves. Locks are waited for before the
+/// database is, so a session holding what another wants is never stuck
+/// behind it.
+pub struct SharedDataBase {
+    db: RwLock<DataBase>,
+    locks: Arc<LockManager>,
+}
+
+impl SharedDataBase {
+    pub fn new(db: DataBase) -> Self {
+        let locks = Arc::clone(&db.locks);
+        Self {
+            db: RwLock::new(db),
+            locks,
+        }
+    }
+
+    pub fn connect(&self) -> SessionId {
+        self.read().connect()
+    }
+
+    pub fn disconnect(&self, session: SessionId) {
+        self.read().disconnect(session)
+    }
+
+    pub fn execute_in(&self, session: SessionId, action: Action) -> Reaction {
+        let changes_tables = action.changes_tables();
+        let mode = if changes_tables {
+            LockMode::Exclusive
+        } else if action.is_read_only() {
+            LockMode::IntentionShared
+        } else {
+            LockMode::IntentionExclusive
+        };
+        // Checked before the database is locked, as a transaction would hold
+        // on to that lock until it ends
+        if changes_tables {
+            if let Err(err) = self.read().check_outside_transaction(session) {
+                return Reaction::Error(err);
+            }
+        }
+        if let Err(err) = self.lock_database(session, mode) {
+            return Reaction::Error(err);
+        }
+        if changes_tables {
+            self.write().execute_in(session, action)
+        } else {
+            self.read().execute_shared(session, action)
+        }
+    }
+
+    /// Locks the whole database for a statement that's about to run, without
+    /// holding on to the database while it waits. Failing to get the lock
+    /// fails the statement, as it would for any other lock.
+    fn lock_database(&self, session: SessionId, mode: LockMode) -> Result<(), Error> {
+        match self.locks.lock(session, LockTarget::Database, mode) {
+            Ok(()) => Ok(()),
+            Err(err) => self.read().statement(session, || Err(err)),
+        }
+    }
+
+    // As with the buffer pool's lock, one session panicking shouldn't stop
+    // every other session from being served
+
+    fn read(&self) -> RwLockReadGuard<'_, DataBase> {
+        self.db
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, DataBase> {
+        self.db
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl DataBase {
+    /// Runs a statement for a session. Its locks are let go of once it's done,
+    /// unless the session is in a transaction, which holds them until it
+    /// ends. A transaction that deadlocks is rolled back, so the sessions it
+    /// was holding up can carry on.
+    pub(super) fn statement<T, F>(&self, session: SessionId, run: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let result = run();
+        if matches!(result, Err(Error::Deadlock(_))) && self.in_transaction(session) {
+            // Can't fail, the transaction was just there
+            let _ = self.rollback(session);
+        }
+        self.end_statement(session);
+        result
+    }
+
+    /// Lets go of the locks a statement took, unless its session's in a
+    /// transaction.
+    pub(super) fn end_statement(&self, session: SessionId) {
+        if !self.in_transaction(session) {
+            self.locks.release_all(session);
+        }
+    }
+
+    pub(super) fn lock_table(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        mode: LockMode,
+    ) -> Result<(), Error> {
+        self.locks
+            .lock(session, LockTarget::Table(table_name.to_owned()), mode)
+    }
+
+    /// Locks a row, once its table is locked with the matching intention
+    /// mode.
+    pub(super) fn lock_row(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        location: RowLocation,
+        mode: LockMode,
+    ) -> Result<(), Error> {
+        self.locks.lock(
+            session,
+            LockTarget::Row(table_name.to_owned(), location),
+            mode,
+        )
+    }
+
+    /// Locks a table a query is about to read the rows of. Only a transaction
+    /// needs to, so nothing it's read changes before it ends. Anything else
+    /// reads what was last committed, without waiting on anyone.
+    pub(super) fn lock_for_reading(
+        &self,
+        session: SessionId,
+        table_name: &str,
+    ) -> Result<(), Error> {
+        if self.in_transaction(session) {
+            self.lock_table(session, table_name, LockMode::IntentionShared)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::common::{ColumnType, DataType};
+    use crate::storage_engine::{Action, Expression, FilterType, Predicate, Reaction};
+    use crate::test_utils::temp_db;
+
+    use super::*;
+
+    fn row(slot: u64) -> LockTarget {
+        LockTarget::Row("heroes".into(), (0, slot))
+    }
+
+    #[test]
+    fn test_lock_modes() {
+        use LockMode::*;
+        let modes = [
+            IntentionShared,
+            IntentionExclusive,
+            Shared,
+            SharedIntentionExclusive,
+            Exclusive,
+        ];
+        // The usual compatibility matrix, in the order above
+        let expected = [
+            [true, true, true, true, false],
+            [true, true, false, false, false],
+            [true, false, true, false, false],
+            [true, false, false, false, false],
+            [false, false, false, false, false],
+        ];
+        for (i, left) in modes.iter().enumerate() {
+            for (j, right) in modes.iter().enumerate() {
+                assert_eq!(left.compatible(*right), expected[i][j]);
+            }
+        }
+        assert_eq!(Shared.join(IntentionExclusive), SharedIntentionExclusive);
+        assert_eq!(IntentionShared.join(Shared), Shared);
+        assert_eq!(SharedIntentionExclusive.join(Exclusive), Exclusive);
+    }
+
+    #[test]
+    fn test_conflicting_lock_waits_for_release() {
+        let locks = Arc::new(LockManager::default());
+        locks.lock(1, row(0), LockMode::Shared).unwrap();
+        locks.lock(2, row(0), LockMode::Shared).unwrap();
+        // Holding a lock already is no reason to wait for it
+        locks.lock(1, row(0), LockMode::Shared).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let waiting = {
+            let locks = Arc::clone(&locks);
+            thread::spawn(move || {
+                locks.lock(3, row(0), LockMode::Exclusive).unwrap();
+                sender.send(()).unwrap();
+            })
+        };
+        // Still waiting after one of the two readers lets go
+        locks.release_all(1);
+        assert!(receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        locks.release_all(2);
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn test_deadlock_detected() {
+        let locks = Arc::new(LockManager::default());
+        locks.lock(1, row(0), LockMode::Exclusive).unwrap();
+        locks.lock(2, row(1), LockMode::Exclusive).unwrap();
+
+        let waiting = {
+            let locks = Arc::clone(&locks);
+            thread::spawn(move || locks.lock(1, row(1), LockMode::Exclusive))
+        };
+        // Wait for session 1 to be waiting on session 2
+        while !locks.table().waits_for.contains_key(&1) {
+            thread::yield_now();
+        }
+        match locks.lock(2, row(0), LockMode::Exclusive) {
+            Err(Error::Deadlock(_)) => (),
+            result => panic!("Expected a deadlock, got {:?}", result),
+        }
+        // Session 2 gives up, so session 1 gets its lock
+        locks.release_all(2);
+        waiting.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_writers_wait_on_transactions() {
+        let (_dir, db) = temp_db("lock_rows");
+        let purse = |gold| vec![DataType::Integer(gold)];
+        db.insert(1, "currency", vec!["Gold".into()], vec![purse(1), purse(2)])
+            .unwrap();
+        let set_gold = |session, index, gold| {
+            db.update(
+                session,
+                "currency",
+                vec![("Gold".into(), Expression::Literal(DataType::Integer(gold)))],
+                Predicate::Filter(FilterType::EqualTo(
+                    "index".into(),
+                    DataType::Integer(index),
+                )),
+            )
+        };
+
+        db.begin(1).unwrap();
+        db.begin(2).unwrap();
+        set_gold(1, 1, 10).unwrap();
+        set_gold(2, 2, 20).unwrap();
+        thread::scope(|scope| {
+            // Row 2 is session 2's until it commits or rolls back
+            let waiting = scope.spawn(|| set_gold(1, 2, 11));
+            while !db.locks.table().waits_for.contains_key(&1) {
+                thread::yield_now();
+            }
+            // Which it does, having deadlocked by wanting row 1
+            assert!(matches!(set_gold(2, 1, 21), Err(Error::Deadlock(_))));
+            assert!(!db.in_transaction(2));
+            assert_eq!(waiting.join().unwrap().unwrap(), 1);
+        });
+        db.commit(1).unwrap();
+
+        let block = db.load_block_at("currency", 0).unwrap();
+        let gold = |slot| crate::common::read_row(&block, slot, 5)[2];
+        assert_eq!((gold(0), gold(1)), (10, 11));
+    }
+
+    #[test]
+    fn test_table_change_in_transaction_locks_nothing() {
+        let (_dir, db) = temp_db("lock_table_change");
+        let db = SharedDataBase::new(db);
+        let (first, second) = (db.connect(), db.connect());
+        assert!(matches!(
+            db.execute_in(first, Action::Begin),
+            Reaction::Done
+        ));
+        let create = || {
+            Action::CreateTable(
+                "spells".into(),
+                vec![("index".into(), ColumnType::Integer)],
+                false,
+            )
+        };
+        assert!(matches!(
+            db.execute_in(first, create()),
+            Reaction::Error(Error::QueryError(_))
+        ));
+        // The transaction still only means to write rows
+        assert_eq!(
+            db.locks.table().granted[&LockTarget::Database][&first],
+            LockMode::IntentionExclusive
+        );
+
+        // So other sessions can carry on writing alongside it
+        let purse = Action::Insert(
+            "currency".into(),
+            vec![],
+            vec![vec![DataType::Integer(1); 4]],
+        );
+        assert!(matches!(
+            db.execute_in(second, purse),
+            Reaction::Inserted { .. }
+        ));
+        assert!(matches!(
+            db.execute_in(first, Action::Commit),
+            Reaction::Done
+        ));
+        assert!(matches!(db.execute_in(second, create()), Reaction::Done));
+    }
+}
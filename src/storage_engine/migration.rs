@@ -0,0 +1,499 @@
+use std::fs::File;
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::{
+    error::Error, map_table_info, write_row, AsRawRows, ColumnType, DBSchema, TableInfo,
+    BLOCK_SIZE, COLUMN_WIDTH,
+};
+
+use super::index::{primary_file_name, IndexKind};
+use super::overflow::TextCounts;
+use super::{
+    sync_parent_dir, validate_table_info, write_atomically, write_schema, DataBase, PathInfo,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Alteration {
+    AddColumn(String, ColumnType, bool),
+    DropColumn(String, bool),
+    RenameColumn(String, String),
+}
+
+/// A table rewrite that has been decided on, but maybe not yet finished.
+///
+/// Once the journal holding this is on disk the rewritten data is complete,
+/// so opening the database finishes the job instead of losing it.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct Migration {
+    table_name: String,
+    schema: DBSchema,
+    // The primary index is built again from the moved rows, which may not
+    // hold the highest id the table has handed out
+    #[serde(default)]
+    highest_id: u64,
+    // Each index of the table, its kind and the column it's built again
+    // for, or None if its column was dropped
+    #[serde(default)]
+    indexes: Vec<(String, IndexKind, Option<String>)>,
+}
+
+impl Migration {
+    /// Whether this migration moved the rows of a table.
+    pub(super) fn moved(&self, table_name: &str) -> bool {
+        self.table_name == table_name
+    }
+}
+
+impl DataBase {
+    /// Changes the columns of a table, rewriting its rows to the new layout
+    /// when columns are added or dropped. Indexes follow their columns, and
+    /// go with them when they're dropped.
+    pub fn alter_table(&mut self, table_name: &str, alteration: Alteration) -> Result<(), Error> {
+        let table_info = self.table_info(table_name)?.to_owned();
+        let table_map = map_table_info(&table_info);
+
+        // Each new column is filled from an old one, or zeroed when None
+        let (new_info, sources): (TableInfo, Vec<Option<usize>>) = match alteration {
+            Alteration::AddColumn(name, _, true) if table_map.contains_key(&name) => return Ok(()),
+            Alteration::AddColumn(name, column_type, _) => {
+                let mut new_info = table_info.to_owned();
+                new_info.push((name, column_type));
+                let mut sources: Vec<_> = (0..table_info.len()).map(Some).collect();
+                sources.push(None);
+                (new_info, sources)
+            }
+            Alteration::DropColumn(name, true) if !table_map.contains_key(&name) => return Ok(()),
+            Alteration::DropColumn(name, _) => {
+                let dropped = column_offset(&table_info, &name)?;
+                if dropped == 0 {
+                    return Err(Error::SchemaError(format!(
+                        "Column {} is the row id, which can't be dropped",
+                        name
+                    )));
+                }
+                table_info
+                    .iter()
+                    .enumerate()
+                    .filter(|(offset, _)| *offset != dropped)
+                    .map(|(offset, column)| (column.to_owned(), Some(offset)))
+                    .unzip()
+            }
+            Alteration::RenameColumn(old_name, new_name) => {
+                let renamed = column_offset(&table_info, &old_name)?;
+                let mut new_info = table_info.to_owned();
+                new_info[renamed].0 = new_name;
+                (new_info, (0..table_info.len()).map(Some).collect())
+            }
+        };
+        validate_table_info(&new_info)?;
+        self.end_queries(table_name);
+
+        let mut schema = self.schema.clone();
+        schema.insert(table_name.to_owned(), new_info);
+
+        if keeps_layout(&table_info, &sources) {
+            // Only names changed, the rows can stay as they are
+            write_schema(&self.path, &schema)?;
+            self.schema = schema;
+            return Ok(());
+        }
+
+        // The rows are about to move, so the table's indexes are built again
+        // afterwards, for the columns that are left. Until the journal is
+        // written they still find the rows where they were.
+        let indexes = self
+            .table_indexes(table_name)
+            .map(|(index_name, info)| {
+                let column = sources
+                    .iter()
+                    .position(|source| *source == Some(info.column))
+                    .map(|offset| schema[table_name][offset].0.to_owned());
+                (index_name.to_owned(), info.kind, column)
+            })
+            .collect();
+
+        // The log mustn't hold blocks laid out for the file being replaced,
+        // nor pages of the primary index, which is built again once the rows
+        // have moved.
+        self.checkpoint()?;
+        let migration = Migration {
+            table_name: table_name.to_owned(),
+            schema,
+            highest_id: self.highest_id(table_name)?,
+            indexes,
+        };
+        // A dropped Text column's texts may have nothing else referring to
+        // them once the rows have moved
+        let mut texts = TextCounts::default();
+        self.count_table_texts(table_name, -1, &mut texts)?;
+        self.copy_rows(&migration, &sources)?;
+        write_atomically(&journal_path(&self.path), &serde_json::to_vec(&migration)?)?;
+        finish_migration(&self.path, &self.path_info()?, &migration)?;
+
+        // The old handle still points at the data file we just replaced
+        let table_path = self.path_info()?.generate_table_path(&migration.table_name);
+        let table_file = File::options().read(true).write(true).open(table_path)?;
+        self.tables
+            .insert(migration.table_name.to_owned(), table_file);
+        self.allocators().remove(&migration.table_name);
+        self.cache().forget(&migration.table_name);
+        self.versions.forget(&migration.table_name);
+        self.schema = migration.schema.clone();
+        // A dropped Clob or Blob column's values are free now
+        self.rescan_heap(table_name)?;
+        self.count_table_texts(table_name, 1, &mut texts)?;
+        self.count_texts(self.versions.next_commit(), texts);
+        self.rebuild_indexes(&migration)
+    }
+
+    /// Builds the indexes of a table that has moved again, then clears the
+    /// journal. If we crash first, opening the database does it all over.
+    pub(super) fn rebuild_indexes(&mut self, migration: &Migration) -> Result<(), Error> {
+        let table_name = &migration.table_name;
+        self.build_primary_index(table_name, migration.highest_id)?;
+        for (index_name, _, _) in &migration.indexes {
+            self.drop_index(index_name, true)?;
+        }
+        for (index_name, kind, column) in &migration.indexes {
+            if let Some(column) = column {
+                self.create_index(index_name, table_name, column, *kind, false)?;
+            }
+        }
+        std::fs::remove_file(journal_path(&self.path))?;
+        sync_parent_dir(&self.path)
+    }
+
+    /// Writes every live row of the table, laid out for its new columns, to
+    /// a scratch file beside the table's data file.
+    fn copy_rows(&self, migration: &Migration, sources: &[Option<usize>]) -> Result<(), Error> {
+        let table_name = &migration.table_name;
+        let old_columns = self.table_info(table_name)?.len();
+        let rows_per_block = BLOCK_SIZE / (sources.len() * COLUMN_WIDTH);
+
+        let table_path = self.path_info()?.generate_table_path(table_name);
+        let scratch = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(scratch_path(&table_path))?;
+
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut slot = 0;
+        let mut offset = 0u64;
+        for old_offset in 0..self.block_count(table_name)? {
+            for raw_row in self
+                .load_block_at(table_name, old_offset)?
+                .as_rows(old_columns)
+            {
+                let new_row = sources
+                    .iter()
+                    .map(|source| source.map_or(0, |source| raw_row[source]))
+                    .collect();
+                write_row(&mut block, slot, &new_row);
+                slot += 1;
+                if slot == rows_per_block {
+                    scratch.write_at(&block, offset * BLOCK_SIZE as u64)?;
+                    block = [0u8; BLOCK_SIZE];
+                    slot = 0;
+                    offset += 1;
+                }
+            }
+        }
+        if slot > 0 {
+            scratch.write_at(&block, offset * BLOCK_SIZE as u64)?;
+        }
+        scratch.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Whether every column stays at the same offset, so no rows need moving.
+fn keeps_layout(old_info: &TableInfo, sources: &[Option<usize>]) -> bool {
+    sources.len() == old_info.len()
+        && sources
+            .iter()
+            .enumerate()
+            .all(|(offset, source)| *source == Some(offset))
+}
+
+fn column_offset(table_info: &TableInfo, column: &str) -> Result<usize, Error> {
+    table_info
+        .iter()
+        .position(|(name, _)| name == column)
+        .ok_or_else(|| Error::SchemaError(format!("Column {} does not exist", column)))
+}
+
+fn journal_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".alter");
+    PathBuf::from(path)
+}
+
+fn scratch_path(table_path: &Path) -> PathBuf {
+    let mut path = table_path.as_os_str().to_owned();
+    path.push(".migrate");
+    PathBuf::from(path)
+}
+
+/// Swaps in the rewritten data and the schema describing it. Every step can
+/// be safely repeated if we crash part way through, and the journal stays
+/// until the table's indexes are built again.
+fn finish_migration(
+    db_path: &Path,
+    path_info: &PathInfo,
+    migration: &Migration,
+) -> Result<(), Error> {
+    let table_path = path_info.generate_table_path(&migration.table_name);
+    // The primary index points to where the rows used to be, and opening the
+    // database builds a missing one again
+    let primary = path_info.generate_table_path(&primary_file_name(&migration.table_name));
+    if primary.exists() {
+        std::fs::remove_file(primary)?;
+    }
+    let scratch = scratch_path(&table_path);
+    if scratch.exists() {
+        std::fs::rename(scratch, table_path)?;
+        sync_parent_dir(db_path)?;
+    }
+    write_schema(db_path, &migration.schema)
+}
+
+/// Finishes moving the data of a migration whose journal made it to disk,
+/// giving it back so its indexes can be built again, and throws away the
+/// scratch files of any that didn't. Runs before the schema is read.
+pub(super) fn recover_migrations(
+    db_path: &Path,
+    path_info: &PathInfo,
+) -> Result<Option<Migration>, Error> {
+    let journal = journal_path(db_path);
+    if !journal.exists() {
+        return Ok(None);
+    }
+    let migration: Migration = serde_json::from_slice(&std::fs::read(journal)?)?;
+    finish_migration(db_path, path_info, &migration)?;
+    Ok(Some(migration))
+}
+
+pub(super) fn remove_scratch_files(path_info: &PathInfo, schema: &DBSchema) -> Result<(), Error> {
+    for table_name in schema.keys() {
+        let scratch = scratch_path(&path_info.generate_table_path(table_name));
+        if scratch.exists() {
+            std::fs::remove_file(scratch)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{read_row, DataType};
+    use crate::storage_engine::{
+        Action, Expression, FilterType, Predicate, Reaction, DEFAULT_SESSION,
+    };
+    use crate::test_utils::{mint, temp_db};
+
+    use super::*;
+
+    #[test]
+    fn test_add_column() {
+        let (dir, mut db) = temp_db("alter_add");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let reaction = db.execute(Action::AlterTable(
+            "currency".into(),
+            Alteration::AddColumn("Electrum".into(), ColumnType::Integer, false),
+        ));
+        assert!(matches!(reaction, Reaction::Done));
+
+        // 204 rows of 6 columns no longer fit in one block
+        assert_eq!(db.block_count("currency").unwrap(), 2);
+        assert_eq!(
+            read_row(&db.load_block_at("currency", 0).unwrap(), 0, 6),
+            vec![1, 1, 3, 5, 7, 0]
+        );
+        assert_eq!(
+            read_row(&db.load_block_at("currency", 1).unwrap(), 0, 6),
+            vec![171, 71, 3, 5, 7, 0]
+        );
+
+        // Rows can be written to the new column, and survive a restart
+        db.update(
+            DEFAULT_SESSION,
+            "currency",
+            vec![("Electrum".into(), Expression::Literal(DataType::Integer(2)))],
+            Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(204))),
+        )
+        .unwrap();
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Electrum".into()],
+                vec![vec![DataType::Integer(9)]],
+            )
+            .unwrap();
+        assert_eq!(ids, vec![205]);
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(db.schema["currency"].len(), 6);
+        let block = db.load_block_at("currency", 1).unwrap();
+        assert_eq!(read_row(&block, 33, 6), vec![204, 4, 2, 0, 8, 2]);
+        assert_eq!(read_row(&block, 34, 6), vec![205, 0, 0, 0, 0, 9]);
+        assert!(!journal_path(&dir.db_path()).exists());
+    }
+
+    #[test]
+    fn test_drop_and_rename_column() {
+        let (_dir, mut db) = temp_db("alter_drop");
+        db.store("currency", vec![mint(0)]).unwrap();
+        db.alter_table("currency", Alteration::DropColumn("Gold".into(), false))
+            .unwrap();
+        db.alter_table(
+            "currency",
+            Alteration::RenameColumn("Silver".into(), "Shillings".into()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.schema["currency"],
+            vec![
+                ("index".to_owned(), ColumnType::Integer),
+                ("Platinum".to_owned(), ColumnType::Integer),
+                ("Shillings".to_owned(), ColumnType::Integer),
+                ("Copper".to_owned(), ColumnType::Integer),
+            ]
+        );
+        let block = db.load_block_at("currency", 0).unwrap();
+        assert_eq!(read_row(&block, 11, 4), vec![12, 12, 0, 4]);
+        assert_eq!(block.as_rows(4).len(), 204);
+    }
+
+    #[test]
+    fn test_bad_alterations() {
+        let (_dir, mut db) = temp_db("alter_bad");
+        let add = |name: &str, if_not_exists| {
+            Alteration::AddColumn(name.into(), ColumnType::Integer, if_not_exists)
+        };
+        assert!(db.alter_table("currency", add("Gold", false)).is_err());
+        assert!(db.alter_table("currency", add("Gold", true)).is_ok());
+        assert!(db.alter_table("dragons", add("Gold", false)).is_err());
+        assert!(db
+            .alter_table("currency", Alteration::DropColumn("index".into(), false))
+            .is_err());
+        assert!(db
+            .alter_table("currency", Alteration::DropColumn("Mithril".into(), false))
+            .is_err());
+        assert!(db
+            .alter_table("currency", Alteration::DropColumn("Mithril".into(), true))
+            .is_ok());
+        assert!(db
+            .alter_table(
+                "currency",
+                Alteration::RenameColumn("Gold".into(), "Silver".into())
+            )
+            .is_err());
+        assert_eq!(db.schema["currency"].len(), 5);
+    }
+
+    #[test]
+    fn test_recover_committed_migration() {
+        let (dir, mut db) = temp_db("alter_recover");
+        db.store("currency", vec![mint(0)]).unwrap();
+        db.create_index("by_gold", "currency", "Gold", IndexKind::Hash, false)
+            .unwrap();
+        db.create_index("by_silver", "currency", "Silver", IndexKind::BTree, false)
+            .unwrap();
+
+        // Crash right after the journal is written
+        let mut schema = db.schema.clone();
+        schema
+            .get_mut("currency")
+            .unwrap()
+            .push(("Electrum".into(), ColumnType::Integer));
+        let migration = Migration {
+            table_name: "currency".into(),
+            schema,
+            highest_id: 300,
+            indexes: vec![
+                ("by_gold".into(), IndexKind::Hash, Some("Gold".into())),
+                ("by_silver".into(), IndexKind::BTree, None),
+            ],
+        };
+        db.copy_rows(
+            &migration,
+            &[Some(0), Some(1), Some(2), Some(3), Some(4), None],
+        )
+        .unwrap();
+        write_atomically(
+            &journal_path(&dir.db_path()),
+            &serde_json::to_vec(&migration).unwrap(),
+        )
+        .unwrap();
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(db.schema["currency"].len(), 6);
+        assert_eq!(
+            read_row(&db.load_block_at("currency", 0).unwrap(), 1, 6),
+            vec![2, 2, 6, 0, 4, 0]
+        );
+        assert!(!journal_path(&dir.db_path()).exists());
+        // Rows are found by id where they are now
+        let table_schema = map_table_info(&db.schema["currency"]);
+        let second = Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(2)));
+        let locations = db
+            .index_candidates(DEFAULT_SESSION, "currency", &table_schema, &second, None)
+            .unwrap();
+        assert_eq!(locations, Some([(0, 1)].into()));
+        // Ids the table handed out before it moved stay used
+        assert_eq!(db.highest_id("currency").unwrap(), 300);
+
+        // An index the journal keeps is built again, for rows where they are
+        // now, and one whose column it says is gone goes too
+        assert_eq!(db.indexes["by_gold"].kind, IndexKind::Hash);
+        assert!(!db.indexes.contains_key("by_silver"));
+        let gold = Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(3)));
+        let locations = db
+            .index_candidates(DEFAULT_SESSION, "currency", &table_schema, &gold, None)
+            .unwrap();
+        // Gold is id * 3 % 10, and 170 rows of 6 columns fit in a block
+        let expected = (1..=204u64)
+            .filter(|id| id % 10 == 1)
+            .map(|id| ((id - 1) / 170, (id - 1) % 170))
+            .collect();
+        assert_eq!(locations, Some(expected));
+    }
+
+    #[test]
+    fn test_abandon_uncommitted_migration() {
+        let (dir, db) = temp_db("alter_abandon");
+        db.store("currency", vec![mint(0)]).unwrap();
+
+        // Crash before the journal is written
+        let migration = Migration {
+            table_name: "currency".into(),
+            schema: db.schema.clone(),
+            highest_id: 204,
+            indexes: vec![],
+        };
+        db.copy_rows(&migration, &[Some(0), Some(2)]).unwrap();
+        let table_path = db
+            .path_info()
+            .unwrap()
+            .generate_table_path(&"currency".into());
+        assert!(scratch_path(&table_path).exists());
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        assert!(!scratch_path(&table_path).exists());
+        assert_eq!(db.schema["currency"].len(), 5);
+        assert_eq!(
+            read_row(&db.load_block_at("currency", 0).unwrap(), 1, 5),
+            vec![2, 2, 6, 0, 4]
+        );
+    }
+}
@@ -1,21 +1,105 @@
 // Rust Builtin Imports
 
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::Read;
-use std::os::unix::prelude::FileExt;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::{collections::HashMap, io::Write};
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, MutexGuard};
 
 // Third party library imports
 use serde_json::from_str;
 
-use crate::common::{convert_row_field, AsRawRows, DataType, RawRow};
+use crate::common::{
+    convert_row_field, encode_field, read_row, ColumnType, DataType, Overflow, RawRow, Row,
+    TableInfo,
+};
 // First party library imports
-use crate::common::{error::Error, map_table_info, Block, DBSchema, TableInfoMap, BLOCK_SIZE};
+use crate::common::{
+    error::Error, map_table_info, Block, DBSchema, TableInfoMap, BLOCK_SIZE, COLUMN_WIDTH,
+};
 
+mod aggregate;
+mod allocator;
+mod btree;
 mod cache;
+mod hash;
+mod heap;
+mod index;
+mod join;
+mod lock;
+mod migration;
+mod mvcc;
+mod overflow;
+mod projection;
+mod scan;
+mod sort;
+mod transaction;
+mod wal;
+
+use aggregate::plan_aggregation;
+pub use aggregate::{Function, Grouping};
+use allocator::{RowLocation, TableAllocator};
+use cache::{Cache, CACHE_BLOCKS};
+use heap::{heap_file_name, Heaps};
+pub use index::IndexKind;
+use index::{primary_file_name, read_catalog, write_catalog, IndexCatalog};
+pub use join::{Join, Source};
+use lock::LockManager;
+pub use lock::{LockMode, LockTarget, SharedDataBase};
+
+pub use migration::Alteration;
+use migration::{recover_migrations, remove_scratch_files};
+use mvcc::VersionStore;
+use overflow::{TextCounts, Texts, TEXT_FILE, TEXT_HASHES_FILE};
+
+use projection::{plan_projection, project_row};
+pub use projection::{Expression, Operator, Projection};
+use scan::Rows;
+pub use sort::Direction;
+use sort::{plan_sort, SortedRows, Sorter, SORT_BUFFER_SIZE};
+use transaction::Transaction;
+pub use transaction::{SessionId, DEFAULT_SESSION};
+use wal::{recover_log, WriteAheadLog, CHECKPOINT_SIZE};
+
+/// The rows of a running query, produced as the client asks for them.
+enum QueryRows {
+    // Projected as they're read, so rows straight from a table are only read
+    // once the client asks for them
+    Projected(Box<Rows>, Vec<Expression>, TableInfoMap),
+    Grouped(std::vec::IntoIter<Row>),
+    Sorted(SortedRows),
+}
+
+struct Query {
+    session: SessionId,
+    // The tables it reads from, changing one of them ends the query
+    tables: Vec<String>,
+    rows: QueryRows,
+    // How many more rows LIMIT allows
+    remaining: Option<u64>,
+}
+
+impl Query {
+    fn next_row(&mut self, db: &DataBase) -> Option<Result<Row, Error>> {
+        if self.remaining == Some(0) {
+            return None;
+        }
+        let next = match &mut self.rows {
+            QueryRows::Projected(rows, expressions, source_schema) => rows
+                .next_row(db)?
+                .and_then(|raw_row| project_row(&raw_row, expressions, source_schema, db)),
+            QueryRows::Grouped(rows) => Ok(rows.next()?),
+            QueryRows::Sorted(rows) => rows.next()?,
+        };
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining -= 1;
+        }
+        Some(next)
+    }
+}
 
 struct PathInfo<'a> {
     base_path: &'a Path,
@@ -55,32 +139,80 @@ impl<'a> PathInfo<'a> {
 }
 
 pub struct DataBase {
-    schema_file: File,
+    path: PathBuf,
     schema: DBSchema,
     tables: HashMap<String, File>,
-    queries: HashMap<u64, Vec<RawRow>>,
+    // Running queries, locked on their own so sessions can read side by side
+    queries: Mutex<HashMap<u64, Query>>,
+    allocators: Mutex<HashMap<String, TableAllocator>>,
+    // Every block read or written goes through here
+    cache: Mutex<Cache>,
+    // Locked after the cache whenever both are needed
+    wal: Mutex<WriteAheadLog>,
+    // The open transaction of each session that has one
+    transactions: Mutex<HashMap<SessionId, Transaction>>,
+    next_session: AtomicU64,
+    locks: Arc<LockManager>,
+    // Held while a statement's rows are written to their blocks and logged,
+    // so each statement is a commit of its own in the log
+    applying: Mutex<()>,
+    // What's kept in the text file, locked before the heaps
+    texts: Mutex<Texts>,
+    // Which pages of each table's heap are free, locked before the cache
+    heaps: Mutex<Heaps>,
+    // What the rows committed since each open snapshot used to be
+    versions: Arc<VersionStore>,
+    // Every index, by name. Their files are kept alongside the tables'.
+    indexes: IndexCatalog,
 }
 
 impl DataBase {
     pub fn create(path: &Path, schema: DBSchema) -> Result<Self, Error> {
         if let Some(path_info) = PathInfo::from_path(path) {
-            let mut schema_file = File::create(path)?;
-            schema_file.write_all(&serde_json::to_vec(&schema)?)?;
-
             let mut tables = HashMap::new();
 
             for table_name in schema.keys() {
+                validate_table_name(table_name)?;
                 let table_path = path_info.generate_table_path(table_name);
-                let table_file = File::create(table_path)?;
+                let table_file = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(table_path)?;
                 tables.insert(table_name.to_owned(), table_file);
             }
+            write_schema(path, &schema)?;
+            write_catalog(path, &IndexCatalog::new())?;
+            // Whatever a database that used to be here logged is no use now
+            let mut wal = WriteAheadLog::open(path)?;
+            wal.truncate()?;
 
-            Ok(DataBase {
-                schema_file,
+            let mut db = DataBase {
+                path: path.to_owned(),
                 schema,
                 tables,
-                queries: HashMap::new(),
-            })
+                queries: Mutex::new(HashMap::new()),
+                allocators: Mutex::new(HashMap::new()),
+                cache: Mutex::new(Cache::new(CACHE_BLOCKS)),
+                wal: Mutex::new(wal),
+                transactions: Mutex::new(HashMap::new()),
+                next_session: AtomicU64::new(DEFAULT_SESSION),
+                locks: Arc::new(LockManager::default()),
+                applying: Mutex::new(()),
+                texts: Mutex::new(Texts::default()),
+                heaps: Mutex::new(Heaps::default()),
+                versions: Arc::new(VersionStore::default()),
+                indexes: IndexCatalog::new(),
+            };
+            for table_name in db.schema.keys().cloned().collect::<Vec<_>>() {
+                db.write_index(&primary_file_name(&table_name), IndexKind::BTree, &[])?;
+            }
+            db.create_overflow()?;
+            for table_name in db.schema.keys().cloned().collect::<Vec<_>>() {
+                db.create_heap(&table_name)?;
+            }
+            Ok(db)
         } else {
             Err(Error::PathError(format!(
                 "Failed to parse PathInfo from {}",
@@ -91,12 +223,15 @@ impl DataBase {
 
     pub fn open(path: &Path) -> Result<Self, Error> {
         if let Some(path_info) = PathInfo::from_path(path) {
+            let migrated = recover_migrations(path, &path_info)?;
+
             let mut schema_file = File::options().read(true).write(true).open(path)?;
 
             let mut raw_schema = String::new();
             schema_file.read_to_string(&mut raw_schema)?;
 
             let schema: DBSchema = from_str(&raw_schema)?;
+            remove_scratch_files(&path_info, &schema)?;
 
             let mut tables = HashMap::new();
 
@@ -105,13 +240,74 @@ impl DataBase {
                 let table_file = File::options().read(true).write(true).open(table_path)?;
                 tables.insert(table_name.to_owned(), table_file);
             }
+            let indexes = read_catalog(path)?;
+            let mut index_files: Vec<String> = indexes
+                .iter()
+                .map(|(index_name, info)| info.file_name(index_name))
+                .collect();
+            // A primary index that's missing is built again below, and a
+            // missing heap started off empty
+            let mut missing = Vec::new();
+            for table_name in schema.keys() {
+                let file_name = primary_file_name(table_name);
+                if path_info.generate_table_path(&file_name).exists() {
+                    index_files.push(file_name);
+                } else {
+                    missing.push(table_name.to_owned());
+                }
+                let file_name = heap_file_name(table_name);
+                if path_info.generate_table_path(&file_name).exists() {
+                    index_files.push(file_name);
+                }
+            }
+            // So is overflowing text, if there's anywhere for it yet
+            for file_name in [TEXT_FILE, TEXT_HASHES_FILE] {
+                if path_info
+                    .generate_table_path(&file_name.to_owned())
+                    .exists()
+                {
+                    index_files.push(file_name.to_owned());
+                }
+            }
+            for file_name in index_files {
+                let index_path = path_info.generate_table_path(&file_name);
+                let index_file = File::options().read(true).write(true).open(index_path)?;
+                tables.insert(file_name, index_file);
+            }
+            let wal = recover_log(path, &tables)?;
 
-            Ok(DataBase {
-                schema_file,
+            let mut db = DataBase {
+                path: path.to_owned(),
                 schema,
                 tables,
-                queries: HashMap::new(),
-            })
+                queries: Mutex::new(HashMap::new()),
+                allocators: Mutex::new(HashMap::new()),
+                cache: Mutex::new(Cache::new(CACHE_BLOCKS)),
+                wal: Mutex::new(wal),
+                transactions: Mutex::new(HashMap::new()),
+                next_session: AtomicU64::new(DEFAULT_SESSION),
+                locks: Arc::new(LockManager::default()),
+                applying: Mutex::new(()),
+                texts: Mutex::new(Texts::default()),
+                heaps: Mutex::new(Heaps::default()),
+                versions: Arc::new(VersionStore::default()),
+                indexes,
+            };
+            for table_name in missing {
+                if !migrated
+                    .as_ref()
+                    .is_some_and(|migration| migration.moved(&table_name))
+                {
+                    db.build_primary_index(&table_name, 0)?;
+                }
+            }
+            db.open_overflow()?;
+            db.open_heaps()?;
+            // Text is read from the overflow file to index it
+            if let Some(migration) = migrated {
+                db.rebuild_indexes(&migration)?;
+            }
+            Ok(db)
         } else {
             Err(Error::PathError(format!(
                 "Failed to parse PathInfo from {}",
@@ -120,19 +316,137 @@ impl DataBase {
         }
     }
 
-    pub fn store(&self, table_name: &str, data: Vec<Block>) -> Result<(), Error> {
-        match self.tables.get(table_name) {
-            Some(table) => {
-                for (index, datum) in data.iter().enumerate() {
-                    table.write_at(datum, (index * BLOCK_SIZE) as u64)?;
-                }
+    /// Adds a new, empty table to the database.
+    ///
+    /// The data file, primary index and heap are created before the schema
+    /// that names the table is swapped in, so a crash part way through leaves
+    /// at worst empty files nothing uses.
+    pub fn create_table(
+        &mut self,
+        table_name: &str,
+        table_info: TableInfo,
+        if_not_exists: bool,
+    ) -> Result<(), Error> {
+        if self.schema.contains_key(table_name) {
+            return if if_not_exists {
                 Ok(())
+            } else {
+                Err(Error::SchemaError(format!(
+                    "Table {} already exists",
+                    table_name
+                )))
+            };
+        }
+        validate_table_name(table_name)?;
+        validate_table_info(&table_info)?;
+
+        let table_path = self
+            .path_info()?
+            .generate_table_path(&table_name.to_owned());
+        let table_file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(table_path)?;
+        table_file.sync_all()?;
+        self.write_index(&primary_file_name(table_name), IndexKind::BTree, &[])?;
+        self.create_heap(table_name)?;
+
+        let mut schema = self.schema.clone();
+        schema.insert(table_name.to_owned(), table_info);
+        write_schema(&self.path, &schema)?;
+
+        self.schema = schema;
+        self.tables.insert(table_name.to_owned(), table_file);
+        Ok(())
+    }
+
+    /// Removes a table, its indexes and its data.
+    ///
+    /// The schema stops naming the table before its data file is removed, so
+    /// a crash part way through leaves at worst a data file nothing uses.
+    pub fn drop_table(&mut self, table_name: &str, if_exists: bool) -> Result<(), Error> {
+        if !self.schema.contains_key(table_name) {
+            return if if_exists {
+                Ok(())
+            } else {
+                Err(Error::SchemaError(format!(
+                    "Table {} does not exist",
+                    table_name
+                )))
+            };
+        }
+
+        let index_names: Vec<String> = self
+            .table_indexes(table_name)
+            .map(|(index_name, _)| index_name.to_owned())
+            .collect();
+        for index_name in index_names {
+            self.drop_index(&index_name, false)?;
+        }
+
+        self.end_queries(table_name);
+        let mut texts = TextCounts::default();
+        self.count_table_texts(table_name, -1, &mut texts)?;
+        // The log mustn't hold blocks for a table file that's gone
+        self.checkpoint()?;
+        let mut schema = self.schema.clone();
+        schema.remove(table_name);
+        write_schema(&self.path, &schema)?;
+
+        self.schema = schema;
+        self.tables.remove(table_name);
+        self.allocators().remove(table_name);
+        self.cache().forget(table_name);
+        self.versions.forget(table_name);
+        let table_path = self
+            .path_info()?
+            .generate_table_path(&table_name.to_owned());
+        std::fs::remove_file(table_path)?;
+        self.remove_index(&primary_file_name(table_name))?;
+        self.remove_heap(table_name)?;
+        self.count_texts(self.versions.next_commit(), texts);
+        Ok(())
+    }
+
+    /// Forgets every running query that reads from a table about to change,
+    /// as its rows won't be where it left off. Asking one for more rows is an
+    /// error.
+    fn end_queries(&self, table_name: &str) {
+        self.queries()
+            .retain(|_, query| !query.tables.iter().any(|table| table == table_name));
+    }
+
+    fn path_info(&self) -> Result<PathInfo<'_>, Error> {
+        PathInfo::from_path(&self.path).ok_or_else(|| {
+            Error::PathError(format!(
+                "Failed to parse PathInfo from {}",
+                &self.path.display()
+            ))
+        })
+    }
+
+    /// Writes whole blocks into a table, starting from its first, and moves
+    /// the rows they replace out of the table's indexes.
+    pub fn store(&self, table_name: &str, data: Vec<Block>) -> Result<(), Error> {
+        let columns = self.table_info(table_name)?.len();
+        let rows_per_block = BLOCK_SIZE / (columns * COLUMN_WIDTH);
+        let _applying = self.applying();
+        for (offset, block) in data.iter().enumerate() {
+            let offset = offset as u64;
+            let old = self.load_block_at(table_name, offset)?;
+            for slot in 0..rows_per_block {
+                self.reindex_row(
+                    table_name,
+                    (offset, slot as u64),
+                    &read_row(&old, slot, columns),
+                    &read_row(block, slot, columns),
+                )?;
             }
-            None => Err(Error::SchemaError(format!(
-                "Table {} does not exist",
-                table_name
-            ))),
+            self.store_block_at(table_name, offset, block)?;
         }
+        self.flush()
     }
 
     pub fn store_block_at(
@@ -141,10 +455,543 @@ impl DataBase {
         offset: u64,
         block: &Block,
     ) -> Result<(), Error> {
+        let mut cache = self.cache();
+        let frame = cache.pin(&self.tables, table_name, offset)?;
+        cache.block_mut(frame).copy_from_slice(block);
+        cache.unpin(frame, true);
+        Ok(())
+    }
+
+    pub fn load(&self, table_name: &str) -> Result<(TableInfoMap, Vec<Block>), Error> {
+        let table_map = map_table_info(self.table_info(table_name)?);
+        let data = (0..self.block_count(table_name)?)
+            .map(|offset| self.load_block_at(table_name, offset))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((table_map, data))
+    }
+
+    pub fn load_block_at(&self, table_name: &str, offset: u64) -> Result<Block, Error> {
+        let mut cache = self.cache();
+        let frame = cache.pin(&self.tables, table_name, offset)?;
+        let block = *cache.block(frame);
+        cache.unpin(frame, false);
+        Ok(block)
+    }
+
+    /// Makes every block changed since the last flush durable, as one commit
+    /// to the write-ahead log. The blocks themselves reach their tables when
+    /// they're evicted, or at the next checkpoint.
+    pub fn flush(&self) -> Result<(), Error> {
+        let mut cache = self.cache();
+        let mut wal = self.wal();
+        cache.log_changes(&mut wal)?;
+        if wal.size() >= CHECKPOINT_SIZE {
+            self.write_checkpoint(&mut cache, &mut wal)?;
+        }
+        Ok(())
+    }
+
+    /// Writes every changed block to its table, after which the log can be
+    /// emptied.
+    pub fn checkpoint(&self) -> Result<(), Error> {
+        let mut cache = self.cache();
+        let mut wal = self.wal();
+        cache.log_changes(&mut wal)?;
+        self.write_checkpoint(&mut cache, &mut wal)
+    }
+
+    fn write_checkpoint(&self, cache: &mut Cache, wal: &mut WriteAheadLog) -> Result<(), Error> {
+        cache.flush(&self.tables)?;
+        for table in self.tables.values() {
+            table.sync_data()?;
+        }
+        wal.truncate()
+    }
+
+    // Neither lock is held across anything that could leave what it guards
+    // half-updated, so a panic elsewhere doesn't make either unusable
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn wal(&self) -> MutexGuard<'_, WriteAheadLog> {
+        self.wal
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn queries(&self) -> MutexGuard<'_, HashMap<u64, Query>> {
+        self.queries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn allocators(&self) -> MutexGuard<'_, HashMap<String, TableAllocator>> {
+        self.allocators
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn applying(&self) -> MutexGuard<'_, ()> {
+        self.applying
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn texts(&self) -> MutexGuard<'_, Texts> {
+        self.texts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn heaps(&self) -> MutexGuard<'_, Heaps> {
+        self.heaps
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn execute(&mut self, action: Action) -> Reaction {
+        self.execute_in(DEFAULT_SESSION, action)
+    }
+
+    /// Carries out an action for a session, inside its transaction if it has
+    /// one open.
+    pub fn execute_in(&mut self, session: SessionId, action: Action) -> Reaction {
+        let reaction = match action {
+            Action::GetAll(..)
+            | Action::GetFiltered(..)
+            | Action::GetMore(..)
+            | Action::Insert(..)
+            | Action::Update(..)
+            | Action::Delete(..)
+            | Action::Begin
+            | Action::Commit
+            | Action::Rollback => return self.execute_shared(session, action),
+            Action::CreateTable(table_name, table_info, if_not_exists) => {
+                match self
+                    .check_table_change(session, &table_name)
+                    .and_then(|_| self.create_table(&table_name, table_info, if_not_exists))
+                {
+                    Ok(()) => Reaction::Done,
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::DropTable(table_name, if_exists) => {
+                match self
+                    .check_table_change(session, &table_name)
+                    .and_then(|_| self.drop_table(&table_name, if_exists))
+                {
+                    Ok(()) => Reaction::Done,
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::AlterTable(table_name, alteration) => {
+                match self
+                    .check_table_change(session, &table_name)
+                    .and_then(|_| self.alter_table(&table_name, alteration))
+                {
+                    Ok(()) => Reaction::Done,
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::CreateIndex(index_name, table_name, column, kind, if_not_exists) => {
+                match self.check_table_change(session, &table_name).and_then(|_| {
+                    self.create_index(&index_name, &table_name, &column, kind, if_not_exists)
+                }) {
+                    Ok(()) => Reaction::Done,
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::DropIndex(index_name, if_exists) => {
+                // An index that doesn't exist has no table for a transaction
+                // to have touched
+                let table_name = self
+                    .indexes
+                    .get(&index_name)
+                    .map(|info| info.table_name.to_owned())
+                    .unwrap_or_default();
+                match self
+                    .check_table_change(session, &table_name)
+                    .and_then(|_| self.drop_index(&index_name, if_exists))
+                {
+                    Ok(()) => Reaction::Done,
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+        };
+        self.end_statement(session);
+        reaction
+    }
+
+    /// Carries out anything but a change to a table, which needs no more than
+    /// shared access, so any number of sessions can be running these at once.
+    /// Sessions keep out of each other's way by locking what they touch.
+    pub fn execute_shared(&self, session: SessionId, action: Action) -> Reaction {
+        let reaction = match action {
+            Action::GetAll(query, projections, grouping, order_by, limit) => {
+                match self.begin_query(
+                    session,
+                    query,
+                    Predicate::Filter(FilterType::All),
+                    projections,
+                    grouping,
+                    order_by,
+                    limit,
+                ) {
+                    Ok((qid, schema)) => Reaction::QueryStart { schema, qid },
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::GetMore(qid, batch_size) => match self.next_batch(qid, batch_size) {
+                Ok(Some(data)) => Reaction::Data(data),
+                Ok(None) => Reaction::Empty,
+                Err(err) => Reaction::Error(err),
+            },
+            Action::GetFiltered(query, predicate, projections, grouping, order_by, limit) => {
+                match self.begin_query(
+                    session,
+                    query,
+                    predicate,
+                    projections,
+                    grouping,
+                    order_by,
+                    limit,
+                ) {
+                    Ok((qid, schema)) => Reaction::QueryStart { schema, qid },
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::Update(table_name, assignments, predicate) => {
+                match self.update(session, &table_name, assignments, predicate) {
+                    Ok(count) => Reaction::Affected(count),
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::Delete(table_name, predicate) => {
+                match self.delete(session, &table_name, predicate) {
+                    Ok(count) => Reaction::Affected(count),
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::Insert(table_name, columns, rows) => {
+                match self.insert(session, &table_name, columns, rows) {
+                    Ok(ids) => Reaction::Inserted {
+                        count: ids.len() as u64,
+                        ids,
+                    },
+                    Err(err) => Reaction::Error(err),
+                }
+            }
+            Action::Begin => match self.begin(session) {
+                Ok(()) => Reaction::Done,
+                Err(err) => Reaction::Error(err),
+            },
+            Action::Commit => match self.commit(session) {
+                Ok(()) => Reaction::Done,
+                Err(err) => Reaction::Error(err),
+            },
+            Action::Rollback => match self.rollback(session) {
+                Ok(()) => Reaction::Done,
+                Err(err) => Reaction::Error(err),
+            },
+            _ => Reaction::Error(Error::QueryError(
+                "Tables can't be changed alongside other sessions".into(),
+            )),
+        };
+        self.end_statement(session);
+        reaction
+    }
+
+    /// Writes new rows into `table_name`, giving back the ids assigned to them.
+    ///
+    /// `columns` names the column each value goes to, or every column but the
+    /// id when empty. Columns left out are zeroed.
+    pub fn insert(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        columns: Vec<String>,
+        rows: Vec<Vec<DataType>>,
+    ) -> Result<Vec<u64>, Error> {
+        self.statement(session, || {
+            let table_info = self.table_info(table_name)?;
+            let targets = insert_targets(table_info, columns)?;
+            // Every row's checked before any value's written to the heap
+            let mut checked = Vec::new();
+            for row in rows {
+                if row.len() != targets.len() {
+                    return Err(Error::SchemaError(format!(
+                        "Expected {} values per row, found {}",
+                        targets.len(),
+                        row.len()
+                    )));
+                }
+                let mut values = Vec::new();
+                for (target, value) in targets.iter().zip(row) {
+                    let (column_name, column_type) = &table_info[*target];
+                    let value = stored_value(value, column_type);
+                    if value.column_type() != *column_type {
+                        return Err(Error::SchemaError(format!(
+                            "Column {} holds {:?}, found {:?}",
+                            column_name, column_type, value
+                        )));
+                    }
+                    values.push((*target, value));
+                }
+                checked.push(values);
+            }
+
+            self.writing_objects(session, table_name, |overflow| {
+                let mut raw_rows = Vec::new();
+                for values in checked {
+                    let mut raw_row: RawRow = vec![0u64; table_info.len()];
+                    for (target, value) in values {
+                        raw_row[target] = encode_field(&value, overflow)?;
+                    }
+                    raw_rows.push(raw_row);
+                }
+
+                self.lock_table(session, table_name, LockMode::IntentionExclusive)?;
+                let mut placed = Vec::new();
+                {
+                    let mut allocators = self.allocators();
+                    let allocator = self.allocator(&mut allocators, table_name)?;
+                    for mut raw_row in raw_rows {
+                        raw_row[0] = allocator.next_id()?;
+                        placed.push((allocator.take_slot(), raw_row));
+                    }
+                }
+
+                let ids = placed.iter().map(|(_, raw_row)| raw_row[0]).collect();
+                if let Some(transaction) = self.transactions().get_mut(&session) {
+                    transaction.taken.extend(
+                        placed
+                            .iter()
+                            .map(|(location, _)| (table_name.to_owned(), *location)),
+                    );
+                }
+                // A slot freed by a delete stays locked until the delete's done
+                for (location, _) in &placed {
+                    self.lock_row(session, table_name, *location, LockMode::Exclusive)?;
+                }
+                self.write_rows(session, table_name, placed)?;
+                Ok(ids)
+            })
+        })
+    }
+
+    /// Applies `assignments` to every row of `table_name` matching `predicate`,
+    /// giving back how many rows were changed.
+    pub fn update(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        assignments: Vec<(String, Expression)>,
+        predicate: Predicate,
+    ) -> Result<u64, Error> {
+        self.statement(session, || {
+            let table_info = self.table_info(table_name)?;
+            let table_schema = map_table_info(table_info);
+
+            let mut targets = Vec::new();
+            for (column, expression) in assignments {
+                let (column_type, offset) = match table_schema.get(&column) {
+                    Some((_, 0)) => {
+                        return Err(Error::SchemaError(format!(
+                            "Column {} is the row id, which can't be changed",
+                            column
+                        )))
+                    }
+                    Some(target) => target,
+                    None => {
+                        return Err(Error::SchemaError(format!(
+                            "Column {} does not exist",
+                            column
+                        )))
+                    }
+                };
+                let output_type = match expression.output_type(&table_schema)? {
+                    ColumnType::Text if *column_type == ColumnType::Clob => ColumnType::Clob,
+                    output_type => output_type,
+                };
+                if output_type != *column_type {
+                    return Err(Error::SchemaError(format!(
+                        "Column {} holds {:?}, but would be set to {:?}",
+                        column, column_type, output_type
+                    )));
+                }
+                targets.push((*offset as usize, column_type, expression));
+            }
+
+            self.writing_objects(session, table_name, |overflow| {
+                self.rewrite_rows(session, table_name, &predicate, |_, raw_row| {
+                    // Every assignment sees the row as it was before the update
+                    let values = targets
+                        .iter()
+                        .map(|(offset, column_type, expression)| {
+                            let value = expression.evaluate(raw_row, &table_schema, self)?;
+                            let value = stored_value(value, column_type);
+                            Ok((*offset, encode_field(&value, overflow)?))
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    for (offset, value) in values {
+                        raw_row[offset] = value;
+                    }
+                    Ok(())
+                })
+            })
+        })
+    }
+
+    /// Frees every row of `table_name` matching `predicate` by zeroing it out,
+    /// giving back how many rows were deleted.
+    pub fn delete(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        predicate: Predicate,
+    ) -> Result<u64, Error> {
+        self.statement(session, || {
+            let mut freed = Vec::new();
+            let affected =
+                self.rewrite_rows(session, table_name, &predicate, |location, raw_row| {
+                    raw_row.iter_mut().for_each(|field| *field = 0);
+                    freed.push(location);
+                    Ok(())
+                })?;
+
+            // Someone else could take a freed slot, so it stays taken until the
+            // delete is committed
+            let freed = match self.transactions().get_mut(&session) {
+                Some(transaction) => {
+                    transaction.freed.extend(
+                        freed
+                            .into_iter()
+                            .map(|location| (table_name.to_owned(), location)),
+                    );
+                    return Ok(affected);
+                }
+                None => freed,
+            };
+            // A table without an allocator yet will find the holes when it scans
+            if let Some(allocator) = self.allocators().get_mut(table_name) {
+                for location in freed {
+                    allocator.free_slot(location);
+                }
+            }
+            Ok(affected)
+        })
+    }
+
+    /// Runs `rewrite` over every live row matching `predicate` as the session
+    /// sees it, reading only the rows an index points to if one can, then
+    /// writes back only the rows that were touched. Nothing is
+    /// written if `rewrite` fails. Each row is locked before it's rewritten.
+    fn rewrite_rows<F>(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        predicate: &Predicate,
+        mut rewrite: F,
+    ) -> Result<u64, Error>
+    where
+        F: FnMut(RowLocation, &mut RawRow) -> Result<(), Error>,
+    {
+        let table_info = self.table_info(table_name)?;
+        let table_schema = map_table_info(table_info);
+        let columns = table_info.len();
+        let rows_per_block = BLOCK_SIZE / (columns * COLUMN_WIDTH);
+
+        let matches = |raw_row: &RawRow| -> Result<bool, Error> {
+            Ok(raw_row[0] != 0 && predicate.evaluate(raw_row, &table_schema, self)?)
+        };
+
+        self.lock_table(session, table_name, LockMode::IntentionExclusive)?;
+        // An index can point out the only rows worth looking at
+        let locations =
+            self.index_candidates(session, table_name, &table_schema, predicate, None)?;
+        let offsets: Vec<u64> = match &locations {
+            Some(locations) => {
+                let mut offsets: Vec<u64> = locations.iter().map(|(offset, _)| *offset).collect();
+                offsets.dedup();
+                offsets
+            }
+            None => (0..self.visible_block_count(session, table_name)?).collect(),
+        };
+        let mut rewritten = Vec::new();
+        for offset in offsets {
+            let block = self.read_block(session, table_name, offset)?;
+            let mut slots = Vec::new();
+            for slot in 0..rows_per_block {
+                let wanted = locations
+                    .as_ref()
+                    .is_none_or(|locations| locations.contains(&(offset, slot as u64)));
+                if wanted && matches(&read_row(&block, slot, columns))? {
+                    slots.push(slot);
+                }
+            }
+            if slots.is_empty() {
+                continue;
+            }
+            for slot in &slots {
+                self.lock_row(
+                    session,
+                    table_name,
+                    (offset, *slot as u64),
+                    LockMode::Exclusive,
+                )?;
+            }
+            // The rows may have changed while waiting for their locks, but
+            // they can't now
+            let block = self.read_block(session, table_name, offset)?;
+            for slot in slots {
+                let mut raw_row = read_row(&block, slot, columns);
+                if !matches(&raw_row)? {
+                    continue;
+                }
+                let location = (offset, slot as u64);
+                rewrite(location, &mut raw_row)?;
+                rewritten.push((location, raw_row));
+            }
+        }
+
+        let affected = rewritten.len() as u64;
+        self.write_rows(session, table_name, rewritten)?;
+        Ok(affected)
+    }
+
+    fn table_info(&self, table_name: &str) -> Result<&TableInfo, Error> {
+        self.schema
+            .get(table_name)
+            .ok_or_else(|| Error::SchemaError(format!("Table {} does not exist", table_name)))
+    }
+
+    /// Gets the allocator for a table, scanning the table to build it on first use.
+    fn allocator<'a>(
+        &self,
+        allocators: &'a mut HashMap<String, TableAllocator>,
+        table_name: &str,
+    ) -> Result<&'a mut TableAllocator, Error> {
+        if !allocators.contains_key(table_name) {
+            let mut allocator = TableAllocator::new(self.table_info(table_name)?.len());
+            for offset in 0..self.block_count(table_name)? {
+                allocator.scan_block(offset, &self.load_block_at(table_name, offset)?);
+            }
+            allocator.skip_ids_to(self.highest_id(table_name)?);
+            allocators.insert(table_name.to_owned(), allocator);
+        }
+        Ok(allocators
+            .get_mut(table_name)
+            .expect("allocator was just inserted"))
+    }
+
+    fn block_count(&self, table_name: &str) -> Result<u64, Error> {
         match self.tables.get(table_name) {
             Some(table) => {
-                table.write_at(block, offset * BLOCK_SIZE as u64)?;
-                Ok(())
+                let written = table.metadata()?.len().div_ceil(BLOCK_SIZE as u64);
+                Ok(written.max(self.cache().block_count(table_name)))
             }
             None => Err(Error::SchemaError(format!(
                 "Table {} does not exist",
@@ -153,188 +1000,436 @@ impl DataBase {
         }
     }
 
-    pub fn load(&self, table_name: &str) -> Result<(TableInfoMap, Vec<Block>), Error> {
-        match (self.tables.get(table_name), self.schema.get(table_name)) {
-            (Some(table), Some(table_info)) => {
-                let table_map = map_table_info(table_info);
-                let mut data = Vec::new();
-
-                let mut buf = [0u8; BLOCK_SIZE];
-                let mut offset = 0u64;
-                // Behold, I learned how to Do While in Rust, and it's... interesting
-                while {
-                    // Read a block from the file
-                    let bytes_read = table.read_at(&mut buf, offset * BLOCK_SIZE as u64)?;
-                    bytes_read > 0
-                } {
-                    // push it to our Vec
-                    data.push(buf);
-                    // Set state for next iteration
-                    offset += 1;
-                    buf = [0u8; BLOCK_SIZE];
+    #[allow(clippy::too_many_arguments)]
+    fn begin_query(
+        &self,
+        session: SessionId,
+        source: Source,
+        predicate: Predicate,
+        projections: Vec<Projection>,
+        grouping: Grouping,
+        order_by: Vec<(Expression, Direction)>,
+        limit: Limit,
+    ) -> Result<(u64, TableInfoMap), Error> {
+        // Reading a transaction's rows locks them, which can deadlock
+        self.statement(session, || {
+            // Outside a transaction, the query reads everything as it was
+            // when it started, however long it takes
+            let snapshot = (!self.in_transaction(session)).then(|| self.versions.snapshot());
+            let tables = source.table_names();
+            let (table_schema, mut rows) =
+                self.scan_source(session, source, predicate, snapshot.as_ref())?;
+            let (mut projections, mut order_by) = (projections, order_by);
+            let aggregation =
+                plan_aggregation(&mut projections, grouping, &mut order_by, &table_schema)?;
+            // Projection and sorting read from the aggregated rows, if there are any
+            let source_schema = match &aggregation {
+                Some(aggregation) => aggregation.schema().to_owned(),
+                None => table_schema.to_owned(),
+            };
+            let (output_schema, expressions) = plan_projection(projections, &source_schema)?;
+            let (sort_expressions, sort_keys) =
+                plan_sort(order_by, &source_schema, &output_schema, &expressions)?;
+            let sort = |rows: &mut dyn Iterator<Item = Result<RawRow, Error>>,
+                        overflow: &dyn Overflow| {
+                let mut sorter = Sorter::new(sort_keys.to_owned(), &self.path, SORT_BUFFER_SIZE);
+                for raw_row in rows {
+                    let raw_row = raw_row?;
+                    let key = sort_expressions
+                        .iter()
+                        .map(|expression| expression.evaluate(&raw_row, &source_schema, overflow))
+                        .collect::<Result<Vec<DataType>, Error>>()?;
+                    sorter.push(
+                        key,
+                        project_row(&raw_row, &expressions, &source_schema, overflow)?,
+                    )?;
+                }
+                sorter.finish().map(QueryRows::Sorted)
+            };
+
+            let rows = match aggregation {
+                // The groups are all in memory already, and their long values
+                // with them, so they're projected right away
+                Some(aggregation) => {
+                    let groups = aggregation.run(rows.read(self), &table_schema, self)?;
+                    let values = aggregation.values();
+                    if sort_keys.is_empty() {
+                        let projected = groups
+                            .iter()
+                            .map(|raw_row| {
+                                project_row(raw_row, &expressions, &source_schema, values)
+                            })
+                            .collect::<Result<Vec<_>, Error>>()?;
+                        QueryRows::Grouped(projected.into_iter())
+                    } else {
+                        sort(&mut groups.into_iter().map(Ok), values)?
+                    }
+                }
+                None if sort_keys.is_empty() => {
+                    QueryRows::Projected(Box::new(rows), expressions, source_schema)
+                }
+                None => sort(&mut rows.read(self), self)?,
+            };
+
+            let mut query = Query {
+                session,
+                tables,
+                rows,
+                remaining: None,
+            };
+            for _ in 0..limit.offset {
+                match query.next_row(self) {
+                    Some(Err(err)) => return Err(err),
+                    Some(Ok(_)) => (),
+                    None => break,
                 }
-                Ok((table_map, data))
             }
-            // TODO: Maybe come up with better error for missing data file
-            (None, Some(_)) => Err(Error::SchemaError(format!(
-                "Table {} is missing its data file",
-                table_name
-            ))),
-            (Some(_), None) => Err(Error::SchemaError(format!(
-                "Table {} has data file, but is missing in schema",
-                table_name
-            ))),
-            (None, None) => Err(Error::SchemaError(format!(
-                "Table {} does not exist",
-                table_name
-            ))),
+            query.remaining = limit.count;
+
+            let mut queries = self.queries();
+            let mut qid = rand::random();
+            // Make sure that qid isn't in use...
+            while queries.contains_key(&qid) {
+                qid = rand::random();
+            }
+            queries.insert(qid, query);
+            Ok((qid, output_schema))
+        })
+    }
+
+    /// Takes up to `batch_size` more rows from a running query, or None once
+    /// it has run out, at which point the query is forgotten.
+    fn next_batch(&self, qid: u64, batch_size: u64) -> Result<Option<Vec<Row>>, Error> {
+        if batch_size == 0 {
+            return Err(Error::QueryError("Batch size must be at least 1".into()));
+        }
+        // The query reads from the database as it goes, so it's taken out of
+        // the map while it does, leaving other queries free to carry on
+        let mut query = self
+            .queries()
+            .remove(&qid)
+            .ok_or_else(|| Error::QueryError(format!("Query {} does not exist", qid)))?;
+        // Reading a transaction's rows locks them, which can deadlock
+        let batch = self.statement(query.session, || {
+            std::iter::from_fn(|| query.next_row(self))
+                .take(batch_size as usize)
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        self.queries().insert(qid, query);
+        Ok(Some(batch))
+    }
+}
+
+impl Drop for DataBase {
+    fn drop(&mut self) {
+        // Nowhere to report a failure to, and the log has everything that
+        // matters anyway. This just saves replaying it next time.
+        let _ = self.checkpoint();
+    }
+}
+
+fn write_schema(path: &Path, schema: &DBSchema) -> Result<(), Error> {
+    write_atomically(path, &serde_json::to_vec(schema)?)
+}
+
+/// Replaces the file at `path` in one step: the contents are written and
+/// synced to a temporary file that is then renamed over the old one, so a
+/// crash leaves either the old file or the new one, never a mix.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let mut temp_file = File::create(&temp_path)?;
+    temp_file.write_all(contents)?;
+    temp_file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Makes renames and removals in the directory holding `path` durable.
+fn sync_parent_dir(path: &Path) -> Result<(), Error> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+fn validate_table_name(table_name: &str) -> Result<(), Error> {
+    validate_name("Table", table_name)
+}
+
+/// Table and index names end up in file names, so keep them to plain
+/// identifiers.
+fn validate_name(kind: &str, name: &str) -> Result<(), Error> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(Error::SchemaError(format!(
+            "{} name {:?} may only use letters, digits and underscores",
+            kind, name
+        )));
+    }
+    Ok(())
+}
+
+fn validate_table_info(table_info: &TableInfo) -> Result<(), Error> {
+    match table_info.first() {
+        Some((_, ColumnType::Integer)) => (),
+        Some((name, column_type)) => {
+            return Err(Error::SchemaError(format!(
+                "The first column holds the row id and must be an Integer, {} is {:?}",
+                name, column_type
+            )))
+        }
+        None => return Err(Error::SchemaError("Tables need at least one column".into())),
+    }
+    if table_info.len() * COLUMN_WIDTH > BLOCK_SIZE {
+        return Err(Error::SchemaError(format!(
+            "Rows of {} columns won't fit in a block",
+            table_info.len()
+        )));
+    }
+    for (index, (name, _)) in table_info.iter().enumerate() {
+        if table_info[..index].iter().any(|(other, _)| other == name) {
+            return Err(Error::SchemaError(format!(
+                "Column {} is defined more than once",
+                name
+            )));
         }
     }
+    Ok(())
+}
 
-    pub fn load_block_at(&self, table_name: &str, offset: u64) -> Result<Block, Error> {
-        match self.tables.get(table_name) {
-            Some(table) => {
-                let mut buf = [0u8; BLOCK_SIZE];
-                table.read_at(&mut buf, offset * BLOCK_SIZE as u64)?;
-                Ok(buf)
+/// Text going into a Clob column is kept as a Clob.
+fn stored_value(value: DataType, column_type: &ColumnType) -> DataType {
+    match (value, column_type) {
+        (DataType::Text(text), ColumnType::Clob) => DataType::Clob(text),
+        (value, _) => value,
+    }
+}
+
+/// Maps the column list of an INSERT to offsets in the table's rows.
+fn insert_targets(table_info: &TableInfo, columns: Vec<String>) -> Result<Vec<usize>, Error> {
+    if columns.is_empty() {
+        return Ok((1..table_info.len()).collect());
+    }
+
+    let mut targets = Vec::new();
+    for column in columns {
+        match table_info.iter().position(|(name, _)| *name == column) {
+            Some(0) => {
+                return Err(Error::SchemaError(format!(
+                    "Column {} is the row id, which is assigned automatically",
+                    column
+                )))
+            }
+            Some(target) if targets.contains(&target) => {
+                return Err(Error::SchemaError(format!(
+                    "Column {} is given more than once",
+                    column
+                )))
+            }
+            Some(target) => targets.push(target),
+            None => {
+                return Err(Error::SchemaError(format!(
+                    "Column {} does not exist",
+                    column
+                )))
             }
-            None => Err(Error::SchemaError(format!(
-                "Table {} does not exist",
-                table_name
-            ))),
         }
     }
+    Ok(targets)
+}
 
-    pub fn execute(&mut self, action: Action) -> Reaction {
-        match action {
-            Action::GetAll(query) => match self.begin_query(query, vec![FilterType::All]) {
-                Ok((qid, schema)) => Reaction::QueryStart { schema, qid },
-                Err(err) => Reaction::Error(err),
-            },
-            Action::GetMore(qid) => {
-                if let Some(data) = self.queries.remove(&qid) {
-                    Reaction::Data(data)
-                } else {
-                    Reaction::Empty
+impl Predicate {
+    pub fn evaluate(
+        &self,
+        raw_row: &RawRow,
+        table_schema: &TableInfoMap,
+        overflow: &dyn Overflow,
+    ) -> Result<bool, Error> {
+        match self {
+            Predicate::Filter(filter) => apply_filter(raw_row, filter, table_schema, overflow),
+            Predicate::And(predicates) => {
+                for predicate in predicates {
+                    if !predicate.evaluate(raw_row, table_schema, overflow)? {
+                        return Ok(false);
+                    }
                 }
+                Ok(true)
+            }
+            Predicate::Or(predicates) => {
+                for predicate in predicates {
+                    if predicate.evaluate(raw_row, table_schema, overflow)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            Predicate::Not(predicate) => {
+                Ok(!predicate.evaluate(raw_row, table_schema, overflow)?)
             }
-            Action::GetFiltered(query, filters) => match self.begin_query(query, filters) {
-                Ok((qid, schema)) => Reaction::QueryStart { schema, qid },
-                Err(err) => Reaction::Error(err),
-            },
         }
     }
 
-    fn begin_query(
-        &mut self,
-        query: String,
-        filters: Vec<FilterType>,
-    ) -> Result<(u64, TableInfoMap), Error> {
-        let (table_schema, data) = self.load(&query)?;
-        let mut qid = rand::random();
-        // Make sure that qid isn't in use...
-        while self.queries.contains_key(&qid) {
-            qid = rand::random();
+    /// Every column the predicate reads.
+    pub fn columns(&self) -> Vec<&String> {
+        match self {
+            Predicate::Filter(filter) => filter.column().into_iter().collect(),
+            Predicate::And(predicates) | Predicate::Or(predicates) => {
+                predicates.iter().flat_map(Predicate::columns).collect()
+            }
+            Predicate::Not(predicate) => predicate.columns(),
         }
-        self.queries.insert(
-            qid,
-            data.as_filtered_rows(table_schema.len(), &mut |raw_row| {
-                apply_filters(raw_row, &filters, &table_schema)
-            }),
-        );
-        Ok((qid, table_schema))
     }
 }
 
-fn apply_filters(raw_row: &RawRow, filters: &Vec<FilterType>, table_schema: &TableInfoMap) -> bool {
-    filters.iter().all(|filter| apply_filter(raw_row, filter, table_schema))
+impl FilterType {
+    pub fn column(&self) -> Option<&String> {
+        match self {
+            FilterType::GreaterThanEqualTo(column, _)
+            | FilterType::GreaterThan(column, _)
+            | FilterType::LessThanEqualTo(column, _)
+            | FilterType::LessThan(column, _)
+            | FilterType::EqualTo(column, _)
+            | FilterType::Between(column, _, _)
+            | FilterType::In(column, _) => Some(column),
+            FilterType::All => None,
+        }
+    }
 }
 
-fn apply_filter(raw_row: &RawRow, filter: &FilterType, table_schema: &TableInfoMap) -> bool {
-    match filter {
-        FilterType::GreaterThanEqualTo(column, value) => {
-            has_ordering(
-                value,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Equal,
-            ) || has_ordering(
-                value,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Greater,
-            )
+fn apply_filter(
+    raw_row: &RawRow,
+    filter: &FilterType,
+    table_schema: &TableInfoMap,
+    overflow: &dyn Overflow,
+) -> Result<bool, Error> {
+    // The field is only read once, as text may have to be fetched for it
+    let field = match filter.column() {
+        Some(column) => match field_from_row(raw_row, column, table_schema, overflow)? {
+            Some(field) => field,
+            None => return Ok(false),
+        },
+        None => return Ok(true),
+    };
+    Ok(match filter {
+        FilterType::GreaterThanEqualTo(_, value) => {
+            has_ordering(value, &field, Ordering::Equal)
+                || has_ordering(value, &field, Ordering::Greater)
         }
-        FilterType::GreaterThan(column, value) => has_ordering(
-            value,
-            field_from_row(raw_row, column, table_schema),
-            Ordering::Greater,
-        ),
-        FilterType::LessThanEqualTo(column, value) => {
-            has_ordering(
-                value,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Equal,
-            ) || has_ordering(
-                value,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Less,
-            )
+        FilterType::GreaterThan(_, value) => has_ordering(value, &field, Ordering::Greater),
+        FilterType::LessThanEqualTo(_, value) => {
+            has_ordering(value, &field, Ordering::Equal)
+                || has_ordering(value, &field, Ordering::Less)
         }
-        FilterType::LessThan(column, value) => has_ordering(
-            value,
-            field_from_row(raw_row, column, table_schema),
-            Ordering::Less,
-        ),
-        FilterType::EqualTo(column, value) => has_ordering(
-            value,
-            field_from_row(raw_row, column, table_schema),
-            Ordering::Equal,
-        ),
-        FilterType::Between(column, lower, upper) => {
-            has_ordering(
-                lower,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Less,
-            ) && has_ordering(
-                upper,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Greater,
-            )
+        FilterType::LessThan(_, value) => has_ordering(value, &field, Ordering::Less),
+        FilterType::EqualTo(_, value) => has_ordering(value, &field, Ordering::Equal),
+        FilterType::Between(_, lower, upper) => {
+            has_ordering(lower, &field, Ordering::Less)
+                && has_ordering(upper, &field, Ordering::Greater)
         }
-        FilterType::In(column, values) => values.iter().any(|value| {
-            has_ordering(
-                value,
-                field_from_row(raw_row, column, table_schema),
-                Ordering::Equal,
-            )
-        }),
+        FilterType::In(_, values) => values
+            .iter()
+            .any(|value| has_ordering(value, &field, Ordering::Equal)),
         FilterType::All => true,
-    }
+    })
 }
 
 fn field_from_row(
     raw_row: &RawRow,
     column_name: &String,
     table_schema: &TableInfoMap,
-) -> Option<DataType> {
-    let (to_type, offset) = table_schema.get(column_name)?;
-    convert_row_field(raw_row, to_type, *offset)
+    overflow: &dyn Overflow,
+) -> Result<Option<DataType>, Error> {
+    match table_schema.get(column_name) {
+        Some((to_type, offset)) => {
+            convert_row_field(raw_row, to_type, *offset, overflow).transpose()
+        }
+        None => Ok(None),
+    }
 }
 
-fn has_ordering(left: &DataType, right: Option<DataType>, order: Ordering) -> bool {
-    if let Some(right) = right {
-        println!("Comparing {:?} to {:?} -- {:?}", left, right, left.partial_cmp(&right));
-        left.partial_cmp(&right) == Some(order)
-    } else {
-        false
-    }
+fn has_ordering(left: &DataType, right: &DataType, order: Ordering) -> bool {
+    left.partial_cmp(right) == Some(order)
 }
 
+#[derive(Debug, PartialEq)]
 pub enum Action {
-    GetAll(String),
-    GetFiltered(String, Vec<FilterType>),
-    GetMore(u64),
+    GetAll(
+        Source,
+        Vec<Projection>,
+        Grouping,
+        Vec<(Expression, Direction)>,
+        Limit,
+    ),
+    GetFiltered(
+        Source,
+        Predicate,
+        Vec<Projection>,
+        Grouping,
+        Vec<(Expression, Direction)>,
+        Limit,
+    ),
+    // Query id, and the most rows to send back
+    GetMore(u64, u64),
+    Insert(String, Vec<String>, Vec<Vec<DataType>>),
+    Update(String, Vec<(String, Expression)>, Predicate),
+    Delete(String, Predicate),
+    CreateTable(String, TableInfo, bool),
+    DropTable(String, bool),
+    AlterTable(String, Alteration),
+    // Index name, table, column, kind, and whether it may already exist
+    CreateIndex(String, String, String, IndexKind, bool),
+    DropIndex(String, bool),
+    Begin,
+    Commit,
+    Rollback,
 }
 
+impl Action {
+    /// Whether the action leaves the database as it was.
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Action::GetAll(..) | Action::GetFiltered(..) | Action::GetMore(..)
+        )
+    }
+
+    /// Whether the action creates, drops or alters a table or an index,
+    /// which needs the database to itself.
+    pub fn changes_tables(&self) -> bool {
+        matches!(
+            self,
+            Action::CreateTable(..)
+                | Action::DropTable(..)
+                | Action::AlterTable(..)
+                | Action::CreateIndex(..)
+                | Action::DropIndex(..)
+        )
+    }
+}
+
+/// How many of a query's rows to send, after skipping `offset` of them.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Limit {
+    pub count: Option<u64>,
+    pub offset: u64,
+}
+
+/// A boolean expression tree over FilterTypes, evaluated once per row.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Predicate {
+    Filter(FilterType),
+    And(Vec<Predicate>),
+    Or(Vec<Predicate>),
+    Not(Box<Predicate>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum FilterType {
     GreaterThanEqualTo(String, DataType),
     GreaterThan(String, DataType),
@@ -349,7 +1444,10 @@ pub enum FilterType {
 pub enum Reaction {
     Error(Error),
     QueryStart { schema: TableInfoMap, qid: u64 },
-    Data(Vec<RawRow>),
+    Data(Vec<Row>),
+    Inserted { count: u64, ids: Vec<u64> },
+    Affected(u64),
+    Done,
     Empty,
 }
 
@@ -357,10 +1455,44 @@ pub enum Reaction {
 mod tests {
     use byteorder::{ByteOrder, LE};
 
-    use crate::common::{ColumnType, TableInfo};
+    use crate::common::{overflow_reference, AsRawRows};
+    use crate::test_utils::{mint, temp_db, MemoryOverflow};
 
+    use super::btree::read_u64;
     use super::*;
 
+    /// Runs a query to completion, giving back its schema and every row,
+    /// encoded the way it would be stored.
+    fn fetch_all(db: &mut DataBase, action: Action) -> (TableInfoMap, Vec<RawRow>) {
+        let (schema, qid) = match db.execute(action) {
+            Reaction::QueryStart { schema, qid } => (schema, qid),
+            Reaction::Error(err) => panic!("Query failed with {err}"),
+            _ => panic!("Query didn't start"),
+        };
+        let mut rows = Vec::new();
+        while let Reaction::Data(data) = db.execute(Action::GetMore(qid, 100)) {
+            for row in data {
+                let raw_row = row.iter().map(|value| encode_field(value, db));
+                rows.push(raw_row.collect::<Result<_, _>>().unwrap());
+            }
+        }
+        (schema, rows)
+    }
+
+    fn select_all(table_name: &str) -> Action {
+        Action::GetAll(
+            table_name.into(),
+            vec![Projection::Wildcard],
+            Grouping::default(),
+            vec![],
+            Limit::default(),
+        )
+    }
+
+    fn passes(raw_row: &RawRow, filter: &FilterType, table_schema: &TableInfoMap) -> bool {
+        apply_filter(raw_row, filter, table_schema, &MemoryOverflow::default()).unwrap()
+    }
+
     fn test_data() -> (RawRow, TableInfoMap) {
         let word = LE::read_u64("bird\0\0\0\0".as_bytes());
         let raw_row: RawRow = vec![8675309u64, 0u64, word];
@@ -381,36 +1513,33 @@ mod tests {
             FilterType::GreaterThanEqualTo("ID".into(), DataType::Integer(8675308i64)),
         );
         assert!(
-            apply_filter(&raw_row, &int_over, &table_schema),
+            passes(&raw_row, &int_over, &table_schema),
             "Int GTET failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &int_eq, &table_schema),
+            passes(&raw_row, &int_eq, &table_schema),
             "Int GTET failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &int_under, &table_schema),
+            !passes(&raw_row, &int_under, &table_schema),
             "Int GTET failed of lesser."
         );
 
         let (text_under, text_eq, text_over) = (
-            FilterType::GreaterThanEqualTo("word".into(), DataType::Text(['b'; 8])),
-            FilterType::GreaterThanEqualTo(
-                "word".into(),
-                DataType::Text(['b', 'i', 'r', 'd', '\0', '\0', '\0', '\0']),
-            ),
-            FilterType::GreaterThanEqualTo("word".into(), DataType::Text(['c'; 8])),
+            FilterType::GreaterThanEqualTo("word".into(), DataType::Text("bbbbbbbb".into())),
+            FilterType::GreaterThanEqualTo("word".into(), DataType::Text("bird".into())),
+            FilterType::GreaterThanEqualTo("word".into(), DataType::Text("cccccccc".into())),
         );
         assert!(
-            apply_filter(&raw_row, &text_over, &table_schema),
+            passes(&raw_row, &text_over, &table_schema),
             "Text GTET failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &text_eq, &table_schema),
+            passes(&raw_row, &text_eq, &table_schema),
             "Text GTET failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &text_under, &table_schema),
+            !passes(&raw_row, &text_under, &table_schema),
             "Text GTET failed of lesser."
         );
     }
@@ -424,36 +1553,33 @@ mod tests {
             FilterType::GreaterThan("ID".into(), DataType::Integer(8675308i64)),
         );
         assert!(
-            apply_filter(&raw_row, &int_over, &table_schema),
+            passes(&raw_row, &int_over, &table_schema),
             "Int GT failed on greater."
         );
         assert!(
-            !apply_filter(&raw_row, &int_eq, &table_schema),
+            !passes(&raw_row, &int_eq, &table_schema),
             "Int GT failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &int_under, &table_schema),
+            !passes(&raw_row, &int_under, &table_schema),
             "Int GT failed of lesser."
         );
 
         let (text_under, text_eq, text_over) = (
-            FilterType::GreaterThan("word".into(), DataType::Text(['b'; 8])),
-            FilterType::GreaterThan(
-                "word".into(),
-                DataType::Text(['b', 'i', 'r', 'd', '\0', '\0', '\0', '\0']),
-            ),
-            FilterType::GreaterThan("word".into(), DataType::Text(['c'; 8])),
+            FilterType::GreaterThan("word".into(), DataType::Text("bbbbbbbb".into())),
+            FilterType::GreaterThan("word".into(), DataType::Text("bird".into())),
+            FilterType::GreaterThan("word".into(), DataType::Text("cccccccc".into())),
         );
         assert!(
-            apply_filter(&raw_row, &text_over, &table_schema),
+            passes(&raw_row, &text_over, &table_schema),
             "Text GT failed on greater."
         );
         assert!(
-            !apply_filter(&raw_row, &text_eq, &table_schema),
+            !passes(&raw_row, &text_eq, &table_schema),
             "Text GT failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &text_under, &table_schema),
+            !passes(&raw_row, &text_under, &table_schema),
             "Text GT failed of lesser."
         );
     }
@@ -467,36 +1593,33 @@ mod tests {
             FilterType::LessThanEqualTo("ID".into(), DataType::Integer(8675308i64)),
         );
         assert!(
-            !apply_filter(&raw_row, &int_over, &table_schema),
+            !passes(&raw_row, &int_over, &table_schema),
             "Int LTET failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &int_eq, &table_schema),
+            passes(&raw_row, &int_eq, &table_schema),
             "Int LTET failed on equal."
         );
         assert!(
-            apply_filter(&raw_row, &int_under, &table_schema),
+            passes(&raw_row, &int_under, &table_schema),
             "Int LTET failed of lesser."
         );
 
         let (text_under, text_eq, text_over) = (
-            FilterType::LessThanEqualTo("word".into(), DataType::Text(['b'; 8])),
-            FilterType::LessThanEqualTo(
-                "word".into(),
-                DataType::Text(['b', 'i', 'r', 'd', '\0', '\0', '\0', '\0']),
-            ),
-            FilterType::LessThanEqualTo("word".into(), DataType::Text(['c'; 8])),
+            FilterType::LessThanEqualTo("word".into(), DataType::Text("bbbbbbbb".into())),
+            FilterType::LessThanEqualTo("word".into(), DataType::Text("bird".into())),
+            FilterType::LessThanEqualTo("word".into(), DataType::Text("cccccccc".into())),
         );
         assert!(
-            !apply_filter(&raw_row, &text_over, &table_schema),
+            !passes(&raw_row, &text_over, &table_schema),
             "Text LTET failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &text_eq, &table_schema),
+            passes(&raw_row, &text_eq, &table_schema),
             "Text LTET failed on equal."
         );
         assert!(
-            apply_filter(&raw_row, &text_under, &table_schema),
+            passes(&raw_row, &text_under, &table_schema),
             "Text LTET failed of lesser."
         );
     }
@@ -510,36 +1633,33 @@ mod tests {
             FilterType::LessThan("ID".into(), DataType::Integer(8675308i64)),
         );
         assert!(
-            !apply_filter(&raw_row, &int_over, &table_schema),
+            !passes(&raw_row, &int_over, &table_schema),
             "Int LT failed on greater."
         );
         assert!(
-            !apply_filter(&raw_row, &int_eq, &table_schema),
+            !passes(&raw_row, &int_eq, &table_schema),
             "Int LT failed on equal."
         );
         assert!(
-            apply_filter(&raw_row, &int_under, &table_schema),
+            passes(&raw_row, &int_under, &table_schema),
             "Int LT failed of lesser."
         );
 
         let (text_under, text_eq, text_over) = (
-            FilterType::LessThan("word".into(), DataType::Text(['b'; 8])),
-            FilterType::LessThan(
-                "word".into(),
-                DataType::Text(['b', 'i', 'r', 'd', '\0', '\0', '\0', '\0']),
-            ),
-            FilterType::LessThan("word".into(), DataType::Text(['c'; 8])),
+            FilterType::LessThan("word".into(), DataType::Text("bbbbbbbb".into())),
+            FilterType::LessThan("word".into(), DataType::Text("bird".into())),
+            FilterType::LessThan("word".into(), DataType::Text("cccccccc".into())),
         );
         assert!(
-            !apply_filter(&raw_row, &text_over, &table_schema),
+            !passes(&raw_row, &text_over, &table_schema),
             "Text LT failed on greater."
         );
         assert!(
-            !apply_filter(&raw_row, &text_eq, &table_schema),
+            !passes(&raw_row, &text_eq, &table_schema),
             "Text LT failed on equal."
         );
         assert!(
-            apply_filter(&raw_row, &text_under, &table_schema),
+            passes(&raw_row, &text_under, &table_schema),
             "Text LT failed of lesser."
         );
     }
@@ -553,36 +1673,33 @@ mod tests {
             FilterType::EqualTo("ID".into(), DataType::Integer(8675308i64)),
         );
         assert!(
-            !apply_filter(&raw_row, &int_over, &table_schema),
+            !passes(&raw_row, &int_over, &table_schema),
             "Int ET failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &int_eq, &table_schema),
+            passes(&raw_row, &int_eq, &table_schema),
             "Int ET failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &int_under, &table_schema),
+            !passes(&raw_row, &int_under, &table_schema),
             "Int ET failed of lesser."
         );
 
         let (text_under, text_eq, text_over) = (
-            FilterType::EqualTo("word".into(), DataType::Text(['b'; 8])),
-            FilterType::EqualTo(
-                "word".into(),
-                DataType::Text(['b', 'i', 'r', 'd', '\0', '\0', '\0', '\0']),
-            ),
-            FilterType::EqualTo("word".into(), DataType::Text(['c'; 8])),
+            FilterType::EqualTo("word".into(), DataType::Text("bbbbbbbb".into())),
+            FilterType::EqualTo("word".into(), DataType::Text("bird".into())),
+            FilterType::EqualTo("word".into(), DataType::Text("cccccccc".into())),
         );
         assert!(
-            !apply_filter(&raw_row, &text_over, &table_schema),
+            !passes(&raw_row, &text_over, &table_schema),
             "Text ET failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &text_eq, &table_schema),
+            passes(&raw_row, &text_eq, &table_schema),
             "Text ET failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &text_under, &table_schema),
+            !passes(&raw_row, &text_under, &table_schema),
             "Text ET failed of lesser."
         );
     }
@@ -608,45 +1725,45 @@ mod tests {
             ),
         );
         assert!(
-            !apply_filter(&raw_row, &int_over, &table_schema),
+            !passes(&raw_row, &int_over, &table_schema),
             "Int BT failed on below."
         );
         assert!(
-            apply_filter(&raw_row, &int_eq, &table_schema),
+            passes(&raw_row, &int_eq, &table_schema),
             "Int BT failed on within."
         );
         assert!(
-            !apply_filter(&raw_row, &int_under, &table_schema),
+            !passes(&raw_row, &int_under, &table_schema),
             "Int BT failed of above."
         );
 
         let (text_over, text_eq, text_under) = (
             FilterType::Between(
                 "word".into(),
-                DataType::Text(['a'; 8]),
-                DataType::Text(['b'; 8]),
+                DataType::Text("aaaaaaaa".into()),
+                DataType::Text("bbbbbbbb".into()),
             ),
             FilterType::Between(
                 "word".into(),
-                DataType::Text(['a'; 8]),
-                DataType::Text(['c'; 8]),
+                DataType::Text("aaaaaaaa".into()),
+                DataType::Text("cccccccc".into()),
             ),
             FilterType::Between(
                 "word".into(),
-                DataType::Text(['c'; 8]),
-                DataType::Text(['d'; 8]),
+                DataType::Text("cccccccc".into()),
+                DataType::Text("dddddddd".into()),
             ),
         );
         assert!(
-            !apply_filter(&raw_row, &text_over, &table_schema),
+            !passes(&raw_row, &text_over, &table_schema),
             "Text BT failed on greater."
         );
         assert!(
-            apply_filter(&raw_row, &text_eq, &table_schema),
+            passes(&raw_row, &text_eq, &table_schema),
             "Text BT failed on equal."
         );
         assert!(
-            !apply_filter(&raw_row, &text_under, &table_schema),
+            !passes(&raw_row, &text_under, &table_schema),
             "Text BT failed of lesser."
         );
     }
@@ -673,11 +1790,11 @@ mod tests {
             ),
         );
         assert!(
-            apply_filter(&raw_row, &int_within, &table_schema),
+            passes(&raw_row, &int_within, &table_schema),
             "Int IN failed on in"
         );
         assert!(
-            !apply_filter(&raw_row, &int_without, &table_schema),
+            !passes(&raw_row, &int_without, &table_schema),
             "Int IN failed on out"
         );
 
@@ -692,11 +1809,11 @@ mod tests {
             ),
         );
         assert!(
-            apply_filter(&raw_row, &bool_within, &table_schema),
+            passes(&raw_row, &bool_within, &table_schema),
             "Bool IN failed on in"
         );
         assert!(
-            !apply_filter(&raw_row, &bool_without, &table_schema),
+            !passes(&raw_row, &bool_without, &table_schema),
             "Bool IN failed on out"
         );
 
@@ -704,26 +1821,26 @@ mod tests {
             FilterType::In(
                 "word".into(),
                 vec![
-                    DataType::Text(['b'; 8]),
-                    DataType::Text(['b'; 8]),
-                    DataType::Text(['b', 'i', 'r', 'd', '\0', '\0', '\0', '\0']),
+                    DataType::Text("bbbbbbbb".into()),
+                    DataType::Text("bbbbbbbb".into()),
+                    DataType::Text("bird".into()),
                 ],
             ),
             FilterType::In(
                 "word".into(),
                 vec![
-                    DataType::Text(['a'; 8]),
-                    DataType::Text(['b'; 8]),
-                    DataType::Text(['c'; 8]),
+                    DataType::Text("aaaaaaaa".into()),
+                    DataType::Text("bbbbbbbb".into()),
+                    DataType::Text("cccccccc".into()),
                 ],
             ),
         );
         assert!(
-            apply_filter(&raw_row, &text_within, &table_schema),
+            passes(&raw_row, &text_within, &table_schema),
             "Text IN failed on in"
         );
         assert!(
-            !apply_filter(&raw_row, &text_without, &table_schema),
+            !passes(&raw_row, &text_without, &table_schema),
             "Text IN failed on out"
         );
     }
@@ -732,6 +1849,960 @@ mod tests {
     fn test_filter_all() {
         let (raw_row, table_schema) = test_data();
         let all = FilterType::All;
-        assert!(apply_filter(&raw_row, &all, &table_schema))
+        assert!(passes(&raw_row, &all, &table_schema))
+    }
+
+    #[test]
+    fn test_predicate_tree() {
+        let (raw_row, table_schema) = test_data();
+        let overflow = MemoryOverflow::default();
+        let is_id = || {
+            Predicate::Filter(FilterType::EqualTo(
+                "ID".into(),
+                DataType::Integer(8675309i64),
+            ))
+        };
+        let is_truthy = || {
+            Predicate::Filter(FilterType::EqualTo(
+                "truthy".into(),
+                DataType::Boolean(true),
+            ))
+        };
+
+        assert!(
+            !Predicate::And(vec![is_id(), is_truthy()])
+                .evaluate(&raw_row, &table_schema, &overflow)
+                .unwrap(),
+            "AND failed with a false branch"
+        );
+        assert!(
+            Predicate::Or(vec![is_truthy(), is_id()])
+                .evaluate(&raw_row, &table_schema, &overflow)
+                .unwrap(),
+            "OR failed with a true branch"
+        );
+        assert!(
+            !Predicate::Or(vec![is_truthy(), Predicate::Not(Box::new(is_id()))])
+                .evaluate(&raw_row, &table_schema, &overflow)
+                .unwrap(),
+            "OR failed with only false branches"
+        );
+        assert!(
+            Predicate::And(vec![
+                is_id(),
+                Predicate::Not(Box::new(Predicate::Or(vec![
+                    is_truthy(),
+                    Predicate::Not(Box::new(is_id()))
+                ])))
+            ])
+            .evaluate(&raw_row, &table_schema, &overflow)
+            .unwrap(),
+            "Nested NOT failed"
+        );
+        assert!(Predicate::And(vec![])
+            .evaluate(&raw_row, &table_schema, &overflow)
+            .unwrap());
+        assert!(!Predicate::Or(vec![])
+            .evaluate(&raw_row, &table_schema, &overflow)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_insert_into_empty_table() {
+        let (_dir, mut db) = temp_db("insert_empty");
+        let reaction = db.execute(Action::Insert(
+            "currency".into(),
+            vec!["Gold".into(), "Silver".into()],
+            vec![
+                vec![DataType::Integer(10), DataType::Integer(3)],
+                vec![DataType::Integer(-2), DataType::Integer(0)],
+            ],
+        ));
+        match reaction {
+            Reaction::Inserted { count, ids } => {
+                assert_eq!(count, 2);
+                assert_eq!(ids, vec![1, 2]);
+            }
+            _ => panic!("Insert failed"),
+        }
+
+        let (schema, rows) = fetch_all(&mut db, select_all("currency"));
+        assert_eq!(schema.len(), 5);
+        assert_eq!(
+            rows,
+            vec![vec![1, 0, 10, 3, 0], vec![2, 0, (-2i64) as u64, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn test_insert_after_existing_rows() {
+        let (_dir, mut db) = temp_db("insert_existing");
+        // Ids 1 through 408, filling two blocks
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec![],
+                vec![vec![
+                    DataType::Integer(1),
+                    DataType::Integer(2),
+                    DataType::Integer(3),
+                    DataType::Integer(4),
+                ]],
+            )
+            .unwrap();
+        assert_eq!(ids, vec![409]);
+        assert_eq!(db.block_count("currency").unwrap(), 3);
+
+        let (_, rows) = fetch_all(
+            &mut db,
+            Action::GetFiltered(
+                "currency".into(),
+                Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(409))),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit::default(),
+            ),
+        );
+        assert_eq!(rows, vec![vec![409, 1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn test_insert_rejects_bad_rows() {
+        let (_dir, db) = temp_db("insert_bad");
+        let one = || vec![DataType::Integer(1)];
+        assert!(db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["index".into()],
+                vec![one()]
+            )
+            .is_err());
+        assert!(db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Mithril".into()],
+                vec![one()]
+            )
+            .is_err());
+        assert!(db
+            .insert(DEFAULT_SESSION, "dragons", vec!["Gold".into()], vec![one()])
+            .is_err());
+        assert!(db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into(), "Gold".into()],
+                vec![one()]
+            )
+            .is_err());
+        assert!(db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into()],
+                vec![vec![DataType::Boolean(true)]]
+            )
+            .is_err());
+        assert!(db
+            .insert(DEFAULT_SESSION, "currency", vec![], vec![one()])
+            .is_err());
+
+        // Nothing above should have used up an id
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into()],
+                vec![one()],
+            )
+            .unwrap();
+        assert_eq!(ids, vec![1]);
+    }
+
+    #[test]
+    fn test_update_rewrites_matching_rows() {
+        let (_dir, mut db) = temp_db("update");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+
+        // Gold is id * 3 % 10, so ids 1, 11, 21, ... have 3 Gold
+        let reaction = db.execute(Action::Update(
+            "currency".into(),
+            vec![
+                (
+                    "Gold".into(),
+                    Expression::Arithmetic(
+                        Box::new(Expression::Column("Gold".into())),
+                        Operator::Add,
+                        Box::new(Expression::Column("Silver".into())),
+                    ),
+                ),
+                ("Silver".into(), Expression::Literal(DataType::Integer(0))),
+            ],
+            Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(3))),
+        ));
+        assert!(matches!(reaction, Reaction::Affected(41)));
+
+        let (_, rows) = fetch_all(&mut db, select_all("currency"));
+        assert_eq!(rows.len(), 408);
+        for row in rows {
+            let id = row[0];
+            if id % 10 == 1 {
+                // Silver was id * 5 % 10, which is always 5 for these ids
+                assert_eq!(row, vec![id, id % 100, 8, 0, id * 7 % 10]);
+            } else {
+                assert_eq!(
+                    row,
+                    vec![id, id % 100, id * 3 % 10, id * 5 % 10, id * 7 % 10]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_update_failure_writes_nothing() {
+        let (_dir, mut db) = temp_db("update_failure");
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Gold".into()],
+            vec![
+                vec![DataType::Integer(1)],
+                vec![DataType::Integer(i64::MAX)],
+            ],
+        )
+        .unwrap();
+
+        let double_gold = || {
+            vec![(
+                "Gold".into(),
+                Expression::Arithmetic(
+                    Box::new(Expression::Column("Gold".into())),
+                    Operator::Multiply,
+                    Box::new(Expression::Literal(DataType::Integer(2))),
+                ),
+            )]
+        };
+        assert!(db
+            .update(
+                DEFAULT_SESSION,
+                "currency",
+                double_gold(),
+                Predicate::Filter(FilterType::All)
+            )
+            .is_err());
+        assert!(db
+            .update(
+                DEFAULT_SESSION,
+                "currency",
+                vec![("index".into(), Expression::Literal(DataType::Integer(9)))],
+                Predicate::Filter(FilterType::All)
+            )
+            .is_err());
+        assert!(db
+            .update(
+                DEFAULT_SESSION,
+                "currency",
+                vec![("Gold".into(), Expression::Literal(DataType::Boolean(true)))],
+                Predicate::Filter(FilterType::All)
+            )
+            .is_err());
+
+        let (_, rows) = fetch_all(&mut db, select_all("currency"));
+        assert_eq!(
+            rows,
+            vec![vec![1, 0, 1, 0, 0], vec![2, 0, i64::MAX as u64, 0, 0]]
+        );
+    }
+
+    #[test]
+    fn test_order_by() {
+        let (_dir, mut db) = temp_db("order_by");
+        let coins = |gold, silver| vec![DataType::Integer(gold), DataType::Integer(silver)];
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Gold".into(), "Silver".into()],
+            vec![
+                coins(3, 9),
+                coins(-1, 0),
+                coins(7, 2),
+                coins(3, 1),
+                coins(7, 1),
+            ],
+        )
+        .unwrap();
+
+        // Sort on a source column that isn't projected, and on an output alias
+        let (schema, rows) = fetch_all(
+            &mut db,
+            Action::GetFiltered(
+                "currency".into(),
+                Predicate::Not(Box::new(Predicate::Filter(FilterType::EqualTo(
+                    "index".into(),
+                    DataType::Integer(1),
+                )))),
+                vec![Projection::Named(
+                    Expression::Column("index".into()),
+                    "id".into(),
+                )],
+                Grouping::default(),
+                vec![
+                    (Expression::Column("Gold".into()), Direction::Descending),
+                    (Expression::Column("id".into()), Direction::Ascending),
+                ],
+                Limit::default(),
+            ),
+        );
+        assert_eq!(schema.len(), 1);
+        assert_eq!(rows, vec![vec![3], vec![5], vec![4], vec![2]]);
+
+        let reaction = db.execute(Action::GetAll(
+            "currency".into(),
+            vec![Projection::Wildcard],
+            Grouping::default(),
+            vec![(Expression::Column("Mithril".into()), Direction::Ascending)],
+            Limit::default(),
+        ));
+        assert!(matches!(reaction, Reaction::Error(_)));
+    }
+
+    #[test]
+    fn test_aggregate_query() {
+        let (_dir, mut db) = temp_db("aggregate_query");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let gold = || Box::new(Expression::Column("Gold".into()));
+        let treasury = |predicate| {
+            Action::GetFiltered(
+                "currency".into(),
+                predicate,
+                vec![
+                    Projection::Named(
+                        Expression::Aggregate(
+                            Function::Count,
+                            Box::new(Expression::Column("index".into())),
+                        ),
+                        "count".into(),
+                    ),
+                    Projection::Named(Expression::Aggregate(Function::Sum, gold()), "gold".into()),
+                    Projection::Named(Expression::Aggregate(Function::Max, gold()), "most".into()),
+                ],
+                Grouping::default(),
+                vec![(Expression::Column("gold".into()), Direction::Descending)],
+                Limit::default(),
+            )
+        };
+
+        let (schema, rows) = fetch_all(&mut db, treasury(Predicate::Filter(FilterType::All)));
+        assert_eq!(schema["gold"], (ColumnType::Integer, 1));
+        let (table_schema, block) = db.load("currency").unwrap();
+        let offset = table_schema["Gold"].1 as usize;
+        let golds: Vec<u64> = block.as_rows(5).iter().map(|row| row[offset]).collect();
+        assert_eq!(
+            rows,
+            vec![vec![204, golds.iter().sum(), *golds.iter().max().unwrap()]]
+        );
+
+        // Nothing matches, but there's still a row of results
+        let nothing = Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(0)));
+        let (_, rows) = fetch_all(&mut db, treasury(nothing));
+        assert_eq!(rows, vec![vec![0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_group_by_query() {
+        let (_dir, mut db) = temp_db("group_by_query");
+        let coins = |platinum, gold| vec![DataType::Integer(platinum), DataType::Integer(gold)];
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Platinum".into(), "Gold".into()],
+            vec![
+                coins(1, 5),
+                coins(2, 4),
+                coins(1, 6),
+                coins(3, 1),
+                coins(2, 9),
+            ],
+        )
+        .unwrap();
+
+        let total =
+            Expression::Aggregate(Function::Sum, Box::new(Expression::Column("Gold".into())));
+        let (schema, rows) = fetch_all(
+            &mut db,
+            Action::GetAll(
+                "currency".into(),
+                vec![
+                    Projection::Named(Expression::Column("Platinum".into()), "Platinum".into()),
+                    Projection::Named(total.clone(), "total".into()),
+                ],
+                Grouping {
+                    keys: vec![Expression::Column("Platinum".into())],
+                    having: Predicate::Filter(FilterType::LessThan(
+                        "SUM(Gold)".into(),
+                        DataType::Integer(5),
+                    )),
+                    aggregates: vec![total],
+                },
+                vec![(Expression::Column("total".into()), Direction::Descending)],
+                Limit::default(),
+            ),
+        );
+        assert_eq!(schema["total"], (ColumnType::Integer, 1));
+        assert_eq!(rows, vec![vec![2, 13], vec![1, 11]]);
+    }
+
+    #[test]
+    fn test_join_query() {
+        let (_dir, mut db) = temp_db("join_query");
+        let integers = |values: &[i64]| values.iter().map(|v| DataType::Integer(*v)).collect();
+        db.insert(
+            DEFAULT_SESSION,
+            "attributes",
+            vec!["Strength".into()],
+            vec![integers(&[12]), integers(&[8]), integers(&[15])],
+        )
+        .unwrap();
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Gold".into()],
+            vec![integers(&[30]), integers(&[5])],
+        )
+        .unwrap();
+
+        let (schema, rows) = fetch_all(
+            &mut db,
+            Action::GetAll(
+                Source::Join(
+                    Box::new(Source::Table("attributes".into(), Some("a".into()))),
+                    Box::new("currency".into()),
+                    Join::Left(
+                        vec![("a.index".into(), "currency.index".into())],
+                        Predicate::Filter(FilterType::All),
+                    ),
+                ),
+                vec![
+                    Projection::Named(Expression::Column("a.index".into()), "id".into()),
+                    Projection::Named(Expression::Column("Strength".into()), "Strength".into()),
+                    Projection::Named(Expression::Column("Gold".into()), "Gold".into()),
+                ],
+                Grouping::default(),
+                vec![(Expression::Column("Strength".into()), Direction::Ascending)],
+                Limit::default(),
+            ),
+        );
+        assert_eq!(schema.len(), 3);
+        assert_eq!(schema["Gold"], (ColumnType::Integer, 2));
+        assert_eq!(rows.len(), 3);
+        // The third hero has no purse, so their gold comes back zeroed
+        let gold: Vec<u64> = rows.iter().map(|row| row[2]).collect();
+        let strength: Vec<u64> = rows.iter().map(|row| row[1]).collect();
+        assert_eq!(strength, vec![8, 12, 15]);
+        assert_eq!(gold, vec![5, 30, 0]);
+    }
+
+    #[test]
+    fn test_cursor_batches() {
+        let (_dir, mut db) = temp_db("cursor_batches");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let qid = match db.execute(select_all("currency")) {
+            Reaction::QueryStart { qid, .. } => qid,
+            _ => panic!("Query didn't start"),
+        };
+
+        let batch_len = |reaction| match reaction {
+            Reaction::Data(data) => data.len(),
+            _ => panic!("Expected a batch of rows"),
+        };
+        assert!(matches!(
+            db.execute(Action::GetMore(qid, 0)),
+            Reaction::Error(_)
+        ));
+        assert_eq!(batch_len(db.execute(Action::GetMore(qid, 150))), 150);
+        assert_eq!(batch_len(db.execute(Action::GetMore(qid, 150))), 54);
+        assert!(matches!(
+            db.execute(Action::GetMore(qid, 150)),
+            Reaction::Empty
+        ));
+        // The query is gone once it has been used up
+        assert!(matches!(
+            db.execute(Action::GetMore(qid, 150)),
+            Reaction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_streamed_query() {
+        let (_dir, mut db) = temp_db("streamed_query");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let qid = match db.execute(select_all("currency")) {
+            Reaction::QueryStart { qid, .. } => qid,
+            _ => panic!("Query didn't start"),
+        };
+        // Nothing is read until it's asked for, so a block added after the
+        // query started still shows up
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        let mut ids = Vec::new();
+        while let Reaction::Data(data) = db.execute(Action::GetMore(qid, 100)) {
+            ids.extend(data.iter().map(|row| row[0].to_owned()));
+        }
+        assert_eq!(ids, (1..=408).map(DataType::Integer).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_queries_run_side_by_side() {
+        let (_dir, db) = temp_db("side_by_side");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        // Each thread only has shared access, and their batches interleave
+        let counts: Vec<usize> = std::thread::scope(|scope| {
+            let readers: Vec<_> = (1..=4)
+                .map(|session| {
+                    let db = &db;
+                    scope.spawn(move || {
+                        let qid = match db.execute_shared(session, select_all("currency")) {
+                            Reaction::QueryStart { qid, .. } => qid,
+                            _ => panic!("Query didn't start"),
+                        };
+                        let mut count = 0;
+                        while let Reaction::Data(data) =
+                            db.execute_shared(session, Action::GetMore(qid, 10))
+                        {
+                            count += data.len();
+                        }
+                        count
+                    })
+                })
+                .collect();
+            readers
+                .into_iter()
+                .map(|reader| reader.join().unwrap())
+                .collect()
+        });
+        assert_eq!(counts, vec![408; 4]);
+        assert!(matches!(
+            db.execute_shared(DEFAULT_SESSION, Action::DropTable("currency".into(), false)),
+            Reaction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_table_change_ends_queries() {
+        let (_dir, mut db) = temp_db("change_ends_queries");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let start = |db: &mut DataBase, table_name| match db.execute(select_all(table_name)) {
+            Reaction::QueryStart { qid, .. } => qid,
+            _ => panic!("Query didn't start"),
+        };
+        let currency = start(&mut db, "currency");
+        let attributes = start(&mut db, "attributes");
+        assert!(matches!(
+            db.execute(Action::GetMore(currency, 10)),
+            Reaction::Data(_)
+        ));
+
+        let reaction = db.execute(Action::AlterTable(
+            "currency".into(),
+            Alteration::AddColumn("Electrum".into(), ColumnType::Integer, false),
+        ));
+        assert!(matches!(reaction, Reaction::Done));
+        assert!(matches!(
+            db.execute(Action::GetMore(currency, 10)),
+            Reaction::Error(_)
+        ));
+        // Queries of other tables carry on
+        assert!(matches!(
+            db.execute(Action::GetMore(attributes, 10)),
+            Reaction::Empty
+        ));
+
+        // Nor does a query read a new table that took the name of its own
+        let currency = start(&mut db, "currency");
+        let currency_info = db.schema["currency"].to_owned();
+        db.execute(Action::DropTable("currency".into(), false));
+        db.execute(Action::CreateTable("currency".into(), currency_info, false));
+        assert!(matches!(
+            db.execute(Action::GetMore(currency, 10)),
+            Reaction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_limit_and_offset() {
+        let (_dir, mut db) = temp_db("limit_offset");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let ids = |order_by, count, offset| {
+            Action::GetAll(
+                "currency".into(),
+                vec![Projection::Named(
+                    Expression::Column("index".into()),
+                    "index".into(),
+                )],
+                Grouping::default(),
+                order_by,
+                Limit { count, offset },
+            )
+        };
+
+        let (_, rows) = fetch_all(&mut db, ids(vec![], Some(3), 10));
+        assert_eq!(rows, vec![vec![11], vec![12], vec![13]]);
+
+        let descending = vec![(Expression::Column("index".into()), Direction::Descending)];
+        let (_, rows) = fetch_all(&mut db, ids(descending, None, 202));
+        assert_eq!(rows, vec![vec![2], vec![1]]);
+
+        let (_, rows) = fetch_all(&mut db, ids(vec![], Some(0), 0));
+        assert!(rows.is_empty());
+        let (_, rows) = fetch_all(&mut db, ids(vec![], Some(5), 500));
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn test_delete_frees_slots_for_reuse() {
+        let (_dir, mut db) = temp_db("delete");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        // Build the allocator before deleting, so the freed slots get handed back
+        db.insert(DEFAULT_SESSION, "currency", vec![], vec![])
+            .unwrap();
+
+        // Gold is id * 3 % 10, so this is every tenth row
+        let reaction = db.execute(Action::Delete(
+            "currency".into(),
+            Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(0))),
+        ));
+        assert!(matches!(reaction, Reaction::Affected(40)));
+
+        let (_, rows) = fetch_all(&mut db, select_all("currency"));
+        assert_eq!(rows.len(), 368);
+        assert!(rows.iter().all(|row| row[0] % 10 != 0));
+
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into()],
+                vec![vec![DataType::Integer(100)], vec![DataType::Integer(200)]],
+            )
+            .unwrap();
+        assert_eq!(ids, vec![409, 410]);
+        // Both went into holes (ids 10 and 20) rather than a new block
+        assert_eq!(db.block_count("currency").unwrap(), 2);
+        let block = db.load_block_at("currency", 0).unwrap();
+        assert_eq!(read_row(&block, 9, 5), vec![409, 0, 100, 0, 0]);
+        assert_eq!(read_row(&block, 19, 5), vec![410, 0, 200, 0, 0]);
+    }
+
+    #[test]
+    fn test_deleted_slots_found_on_reopen() {
+        let (dir, db) = temp_db("delete_reopen");
+        db.store("currency", vec![mint(0)]).unwrap();
+        db.delete(
+            DEFAULT_SESSION,
+            "currency",
+            Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(7))),
+        )
+        .unwrap();
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into()],
+                vec![vec![DataType::Integer(1)]],
+            )
+            .unwrap();
+        assert_eq!(ids, vec![205]);
+        let block = db.load_block_at("currency", 0).unwrap();
+        assert_eq!(read_row(&block, 6, 5), vec![205, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_deleted_ids_not_reused_on_reopen() {
+        let (dir, db) = temp_db("delete_ids_reopen");
+        db.store("currency", vec![mint(0)]).unwrap();
+        // Only rows with ids above 200 go
+        let deleted = db
+            .delete(
+                DEFAULT_SESSION,
+                "currency",
+                Predicate::Filter(FilterType::LessThan("index".into(), DataType::Integer(200))),
+            )
+            .unwrap();
+        assert_eq!(deleted, 4);
+        let gold = |value| vec![vec![DataType::Integer(value)]];
+        drop(db);
+
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        let ids = db
+            .insert(DEFAULT_SESSION, "currency", vec!["Gold".into()], gold(1))
+            .unwrap();
+        assert_eq!(ids, vec![205]);
+
+        // Nor once the table has moved, which builds its primary index again
+        db.delete(
+            DEFAULT_SESSION,
+            "currency",
+            Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(205))),
+        )
+        .unwrap();
+        db.alter_table(
+            "currency",
+            Alteration::AddColumn("Electrum".into(), ColumnType::Integer, false),
+        )
+        .unwrap();
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        let ids = db
+            .insert(DEFAULT_SESSION, "currency", vec!["Gold".into()], gold(2))
+            .unwrap();
+        assert_eq!(ids, vec![206]);
+    }
+
+    #[test]
+    fn test_recover_from_log() {
+        let (dir, db) = temp_db("wal_recover");
+        db.store("currency", vec![mint(0)]).unwrap();
+        db.checkpoint().unwrap();
+        db.update(
+            DEFAULT_SESSION,
+            "currency",
+            vec![("Gold".into(), Expression::Literal(DataType::Integer(42)))],
+            Predicate::Filter(FilterType::EqualTo("index".into(), DataType::Integer(3))),
+        )
+        .unwrap();
+        // The update is only in the log when we crash
+        let table_path = dir.0.join("test_currency.ogmadb");
+        let on_disk = std::fs::read(&table_path).unwrap();
+        std::mem::forget(db);
+        assert_eq!(
+            LE::read_u64(&on_disk[2 * 5 * COLUMN_WIDTH + 2 * COLUMN_WIDTH..]),
+            9
+        );
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        let block = db.load_block_at("currency", 0).unwrap();
+        assert_eq!(read_row(&block, 2, 5), vec![3, 3, 42, 5, 1]);
+        // Recovery put the block in place and emptied the log
+        let mut wal_path = dir.db_path().into_os_string();
+        wal_path.push(".wal");
+        assert_eq!(std::fs::metadata(wal_path).unwrap().len(), 0);
+        assert_eq!(std::fs::read(&table_path).unwrap()[..BLOCK_SIZE], block);
+    }
+
+    #[test]
+    fn test_create_and_drop_table() {
+        let (dir, mut db) = temp_db("create_drop");
+        let table_info: TableInfo = vec![
+            ("index".into(), ColumnType::Integer),
+            ("name".into(), ColumnType::Text),
+            ("alive".into(), ColumnType::Boolean),
+        ];
+        let reaction = db.execute(Action::CreateTable(
+            "heroes".into(),
+            table_info.clone(),
+            false,
+        ));
+        assert!(matches!(reaction, Reaction::Done));
+        let heroes_path = dir.0.join("test_heroes.ogmadb");
+        assert!(heroes_path.exists());
+
+        assert!(db
+            .create_table("heroes", table_info.clone(), false)
+            .is_err());
+        assert!(db.create_table("heroes", vec![], true).is_ok());
+
+        db.insert(
+            DEFAULT_SESSION,
+            "heroes",
+            vec!["alive".into()],
+            vec![vec![DataType::Boolean(true)]],
+        )
+        .unwrap();
+        drop(db);
+
+        // The new table survives a restart
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(db.schema["heroes"], table_info);
+        let (schema, rows) = fetch_all(&mut db, select_all("heroes"));
+        assert_eq!(schema.len(), 3);
+        assert_eq!(rows, vec![vec![1, 0, 1]]);
+
+        let reaction = db.execute(Action::DropTable("heroes".into(), false));
+        assert!(matches!(reaction, Reaction::Done));
+        assert!(!heroes_path.exists());
+        assert!(db.drop_table("heroes", false).is_err());
+        assert!(db.drop_table("heroes", true).is_ok());
+        drop(db);
+
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        assert!(!db.schema.contains_key("heroes"));
+        assert!(db.schema.contains_key("currency"));
+    }
+
+    #[test]
+    fn test_create_table_validation() {
+        let (_dir, mut db) = temp_db("create_validation");
+        let id = || ("index".to_owned(), ColumnType::Integer);
+        assert!(db.create_table("../escape", vec![id()], false).is_err());
+        assert!(db.create_table("empty", vec![], false).is_err());
+        assert!(db
+            .create_table("texty", vec![("name".into(), ColumnType::Text)], false)
+            .is_err());
+        assert!(db.create_table("twice", vec![id(), id()], false).is_err());
+        assert!(!db.schema.contains_key("twice"));
+    }
+
+    #[test]
+    fn test_long_text() {
+        let (dir, mut db) = temp_db("long_text");
+        let table_info: TableInfo = vec![
+            ("index".into(), ColumnType::Integer),
+            ("name".into(), ColumnType::Text),
+        ];
+        db.create_table("heroes", table_info, false).unwrap();
+        let names = [
+            "Bo",
+            "Sir Lancelot du Lac",
+            "Zoë the Ünbowed",
+            "Sir Galahad",
+            "Sir Lancelot du Lac",
+            "Sir Lancelot of the Lake",
+        ];
+        db.insert(
+            DEFAULT_SESSION,
+            "heroes",
+            vec!["name".into()],
+            names.iter().map(|name| vec![(*name).into()]).collect(),
+        )
+        .unwrap();
+        db.create_index("by_name", "heroes", "name", IndexKind::BTree, false)
+            .unwrap();
+
+        // The names a filter matches, in order
+        let by_name = |db: &mut DataBase, filter: FilterType| {
+            let (qid, _) = db
+                .begin_query(
+                    DEFAULT_SESSION,
+                    "heroes".into(),
+                    Predicate::Filter(filter),
+                    vec![Projection::Named(
+                        Expression::Column("name".into()),
+                        "name".into(),
+                    )],
+                    Grouping::default(),
+                    vec![(Expression::Column("name".into()), Direction::Ascending)],
+                    Limit::default(),
+                )
+                .unwrap();
+            let mut names = Vec::new();
+            while let Some(rows) = db.next_batch(qid, 10).unwrap() {
+                names.extend(rows.into_iter().map(|mut row| row.remove(0)));
+            }
+            names
+        };
+        let past_lancelot = || FilterType::LessThan("name".into(), "Sir Lancelot".into());
+        let expected: Vec<DataType> = vec![
+            "Sir Lancelot du Lac".into(),
+            "Sir Lancelot du Lac".into(),
+            "Sir Lancelot of the Lake".into(),
+            "Zoë the Ünbowed".into(),
+        ];
+        assert_eq!(by_name(&mut db, past_lancelot()), expected);
+        // The index only holds the start of each name, the rest is checked
+        let lancelot = || FilterType::EqualTo("name".into(), "Sir Lancelot du Lac".into());
+        assert_eq!(by_name(&mut db, lancelot()), expected[..2]);
+
+        // The same text is only kept once
+        let block = db.load_block_at("heroes", 0).unwrap();
+        let [lancelot_field, again] = [1, 4].map(|slot| read_row(&block, slot, 2)[1]);
+        assert_eq!(lancelot_field, again);
+        assert_eq!(read_row(&block, 0, 2)[1], LE::read_u64(b"Bo\0\0\0\0\0\0"));
+        drop(db);
+
+        // Text survives a restart, and can still be found without its hash index
+        let hashes_path = dir.0.join("test_overflow-text.hashes.ogmadb");
+        std::fs::remove_file(hashes_path).unwrap();
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(by_name(&mut db, past_lancelot()), expected);
+        let field = encode_field(&"Sir Lancelot du Lac".into(), &db).unwrap();
+        assert_eq!(field, lancelot_field);
+    }
+
+    #[test]
+    fn test_text_reclaimed() {
+        let (dir, mut db) = temp_db("text_reclaimed");
+        let table_info: TableInfo = vec![
+            ("index".into(), ColumnType::Integer),
+            ("name".into(), ColumnType::Text),
+        ];
+        db.create_table("heroes", table_info, false).unwrap();
+        let add = |db: &DataBase, name: &str| {
+            db.insert(DEFAULT_SESSION, "heroes", vec![], vec![vec![name.into()]])
+                .unwrap()[0]
+        };
+        let remove = |db: &DataBase, id: u64| {
+            let hero = FilterType::EqualTo("index".into(), DataType::Integer(id as i64));
+            db.delete(DEFAULT_SESSION, "heroes", Predicate::Filter(hero))
+                .unwrap();
+        };
+        let name_field = |db: &DataBase, id: u64| {
+            let block = db.load_block_at("heroes", 0).unwrap();
+            (0..BLOCK_SIZE / (2 * COLUMN_WIDTH))
+                .map(|slot| read_row(&block, slot, 2))
+                .find(|raw_row| raw_row[0] == id)
+                .expect("the hero is there")[1]
+        };
+        let text_end = |db: &DataBase| read_u64(&db.load_block_at(TEXT_FILE, 0).unwrap(), 0);
+
+        let lancelot = add(&db, "Sir Lancelot du Lac");
+        let galahad = add(&db, "Sir Galahad the Pure");
+        let twin = add(&db, "Sir Galahad the Pure");
+        let told = name_field(&db, lancelot);
+        let end = text_end(&db);
+
+        // A snapshot that can still read the name keeps its space in use
+        let snapshot = db.versions.snapshot();
+        remove(&db, lancelot);
+        add(&db, "Sir Bedivere, Bold");
+        assert!(text_end(&db) > end);
+        drop(snapshot);
+        let end = text_end(&db);
+        let tristan = add(&db, "Sir Tristan de Lyo");
+        assert_eq!(name_field(&db, tristan), told);
+        assert_eq!(text_end(&db), end);
+
+        // Text still referred to by another row stays
+        remove(&db, twin);
+        add(&db, "Sir Gawain the Green");
+        assert!(text_end(&db) > end);
+        let end = text_end(&db);
+
+        // Text only a rolled back transaction wrote doesn't
+        db.begin(DEFAULT_SESSION).unwrap();
+        add(&db, "Sir Percival of Wales");
+        db.rollback(DEFAULT_SESSION).unwrap();
+        let kay = add(&db, "Sir Kay the Seneschal");
+        assert_eq!(overflow_reference(name_field(&db, kay)), Some(end));
+        drop(db);
+
+        // What nothing refers to is found free again on opening
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        remove(&db, tristan);
+        let field = encode_field(&"Sir Galahad the Pure".into(), &db).unwrap();
+        assert_eq!(field, name_field(&db, galahad));
+        drop(db);
+        let db = DataBase::open(&dir.db_path()).unwrap();
+        let end = text_end(&db);
+        let mordred = add(&db, "Sir Mordred Traitor");
+        assert_eq!(name_field(&db, mordred), told);
+        assert_eq!(text_end(&db), end);
     }
 }
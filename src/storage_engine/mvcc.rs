@@ -0,0 +1,351 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::common::{error::Error, write_row, Block, RawRow};
+
+use super::allocator::RowLocation;
+use super::DataBase;
+
+/// Counts the commits made to the shared blocks. A snapshot sees every
+/// commit up to and including the one it was taken at.
+pub type CommitId = u64;
+
+/// The rows each commit overwrote, kept for as long as a snapshot taken
+/// before that commit might read them.
+///
+/// The shared blocks only ever hold the latest committed rows, so a snapshot
+/// reading a block puts back the rows changed since it was taken. Queries
+/// started outside a transaction read from a snapshot, which lets them run
+/// as long as they like without waiting on, or holding up, anyone writing.
+#[derive(Default)]
+pub struct VersionStore {
+    state: Mutex<Versions>,
+}
+
+#[derive(Default)]
+struct Versions {
+    committed: CommitId,
+    // How many snapshots are open at each commit
+    open: BTreeMap<CommitId, usize>,
+    // Each overwritten row, by the commit that overwrote it, oldest first
+    rows: HashMap<String, BTreeMap<RowLocation, Vec<(CommitId, RawRow)>>>,
+}
+
+impl VersionStore {
+    /// Takes a snapshot of everything committed so far. It holds on to the
+    /// rows it needs until it's dropped.
+    pub fn snapshot(self: &Arc<Self>) -> Snapshot {
+        let mut versions = self.versions();
+        let commit = versions.committed;
+        *versions.open.entry(commit).or_default() += 1;
+        Snapshot {
+            commit,
+            store: Arc::clone(self),
+        }
+    }
+
+    /// The commit the next writes will be part of, once they're published.
+    pub fn next_commit(&self) -> CommitId {
+        self.versions().committed + 1
+    }
+
+    /// Keeps the rows a commit is about to overwrite. This has to happen
+    /// before the new rows reach the shared blocks, so no snapshot can read
+    /// them without finding what they replaced.
+    pub fn record<I>(&self, table_name: &str, commit: CommitId, overwritten: I)
+    where
+        I: IntoIterator<Item = (RowLocation, RawRow)>,
+    {
+        let mut versions = self.versions();
+        let table = versions.rows.entry(table_name.to_owned()).or_default();
+        for (location, raw_row) in overwritten {
+            table.entry(location).or_default().push((commit, raw_row));
+        }
+    }
+
+    /// Makes a commit visible to the snapshots taken from now on.
+    pub fn publish(&self, commit: CommitId) {
+        let mut versions = self.versions();
+        versions.committed = commit;
+        versions.collect_garbage();
+    }
+
+    /// Puts the rows of a block back the way they were at `commit`.
+    pub fn rewind(&self, table_name: &str, offset: u64, commit: CommitId, block: &mut Block) {
+        let versions = self.versions();
+        let Some(table) = versions.rows.get(table_name) else {
+            return;
+        };
+        for ((_, slot), history) in table.range((offset, 0)..(offset + 1, 0)) {
+            // The row as it was is whatever the first commit after the
+            // snapshot overwrote
+            if let Some((_, raw_row)) = history
+                .iter()
+                .find(|(overwritten, _)| *overwritten > commit)
+            {
+                write_row(block, *slot as usize, raw_row);
+            }
+        }
+    }
+
+    /// Where the rows of a table changed since `commit` are.
+    pub fn changed_since(&self, table_name: &str, commit: CommitId) -> Vec<RowLocation> {
+        let versions = self.versions();
+        let Some(table) = versions.rows.get(table_name) else {
+            return Vec::new();
+        };
+        table
+            .iter()
+            .filter(|(_, history)| history.iter().any(|(overwritten, _)| *overwritten > commit))
+            .map(|(location, _)| *location)
+            .collect()
+    }
+
+    /// Throws away the versions of a table whose file is removed or
+    /// replaced.
+    pub fn forget(&self, table_name: &str) {
+        self.versions().rows.remove(table_name);
+    }
+
+    /// Whether a snapshot could still read what the given commit overwrote.
+    pub fn still_seen(&self, overwritten: CommitId) -> bool {
+        self.versions().needs(overwritten)
+    }
+
+    fn release(&self, commit: CommitId) {
+        let mut versions = self.versions();
+        if let Some(count) = versions.open.get_mut(&commit) {
+            *count -= 1;
+            if *count == 0 {
+                versions.open.remove(&commit);
+            }
+        }
+        versions.collect_garbage();
+    }
+
+    fn versions(&self) -> MutexGuard<'_, Versions> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Versions {
+    /// Drops every version no open snapshot can read, which is any row
+    /// overwritten at or before the oldest of them, or all of them if none
+    /// are open. Rows a commit that's yet to be published overwrote are kept
+    /// for the snapshots taken before it is.
+    fn collect_garbage(&mut self) {
+        let mut rows = std::mem::take(&mut self.rows);
+        for table in rows.values_mut() {
+            table.retain(|_, history| {
+                history.retain(|(overwritten, _)| self.needs(*overwritten));
+                !history.is_empty()
+            });
+        }
+        rows.retain(|_, table| !table.is_empty());
+        self.rows = rows;
+    }
+
+    fn needs(&self, overwritten: CommitId) -> bool {
+        overwritten > self.committed
+            || self
+                .open
+                .keys()
+                .next()
+                .is_some_and(|oldest| overwritten > *oldest)
+    }
+
+    #[cfg(test)]
+    fn count(&self) -> usize {
+        self.rows
+            .values()
+            .flat_map(|table| table.values())
+            .map(Vec::len)
+            .sum()
+    }
+}
+
+/// A consistent view of the shared blocks as of one commit, open until it's
+/// dropped.
+pub struct Snapshot {
+    commit: CommitId,
+    store: Arc<VersionStore>,
+}
+
+impl Snapshot {
+    pub fn commit(&self) -> CommitId {
+        self.commit
+    }
+}
+
+impl Clone for Snapshot {
+    fn clone(&self) -> Self {
+        *self.store.versions().open.entry(self.commit).or_default() += 1;
+        Self {
+            commit: self.commit,
+            store: Arc::clone(&self.store),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.store.release(self.commit);
+    }
+}
+
+impl DataBase {
+    /// Reads a block as it was when a snapshot was taken.
+    pub(super) fn read_snapshot_block(
+        &self,
+        snapshot: &Snapshot,
+        table_name: &str,
+        offset: u64,
+    ) -> Result<Block, Error> {
+        let mut block = self.load_block_at(table_name, offset)?;
+        self.versions
+            .rewind(table_name, offset, snapshot.commit(), &mut block);
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{read_row, DataType, BLOCK_SIZE};
+    use crate::storage_engine::{
+        Action, Expression, FilterType, Grouping, Limit, Predicate, Projection, Reaction,
+        DEFAULT_SESSION,
+    };
+    use crate::test_utils::{mint, temp_db};
+
+    use super::*;
+
+    fn commit(store: &VersionStore, overwritten: Vec<(RowLocation, RawRow)>) {
+        let commit = store.next_commit();
+        store.record("heroes", commit, overwritten);
+        store.publish(commit);
+    }
+
+    #[test]
+    fn test_snapshot_rewinds_rows() {
+        let store = Arc::new(VersionStore::default());
+        let before = store.snapshot();
+        commit(&store, vec![((0, 0), vec![1, 10]), ((0, 1), vec![0, 0])]);
+        let between = store.snapshot();
+        commit(&store, vec![((0, 0), vec![1, 20])]);
+
+        // The block as it is after both commits
+        let mut latest = [0u8; BLOCK_SIZE];
+        write_row(&mut latest, 0, &vec![1, 30]);
+        write_row(&mut latest, 1, &vec![2, 5]);
+
+        let mut block = latest;
+        store.rewind("heroes", 0, before.commit(), &mut block);
+        assert_eq!(read_row(&block, 0, 2), vec![1, 10]);
+        assert_eq!(read_row(&block, 1, 2), vec![0, 0]);
+        let mut block = latest;
+        store.rewind("heroes", 0, between.commit(), &mut block);
+        assert_eq!(read_row(&block, 0, 2), vec![1, 20]);
+        assert_eq!(read_row(&block, 1, 2), vec![2, 5]);
+        // Other blocks are left alone
+        let mut block = latest;
+        store.rewind("heroes", 1, before.commit(), &mut block);
+        assert_eq!(block, latest);
+    }
+
+    #[test]
+    fn test_versions_collected_with_snapshots() {
+        let store = Arc::new(VersionStore::default());
+        // Nothing's kept when no one could read it
+        commit(&store, vec![((0, 0), vec![1, 10])]);
+        assert_eq!(store.versions().count(), 0);
+
+        let first = store.snapshot();
+        let copy = first.clone();
+        commit(&store, vec![((0, 0), vec![1, 20])]);
+        let second = store.snapshot();
+        commit(&store, vec![((0, 0), vec![1, 30])]);
+        assert_eq!(store.versions().count(), 2);
+
+        // Only the second snapshot reads the last version
+        drop(first);
+        assert_eq!(store.versions().count(), 2);
+        drop(copy);
+        assert_eq!(store.versions().count(), 1);
+        drop(second);
+        assert_eq!(store.versions().count(), 0);
+    }
+
+    #[test]
+    fn test_query_reads_its_snapshot() {
+        let (_dir, mut db) = temp_db("snapshot_query");
+        db.store("currency", vec![mint(0)]).unwrap();
+        let select = || {
+            Action::GetAll(
+                "currency".into(),
+                vec![Projection::Wildcard],
+                Grouping::default(),
+                vec![],
+                Limit::default(),
+            )
+        };
+        let qid = match db.execute(select()) {
+            Reaction::QueryStart { qid, .. } => qid,
+            _ => panic!("Query didn't start"),
+        };
+        let mut rows = match db.execute(Action::GetMore(qid, 10)) {
+            Reaction::Data(data) => data,
+            _ => panic!("Expected a batch of rows"),
+        };
+
+        // None of this shows up in the rest of the query
+        let all = || Predicate::Filter(FilterType::All);
+        let gold = Expression::Literal(DataType::Integer(100));
+        db.update(
+            DEFAULT_SESSION,
+            "currency",
+            vec![("Gold".into(), gold)],
+            all(),
+        )
+        .unwrap();
+        let first_ten = Predicate::Filter(FilterType::GreaterThan(
+            "index".into(),
+            DataType::Integer(11),
+        ));
+        db.delete(DEFAULT_SESSION, "currency", first_ten).unwrap();
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec![],
+            vec![vec![DataType::Integer(1); 4]],
+        )
+        .unwrap();
+        assert!(db.versions.versions().count() > 0);
+
+        while let Reaction::Data(data) = db.execute(Action::GetMore(qid, 50)) {
+            rows.extend(data);
+        }
+        assert_eq!(rows.len(), 204);
+        assert!(rows.iter().all(|row| match row[..] {
+            [DataType::Integer(index), _, DataType::Integer(gold), ..] => gold == index * 3 % 10,
+            _ => false,
+        }));
+        // Nobody needs the old rows once the query's done
+        assert_eq!(db.versions.versions().count(), 0);
+
+        // A new query sees everything
+        let qid = match db.execute(select()) {
+            Reaction::QueryStart { qid, .. } => qid,
+            _ => panic!("Query didn't start"),
+        };
+        let mut rows = Vec::new();
+        while let Reaction::Data(data) = db.execute(Action::GetMore(qid, 50)) {
+            rows.extend(data);
+        }
+        assert_eq!(rows.len(), 204 - 10 + 1);
+        assert!(rows
+            .iter()
+            .all(|row| row[2] == DataType::Integer(100) || row[1] == DataType::Integer(1)));
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+
+use crate::common::{
+    error::Error, overflow_reference, AsRawRows, ColumnType, Overflow, RawRow, BLOCK_SIZE,
+};
+
+use super::btree::{read_u64, write_u64};
+use super::hash::HashIndex;
+use super::index::{IndexKind, IndexPages};
+use super::mvcc::CommitId;
+use super::DataBase;
+
+/// The file text too long for its field overflows to. Table and index names
+/// can't hold a '-', so it never clashes with their files.
+///
+/// Its first page holds where the text after it ends. Each text is its
+/// length then its bytes, running on from one page to the next, and is
+/// referred to by where it starts. Text nothing refers to any more leaves a
+/// gap the next texts are written to.
+pub(super) const TEXT_FILE: &str = "overflow-text";
+/// A hash index of the text kept in the text file, so the same text is only
+/// ever kept once.
+pub(super) const TEXT_HASHES_FILE: &str = "overflow-text.hashes";
+
+const LENGTH_WIDTH: u64 = 8;
+
+/// What's kept in the text file and the gaps between it, locked before the
+/// cache.
+///
+/// A text's space is only free again once no committed row refers to it,
+/// no statement or transaction that wrote it is still running, and no
+/// snapshot can read a row that used to refer to it. What's free is worked
+/// out again from the rows whenever the database is opened, which also finds
+/// the text of rows whose changes never committed.
+#[derive(Default)]
+pub(super) struct Texts {
+    // How many committed rows refer to each text
+    counts: HashMap<u64, u64>,
+    // How many running statements and transactions wrote each text
+    pins: HashMap<u64, u64>,
+    // Where each gap starts, and how long it is
+    gaps: BTreeMap<u64, u64>,
+    // Texts that were left unreferenced, and the commit that left them so
+    released: Vec<(CommitId, u64)>,
+}
+
+/// How far the rows referring to each text went up or down.
+#[derive(Default)]
+pub(super) struct TextCounts(HashMap<u64, i64>);
+
+impl TextCounts {
+    /// Counts the texts a row refers to, `by` times over.
+    pub(super) fn add(&mut self, table_info: &[(String, ColumnType)], raw_row: &RawRow, by: i64) {
+        if raw_row[0] == 0 {
+            return;
+        }
+        for column in text_columns(table_info) {
+            if let Some(reference) = overflow_reference(raw_row[column]) {
+                *self.0.entry(reference).or_default() += by;
+            }
+        }
+    }
+}
+
+/// Spreads text over hash index keys, the same way every time.
+fn text_hash(text: &str) -> u64 {
+    // FNV-1a
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Overflow for DataBase {
+    fn read_text(&self, reference: u64) -> Result<String, Error> {
+        let length = u64::from_le_bytes(
+            self.read_text_bytes(reference, LENGTH_WIDTH)?
+                .try_into()
+                .expect("the length is 8 bytes"),
+        );
+        let bytes = self.read_text_bytes(reference + LENGTH_WIDTH, length)?;
+        String::from_utf8(bytes).map_err(|err| {
+            Error::SchemaError(format!("Text at {} isn't UTF-8: {}", reference, err))
+        })
+    }
+
+    /// Keeps text in the text file, unless it's already there, and pins it
+    /// until the statement or transaction writing it is done. The same text
+    /// is only ever kept once, however many rows refer to it.
+    fn write_text(&self, text: &str) -> Result<u64, Error> {
+        let mut texts = self.texts();
+        self.reclaim_texts(&mut texts)?;
+        let hashes = self.text_hashes();
+        let hash = text_hash(text);
+        for (reference, _) in hashes.get(hash)? {
+            if self.read_text(reference)? == text {
+                *texts.pins.entry(reference).or_default() += 1;
+                return Ok(reference);
+            }
+        }
+
+        let mut bytes = (text.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(text.as_bytes());
+        let reference = self.place_text(&mut texts, bytes.len() as u64)?;
+        self.write_text_bytes(reference, &bytes)?;
+        hashes.insert((hash, reference, 0))?;
+        texts.pins.insert(reference, 1);
+        Ok(reference)
+    }
+
+    fn read_object(&self, reference: u64) -> Result<Vec<u8>, Error> {
+        self.read_heap_object(reference)
+    }
+
+    /// Clob and Blob values go in the heap of the table they're stored in,
+    /// so they can only be written through that table.
+    fn write_object(&self, _bytes: &[u8]) -> Result<u64, Error> {
+        Err(Error::QueryError(
+            "Clob and Blob values can only be kept in a table".into(),
+        ))
+    }
+}
+
+impl DataBase {
+    /// Starts the text file and its hash index off empty, replacing any
+    /// left by a database that used to be here.
+    pub(super) fn create_overflow(&mut self) -> Result<(), Error> {
+        let text_path = self.path_info()?.generate_table_path(&TEXT_FILE.to_owned());
+        let text_file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(text_path)?;
+        self.tables.insert(TEXT_FILE.to_owned(), text_file);
+        self.write_index(TEXT_HASHES_FILE, IndexKind::Hash, &[])
+    }
+
+    /// Makes sure the text file and its hash index are open once the rest
+    /// of the database is, and works out which text the rows refer to.
+    /// Databases made before text could overflow don't have them yet. Space
+    /// no row refers to is free again, and the hash index is built again for
+    /// what's left.
+    pub(super) fn open_overflow(&mut self) -> Result<(), Error> {
+        if !self.tables.contains_key(TEXT_FILE) {
+            return self.create_overflow();
+        }
+        let mut counts = TextCounts::default();
+        for table_name in self.schema.keys() {
+            self.count_table_texts(table_name, 1, &mut counts)?;
+        }
+        let mut kept: Vec<u64> = counts.0.keys().copied().collect();
+        kept.sort_unstable();
+
+        let mut texts = Texts::default();
+        let mut entries = Vec::new();
+        let mut end = 0;
+        for reference in kept {
+            let text = self.read_text(reference)?;
+            if reference > end {
+                texts.gaps.insert(end, reference - end);
+            }
+            end = reference + LENGTH_WIDTH + text.len() as u64;
+            entries.push((text_hash(&text), reference, 0));
+        }
+        texts.counts = counts
+            .0
+            .into_iter()
+            .map(|(reference, count)| (reference, count as u64))
+            .collect();
+        let mut meta = [0u8; BLOCK_SIZE];
+        write_u64(&mut meta, 0, end);
+        self.store_block_at(TEXT_FILE, 0, &meta)?;
+        self.write_index(TEXT_HASHES_FILE, IndexKind::Hash, &entries)?;
+        *self.texts() = texts;
+        Ok(())
+    }
+
+    /// Counts the texts every row of a table refers to, `by` times over.
+    pub(super) fn count_table_texts(
+        &self,
+        table_name: &str,
+        by: i64,
+        counts: &mut TextCounts,
+    ) -> Result<(), Error> {
+        let table_info = self.table_info(table_name)?;
+        if text_columns(table_info).next().is_none() {
+            return Ok(());
+        }
+        for offset in 0..self.block_count(table_name)? {
+            for raw_row in self
+                .load_block_at(table_name, offset)?
+                .as_rows(table_info.len())
+            {
+                counts.add(table_info, &raw_row, by);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes what `commit` changed the rows referring to each text by into
+    /// account. Text left unreferenced is freed once no snapshot from before
+    /// the commit is open.
+    pub(super) fn count_texts(&self, commit: CommitId, counts: TextCounts) {
+        let mut texts = self.texts();
+        for (reference, by) in counts.0 {
+            let count = texts.counts.remove(&reference).unwrap_or(0) as i64 + by;
+            if count > 0 {
+                texts.counts.insert(reference, count as u64);
+            } else if by < 0 {
+                texts.released.push((commit, reference));
+            }
+        }
+    }
+
+    /// Lets go of the texts a statement or transaction wrote, now it's done.
+    /// Any it didn't leave a committed row referring to can be freed, once
+    /// the snapshots of the commits that left them unreferenced are done.
+    pub(super) fn unpin_texts(&self, references: Vec<u64>) {
+        // No committed row referred to it since, so none of the snapshots
+        // open now need it unless an earlier release says so
+        let commit = self.versions.next_commit() - 1;
+        let mut texts = self.texts();
+        for reference in references {
+            let Some(pins) = texts.pins.get_mut(&reference) else {
+                continue;
+            };
+            *pins -= 1;
+            if *pins == 0 {
+                texts.pins.remove(&reference);
+                if !texts.counts.contains_key(&reference) {
+                    texts.released.push((commit, reference));
+                }
+            }
+        }
+    }
+
+    /// Frees the released texts nothing refers to or pins any more, once no
+    /// snapshot can read a row that did. A text released more than once
+    /// waits for the last of them.
+    fn reclaim_texts(&self, texts: &mut Texts) -> Result<(), Error> {
+        let seen: HashSet<u64> = texts
+            .released
+            .iter()
+            .filter(|(commit, _)| self.versions.still_seen(*commit))
+            .map(|(_, reference)| *reference)
+            .collect();
+        let mut freed = HashSet::new();
+        let released = std::mem::take(&mut texts.released);
+        for (commit, reference) in released {
+            if seen.contains(&reference) {
+                texts.released.push((commit, reference));
+                continue;
+            }
+            if texts.counts.contains_key(&reference)
+                || texts.pins.contains_key(&reference)
+                || !freed.insert(reference)
+            {
+                continue;
+            }
+            let text = self.read_text(reference)?;
+            self.text_hashes()
+                .remove((text_hash(&text), reference, 0))?;
+            free_gap(&mut texts.gaps, reference, LENGTH_WIDTH + text.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// Where to write a text of `length` bytes, in the first gap it fits or
+    /// else at the end of the file.
+    fn place_text(&self, texts: &mut Texts, length: u64) -> Result<u64, Error> {
+        let gap = texts
+            .gaps
+            .iter()
+            .find(|(_, gap)| **gap >= length)
+            .map(|(start, gap)| (*start, *gap));
+        if let Some((start, gap)) = gap {
+            texts.gaps.remove(&start);
+            if gap > length {
+                texts.gaps.insert(start + length, gap - length);
+            }
+            return Ok(start);
+        }
+        let end = read_u64(&self.load_block_at(TEXT_FILE, 0)?, 0);
+        let mut meta = [0u8; BLOCK_SIZE];
+        write_u64(&mut meta, 0, end + length);
+        self.store_block_at(TEXT_FILE, 0, &meta)?;
+        Ok(end)
+    }
+
+    fn text_hashes(&self) -> HashIndex<IndexPages<'_>> {
+        HashIndex::new(IndexPages::new(self, TEXT_HASHES_FILE))
+    }
+
+    fn read_text_bytes(&self, start: u64, length: u64) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::with_capacity(length as usize);
+        let mut position = start;
+        while position < start + length {
+            let (page, at) = text_position(position);
+            let block = self.load_block_at(TEXT_FILE, page)?;
+            let taken = (BLOCK_SIZE - at).min((start + length - position) as usize);
+            bytes.extend_from_slice(&block[at..at + taken]);
+            position += taken as u64;
+        }
+        Ok(bytes)
+    }
+
+    fn write_text_bytes(&self, start: u64, bytes: &[u8]) -> Result<(), Error> {
+        let mut written = 0;
+        while written < bytes.len() {
+            let (page, at) = text_position(start + written as u64);
+            let mut block = self.load_block_at(TEXT_FILE, page)?;
+            let taken = (BLOCK_SIZE - at).min(bytes.len() - written);
+            block[at..at + taken].copy_from_slice(&bytes[written..written + taken]);
+            self.store_block_at(TEXT_FILE, page, &block)?;
+            written += taken;
+        }
+        Ok(())
+    }
+}
+
+/// The page, and where in it, a byte of text is. Text starts after the
+/// first page.
+fn text_position(position: u64) -> (u64, usize) {
+    (
+        1 + position / BLOCK_SIZE as u64,
+        (position % BLOCK_SIZE as u64) as usize,
+    )
+}
+
+/// Adds the space a text took to the gaps, joining it to those either side.
+fn free_gap(gaps: &mut BTreeMap<u64, u64>, mut start: u64, mut length: u64) {
+    if let Some(next) = gaps.remove(&(start + length)) {
+        length += next;
+    }
+    if let Some((before, gap)) = gaps.range(..start).next_back().map(|(at, gap)| (*at, *gap)) {
+        if before + gap == start {
+            gaps.remove(&before);
+            start = before;
+            length += gap;
+        }
+    }
+    gaps.insert(start, length);
+}
+
+/// The offsets of a table's Text columns.
+fn text_columns(table_info: &[(String, ColumnType)]) -> impl Iterator<Item = usize> + '_ {
+    table_info
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, column_type))| *column_type == ColumnType::Text)
+        .map(|(column, _)| column)
+}
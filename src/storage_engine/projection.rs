@@ -0,0 +1,387 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+use crate::common::{
+    convert_row_field, error::Error, ColumnType, DataType, Overflow, RawRow, Row, TableInfoMap,
+};
+
+use super::aggregate::Function;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expression {
+    Column(String),
+    Literal(DataType),
+    Negate(Box<Expression>),
+    Arithmetic(Box<Expression>, Operator, Box<Expression>),
+    // Only found in projections and sort keys, until aggregation takes it out
+    Aggregate(Function, Box<Expression>),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Projection {
+    // Every column of the source, in table order
+    Wildcard,
+    // An expression, and the name its output column goes by
+    Named(Expression, String),
+}
+
+impl Expression {
+    /// Works out what an expression will produce when run against rows of
+    /// `table_schema`, without needing a row to do it.
+    pub fn output_type(&self, table_schema: &TableInfoMap) -> Result<ColumnType, Error> {
+        match self {
+            Expression::Column(column) => match table_schema.get(column) {
+                Some((column_type, _)) => Ok(column_type.to_owned()),
+                None => Err(Error::SchemaError(format!(
+                    "Column {} does not exist",
+                    column
+                ))),
+            },
+            Expression::Literal(value) => Ok(value.column_type()),
+            Expression::Negate(inner) => integer_operand(inner, table_schema),
+            Expression::Arithmetic(left, _, right) => {
+                integer_operand(left, table_schema)?;
+                integer_operand(right, table_schema)
+            }
+            Expression::Aggregate(function, argument) => {
+                function.output_type(argument.output_type(table_schema)?)
+            }
+        }
+    }
+
+    pub fn has_aggregate(&self) -> bool {
+        match self {
+            Expression::Column(_) | Expression::Literal(_) => false,
+            Expression::Negate(inner) => inner.has_aggregate(),
+            Expression::Arithmetic(left, _, right) => left.has_aggregate() || right.has_aggregate(),
+            Expression::Aggregate(..) => true,
+        }
+    }
+
+    pub fn evaluate(
+        &self,
+        raw_row: &RawRow,
+        table_schema: &TableInfoMap,
+        overflow: &dyn Overflow,
+    ) -> Result<DataType, Error> {
+        match self {
+            Expression::Column(column) => {
+                let (to_type, offset) = table_schema.get(column).ok_or_else(|| {
+                    Error::SchemaError(format!("Column {} does not exist", column))
+                })?;
+                convert_row_field(raw_row, to_type, *offset, overflow).ok_or_else(|| {
+                    Error::SchemaError(format!("Row is missing column {}", column))
+                })?
+            }
+            Expression::Literal(value) => Ok(value.clone()),
+            Expression::Negate(inner) => match inner.evaluate(raw_row, table_schema, overflow)? {
+                DataType::Integer(value) => value
+                    .checked_neg()
+                    .map(DataType::Integer)
+                    .ok_or_else(|| Error::QueryError(format!("Integer overflow negating {value}"))),
+                value => Err(Error::QueryError(format!("Cannot negate {:?}", value))),
+            },
+            Expression::Arithmetic(left, operator, right) => match (
+                left.evaluate(raw_row, table_schema, overflow)?,
+                right.evaluate(raw_row, table_schema, overflow)?,
+            ) {
+                (DataType::Integer(left), DataType::Integer(right)) => {
+                    operator.apply(left, right).map(DataType::Integer)
+                }
+                (left, right) => Err(Error::QueryError(format!(
+                    "Cannot apply {:?} to {:?} and {:?}",
+                    operator, left, right
+                ))),
+            },
+            Expression::Aggregate(..) => Err(Error::QueryError(format!(
+                "{} can only be used in SELECT and ORDER BY",
+                self
+            ))),
+        }
+    }
+}
+
+/// Writes expressions out much like the SQL they came from. Aggregated
+/// columns are named this way, so it needs to be the same every time.
+impl Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expression::Column(column) => write!(f, "{}", column),
+            Expression::Literal(DataType::Integer(value)) => write!(f, "{}", value),
+            Expression::Literal(DataType::Boolean(value)) => write!(f, "{}", value),
+            Expression::Literal(DataType::Text(text)) => write!(f, "'{}'", text),
+            Expression::Literal(value) => write!(f, "{:?}", value),
+            Expression::Negate(inner) => write!(f, "-{}", inner),
+            Expression::Arithmetic(left, operator, right) => {
+                write!(f, "({} {} {})", left, operator, right)
+            }
+            Expression::Aggregate(function, argument) => write!(f, "{}({})", function, argument),
+        }
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Modulo => "%",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+fn integer_operand(
+    expression: &Expression,
+    table_schema: &TableInfoMap,
+) -> Result<ColumnType, Error> {
+    match expression.output_type(table_schema)? {
+        ColumnType::Integer => Ok(ColumnType::Integer),
+        other => Err(Error::QueryError(format!(
+            "Arithmetic needs Integer operands, {:?} is {:?}",
+            expression, other
+        ))),
+    }
+}
+
+impl Operator {
+    fn apply(&self, left: i64, right: i64) -> Result<i64, Error> {
+        let result = match self {
+            Operator::Add => left.checked_add(right),
+            Operator::Subtract => left.checked_sub(right),
+            Operator::Multiply => left.checked_mul(right),
+            Operator::Divide | Operator::Modulo if right == 0 => {
+                return Err(Error::QueryError("Division by zero".into()))
+            }
+            Operator::Divide => left.checked_div(right),
+            Operator::Modulo => left.checked_rem(right),
+        };
+        result.ok_or_else(|| {
+            Error::QueryError(format!("Integer overflow in {left} {:?} {right}", self))
+        })
+    }
+}
+
+/// Expands `projections` against the source schema, giving back the schema
+/// of the output rows and the expression that fills each output column.
+pub fn plan_projection(
+    projections: Vec<Projection>,
+    table_schema: &TableInfoMap,
+) -> Result<(TableInfoMap, Vec<Expression>), Error> {
+    let mut columns = Vec::new();
+    for projection in projections {
+        match projection {
+            Projection::Wildcard => {
+                // A column that goes by several names, like `Gold` and
+                // `currency.Gold`, is only listed once, under its shortest
+                let mut source: BTreeMap<u64, &String> = BTreeMap::new();
+                for (name, (_, offset)) in table_schema {
+                    let shortest = source.entry(*offset).or_insert(name);
+                    if (name.len(), name) < (shortest.len(), *shortest) {
+                        *shortest = name;
+                    }
+                }
+                for name in source.into_values() {
+                    columns.push((name.to_owned(), Expression::Column(name.to_owned())));
+                }
+            }
+            Projection::Named(expression, name) => columns.push((name, expression)),
+        }
+    }
+
+    let mut output_schema = HashMap::new();
+    let mut expressions = Vec::new();
+    for (offset, (name, expression)) in columns.into_iter().enumerate() {
+        let column_type = expression.output_type(table_schema)?;
+        if output_schema
+            .insert(name.to_owned(), (column_type, offset as u64))
+            .is_some()
+        {
+            return Err(Error::QueryError(format!(
+                "Column {} appears more than once in the output, use AS to rename it",
+                name
+            )));
+        }
+        expressions.push(expression);
+    }
+    Ok((output_schema, expressions))
+}
+
+pub fn project_row(
+    raw_row: &RawRow,
+    expressions: &[Expression],
+    table_schema: &TableInfoMap,
+    overflow: &dyn Overflow,
+) -> Result<Row, Error> {
+    expressions
+        .iter()
+        .map(|expression| expression.evaluate(raw_row, table_schema, overflow))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{map_table_info, TableInfo};
+    use crate::test_utils::MemoryOverflow;
+
+    use super::*;
+
+    fn test_schema() -> TableInfoMap {
+        let table_info: TableInfo = vec![
+            ("index".into(), ColumnType::Integer),
+            ("Gold".into(), ColumnType::Integer),
+            ("Silver".into(), ColumnType::Integer),
+            ("cursed".into(), ColumnType::Boolean),
+        ];
+        map_table_info(&table_info)
+    }
+
+    fn column(name: &str) -> Box<Expression> {
+        Box::new(Expression::Column(name.into()))
+    }
+
+    #[test]
+    fn test_projection_order_and_schema() {
+        let table_schema = test_schema();
+        let (output_schema, expressions) = plan_projection(
+            vec![
+                Projection::Named(Expression::Column("Silver".into()), "Silver".into()),
+                Projection::Named(Expression::Column("Gold".into()), "money".into()),
+                Projection::Named(Expression::Literal(DataType::Boolean(true)), "yes".into()),
+            ],
+            &table_schema,
+        )
+        .unwrap();
+
+        assert_eq!(output_schema.len(), 3);
+        assert_eq!(output_schema["Silver"], (ColumnType::Integer, 0));
+        assert_eq!(output_schema["money"], (ColumnType::Integer, 1));
+        assert_eq!(output_schema["yes"], (ColumnType::Boolean, 2));
+
+        let raw_row: RawRow = vec![7, 12, 3, 0];
+        assert_eq!(
+            project_row(
+                &raw_row,
+                &expressions,
+                &table_schema,
+                &MemoryOverflow::default()
+            )
+            .unwrap(),
+            vec![3.into(), 12.into(), true.into()]
+        );
+    }
+
+    #[test]
+    fn test_wildcard_projection() {
+        let table_schema = test_schema();
+        let (output_schema, expressions) = plan_projection(
+            vec![
+                Projection::Named(Expression::Column("cursed".into()), "first".into()),
+                Projection::Wildcard,
+            ],
+            &table_schema,
+        )
+        .unwrap();
+
+        assert_eq!(output_schema.len(), 5);
+        assert_eq!(output_schema["first"], (ColumnType::Boolean, 0));
+        assert_eq!(output_schema["index"], (ColumnType::Integer, 1));
+        assert_eq!(output_schema["cursed"], (ColumnType::Boolean, 4));
+
+        let raw_row: RawRow = vec![7, 12, 3, 1];
+        assert_eq!(
+            project_row(
+                &raw_row,
+                &expressions,
+                &table_schema,
+                &MemoryOverflow::default()
+            )
+            .unwrap(),
+            vec![true.into(), 7.into(), 12.into(), 3.into(), true.into()]
+        );
+    }
+
+    #[test]
+    fn test_computed_projection() {
+        let table_schema = test_schema();
+        // Gold * 10 + Silver, -Gold
+        let total = Expression::Arithmetic(
+            Box::new(Expression::Arithmetic(
+                column("Gold"),
+                Operator::Multiply,
+                Box::new(Expression::Literal(DataType::Integer(10))),
+            )),
+            Operator::Add,
+            column("Silver"),
+        );
+        let (output_schema, expressions) = plan_projection(
+            vec![
+                Projection::Named(total, "total".into()),
+                Projection::Named(Expression::Negate(column("Gold")), "debt".into()),
+            ],
+            &table_schema,
+        )
+        .unwrap();
+        assert_eq!(output_schema["total"], (ColumnType::Integer, 0));
+
+        let raw_row: RawRow = vec![7, 12, 3, 0];
+        assert_eq!(
+            project_row(
+                &raw_row,
+                &expressions,
+                &table_schema,
+                &MemoryOverflow::default()
+            )
+            .unwrap(),
+            vec![123.into(), (-12).into()]
+        );
+    }
+
+    #[test]
+    fn test_bad_projections() {
+        let table_schema = test_schema();
+        assert!(plan_projection(
+            vec![Projection::Named(
+                Expression::Column("Platinum".into()),
+                "Platinum".into()
+            )],
+            &table_schema,
+        )
+        .is_err());
+        assert!(plan_projection(
+            vec![Projection::Named(
+                Expression::Arithmetic(column("Gold"), Operator::Add, column("cursed")),
+                "nonsense".into()
+            )],
+            &table_schema,
+        )
+        .is_err());
+        assert!(plan_projection(
+            vec![
+                Projection::Named(Expression::Column("Gold".into()), "Gold".into()),
+                Projection::Wildcard,
+            ],
+            &table_schema,
+        )
+        .is_err());
+
+        let divide = vec![Expression::Arithmetic(
+            column("Gold"),
+            Operator::Divide,
+            Box::new(Expression::Literal(DataType::Integer(0))),
+        )];
+        let overflow = MemoryOverflow::default();
+        assert!(project_row(&vec![1, 1, 1, 0], &divide, &table_schema, &overflow).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::{BTreeSet, HashSet};
+
+use crate::common::{
+    error::Error, read_row, Block, RawRow, TableInfoMap, BLOCK_SIZE, COLUMN_WIDTH,
+};
+
+use super::allocator::RowLocation;
+use super::join::JoinedRows;
+use super::mvcc::Snapshot;
+use super::{DataBase, LockMode, Predicate, SessionId};
+
+/// Reads the live rows of a table matching a predicate, one block at a time
+/// as they're asked for, so a scan holds a single block no matter how big
+/// the table is. Rows are read as they were when the scan's snapshot was
+/// taken, or if it has none, as its session sees them and locked shared if
+/// it's in a transaction. A scan an index has narrowed down only reads the
+/// blocks of the rows it was given.
+pub struct TableScan {
+    session: SessionId,
+    table_name: String,
+    columns: usize,
+    predicate: Predicate,
+    table_schema: TableInfoMap,
+    snapshot: Option<Snapshot>,
+    // The only rows worth reading, if an index picked them out
+    locations: Option<BTreeSet<RowLocation>>,
+    next_block: u64,
+    // Matching rows of the last block read that haven't been taken yet
+    rows: std::vec::IntoIter<RawRow>,
+}
+
+impl TableScan {
+    pub fn new(
+        session: SessionId,
+        table_name: &str,
+        columns: usize,
+        predicate: Predicate,
+        table_schema: TableInfoMap,
+        snapshot: Option<Snapshot>,
+    ) -> Self {
+        Self {
+            session,
+            table_name: table_name.to_owned(),
+            columns,
+            predicate,
+            table_schema,
+            snapshot,
+            locations: None,
+            next_block: 0,
+            rows: Vec::new().into_iter(),
+        }
+    }
+
+    /// Reads no rows but these.
+    pub fn with_locations(mut self, locations: BTreeSet<RowLocation>) -> Self {
+        self.locations = Some(locations);
+        self
+    }
+
+    fn next_row(&mut self, db: &DataBase) -> Option<Result<RawRow, Error>> {
+        loop {
+            if let Some(raw_row) = self.rows.next() {
+                return Some(Ok(raw_row));
+            }
+            let offset = match &self.locations {
+                Some(locations) => locations.range((self.next_block, 0)..).next()?.0,
+                None => self.next_block,
+            };
+            // The table may have grown or shrunk since the last block
+            match db.visible_block_count(self.session, &self.table_name) {
+                Ok(block_count) if offset >= block_count => return None,
+                Ok(_) => (),
+                Err(err) => return Some(Err(err)),
+            }
+            let rows = match self.read_rows(db, offset) {
+                Ok(rows) => rows,
+                Err(err) => return Some(Err(err)),
+            };
+            self.next_block = offset + 1;
+            self.rows = rows.into_iter();
+        }
+    }
+
+    /// The matching rows of a block, locked if need be.
+    fn read_rows(&self, db: &DataBase, offset: u64) -> Result<Vec<RawRow>, Error> {
+        if let Some(snapshot) = &self.snapshot {
+            let block = db.read_snapshot_block(snapshot, &self.table_name, offset)?;
+            return Ok(self
+                .matching_rows(db, offset, &block)?
+                .into_iter()
+                .map(|(_, raw_row)| raw_row)
+                .collect());
+        }
+        let mut block = db.read_block(self.session, &self.table_name, offset)?;
+        if !db.in_transaction(self.session) {
+            return Ok(self
+                .matching_rows(db, offset, &block)?
+                .into_iter()
+                .map(|(_, raw_row)| raw_row)
+                .collect());
+        }
+        // Rows can change while their locks are waited for, and start
+        // matching, so the block is read again until every match is locked
+        let mut locked = HashSet::new();
+        loop {
+            let rows = self.matching_rows(db, offset, &block)?;
+            let unlocked: Vec<u64> = rows
+                .iter()
+                .map(|(slot, _)| *slot)
+                .filter(|slot| !locked.contains(slot))
+                .collect();
+            if unlocked.is_empty() {
+                return Ok(rows.into_iter().map(|(_, raw_row)| raw_row).collect());
+            }
+            for slot in unlocked {
+                db.lock_row(
+                    self.session,
+                    &self.table_name,
+                    (offset, slot),
+                    LockMode::Shared,
+                )?;
+                locked.insert(slot);
+            }
+            block = db.read_block(self.session, &self.table_name, offset)?;
+        }
+    }
+
+    fn matching_rows(
+        &self,
+        db: &DataBase,
+        offset: u64,
+        block: &Block,
+    ) -> Result<Vec<(u64, RawRow)>, Error> {
+        let rows_per_block = BLOCK_SIZE / (self.columns * COLUMN_WIDTH);
+        let mut rows = Vec::new();
+        for slot in 0..rows_per_block as u64 {
+            let wanted = self
+                .locations
+                .as_ref()
+                .is_none_or(|locations| locations.contains(&(offset, slot)));
+            if !wanted {
+                continue;
+            }
+            let raw_row = read_row(block, slot as usize, self.columns);
+            if raw_row[0] != 0 && self.predicate.evaluate(&raw_row, &self.table_schema, db)? {
+                rows.push((slot, raw_row));
+            }
+        }
+        Ok(rows)
+    }
+}
+
+/// The rows a query reads before grouping, sorting or projecting them.
+pub enum Rows {
+    Scan(Box<TableScan>),
+    Join(Box<JoinedRows>),
+}
+
+impl Rows {
+    pub fn next_row(&mut self, db: &DataBase) -> Option<Result<RawRow, Error>> {
+        match self {
+            Rows::Scan(scan) => scan.next_row(db),
+            Rows::Join(joined) => joined.next_row(db),
+        }
+    }
+
+    /// Borrows the rows as an iterator, reading from `db` as it goes.
+    pub fn read<'a>(
+        &'a mut self,
+        db: &'a DataBase,
+    ) -> impl Iterator<Item = Result<RawRow, Error>> + 'a {
+        std::iter::from_fn(move || self.next_row(db))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{map_table_info, DataType};
+    use crate::storage_engine::{FilterType, DEFAULT_SESSION};
+    use crate::test_utils::{mint, temp_db};
+
+    use super::*;
+
+    #[test]
+    fn test_scan_reads_lazily() {
+        let (_dir, db) = temp_db("table_scan");
+        db.store("currency", vec![mint(0), mint(204)]).unwrap();
+        let table_schema = map_table_info(&db.schema["currency"]);
+        let predicate = Predicate::Filter(FilterType::EqualTo("Gold".into(), DataType::Integer(3)));
+        let scan = TableScan::new(
+            DEFAULT_SESSION,
+            "currency",
+            5,
+            predicate,
+            table_schema,
+            None,
+        );
+        let mut rows = Rows::Scan(Box::new(scan));
+
+        // The first block is read, but nothing past it
+        let first = rows.next_row(&db).unwrap().unwrap();
+        assert_eq!(first[0], 1);
+        match &rows {
+            Rows::Scan(scan) => assert_eq!(scan.next_block, 1),
+            Rows::Join(_) => unreachable!(),
+        }
+
+        // Rows written after the scan started are picked up once it gets there
+        db.store("currency", vec![mint(0), mint(204), mint(408)])
+            .unwrap();
+        let rest = rows.read(&db).collect::<Result<Vec<_>, _>>().unwrap();
+        let expected = (1..=3 * 204).filter(|id| id * 3 % 10 == 3).count();
+        assert_eq!(rest.len() + 1, expected);
+        assert!(rest.iter().all(|raw_row| raw_row[2] == 3));
+    }
+}
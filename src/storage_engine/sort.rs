@@ -0,0 +1,463 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LE};
+
+use crate::common::{
+    error::Error, ColumnType, DataType, Row, TableInfoMap, BLOCK_SIZE, COLUMN_WIDTH,
+};
+
+use super::projection::Expression;
+
+/// How much row data a sort keeps in memory before spilling a run to disk.
+pub const SORT_BUFFER_SIZE: usize = 256 * BLOCK_SIZE;
+
+/// How many runs are merged at once. Past that, runs are merged a batch at a
+/// time into longer ones, so only a few files are ever open.
+const MERGE_FAN_IN: usize = 16;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+/// The type a sort key decodes to, and which way it sorts.
+pub type SortKey = (ColumnType, Direction);
+
+/// Resolves ORDER BY keys against a query, giving back the expression each
+/// key is computed with from a source row, and the type and direction of each.
+///
+/// A bare column name that isn't in the source table may name an output
+/// column instead, in which case the key sorts on that column's expression.
+pub fn plan_sort(
+    order_by: Vec<(Expression, Direction)>,
+    table_schema: &TableInfoMap,
+    output_schema: &TableInfoMap,
+    output_expressions: &[Expression],
+) -> Result<(Vec<Expression>, Vec<SortKey>), Error> {
+    let mut expressions = Vec::new();
+    let mut keys = Vec::new();
+    for (expression, direction) in order_by {
+        let expression = match expression {
+            Expression::Column(name) if !table_schema.contains_key(&name) => {
+                match output_schema.get(&name) {
+                    Some((_, offset)) => output_expressions[*offset as usize].clone(),
+                    None => Expression::Column(name),
+                }
+            }
+            expression => expression,
+        };
+        keys.push((expression.output_type(table_schema)?, direction));
+        expressions.push(expression);
+    }
+    Ok((expressions, keys))
+}
+
+fn compare_keys(keys: &[SortKey], left: &[DataType], right: &[DataType]) -> Ordering {
+    for ((_, direction), (left, right)) in keys.iter().zip(left.iter().zip(right)) {
+        let order = left.partial_cmp(right).unwrap_or(Ordering::Equal);
+        let order = match direction {
+            Direction::Ascending => order,
+            Direction::Descending => order.reverse(),
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Roughly how much memory a value takes up while it's being sorted.
+fn value_size(value: &DataType) -> usize {
+    match value {
+        DataType::Text(text) | DataType::Clob(text) => COLUMN_WIDTH + text.len(),
+        DataType::Blob(bytes) => COLUMN_WIDTH + bytes.len(),
+        _ => COLUMN_WIDTH,
+    }
+}
+
+type SortRecord = (Vec<DataType>, Row);
+
+/// Sorts rows by precomputed keys, spilling sorted runs to temporary files
+/// next to `spill_path` once more than `buffer_size` bytes are held, and
+/// merging them back together at the end.
+///
+/// Rows with equal keys come out in the order they were pushed.
+pub struct Sorter {
+    keys: Arc<[SortKey]>,
+    spill_path: PathBuf,
+    buffer_size: usize,
+    buffer: Vec<SortRecord>,
+    buffered: usize,
+    // Each run along with how many times its records have been merged.
+    // Levels never go up along the list.
+    runs: Vec<(usize, Run)>,
+}
+
+impl Sorter {
+    pub fn new(keys: Vec<SortKey>, spill_path: &Path, buffer_size: usize) -> Self {
+        Self {
+            keys: keys.into(),
+            spill_path: spill_path.to_owned(),
+            buffer_size,
+            buffer: Vec::new(),
+            buffered: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, key: Vec<DataType>, row: Row) -> Result<(), Error> {
+        self.buffered += key.iter().chain(&row).map(value_size).sum::<usize>();
+        self.buffer.push((key, row));
+        if self.buffered >= self.buffer_size {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort_buffer(&mut self) {
+        let keys = &self.keys;
+        self.buffer
+            .sort_by(|(left, _), (right, _)| compare_keys(keys, left, right));
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        self.sort_buffer();
+        let records = std::mem::take(&mut self.buffer);
+        self.buffered = 0;
+        let run = Run::write(&self.spill_path, records.into_iter().map(Ok))?;
+        self.runs.push((0, run));
+        // A full batch of runs at one level is merged into one at the next
+        while let Some(level) = self.full_level() {
+            let batch = self.runs.split_off(self.runs.len() - MERGE_FAN_IN);
+            let run = self.merge_runs(batch.into_iter().map(|(_, run)| run).collect())?;
+            self.runs.push((level + 1, run));
+        }
+        Ok(())
+    }
+
+    /// The level of the last `MERGE_FAN_IN` runs, if they're all at one.
+    fn full_level(&self) -> Option<usize> {
+        let batch = &self.runs[self.runs.len().checked_sub(MERGE_FAN_IN)?..];
+        let (level, _) = batch[0];
+        batch
+            .iter()
+            .all(|(other, _)| *other == level)
+            .then_some(level)
+    }
+
+    fn merge_runs(&self, runs: Vec<Run>) -> Result<Run, Error> {
+        let mut merge = Merge::new(&self.keys, runs)?;
+        Run::write(
+            &self.spill_path,
+            std::iter::from_fn(|| merge.next_record().transpose()),
+        )
+    }
+
+    pub fn finish(mut self) -> Result<SortedRows, Error> {
+        if self.runs.is_empty() {
+            self.sort_buffer();
+            return Ok(SortedRows::Memory(self.buffer.into_iter()));
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let mut runs: Vec<Run> = std::mem::take(&mut self.runs)
+            .into_iter()
+            .map(|(_, run)| run)
+            .collect();
+        while runs.len() > MERGE_FAN_IN {
+            let rest = runs.split_off(MERGE_FAN_IN);
+            let merged = self.merge_runs(runs)?;
+            runs = std::iter::once(merged).chain(rest).collect();
+        }
+        Ok(SortedRows::Merge(Merge::new(&self.keys, runs)?))
+    }
+}
+
+/// A sorted run of records in an unnamed temporary file, each written as
+/// its length and then its JSON.
+pub struct Run {
+    reader: BufReader<File>,
+    remaining: usize,
+}
+
+impl Run {
+    fn write<I>(spill_path: &Path, records: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = Result<SortRecord, Error>>,
+    {
+        let mut path = spill_path.as_os_str().to_owned();
+        path.push(format!(".sort-{:016x}", rand::random::<u64>()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // The open handle keeps the data around, and nothing is left behind
+        // if we crash mid-query
+        std::fs::remove_file(&path)?;
+
+        let mut remaining = 0;
+        let mut writer = BufWriter::new(file);
+        let mut length = [0u8; COLUMN_WIDTH];
+        for record in records {
+            let json = serde_json::to_vec(&record?)?;
+            remaining += 1;
+            LE::write_u64(&mut length, json.len() as u64);
+            writer.write_all(&length)?;
+            writer.write_all(&json)?;
+        }
+        let mut file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(Self {
+            reader: BufReader::new(file),
+            remaining,
+        })
+    }
+
+    fn next_record(&mut self) -> Result<Option<SortRecord>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        let mut length = [0u8; COLUMN_WIDTH];
+        self.reader.read_exact(&mut length)?;
+        let mut json = vec![0u8; LE::read_u64(&length) as usize];
+        self.reader.read_exact(&mut json)?;
+        Ok(Some(serde_json::from_slice(&json)?))
+    }
+}
+
+/// The smallest record of one run not yet merged. Ordered so the smallest
+/// key comes out of a `BinaryHeap` first, and earlier runs win ties, which
+/// keeps the sort stable.
+struct Head {
+    keys: Arc<[SortKey]>,
+    record: SortRecord,
+    run: usize,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(&self.keys, &other.record.0, &self.record.0).then(other.run.cmp(&self.run))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// Merges sorted runs into one sorted stream of records.
+pub struct Merge {
+    runs: Vec<Run>,
+    heads: BinaryHeap<Head>,
+}
+
+impl Merge {
+    fn new(keys: &Arc<[SortKey]>, mut runs: Vec<Run>) -> Result<Self, Error> {
+        let mut heads = BinaryHeap::with_capacity(runs.len());
+        for (run, source) in runs.iter_mut().enumerate() {
+            if let Some(record) = source.next_record()? {
+                heads.push(Head {
+                    keys: keys.clone(),
+                    record,
+                    run,
+                });
+            }
+        }
+        Ok(Self { runs, heads })
+    }
+
+    fn next_record(&mut self) -> Result<Option<SortRecord>, Error> {
+        let Some(Head { keys, record, run }) = self.heads.pop() else {
+            return Ok(None);
+        };
+        if let Some(next) = self.runs[run].next_record()? {
+            self.heads.push(Head {
+                keys,
+                record: next,
+                run,
+            });
+        }
+        Ok(Some(record))
+    }
+}
+
+pub enum SortedRows {
+    Memory(std::vec::IntoIter<SortRecord>),
+    Merge(Merge),
+}
+
+impl Iterator for SortedRows {
+    type Item = Result<Row, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            SortedRows::Memory(records) => records.next().map(|(_, row)| Ok(row)),
+            SortedRows::Merge(merge) => merge
+                .next_record()
+                .transpose()
+                .map(|record| record.map(|(_, row)| row)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::map_table_info;
+    use crate::test_utils::TempDir;
+
+    use super::*;
+
+    fn sort(keys: Vec<SortKey>, rows: Vec<Row>, spill_path: &Path, buffer_size: usize) -> Vec<Row> {
+        let mut sorter = Sorter::new(keys.to_owned(), spill_path, buffer_size);
+        for row in rows {
+            let key = row[..keys.len()].to_vec();
+            sorter.push(key, row).unwrap();
+        }
+        sorter.finish().unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_multi_column_sort() {
+        let dir = TempDir::new("sort_memory");
+        let rows: Vec<Row> = [[1, 5, 1], [3, 2, 2], [3, 7, 3], [-4, 0, 4], [1, 5, 5]]
+            .iter()
+            .map(|row| row.map(DataType::Integer).to_vec())
+            .collect();
+        let keys = vec![
+            (ColumnType::Integer, Direction::Descending),
+            (ColumnType::Integer, Direction::Ascending),
+        ];
+        let sorted = sort(keys, rows, &dir.db_path(), SORT_BUFFER_SIZE);
+        let order: Vec<DataType> = sorted.iter().map(|row| row[2].to_owned()).collect();
+        assert_eq!(order, [2, 3, 1, 5, 4].map(DataType::Integer));
+    }
+
+    #[test]
+    fn test_external_merge_sort() {
+        let dir = TempDir::new("sort_external");
+        // A few hundred rows through a buffer that holds about ten of them
+        let rows: Vec<Row> = (0..500i64)
+            .map(|i| {
+                vec![
+                    ((i * 7919) % 61).into(),
+                    i.into(),
+                    format!("row {}", i).into(),
+                ]
+            })
+            .collect();
+        let keys = vec![(ColumnType::Integer, Direction::Ascending)];
+        let sorted = sort(keys, rows, &dir.db_path(), 10 * 5 * COLUMN_WIDTH);
+
+        assert_eq!(sorted.len(), 500);
+        for pair in sorted.windows(2) {
+            assert!(
+                pair[0][0] < pair[1][0] || (pair[0][0] == pair[1][0] && pair[0][1] < pair[1][1])
+            );
+        }
+        // Rows come back out of the runs whole
+        for row in &sorted {
+            let DataType::Integer(i) = row[1] else {
+                panic!("{:?} isn't an Integer", row[1]);
+            };
+            assert_eq!(row[2], format!("row {}", i).into());
+        }
+        // The run files are gone as soon as they're created
+        assert_eq!(std::fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_merge_in_passes() {
+        let dir = TempDir::new("sort_passes");
+        let keys = vec![(ColumnType::Integer, Direction::Ascending)];
+        // Every row spills a run of its own
+        let mut sorter = Sorter::new(keys, &dir.db_path(), 1);
+        for i in 0..2000i64 {
+            sorter.push(vec![(i % 7).into()], vec![i.into()]).unwrap();
+            // Runs are merged as they pile up, so few are open at once
+            assert!(sorter.runs.len() < 3 * MERGE_FAN_IN);
+        }
+        let sorted: Vec<Row> = sorter.finish().unwrap().collect::<Result<_, _>>().unwrap();
+
+        // Sorted by key, with equal keys still in the order they came
+        let expected: Vec<Row> = (0..7)
+            .flat_map(|key| (key..2000).step_by(7))
+            .map(|i: i64| vec![i.into()])
+            .collect();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_large_values_spill() {
+        let dir = TempDir::new("sort_large");
+        let keys = vec![(ColumnType::Integer, Direction::Ascending)];
+        let mut sorter = Sorter::new(keys, &dir.db_path(), 4 * BLOCK_SIZE);
+        // Portraits and backstories count for their whole size
+        for i in 0..8i64 {
+            let row = vec![
+                DataType::Blob(vec![i as u8; BLOCK_SIZE]),
+                DataType::Clob("x".repeat(BLOCK_SIZE)),
+            ];
+            sorter.push(vec![(-i).into()], row).unwrap();
+        }
+        assert!(!sorter.runs.is_empty());
+        let sorted: Vec<Row> = sorter.finish().unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(sorted[0][0], DataType::Blob(vec![7; BLOCK_SIZE]));
+    }
+
+    #[test]
+    fn test_plan_sort_resolves_output_names() {
+        let table_schema = map_table_info(&vec![
+            ("index".into(), ColumnType::Integer),
+            ("Gold".into(), ColumnType::Integer),
+        ]);
+        let output_schema = map_table_info(&vec![("wealth".into(), ColumnType::Integer)]);
+        let output_expressions = vec![Expression::Column("Gold".into())];
+
+        let (expressions, keys) = plan_sort(
+            vec![
+                (Expression::Column("wealth".into()), Direction::Descending),
+                (Expression::Column("index".into()), Direction::Ascending),
+            ],
+            &table_schema,
+            &output_schema,
+            &output_expressions,
+        )
+        .unwrap();
+        assert_eq!(
+            expressions,
+            vec![
+                Expression::Column("Gold".into()),
+                Expression::Column("index".into())
+            ]
+        );
+        assert_eq!(keys[0], (ColumnType::Integer, Direction::Descending));
+
+        assert!(plan_sort(
+            vec![(Expression::Column("Mithril".into()), Direction::Ascending)],
+            &table_schema,
+            &output_schema,
+            &output_expressions,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::Ordering;
+use std::sync::MutexGuard;
+
+use crate::common::{error::Error, read_row, write_row, Block, RawRow};
+
+use super::allocator::RowLocation;
+use super::mvcc::CommitId;
+use super::overflow::TextCounts;
+use super::DataBase;
+
+/// Tells apart the clients sharing a database, so each gets its own
+/// transaction.
+pub type SessionId = u64;
+
+/// The session `execute` acts for, for callers that only ever need one.
+pub const DEFAULT_SESSION: SessionId = 0;
+
+/// Rows a transaction has written but not committed. They're kept out of the
+/// shared blocks, so no other session sees them, and laid over those blocks
+/// whenever the transaction reads.
+#[derive(Default)]
+pub struct Transaction {
+    // The latest image of each row written, by table and location. Deleted
+    // rows are all zeros.
+    writes: HashMap<String, BTreeMap<RowLocation, RawRow>>,
+    // Slots taken by inserts, handed back if the transaction rolls back
+    pub taken: Vec<(String, RowLocation)>,
+    // Slots emptied by deletes, only free for reuse once it commits
+    pub freed: Vec<(String, RowLocation)>,
+    // Chains written to heaps for its rows, handed back unless it commits
+    // rows that still refer to them
+    pub objects: Vec<(String, u64)>,
+    // Texts written for its rows, pinned until it's done
+    pub texts: Vec<u64>,
+}
+
+impl Transaction {
+    pub fn write(&mut self, table_name: &str, location: RowLocation, raw_row: RawRow) {
+        self.writes
+            .entry(table_name.to_owned())
+            .or_default()
+            .insert(location, raw_row);
+    }
+
+    /// Lays the rows written to a block over what's been committed.
+    pub fn apply(&self, table_name: &str, offset: u64, block: &mut Block) {
+        if let Some(rows) = self.writes.get(table_name) {
+            for ((_, slot), raw_row) in rows.range((offset, 0)..(offset + 1, 0)) {
+                write_row(block, *slot as usize, raw_row);
+            }
+        }
+    }
+
+    /// One past the last block the transaction has written to.
+    pub fn block_count(&self, table_name: &str) -> u64 {
+        self.writes
+            .get(table_name)
+            .and_then(|rows| rows.keys().next_back())
+            .map_or(0, |(offset, _)| offset + 1)
+    }
+
+    /// Where every row the transaction has written to a table is.
+    pub fn written(&self, table_name: &str) -> Vec<RowLocation> {
+        self.writes
+            .get(table_name)
+            .map(|rows| rows.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn touches(&self, table_name: &str) -> bool {
+        self.writes.contains_key(table_name)
+    }
+
+    /// Every row written, by table, ready to be applied on commit.
+    pub fn into_writes(self) -> HashMap<String, BTreeMap<RowLocation, RawRow>> {
+        self.writes
+    }
+}
+
+impl DataBase {
+    /// Starts a new session, for a client that may want transactions of its
+    /// own.
+    pub fn connect(&self) -> SessionId {
+        self.next_session.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Ends a session, rolling back its transaction, forgetting its queries
+    /// and letting go of its locks.
+    pub fn disconnect(&self, session: SessionId) {
+        if self.in_transaction(session) {
+            self.rollback(session)
+                .expect("there's a transaction to roll back");
+        }
+        self.queries().retain(|_, query| query.session != session);
+        self.locks.release_all(session);
+    }
+
+    pub fn begin(&self, session: SessionId) -> Result<(), Error> {
+        let mut transactions = self.transactions();
+        if transactions.contains_key(&session) {
+            return Err(Error::QueryError(
+                "A transaction is already in progress".into(),
+            ));
+        }
+        transactions.insert(session, Transaction::default());
+        Ok(())
+    }
+
+    pub fn in_transaction(&self, session: SessionId) -> bool {
+        self.transactions().contains_key(&session)
+    }
+
+    /// Makes every change of a session's transaction visible at once. The
+    /// rows are written to their blocks and logged together, so a crash
+    /// keeps all of them or none.
+    pub fn commit(&self, session: SessionId) -> Result<(), Error> {
+        let mut transaction = self.take_transaction(session)?;
+        let freed = std::mem::take(&mut transaction.freed);
+        let objects = std::mem::take(&mut transaction.objects);
+        let texts = std::mem::take(&mut transaction.texts);
+        let writes = transaction.into_writes();
+        let referred = self.referred_objects(&writes);
+        let result = self.apply_writes(writes);
+        if result.is_ok() {
+            self.free_slots(freed);
+        }
+        // Values a later statement of the transaction replaced were never
+        // seen by anyone else
+        self.free_objects(
+            objects
+                .into_iter()
+                .filter(|(_, reference)| result.is_err() || !referred.contains(reference)),
+        );
+        self.unpin_texts(texts);
+        self.locks.release_all(session);
+        result
+    }
+
+    /// Throws away every change of a session's transaction.
+    pub fn rollback(&self, session: SessionId) -> Result<(), Error> {
+        let transaction = self.take_transaction(session)?;
+        self.free_slots(transaction.taken);
+        self.free_objects(transaction.objects);
+        self.unpin_texts(transaction.texts);
+        self.locks.release_all(session);
+        Ok(())
+    }
+
+    fn take_transaction(&self, session: SessionId) -> Result<Transaction, Error> {
+        self.transactions()
+            .remove(&session)
+            .ok_or_else(|| Error::QueryError("No transaction is in progress".into()))
+    }
+
+    fn free_slots(&self, slots: Vec<(String, RowLocation)>) {
+        let mut allocators = self.allocators();
+        for (table_name, location) in slots {
+            if let Some(allocator) = allocators.get_mut(&table_name) {
+                allocator.free_slot(location);
+            }
+        }
+    }
+
+    pub(super) fn transactions(&self) -> MutexGuard<'_, HashMap<SessionId, Transaction>> {
+        self.transactions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Reads a block as a session sees it, with its uncommitted rows in place.
+    pub(super) fn read_block(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        offset: u64,
+    ) -> Result<Block, Error> {
+        let mut block = self.load_block_at(table_name, offset)?;
+        if let Some(transaction) = self.transactions().get(&session) {
+            transaction.apply(table_name, offset, &mut block);
+        }
+        Ok(block)
+    }
+
+    /// How many blocks a table has as a session sees it.
+    pub(super) fn visible_block_count(
+        &self,
+        session: SessionId,
+        table_name: &str,
+    ) -> Result<u64, Error> {
+        let committed = self.block_count(table_name)?;
+        Ok(match self.transactions().get(&session) {
+            Some(transaction) => committed.max(transaction.block_count(table_name)),
+            None => committed,
+        })
+    }
+
+    /// Writes rows for a session, holding them back until commit if it's in
+    /// a transaction, or writing and logging them straight away if not.
+    pub(super) fn write_rows(
+        &self,
+        session: SessionId,
+        table_name: &str,
+        rows: Vec<(RowLocation, RawRow)>,
+    ) -> Result<(), Error> {
+        if let Some(transaction) = self.transactions().get_mut(&session) {
+            for (location, raw_row) in rows {
+                transaction.write(table_name, location, raw_row);
+            }
+            return Ok(());
+        }
+        self.apply_writes([(table_name.to_owned(), rows)])
+    }
+
+    /// Writes the rows of a statement or a transaction into the shared
+    /// blocks, then logs them as one commit. One set of writes goes in at a
+    /// time, so none of them can end up in another's commit. If any of it
+    /// fails, the blocks it changed are put back as they were.
+    fn apply_writes<I, R>(&self, writes: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (String, R)>,
+        R: IntoIterator<Item = (RowLocation, RawRow)>,
+    {
+        let _applying = self.applying();
+        let commit = self.versions.next_commit();
+        let mut changed = Vec::new();
+        let mut replaced = Vec::new();
+        let mut texts = TextCounts::default();
+        let result = writes
+            .into_iter()
+            .try_for_each(|(table_name, rows)| {
+                changed.push(table_name.to_owned());
+                changed.extend(self.index_files(&table_name));
+                let objects = self.apply_rows(&table_name, rows, commit, &mut texts)?;
+                replaced.push((table_name, objects));
+                Ok(())
+            })
+            .and_then(|_| self.flush());
+        match &result {
+            Ok(()) => {
+                for (table_name, objects) in replaced {
+                    self.release_objects(&table_name, commit, objects);
+                }
+                self.count_texts(commit, texts);
+            }
+            Err(_) => self.cache().discard_unlogged(&changed),
+        }
+        self.versions.publish(commit);
+        result
+    }
+
+    /// Writes rows into the shared blocks as part of `commit`, reading each
+    /// block once, and moves them in the table's indexes. The rows they
+    /// replace are kept for the snapshots that still need them. Gives back
+    /// the chains of long values the rows no longer refer to, and counts how
+    /// the rows referring to each text changed.
+    fn apply_rows<I>(
+        &self,
+        table_name: &str,
+        rows: I,
+        commit: CommitId,
+        texts: &mut TextCounts,
+    ) -> Result<Vec<u64>, Error>
+    where
+        I: IntoIterator<Item = (RowLocation, RawRow)>,
+    {
+        let mut blocks: BTreeMap<u64, Block> = BTreeMap::new();
+        let mut overwritten = Vec::new();
+        let mut replaced = Vec::new();
+        for ((offset, slot), raw_row) in rows {
+            let block = match blocks.entry(offset) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(self.load_block_at(table_name, offset)?),
+            };
+            let old = read_row(block, slot as usize, raw_row.len());
+            self.reindex_row(table_name, (offset, slot), &old, &raw_row)?;
+            replaced.extend(self.replaced_objects(table_name, &old, &raw_row)?);
+            let table_info = self.table_info(table_name)?;
+            texts.add(table_info, &old, -1);
+            texts.add(table_info, &raw_row, 1);
+            overwritten.push(((offset, slot), old));
+            write_row(block, slot as usize, &raw_row);
+        }
+        self.versions.record(table_name, commit, overwritten);
+        for (offset, block) in blocks.iter() {
+            self.store_block_at(table_name, *offset, block)?;
+        }
+        Ok(replaced)
+    }
+
+    /// Table changes can't be undone, so they aren't allowed in a transaction.
+    pub(super) fn check_outside_transaction(&self, session: SessionId) -> Result<(), Error> {
+        if self.in_transaction(session) {
+            return Err(Error::QueryError(
+                "Tables can't be changed inside a transaction".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Nor can a table be changed while some transaction has uncommitted rows
+    /// for it.
+    pub(super) fn check_table_change(
+        &self,
+        session: SessionId,
+        table_name: &str,
+    ) -> Result<(), Error> {
+        self.check_outside_transaction(session)?;
+        if self
+            .transactions()
+            .values()
+            .any(|transaction| transaction.touches(table_name))
+        {
+            return Err(Error::QueryError(format!(
+                "Table {} has uncommitted changes",
+                table_name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{read_row, DataType, BLOCK_SIZE};
+    use crate::storage_engine::{
+        Action, Expression, FilterType, Grouping, Limit, Predicate, Projection, Reaction,
+    };
+    use crate::test_utils::temp_db;
+
+    use super::*;
+
+    /// Every (index, Gold) pair of currency, as a session sees it.
+    fn gold(db: &mut DataBase, session: SessionId) -> Vec<(u64, u64)> {
+        let select = Action::GetAll(
+            "currency".into(),
+            vec![Projection::Wildcard],
+            Grouping::default(),
+            vec![],
+            Limit::default(),
+        );
+        let qid = match db.execute_in(session, select) {
+            Reaction::QueryStart { qid, .. } => qid,
+            _ => panic!("Query didn't start"),
+        };
+        let mut rows = Vec::new();
+        while let Reaction::Data(data) = db.execute_in(session, Action::GetMore(qid, 100)) {
+            rows.extend(data.iter().map(|row| match row[..] {
+                [DataType::Integer(index), _, DataType::Integer(gold), ..] => {
+                    (index as u64, gold as u64)
+                }
+                _ => panic!("{:?} isn't a row of currency", row),
+            }));
+        }
+        rows
+    }
+
+    fn set_gold(db: &mut DataBase, session: SessionId, index: i64, gold: i64) {
+        let reaction = db.execute_in(
+            session,
+            Action::Update(
+                "currency".into(),
+                vec![("Gold".into(), Expression::Literal(DataType::Integer(gold)))],
+                Predicate::Filter(FilterType::EqualTo(
+                    "index".into(),
+                    DataType::Integer(index),
+                )),
+            ),
+        );
+        assert!(matches!(reaction, Reaction::Affected(1)));
+    }
+
+    #[test]
+    fn test_writes_overlay_blocks() {
+        let mut transaction = Transaction::default();
+        transaction.write("heroes", (0, 1), vec![7, 70]);
+        transaction.write("heroes", (2, 0), vec![8, 80]);
+        transaction.write("heroes", (0, 1), vec![7, 71]);
+
+        let mut block = [0u8; BLOCK_SIZE];
+        write_row(&mut block, 0, &vec![1, 10]);
+        transaction.apply("heroes", 0, &mut block);
+        assert_eq!(read_row(&block, 0, 2), vec![1, 10]);
+        assert_eq!(read_row(&block, 1, 2), vec![7, 71]);
+
+        // Writes to other blocks and tables stay out of it
+        let mut block = [0u8; BLOCK_SIZE];
+        transaction.apply("heroes", 1, &mut block);
+        transaction.apply("purses", 2, &mut block);
+        assert_eq!(block, [0u8; BLOCK_SIZE]);
+
+        assert_eq!(transaction.block_count("heroes"), 3);
+        assert_eq!(transaction.block_count("purses"), 0);
+        assert!(!transaction.touches("purses"));
+    }
+
+    #[test]
+    fn test_commit_is_atomic_and_isolated() {
+        let (_dir, mut db) = temp_db("transaction_commit");
+        let purse = |gold| vec![DataType::Integer(gold)];
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Gold".into()],
+            vec![purse(50), purse(0)],
+        )
+        .unwrap();
+        let (alice, bob) = (db.connect(), db.connect());
+
+        assert!(matches!(
+            db.execute_in(alice, Action::Begin),
+            Reaction::Done
+        ));
+        set_gold(&mut db, alice, 1, 20);
+        set_gold(&mut db, alice, 2, 30);
+        db.insert(alice, "currency", vec!["Gold".into()], vec![purse(7)])
+            .unwrap();
+        // Alice sees her own changes, Bob sees none of them
+        assert_eq!(gold(&mut db, alice), vec![(1, 20), (2, 30), (3, 7)]);
+        assert_eq!(gold(&mut db, bob), vec![(1, 50), (2, 0)]);
+
+        // Bob's own change to another row in the same block survives the commit
+        db.insert(bob, "currency", vec!["Gold".into()], vec![purse(9)])
+            .unwrap();
+        assert!(matches!(
+            db.execute_in(alice, Action::Commit),
+            Reaction::Done
+        ));
+        assert_eq!(gold(&mut db, bob), vec![(1, 20), (2, 30), (3, 7), (4, 9)]);
+        assert!(matches!(
+            db.execute_in(alice, Action::Commit),
+            Reaction::Error(_)
+        ));
+    }
+
+    #[test]
+    fn test_rollback_and_disconnect() {
+        let (_dir, mut db) = temp_db("transaction_rollback");
+        let purse = |gold| vec![DataType::Integer(gold)];
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Gold".into()],
+            vec![purse(50)],
+        )
+        .unwrap();
+        let session = db.connect();
+
+        db.begin(session).unwrap();
+        set_gold(&mut db, session, 1, 0);
+        db.delete(session, "currency", Predicate::Filter(FilterType::All))
+            .unwrap();
+        db.insert(session, "currency", vec!["Gold".into()], vec![purse(1)])
+            .unwrap();
+        assert_eq!(gold(&mut db, session), vec![(2, 1)]);
+        // Tables can't change underneath a transaction
+        assert!(matches!(
+            db.execute(Action::DropTable("currency".into(), false)),
+            Reaction::Error(_)
+        ));
+        db.rollback(session).unwrap();
+        assert_eq!(gold(&mut db, session), vec![(1, 50)]);
+
+        // The slot the rolled back insert took is free again
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into()],
+                vec![purse(2)],
+            )
+            .unwrap();
+        let block = db.load_block_at("currency", 0).unwrap();
+        assert_eq!(read_row(&block, 1, 5), vec![ids[0], 0, 2, 0, 0]);
+
+        db.begin(session).unwrap();
+        set_gold(&mut db, session, 1, 0);
+        db.disconnect(session);
+        assert_eq!(gold(&mut db, DEFAULT_SESSION), vec![(1, 50), (ids[0], 2)]);
+    }
+
+    #[test]
+    fn test_failed_commit_changes_nothing() {
+        let (dir, mut db) = temp_db("transaction_failed");
+        let purse = |gold| vec![DataType::Integer(gold)];
+        db.insert(
+            DEFAULT_SESSION,
+            "currency",
+            vec!["Gold".into()],
+            vec![purse(50), purse(0)],
+        )
+        .unwrap();
+
+        // The attributes go in whole, and so does the first purse, before
+        // the second turns out to take an id that's in use
+        let writes = vec![
+            (
+                "attributes".to_owned(),
+                vec![((0, 0), vec![1, 10, 0, 0, 0, 0, 0])],
+            ),
+            (
+                "currency".to_owned(),
+                vec![((0, 2), vec![3, 0, 7, 0, 0]), ((0, 3), vec![1, 0, 9, 0, 0])],
+            ),
+        ];
+        assert!(db.apply_writes(writes).is_err());
+        assert_eq!(
+            db.load_block_at("attributes", 0).unwrap(),
+            [0u8; BLOCK_SIZE]
+        );
+        assert_eq!(gold(&mut db, DEFAULT_SESSION), vec![(1, 50), (2, 0)]);
+
+        // Nothing of it is left in the indexes to get logged by the next
+        // commit, which can have the id the failed one tried for
+        let ids = db
+            .insert(
+                DEFAULT_SESSION,
+                "currency",
+                vec!["Gold".into()],
+                vec![purse(8)],
+            )
+            .unwrap();
+        assert_eq!(ids, vec![3]);
+        drop(db);
+        let mut db = DataBase::open(&dir.db_path()).unwrap();
+        assert_eq!(
+            gold(&mut db, DEFAULT_SESSION),
+            vec![(1, 50), (2, 0), (3, 8)]
+        );
+        assert_eq!(
+            db.load_block_at("attributes", 0).unwrap(),
+            [0u8; BLOCK_SIZE]
+        );
+    }
+}
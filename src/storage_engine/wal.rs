@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::prelude::FileExt;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LE};
+
+use crate::common::{error::Error, Block, BLOCK_SIZE};
+
+/// How big the log is allowed to get before its blocks are written to their
+/// tables and it's truncated.
+pub const CHECKPOINT_SIZE: u64 = 1024 * BLOCK_SIZE as u64;
+
+const BLOCK_RECORD: u64 = 1;
+const COMMIT_RECORD: u64 = 2;
+
+/// A redo log of whole block images, kept beside the schema file.
+///
+/// Changed blocks are appended and synced before any of them are written to
+/// their tables, followed by a commit record, so a crash can neither tear a
+/// block nor apply only part of a statement. Each record ends in a checksum,
+/// which stops replay at a record cut short by a crash.
+pub struct WriteAheadLog {
+    file: File,
+    size: u64,
+}
+
+/// A block as the log last saw it, by table and offset.
+pub type LoggedBlocks = HashMap<(String, u64), Block>;
+
+impl WriteAheadLog {
+    pub fn open(db_path: &Path) -> Result<Self, Error> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_path(db_path))?;
+        let size = file.metadata()?.len();
+        Ok(Self { file, size })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Appends blocks as one commit, returning once they're on disk.
+    pub fn append<'a, I>(&mut self, blocks: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (&'a str, u64, &'a Block)>,
+    {
+        let mut records = Vec::new();
+        for (table_name, offset, block) in blocks {
+            let start = records.len();
+            push_u64(&mut records, BLOCK_RECORD);
+            push_u64(&mut records, table_name.len() as u64);
+            records.extend_from_slice(table_name.as_bytes());
+            push_u64(&mut records, offset);
+            records.extend_from_slice(block);
+            let checksum = checksum(&records[start..]);
+            push_u64(&mut records, checksum);
+        }
+        let start = records.len();
+        push_u64(&mut records, COMMIT_RECORD);
+        let checksum = checksum(&records[start..]);
+        push_u64(&mut records, checksum);
+
+        self.file.write_all_at(&records, self.size)?;
+        self.file.sync_data()?;
+        self.size += records.len() as u64;
+        Ok(())
+    }
+
+    /// Reads back every block of every commit that made it to disk whole,
+    /// keeping the latest image of each.
+    pub fn replay(&mut self) -> Result<LoggedBlocks, Error> {
+        let mut log = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut log)?;
+
+        let mut committed = LoggedBlocks::new();
+        let mut pending = Vec::new();
+        let mut reader = LogReader { log: &log, at: 0 };
+        while let Some(record) = reader.next_record() {
+            match record {
+                Record::Block(table_name, offset, block) => {
+                    pending.push(((table_name, offset), *block))
+                }
+                Record::Commit => committed.extend(pending.drain(..)),
+            }
+        }
+        Ok(committed)
+    }
+
+    /// Empties the log, once everything in it is safely in the tables.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Writes every committed block in the log to its table, so the tables are
+/// as they were after the last statement that finished, then empties the
+/// log. Blocks of tables that have since been dropped are skipped.
+pub fn recover_log(db_path: &Path, tables: &HashMap<String, File>) -> Result<WriteAheadLog, Error> {
+    let mut wal = WriteAheadLog::open(db_path)?;
+    for ((table_name, offset), block) in wal.replay()? {
+        if let Some(table) = tables.get(&table_name) {
+            table.write_all_at(&block, offset * BLOCK_SIZE as u64)?;
+        }
+    }
+    for table in tables.values() {
+        table.sync_data()?;
+    }
+    wal.truncate()?;
+    Ok(wal)
+}
+
+fn log_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".wal");
+    PathBuf::from(path)
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0u8; 8];
+    LE::write_u64(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+/// 64-bit FNV-1a, plenty to spot a record that was only partly written.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+enum Record {
+    Block(String, u64, Box<Block>),
+    Commit,
+}
+
+struct LogReader<'a> {
+    log: &'a [u8],
+    at: usize,
+}
+
+impl LogReader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.log.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        Some(bytes)
+    }
+
+    fn take_u64(&mut self) -> Option<u64> {
+        self.take(8).map(LE::read_u64)
+    }
+
+    /// The next whole record, or None at the end of the log or at the first
+    /// record that doesn't check out.
+    fn next_record(&mut self) -> Option<Record> {
+        let start = self.at;
+        let record = match self.take_u64()? {
+            BLOCK_RECORD => {
+                let name_len = self.take_u64()? as usize;
+                let table_name = String::from_utf8(self.take(name_len)?.to_vec()).ok()?;
+                let offset = self.take_u64()?;
+                let block: Block = self.take(BLOCK_SIZE)?.try_into().ok()?;
+                Record::Block(table_name, offset, Box::new(block))
+            }
+            COMMIT_RECORD => Record::Commit,
+            _ => return None,
+        };
+        let end = self.at;
+        (self.take_u64()? == checksum(&self.log[start..end])).then_some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage_engine::cache::CACHE_BLOCKS;
+    use crate::test_utils::{temp_db, TempDir};
+
+    use super::*;
+
+    #[test]
+    fn test_replay_committed_blocks() {
+        let dir = TempDir::new("wal_replay");
+        let mut wal = WriteAheadLog::open(&dir.db_path()).unwrap();
+        let (first, second) = ([1u8; BLOCK_SIZE], [2u8; BLOCK_SIZE]);
+        wal.append([("heroes", 0, &first), ("purses", 3, &first)])
+            .unwrap();
+        wal.append([("heroes", 0, &second)]).unwrap();
+
+        let mut wal = WriteAheadLog::open(&dir.db_path()).unwrap();
+        let blocks = wal.replay().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[&("heroes".into(), 0)], second);
+        assert_eq!(blocks[&("purses".into(), 3)], first);
+
+        wal.truncate().unwrap();
+        assert!(WriteAheadLog::open(&dir.db_path())
+            .unwrap()
+            .replay()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_replay_stops_at_torn_commit() {
+        let dir = TempDir::new("wal_torn");
+        let mut wal = WriteAheadLog::open(&dir.db_path()).unwrap();
+        wal.append([("heroes", 0, &[1u8; BLOCK_SIZE])]).unwrap();
+        let committed = wal.size();
+        wal.append([
+            ("heroes", 0, &[2u8; BLOCK_SIZE]),
+            ("heroes", 1, &[2u8; BLOCK_SIZE]),
+        ])
+        .unwrap();
+
+        // A crash part way through the second commit's last block
+        wal.file.set_len(committed + BLOCK_SIZE as u64).unwrap();
+        let blocks = WriteAheadLog::open(&dir.db_path())
+            .unwrap()
+            .replay()
+            .unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[&("heroes".into(), 0)], [1u8; BLOCK_SIZE]);
+
+        // A block that's all there but scribbled on is no better
+        let mut wal = WriteAheadLog::open(&dir.db_path()).unwrap();
+        wal.truncate().unwrap();
+        wal.append([("heroes", 0, &[3u8; BLOCK_SIZE])]).unwrap();
+        wal.file.write_all_at(&[9u8], 100).unwrap();
+        assert!(wal.replay().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_statement_logged_whole() {
+        let (dir, db) = temp_db("wal_statement");
+        // More than half the pool's blocks, but too few for a checkpoint
+        let changed = CACHE_BLOCKS as u64 / 2 + 100;
+        for offset in 0..changed {
+            db.store_block_at("currency", offset, &[offset as u8; BLOCK_SIZE])
+                .unwrap();
+        }
+        // Nothing is logged until the statement is done
+        let replay = || {
+            WriteAheadLog::open(&dir.db_path())
+                .unwrap()
+                .replay()
+                .unwrap()
+        };
+        assert!(replay().is_empty());
+
+        db.flush().unwrap();
+        let blocks = replay();
+        assert_eq!(blocks.len() as u64, changed);
+        assert_eq!(
+            blocks[&("currency".into(), 600)],
+            [600u64 as u8; BLOCK_SIZE]
+        );
+    }
+}